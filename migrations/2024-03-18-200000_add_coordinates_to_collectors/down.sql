@@ -0,0 +1,2 @@
+ALTER TABLE collectors DROP COLUMN longitude;
+ALTER TABLE collectors DROP COLUMN latitude;
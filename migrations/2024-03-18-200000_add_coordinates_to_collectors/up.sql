@@ -0,0 +1,2 @@
+ALTER TABLE collectors ADD COLUMN latitude DOUBLE;
+ALTER TABLE collectors ADD COLUMN longitude DOUBLE;
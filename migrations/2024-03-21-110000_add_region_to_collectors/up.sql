@@ -0,0 +1 @@
+ALTER TABLE collectors ADD COLUMN region TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE collectors DROP COLUMN as_org;
+ALTER TABLE collectors DROP COLUMN asn;
@@ -0,0 +1,2 @@
+ALTER TABLE collectors ADD COLUMN asn INTEGER;
+ALTER TABLE collectors ADD COLUMN as_org TEXT;
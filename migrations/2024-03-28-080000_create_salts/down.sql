@@ -0,0 +1,4 @@
+DROP INDEX idx_collectors_visitor_hash;
+ALTER TABLE collectors DROP COLUMN visitor_hash;
+
+DROP TABLE salts;
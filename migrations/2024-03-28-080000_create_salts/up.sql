@@ -0,0 +1,7 @@
+CREATE TABLE salts (
+    day DATE PRIMARY KEY NOT NULL,
+    salt TEXT NOT NULL
+);
+
+ALTER TABLE collectors ADD COLUMN visitor_hash TEXT;
+CREATE INDEX idx_collectors_visitor_hash ON collectors (visitor_hash);
@@ -0,0 +1,5 @@
+DROP INDEX idx_collectors_asn;
+DROP INDEX idx_collectors_timestamp;
+DROP INDEX idx_events_collector_id_timestamp;
+DROP INDEX idx_events_name_timestamp;
+DROP INDEX idx_events_timestamp_name;
@@ -0,0 +1,7 @@
+-- Summaries filter every query on a time range, most of them on event names too
+CREATE INDEX idx_events_timestamp_name ON events (timestamp, name);
+CREATE INDEX idx_events_name_timestamp ON events (name, timestamp);
+CREATE INDEX idx_events_collector_id_timestamp ON events (collector_id, timestamp);
+CREATE INDEX idx_collectors_timestamp ON collectors (timestamp);
+-- Datacenter traffic is excluded by looking collectors up by network
+CREATE INDEX idx_collectors_asn ON collectors (asn);
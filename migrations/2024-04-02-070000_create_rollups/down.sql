@@ -0,0 +1,3 @@
+DROP TABLE rollups;
+DROP TABLE stats_daily;
+DROP TABLE stats_hourly;
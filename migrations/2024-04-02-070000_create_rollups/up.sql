@@ -0,0 +1,21 @@
+-- Event counts per url, leaving out measurement events like heartbeats and
+-- web vitals, so long-range summaries don't have to scan the events table
+CREATE TABLE stats_hourly (
+    hour TIMESTAMP NOT NULL,
+    url TEXT NOT NULL,
+    events INTEGER NOT NULL,
+    PRIMARY KEY (hour, url)
+);
+
+CREATE TABLE stats_daily (
+    day TIMESTAMP NOT NULL,
+    url TEXT NOT NULL,
+    events INTEGER NOT NULL,
+    PRIMARY KEY (day, url)
+);
+
+-- End of the last complete period in each rollup table
+CREATE TABLE rollups (
+    name TEXT PRIMARY KEY NOT NULL,
+    rolled_up_to TIMESTAMP NOT NULL
+);
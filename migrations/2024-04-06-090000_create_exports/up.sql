@@ -0,0 +1,5 @@
+-- How far each scheduled export got, events before `exported_to` were sent
+CREATE TABLE exports (
+    name TEXT PRIMARY KEY NOT NULL,
+    exported_to TIMESTAMP NOT NULL
+);
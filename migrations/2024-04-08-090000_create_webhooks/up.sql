@@ -0,0 +1,12 @@
+-- URLs that are POSTed to when traffic matches their rule. `triggered` holds
+-- whether the rule matched at the last check, so each match is sent once.
+CREATE TABLE webhooks (
+    id TEXT PRIMARY KEY NOT NULL,
+    url TEXT NOT NULL,
+    rule TEXT NOT NULL,
+    threshold BIGINT NOT NULL DEFAULT 0,
+    window_minutes INTEGER NOT NULL DEFAULT 60,
+    triggered BOOLEAN NOT NULL DEFAULT FALSE,
+    last_fired_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL
+);
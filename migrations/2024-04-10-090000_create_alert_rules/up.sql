@@ -0,0 +1,17 @@
+-- Rules checked by the `alerts` job, e.g. `pageviews` over the last 60
+-- minutes `<` 10. A notification is POSTed to `destination` when a rule
+-- starts matching and again when it stops.
+CREATE TABLE alert_rules (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    comparison TEXT NOT NULL,
+    threshold BIGINT NOT NULL,
+    window_minutes INTEGER NOT NULL,
+    destination TEXT NOT NULL,
+    triggered BOOLEAN NOT NULL DEFAULT FALSE,
+    last_value BIGINT,
+    last_checked_at TIMESTAMP,
+    last_fired_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,18 @@
+-- Dashboard logins. Once a user exists, the dashboard and API need a session.
+CREATE TABLE users (
+    id TEXT PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+-- Logged in browsers, keyed by the SHA-256 of the session cookie so a copy
+-- of the database can't be used to log in
+CREATE TABLE user_sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_user_sessions_user_id ON user_sessions (user_id);
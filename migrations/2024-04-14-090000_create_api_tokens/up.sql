@@ -0,0 +1,11 @@
+-- Tokens for scripts and integrations, sent as `Authorization: Bearer`.
+-- Only the SHA-256 of the token is stored, it is shown once when created.
+CREATE TABLE api_tokens (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TIMESTAMP,
+    last_used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL
+);
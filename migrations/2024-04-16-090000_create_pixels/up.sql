@@ -0,0 +1,7 @@
+-- Named tracking pixels, e.g. one per newsletter issue. Each open is an
+-- `email_open` event with the pixel's url.
+CREATE TABLE pixels (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,3 @@
+DROP INDEX idx_events_host_timestamp;
+ALTER TABLE events DROP COLUMN path;
+ALTER TABLE events DROP COLUMN host;
@@ -0,0 +1,24 @@
+-- The host and path of `url`, so summaries can filter by host. Existing
+-- events are split here, new ones when they are recorded.
+ALTER TABLE events ADD COLUMN host TEXT;
+ALTER TABLE events ADD COLUMN path TEXT;
+
+UPDATE events SET
+host = SUBSTR(
+    SUBSTR(url, INSTR(url, '://') + 3),
+    1,
+    INSTR(SUBSTR(url, INSTR(url, '://') + 3) || '/', '/') - 1
+),
+path = SUBSTR(
+    SUBSTR(url, INSTR(url, '://') + 3),
+    INSTR(SUBSTR(url, INSTR(url, '://') + 3) || '/', '/')
+)
+WHERE INSTR(url, '://') > 0;
+
+UPDATE events SET path = SUBSTR(path, 1, INSTR(path, '?') - 1) WHERE INSTR(path, '?') > 0;
+UPDATE events SET path = SUBSTR(path, 1, INSTR(path, '#') - 1) WHERE INSTR(path, '#') > 0;
+UPDATE events SET path = RTRIM(path, '/') WHERE path LIKE '%/';
+UPDATE events SET path = '/' WHERE path = '' AND host IS NOT NULL;
+UPDATE events SET host = NULL, path = NULL WHERE host = '';
+
+CREATE INDEX idx_events_host_timestamp ON events (host, timestamp);
@@ -0,0 +1,8 @@
+-- Rewrites of url paths applied when events are recorded, in the order they
+-- were created, e.g. `^/users/\d+` to `/users/:id`
+CREATE TABLE url_rules (
+    id TEXT PRIMARY KEY NOT NULL,
+    pattern TEXT NOT NULL,
+    replacement TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Sections of a site summaries can be grouped by, e.g. `/blog/*` as "Blog".
+-- Patterns are SQLite globs matched against the path of events.
+CREATE TABLE url_groups (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    pattern TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
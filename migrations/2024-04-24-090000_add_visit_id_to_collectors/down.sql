@@ -0,0 +1,2 @@
+DROP INDEX idx_collectors_visit_id;
+ALTER TABLE collectors DROP COLUMN visit_id;
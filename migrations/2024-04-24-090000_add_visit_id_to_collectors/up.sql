@@ -0,0 +1,35 @@
+-- The first collector of the visit a collector belongs to, so collectors
+-- of the same visitor (a new tab, an expired script) can be stitched into
+-- one visit. NULL is a visit of its own. Existing collectors are linked
+-- here when their visitor started another within 30 minutes of the last
+-- one, new ones when they are created.
+ALTER TABLE collectors ADD COLUMN visit_id TEXT;
+
+WITH ordered AS (
+    SELECT id, visitor_hash, origin, timestamp,
+    CASE WHEN LAG(timestamp) OVER visitor IS NULL
+        OR julianday(timestamp) - julianday(LAG(timestamp) OVER visitor) > 30.0 / 1440
+        THEN 1 ELSE 0 END AS starts_visit
+    FROM collectors
+    WHERE visitor_hash IS NOT NULL
+    WINDOW visitor AS (PARTITION BY visitor_hash, origin ORDER BY timestamp, id)
+),
+numbered AS (
+    SELECT id, visitor_hash, origin, timestamp,
+    SUM(starts_visit) OVER (
+        PARTITION BY visitor_hash, origin ORDER BY timestamp, id
+        ROWS UNBOUNDED PRECEDING
+    ) AS visit
+    FROM ordered
+),
+visits AS (
+    SELECT id, FIRST_VALUE(id) OVER (
+        PARTITION BY visitor_hash, origin, visit ORDER BY timestamp, id
+    ) AS visit_id
+    FROM numbered
+)
+UPDATE collectors
+SET visit_id = (SELECT visit_id FROM visits WHERE visits.id = collectors.id)
+WHERE visitor_hash IS NOT NULL;
+
+CREATE INDEX idx_collectors_visit_id ON collectors (visit_id);
@@ -0,0 +1 @@
+DROP INDEX idx_events_path_collector_id;
@@ -0,0 +1,3 @@
+-- Summaries narrowed down to sessions that viewed a page look their
+-- collectors up by path
+CREATE INDEX idx_events_path_collector_id ON events (path, collector_id);
@@ -0,0 +1,4 @@
+DROP TRIGGER urls_add_search;
+DROP TRIGGER events_add_url;
+DROP TABLE url_search;
+DROP TABLE urls;
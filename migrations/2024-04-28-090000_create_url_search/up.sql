@@ -0,0 +1,30 @@
+-- Every url events were recorded on, once, so searching urls doesn't have
+-- to scan the events table. Rows stay when their events are deleted, as
+-- rollups keep counting them.
+CREATE TABLE urls (
+    id INTEGER PRIMARY KEY NOT NULL,
+    url TEXT NOT NULL UNIQUE
+);
+
+-- Substring search over `urls`, the trigram tokenizer matches any part of
+-- three characters or more regardless of case
+CREATE VIRTUAL TABLE url_search USING fts5(
+    url,
+    content = 'urls',
+    content_rowid = 'id',
+    tokenize = 'trigram'
+);
+
+CREATE TRIGGER events_add_url AFTER INSERT ON events BEGIN
+    INSERT OR IGNORE INTO urls (url) VALUES (NEW.url);
+END;
+
+-- Ignored duplicates don't fire insert triggers, so each url is indexed once
+CREATE TRIGGER urls_add_search AFTER INSERT ON urls BEGIN
+    INSERT INTO url_search (rowid, url) VALUES (NEW.id, NEW.url);
+END;
+
+INSERT OR IGNORE INTO urls (url)
+SELECT url FROM events
+UNION
+SELECT url FROM stats_daily;
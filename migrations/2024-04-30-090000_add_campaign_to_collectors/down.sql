@@ -0,0 +1,2 @@
+ALTER TABLE collectors DROP COLUMN utm_campaign;
+ALTER TABLE collectors DROP COLUMN utm_source;
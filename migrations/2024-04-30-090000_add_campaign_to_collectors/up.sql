@@ -0,0 +1,5 @@
+-- The utm_source and utm_campaign of the page a collector entered on, so
+-- revenue can be attributed to campaigns. They are taken from the url
+-- before its query is stripped and stay NULL without them.
+ALTER TABLE collectors ADD COLUMN utm_source TEXT;
+ALTER TABLE collectors ADD COLUMN utm_campaign TEXT;
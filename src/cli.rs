@@ -0,0 +1,251 @@
+use crate::config::Config;
+use crate::db::establish_connection_pool;
+use crate::schema::users;
+use crate::utils::archive::{archive_events, Archive};
+use crate::utils::auth::save_user;
+use crate::utils::backup;
+use crate::utils::city::{backfill_coordinates, Cities};
+use crate::utils::clickhouse::ClickHouse;
+use crate::utils::export::{day_range, export_events, ExportFormat};
+use crate::utils::maintenance::enable_incremental_vacuum;
+use crate::utils::queue::replay_dead_letters;
+use crate::utils::retention::prune;
+use crate::utils::rollup::rebuild_rollups;
+use crate::utils::seed::seed;
+use crate::utils::umami;
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use diesel::prelude::*;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+#[derive(Parser)]
+#[command(version, about = "Self-hosted analytics server")]
+pub struct Cli {
+    /// Config file to read, defaults to STATS_CONFIG or stats.toml when it exists
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the HTTP server (the default)
+    Serve,
+    /// Apply pending database migrations
+    Migrate,
+    /// Delete events older than the given number of days, and visitors left without events
+    Prune {
+        #[arg(long)]
+        older_than_days: usize,
+    },
+    /// Move events older than the given number of days to ARCHIVE_URL as Parquet files
+    Archive {
+        /// Defaults to ARCHIVE_AFTER_DAYS
+        #[arg(long)]
+        older_than_days: Option<usize>,
+    },
+    /// Write events with their visitor details to a file or stdout
+    Export {
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ExportFormat,
+        /// First day to include, e.g. 2024-03-01 (defaults to everything)
+        #[arg(long)]
+        from: Option<NaiveDate>,
+        /// Last day to include (defaults to today)
+        #[arg(long)]
+        to: Option<NaiveDate>,
+        /// File to write to instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Recount the hourly and daily rollups, e.g. after importing events
+    Rollup {
+        /// First day to recount (defaults to the oldest event)
+        #[arg(long)]
+        from: Option<NaiveDate>,
+    },
+    /// Insert the events of a dead-letter file, see DEAD_LETTER_FILE
+    Replay {
+        /// Defaults to DEAD_LETTER_FILE
+        path: Option<PathBuf>,
+    },
+    /// Copy the sessions and events of an Umami database into Stats
+    ImportUmami {
+        /// SQLite file or postgres:// connection URL of the Umami database
+        source: String,
+    },
+    /// Add a dashboard user, or change the password of an existing one. Once
+    /// a user exists, the dashboard and API require a login.
+    CreateUser { username: String },
+    /// Remove a dashboard user and log them out
+    DeleteUser { username: String },
+    /// Fill the database with made-up visits for development
+    Seed {
+        #[arg(long, default_value_t = 500)]
+        visitors: usize,
+        #[arg(long, default_value_t = 30)]
+        days: usize,
+        #[arg(long, default_value = "http://localhost:5775")]
+        origin: String,
+    },
+    /// Rewrite the database file and switch it to incremental vacuuming, so
+    /// the `vacuum` job can give the space of deleted rows back
+    Vacuum,
+    /// Store coordinates on visitors recorded without them, looked up by
+    /// their city, so they show up on the map
+    BackfillCoordinates,
+    /// Write a snapshot of the database to <PATH>
+    Backup { path: PathBuf },
+    /// Replace the database contents with the backup at <PATH>
+    Restore { path: PathBuf },
+}
+
+// Runs every command except `serve`. They share the server's pool options,
+// so WAL and the busy timeout apply and they can run next to a live server.
+pub async fn run(config: &Config, command: Command) -> io::Result<()> {
+    let pool = establish_connection_pool(config);
+    let mut conn = pool.get().map_err(io::Error::other)?;
+
+    match command {
+        Command::Serve => unreachable!("serve is handled in main"),
+        Command::Migrate => {
+            let applied = conn
+                .run_pending_migrations(MIGRATIONS)
+                .map_err(io::Error::other)?;
+            for migration in &applied {
+                println!("Applied {}", migration);
+            }
+            println!("{} migrations applied", applied.len());
+        }
+        Command::Prune { older_than_days } => {
+            let (events, collectors) =
+                prune(&mut conn, older_than_days).map_err(io::Error::other)?;
+            println!("Deleted {} events and {} visitors", events, collectors);
+        }
+        Command::Archive { older_than_days } => {
+            if config.archive_url.is_empty() {
+                return Err(io::Error::other("ARCHIVE_URL is not set"));
+            }
+            let days = older_than_days.unwrap_or(config.archive_after_days);
+            let archive = Archive::new(&config.archive_url, &config.object_store_options)
+                .map_err(io::Error::other)?;
+            let archived = archive_events(&pool, &archive, days, config.bigquery_enabled())
+                .await
+                .map_err(io::Error::other)?;
+            println!("Archived {} events", archived);
+        }
+        Command::Export {
+            format,
+            from,
+            to,
+            output,
+        } => {
+            let (from, to) = day_range(from, to);
+
+            let exported = match &output {
+                Some(path) => export_events(&mut conn, from, to, format, File::create(path)?),
+                None => export_events(&mut conn, from, to, format, io::stdout().lock()),
+            }
+            .map_err(io::Error::other)?;
+            eprintln!("Exported {} events", exported);
+        }
+        Command::Rollup { from } => {
+            let from = from.map(|day| day.and_hms_opt(0, 0, 0).unwrap());
+            let rows = rebuild_rollups(&mut conn, from).map_err(io::Error::other)?;
+            println!("Wrote {} hourly rollup rows", rows);
+        }
+        Command::Replay { path } => {
+            let path = path.unwrap_or_else(|| PathBuf::from(&config.dead_letter_file));
+            let clickhouse = ClickHouse::from_config(config);
+            let inserted = replay_dead_letters(&mut conn, config.event_store, &clickhouse, &path)
+                .await
+                .map_err(io::Error::other)?;
+            println!("Inserted {} events from {}", inserted, path.display());
+        }
+        Command::ImportUmami { source } => {
+            let mut source = umami::Source::open(&source)
+                .await
+                .map_err(io::Error::other)?;
+            let (visitors, events) = umami::import(&mut conn, &mut source)
+                .await
+                .map_err(io::Error::other)?;
+            println!("Imported {} events from {} visitors", events, visitors);
+        }
+        Command::CreateUser { username } => {
+            let password = read_password()?;
+            let user = save_user(&mut conn, &username, &password).map_err(io::Error::other)?;
+            println!("Saved user {}", user.username);
+        }
+        Command::DeleteUser { username } => {
+            let deleted = delete_user(&mut conn, &username).map_err(io::Error::other)?;
+            if deleted == 0 {
+                return Err(io::Error::other(format!("No user {}", username)));
+            }
+            println!("Deleted user {}", username);
+        }
+        Command::Seed {
+            visitors,
+            days,
+            origin,
+        } => {
+            let inserted = seed(&mut conn, &origin, visitors, days).map_err(io::Error::other)?;
+            println!("Inserted {} events from {} visitors", inserted, visitors);
+        }
+        Command::BackfillCoordinates => {
+            let cities = Cities::load(&config.city_data);
+            if !cities.is_loaded() {
+                return Err(io::Error::other(format!(
+                    "No city data at CITY_DATA ({})",
+                    config.city_data
+                )));
+            }
+            let updated = backfill_coordinates(&mut conn, &cities).map_err(io::Error::other)?;
+            println!("Stored coordinates on {} visitors", updated);
+        }
+        Command::Vacuum => {
+            enable_incremental_vacuum(&mut conn).map_err(io::Error::other)?;
+            println!("Vacuumed database, incremental vacuum is enabled");
+        }
+        Command::Backup { path } => {
+            backup::snapshot(&mut conn, &path).map_err(io::Error::other)?;
+            println!("Backed up database to {}", path.display());
+        }
+        Command::Restore { path } => {
+            backup::restore(&mut conn, &path).map_err(io::Error::other)?;
+            println!("Restored database from {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+// STATS_PASSWORD for scripts, otherwise a line from stdin
+fn read_password() -> io::Result<String> {
+    if let Ok(password) = std::env::var("STATS_PASSWORD") {
+        return Ok(password);
+    }
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn delete_user(conn: &mut SqliteConnection, username: &str) -> QueryResult<usize> {
+    use crate::schema::user_sessions;
+
+    conn.transaction(|conn| {
+        let ids = users::table
+            .filter(users::username.eq(username))
+            .select(users::id);
+        diesel::delete(user_sessions::table.filter(user_sessions::user_id.eq_any(ids)))
+            .execute(conn)?;
+        diesel::delete(users::table.filter(users::username.eq(username))).execute(conn)
+    })
+}
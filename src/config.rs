@@ -1,56 +1,797 @@
-use dotenv::dotenv;
+use crate::models::BUILTIN_EVENT_NAMES;
+use crate::utils::url::host_and_path;
+use ipnet::IpNet;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_CONFIG_FILE: &str = "stats.toml";
+
+// What happens to custom events whose name isn't on the site's allowlist
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownEventNames {
+    Reject,
+    Other,
+}
+
+// What happens to events with a url, referrer or name over its maximum length
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedFields {
+    Truncate,
+    Reject,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+// Which forwarding chain the trusted proxies append the visitor address to
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpChain {
+    XForwardedFor,
+    Forwarded,
+}
+
+// Where the locations of visitors are looked up
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GeoipProvider {
+    Maxmind,
+    Ip2location,
+    None,
+}
+
+// Where recorded events are written besides SQLite. Visitors and most
+// summaries only ever read SQLite, so there is no ClickHouse-only store.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventStore {
+    Sqlite,
+    Both,
+}
+
+impl EventStore {
+    // Time-series summaries are read from ClickHouse whenever it gets events
+    pub fn writes_clickhouse(&self) -> bool {
+        matches!(self, EventStore::Both)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamBroker {
+    Kafka,
+    Nats,
+}
+
+impl StreamBroker {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamBroker::Kafka => "kafka",
+            StreamBroker::Nats => "nats",
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct Config {
     pub app_url: String,
     pub service_port: String,
     pub database_url: String,
+    pub database_key: Option<String>,
     pub cors_domains: Vec<String>,
+    pub script_path: String,
+    pub collect_path: String,
+    pub db_pool_size: u32,
+    pub queue_capacity: usize,
+    pub udp_listen_address: String,
     pub processing_batch_size: usize,
+    pub processing_batch_timeout_secs: u64,
+    pub retry_max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub dead_letter_file: String,
+    pub event_store: EventStore,
+    pub clickhouse_url: String,
+    pub clickhouse_database: String,
+    pub clickhouse_user: String,
+    pub clickhouse_password: String,
+    pub event_stream: Option<StreamBroker>,
+    pub event_stream_servers: String,
+    pub event_stream_topic: String,
     pub is_development: bool,
+    pub download_extensions: Vec<String>,
+    pub reporting_currency: String,
+    pub currency_rates: HashMap<String, f64>,
+    pub exclude_datacenters: bool,
+    pub datacenter_asns: Vec<u32>,
+    pub geoip_provider: GeoipProvider,
+    pub geoip_database: String,
+    pub geoip_fallback_database: String,
+    pub geoip_asn_database: String,
+    pub city_data: String,
+    pub geo_locale: String,
+    pub anonymize_ip: bool,
+    pub dedupe_visitors: bool,
+    pub anonymize_after_days: usize,
+    pub archive_after_days: usize,
+    pub archive_url: String,
+    pub bigquery_credentials: String,
+    pub bigquery_project: String,
+    pub bigquery_dataset: String,
+    pub bigquery_table: String,
+    pub share_link_secret: String,
+    pub public_stats_sites: Vec<String>,
+    pub redirect_domains: Vec<String>,
+    pub session_lifetime_hours: usize,
+    pub blocked_ips: Vec<IpNet>,
+    pub trusted_proxies: Vec<IpNet>,
+    // Lowercase name of a single address header the trusted proxies always
+    // overwrite, e.g. cf-connecting-ip, or empty to walk the forwarding chain
+    pub client_ip_header: String,
+    pub client_ip_chain: ClientIpChain,
+    pub referrer_spam_domains: Vec<String>,
+    pub referrer_spam_list_url: String,
+    pub allowed_event_names: HashMap<String, HashSet<String>>,
+    pub keep_query_params: HashMap<String, HashSet<String>>,
+    pub sample_rates: HashMap<String, f64>,
+    pub unknown_event_names: UnknownEventNames,
+    pub max_url_length: usize,
+    pub max_referrer_length: usize,
+    pub max_event_name_length: usize,
+    pub oversized_fields: OversizedFields,
+    pub max_events_per_collector_hour: usize,
+    pub collector_rate_limit: usize,
+    pub daily_event_quotas: HashMap<String, usize>,
+    pub summary_cache_seconds: usize,
+    pub log_format: LogFormat,
+    pub log_file: String,
+    pub log_rotation: LogRotation,
+    pub log_max_files: usize,
+    // In megabytes, 0 for no limit
+    pub log_max_size: usize,
+    // RUST_LOG, read here as `.env` doesn't end up in the environment
+    pub log_filter: String,
+    // AWS_* settings for the archive's object store, keys in lowercase
+    pub object_store_options: Vec<(String, String)>,
+    pub backup_dir: String,
+    pub backup_retention: usize,
+    pub job_intervals: HashMap<String, Duration>,
+    pub job_jitter_secs: u64,
 }
 
 // TODO: potentially replace this with arctix settings later
 impl Config {
-    pub fn new() -> Self {
-        dotenv().ok();
+    // Reads the settings from `stats.toml` (or the file given by `--config` or
+    // STATS_CONFIG) and the environment, environment variables taking
+    // precedence. The file is optional unless a path was given explicitly.
+    pub fn load(path: Option<&Path>) -> Self {
+        let dotenv = read_dotenv();
+
+        let explicit = path.map(Path::to_path_buf).or_else(|| {
+            env::var("STATS_CONFIG")
+                .ok()
+                .or_else(|| dotenv.get("STATS_CONFIG").cloned())
+                .map(PathBuf::from)
+        });
+        let mut settings = match explicit {
+            Some(path) => Settings::from_file(&path),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Settings::from_file(Path::new(DEFAULT_CONFIG_FILE))
+            }
+            None => Settings::default(),
+        };
+        settings.dotenv = dotenv;
+
+        // GEOIP_ENABLED=false predates GEOIP_PROVIDER and still turns lookups off
+        let geoip_provider = match settings.get_env("GEOIP_PROVIDER", "maxmind").as_str() {
+            _ if !settings.get_env_bool("GEOIP_ENABLED", true) => GeoipProvider::None,
+            "maxmind" => GeoipProvider::Maxmind,
+            "ip2location" => GeoipProvider::Ip2location,
+            "none" => GeoipProvider::None,
+            _ => panic!("Failed to parse GEOIP_PROVIDER"),
+        };
 
         Config {
-            app_url: Self::get_env("APP_URL", "127.0.0.1:8080"),
-            service_port: Self::get_env("SERVICE_PORT", "5775"),
-            database_url: Self::get_env("DATABASE_URL", "/data/stats.sqlite"),
-            cors_domains: Self::get_env_list("CORS_DOMAINS", ""),
-            processing_batch_size: Self::get_env_usize("PROCESSING_BATCH_SIZE", 4),
-            is_development: Self::get_env_bool("IS_DEVELOPMENT", false),
+            app_url: settings.get_env("APP_URL", "127.0.0.1:8080"),
+            service_port: settings.get_env("SERVICE_PORT", "5775"),
+            database_url: settings.get_env("DATABASE_URL", "/data/stats.sqlite"),
+            database_key: Some(settings.get_env("DATABASE_KEY", "")).filter(|key| !key.is_empty()),
+            cors_domains: settings.get_env_list("CORS_DOMAINS", ""),
+            script_path: settings.get_env_path("SCRIPT_PATH", "/stats.js"),
+            collect_path: settings.get_env_path("COLLECT_PATH", "/collect"),
+            retry_max_attempts: settings.get_env_usize("RETRY_MAX_ATTEMPTS", 5) as u32,
+            retry_base_delay_ms: settings.get_env_usize("RETRY_BASE_DELAY_MS", 200) as u64,
+            dead_letter_file: settings.get_env("DEAD_LETTER_FILE", "data/dead-letters.jsonl"),
+            event_store: match settings.get_env("EVENT_STORE", "sqlite").as_str() {
+                "sqlite" => EventStore::Sqlite,
+                "both" => EventStore::Both,
+                "clickhouse" => panic!(
+                    "EVENT_STORE=clickhouse is not supported, most summaries read events from SQLite. Use EVENT_STORE=both"
+                ),
+                _ => panic!("Failed to parse EVENT_STORE"),
+            },
+            clickhouse_url: settings.get_env("CLICKHOUSE_URL", "http://localhost:8123"),
+            clickhouse_database: settings.get_env("CLICKHOUSE_DATABASE", "default"),
+            clickhouse_user: settings.get_env("CLICKHOUSE_USER", "default"),
+            clickhouse_password: settings.get_env("CLICKHOUSE_PASSWORD", ""),
+            event_stream: match settings.get_env("EVENT_STREAM", "").as_str() {
+                "" => None,
+                "kafka" => Some(StreamBroker::Kafka),
+                "nats" => Some(StreamBroker::Nats),
+                _ => panic!("Failed to parse EVENT_STREAM"),
+            },
+            event_stream_servers: settings.get_env("EVENT_STREAM_SERVERS", "localhost:9092"),
+            event_stream_topic: settings.get_env("EVENT_STREAM_TOPIC", "stats.events"),
+            db_pool_size: settings.get_env_usize("DB_POOL_SIZE", 16) as u32,
+            queue_capacity: settings.get_env_usize("QUEUE_CAPACITY", 500).max(1),
+            udp_listen_address: settings.get_env("UDP_LISTEN_ADDRESS", ""),
+            processing_batch_size: settings.get_env_usize("PROCESSING_BATCH_SIZE", 100).max(1),
+            processing_batch_timeout_secs: settings.get_env_usize("PROCESSING_BATCH_TIMEOUT_SECS", 5)
+                .max(1) as u64,
+            is_development: settings.get_env_bool("IS_DEVELOPMENT", false),
+            download_extensions: settings.get_env_list(
+                "DOWNLOAD_EXTENSIONS",
+                "pdf,zip,dmg,exe,msi,pkg,deb,rpm,gz,tgz,7z,rar,csv,xlsx,docx,pptx,mp3,mp4",
+            )
+            .into_iter()
+            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+            .collect(),
+            reporting_currency: settings.get_env("REPORTING_CURRENCY", "USD").to_uppercase(),
+            currency_rates: settings.get_env_rates("CURRENCY_RATES", ""),
+            exclude_datacenters: settings.get_env_bool("EXCLUDE_DATACENTERS", false),
+            // AWS, Google Cloud, Azure, DigitalOcean, Hetzner, OVH, Linode,
+            // Vultr, Oracle Cloud and Alibaba Cloud
+            datacenter_asns: settings.get_env_list(
+                "DATACENTER_ASNS",
+                "16509,14618,15169,396982,8075,14061,24940,16276,63949,20473,31898,45102",
+            )
+            .into_iter()
+            .map(|asn| {
+                asn.parse()
+                    .unwrap_or_else(|_| panic!("Failed to parse DATACENTER_ASNS"))
+            })
+            .collect(),
+            geoip_provider,
+            geoip_database: settings.get_env(
+                "GEOIP_DATABASE",
+                match geoip_provider {
+                    GeoipProvider::Ip2location => "data/IP2LOCATION-LITE-DB5.BIN",
+                    _ => "data/GeoLite2-City.mmdb",
+                },
+            ),
+            geoip_fallback_database: settings.get_env(
+                "GEOIP_FALLBACK_DATABASE",
+                "data/dbip-country-lite.csv",
+            ),
+            geoip_asn_database: settings.get_env("GEOIP_ASN_DATABASE", "data/GeoLite2-ASN.mmdb"),
+            city_data: settings.get_env("CITY_DATA", "data/cities5000.txt"),
+            geo_locale: settings.get_env("GEO_LOCALE", "en"),
+            anonymize_ip: settings.get_env_bool("ANONYMIZE_IP", false),
+            dedupe_visitors: settings.get_env_bool("DEDUPE_VISITORS", true),
+            anonymize_after_days: settings.get_env_usize("ANONYMIZE_AFTER_DAYS", 0),
+            archive_after_days: settings.get_env_usize("ARCHIVE_AFTER_DAYS", 0),
+            archive_url: settings.get_env("ARCHIVE_URL", ""),
+            bigquery_credentials: settings.get_env("BIGQUERY_CREDENTIALS", ""),
+            bigquery_project: settings.get_env("BIGQUERY_PROJECT", ""),
+            bigquery_dataset: settings.get_env("BIGQUERY_DATASET", ""),
+            bigquery_table: settings.get_env("BIGQUERY_TABLE", "events"),
+            share_link_secret: settings.get_env("SHARE_LINK_SECRET", ""),
+            public_stats_sites: settings.get_env_list("PUBLIC_STATS_SITES", ""),
+            redirect_domains: settings.get_env_list("REDIRECT_DOMAINS", ""),
+            session_lifetime_hours: settings.get_env_usize("SESSION_LIFETIME_HOURS", 720),
+            blocked_ips: settings.get_env_networks("BLOCKED_IPS", ""),
+            trusted_proxies: settings.get_env_networks("TRUSTED_PROXIES", "127.0.0.1,::1"),
+            client_ip_header: settings
+                .get_env("CLIENT_IP_HEADER", "")
+                .trim()
+                .to_ascii_lowercase(),
+            client_ip_chain: match settings
+                .get_env("CLIENT_IP_CHAIN", "x-forwarded-for")
+                .trim()
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "x-forwarded-for" => ClientIpChain::XForwardedFor,
+                "forwarded" => ClientIpChain::Forwarded,
+                _ => panic!("Failed to parse CLIENT_IP_CHAIN"),
+            },
+            referrer_spam_domains: settings.get_env_list(
+                "REFERRER_SPAM_DOMAINS",
+                "semalt.com,buttons-for-website.com,darodar.com,ilovevitaly.com,priceg.com,best-seo-offer.com,free-social-buttons.com,get-free-traffic-now.com",
+            ),
+            referrer_spam_list_url: settings.get_env("REFERRER_SPAM_LIST_URL", ""),
+            allowed_event_names: settings.get_env_allowlist("ALLOWED_EVENT_NAMES", ""),
+            keep_query_params: settings.get_env_allowlist("KEEP_QUERY_PARAMS", ""),
+            sample_rates: settings.get_env_sample_rates("SAMPLE_RATES", ""),
+            unknown_event_names: match settings.get_env("UNKNOWN_EVENT_NAMES", "reject").as_str() {
+                "reject" => UnknownEventNames::Reject,
+                "other" => UnknownEventNames::Other,
+                _ => panic!("Failed to parse UNKNOWN_EVENT_NAMES"),
+            },
+            max_url_length: settings.get_env_usize("MAX_URL_LENGTH", 2048),
+            max_referrer_length: settings.get_env_usize("MAX_REFERRER_LENGTH", 2048),
+            max_event_name_length: settings.get_env_usize("MAX_EVENT_NAME_LENGTH", 100),
+            oversized_fields: match settings.get_env("OVERSIZED_FIELDS", "truncate").as_str() {
+                "truncate" => OversizedFields::Truncate,
+                "reject" => OversizedFields::Reject,
+                _ => panic!("Failed to parse OVERSIZED_FIELDS"),
+            },
+            max_events_per_collector_hour: settings
+                .get_env_usize("MAX_EVENTS_PER_COLLECTOR_HOUR", 1000),
+            collector_rate_limit: settings.get_env_usize("COLLECTOR_RATE_LIMIT", 60),
+            daily_event_quotas: settings.get_env_quotas("DAILY_EVENT_QUOTAS", ""),
+            summary_cache_seconds: settings.get_env_usize("SUMMARY_CACHE_SECONDS", 15),
+            log_format: match settings.get_env("LOG_FORMAT", "text").as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                _ => panic!("Failed to parse LOG_FORMAT"),
+            },
+            log_file: settings.get_env("LOG_FILE", ""),
+            log_rotation: match settings.get_env("LOG_ROTATION", "daily").as_str() {
+                "hourly" => LogRotation::Hourly,
+                "daily" => LogRotation::Daily,
+                "never" => LogRotation::Never,
+                _ => panic!("Failed to parse LOG_ROTATION"),
+            },
+            log_max_files: settings.get_env_usize("LOG_MAX_FILES", 14),
+            log_max_size: settings.get_env_usize("LOG_MAX_SIZE", 0),
+            log_filter: settings.get_env("RUST_LOG", "error"),
+            object_store_options: settings.get_prefixed("AWS_"),
+            backup_dir: settings.get_env("BACKUP_DIR", "data/backups"),
+            backup_retention: settings.get_env_usize("BACKUP_RETENTION", 7),
+            job_intervals: settings.get_env_intervals(
+                "JOB_INTERVALS",
+                &format!(
+                    "salt:1h,anonymize:1h,archive:1d,rollups:1h,bigquery:1d,webhooks:5m,alerts:5m,wal-checkpoint:1h,vacuum:1d,referrer-spam-list:1d,geoip-refresh:1h,backup:{}h",
+                    settings.get_env_usize("BACKUP_INTERVAL_HOURS", 24)
+                ),
+            ),
+            job_jitter_secs: settings.get_env_usize("JOB_JITTER_SECS", 30) as u64,
+        }
+    }
+
+    // Whether /r may send visitors to `url`: an http(s) url on one of the
+    // REDIRECT_DOMAINS or their subdomains
+    pub fn redirect_allowed(&self, url: &Url) -> bool {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        self.redirect_domains.iter().any(|domain| {
+            host == domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+
+    // Whether a custom event name is allowed on the site `url` belongs to.
+    // Built-in events are always allowed, as is anything on sites without a list.
+    pub fn event_name_allowed(&self, url: &str, name: &str) -> bool {
+        if BUILTIN_EVENT_NAMES.contains(&name) {
+            return true;
+        }
+
+        let origin = Url::parse(url)
+            .map(|url| url.origin().ascii_serialization())
+            .unwrap_or_default();
+        match self
+            .allowed_event_names
+            .get(&origin)
+            .or_else(|| self.allowed_event_names.get("*"))
+        {
+            Some(names) => names.contains(name),
+            None => true,
+        }
+    }
+
+    // The query parameters kept in the urls of the site `url` belongs to,
+    // None when all of them are stripped
+    pub fn kept_query_params(&self, url: &Url) -> Option<&HashSet<String>> {
+        self.keep_query_params
+            .get(&url.origin().ascii_serialization())
+            .or_else(|| self.keep_query_params.get("*"))
+    }
+
+    // The share of visitors recorded on the site with `origin`, 1 for all
+    pub fn sample_rate(&self, origin: &str) -> f64 {
+        self.sample_rates
+            .get(origin)
+            .or_else(|| self.sample_rates.get("*"))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    // What counts of the site on `host` are multiplied by to estimate all of
+    // its traffic. Without a host that is only known when every site has the
+    // same rate, otherwise counts are left as recorded.
+    pub fn sample_scale(&self, host: Option<&str>) -> f64 {
+        let default = self.sample_rates.get("*").copied().unwrap_or(1.0);
+        let rate = match host {
+            Some(host) => self
+                .sample_rates
+                .iter()
+                .find(|(origin, _)| host_and_path(origin).0.as_deref() == Some(host))
+                .map_or(default, |(_, rate)| *rate),
+            None if self.sample_rates.values().all(|rate| *rate == default) => default,
+            None => 1.0,
+        };
+        1.0 / rate
+    }
+
+    // How many events the site with `origin` may record a day, None for
+    // any number
+    pub fn daily_event_quota(&self, origin: &str) -> Option<usize> {
+        self.daily_event_quotas
+            .get(origin)
+            .or_else(|| self.daily_event_quotas.get("*"))
+            .copied()
+    }
+
+    // Whether the `bigquery` job exports events
+    pub fn bigquery_enabled(&self) -> bool {
+        !self.bigquery_dataset.is_empty() && !self.bigquery_credentials.is_empty()
+    }
+
+    // How often the named background job runs, None when it is disabled
+    pub fn job_interval(&self, name: &str) -> Option<Duration> {
+        self.job_intervals
+            .get(name)
+            .copied()
+            .filter(|interval| !interval.is_zero())
+    }
+
+    // Whether requests from `ip` are accepted but never recorded
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        self.blocked_ips.iter().any(|network| network.contains(ip))
+    }
+}
+
+// The live configuration. Reloading swaps it out as a whole, so a request
+// always sees one consistent version.
+pub struct SharedConfig {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Config>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Arc<Config>, path: Option<PathBuf>) -> Self {
+        SharedConfig {
+            path,
+            current: RwLock::new(config),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Re-reads the config file, `.env` and environment. Invalid settings keep
+    // the current configuration. The port, databases, event queue, GeoIP
+    // databases and logging are set up once at startup and keep their values.
+    pub fn reload(&self) -> Result<Arc<Config>, String> {
+        let path = self.path.clone();
+        let mut config =
+            std::panic::catch_unwind(|| Config::load(path.as_deref())).map_err(|panic| {
+                panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "Failed to load config".to_string())
+            })?;
+
+        let mut current = self.current.write().unwrap();
+        config.service_port = current.service_port.clone();
+        config.script_path = current.script_path.clone();
+        config.collect_path = current.collect_path.clone();
+        config.database_url = current.database_url.clone();
+        config.database_key = current.database_key.clone();
+        config.db_pool_size = current.db_pool_size;
+        config.queue_capacity = current.queue_capacity;
+        config.udp_listen_address = current.udp_listen_address.clone();
+        config.processing_batch_size = current.processing_batch_size;
+        config.processing_batch_timeout_secs = current.processing_batch_timeout_secs;
+        config.retry_max_attempts = current.retry_max_attempts;
+        config.retry_base_delay_ms = current.retry_base_delay_ms;
+        config.dead_letter_file = current.dead_letter_file.clone();
+        config.event_store = current.event_store;
+        config.clickhouse_url = current.clickhouse_url.clone();
+        config.clickhouse_database = current.clickhouse_database.clone();
+        config.clickhouse_user = current.clickhouse_user.clone();
+        config.clickhouse_password = current.clickhouse_password.clone();
+        config.event_stream = current.event_stream;
+        config.event_stream_servers = current.event_stream_servers.clone();
+        config.event_stream_topic = current.event_stream_topic.clone();
+        config.geoip_provider = current.geoip_provider;
+        config.geoip_database = current.geoip_database.clone();
+        config.geoip_fallback_database = current.geoip_fallback_database.clone();
+        config.geoip_asn_database = current.geoip_asn_database.clone();
+        config.city_data = current.city_data.clone();
+        config.geo_locale = current.geo_locale.clone();
+        config.log_format = current.log_format;
+        config.log_file = current.log_file.clone();
+        config.log_rotation = current.log_rotation;
+        config.log_max_files = current.log_max_files;
+        config.log_max_size = current.log_max_size;
+        config.log_filter = current.log_filter.clone();
+
+        *current = Arc::new(config);
+        Ok(current.clone())
+    }
+}
+
+// Variables of the `.env` file in the working directory. They are read
+// rather than set in the environment, which other threads read while the
+// configuration is reloaded, so changed and removed values are picked up on
+// every load. dotenv deprecated its iterators in favour of setting the
+// environment, which is what has to be avoided here.
+#[allow(deprecated)]
+fn read_dotenv() -> HashMap<String, String> {
+    match dotenv::from_path_iter(Path::new(".env")) {
+        Ok(variables) => variables.filter_map(Result::ok).collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+// Loaded from the config file, keyed by the same names as the environment
+// variables. Lists and tables are flattened into the string format the
+// environment variables use, so both go through the same parsing. The
+// environment comes first, then `.env`, then the file.
+#[derive(Default)]
+struct Settings {
+    file: HashMap<String, String>,
+    dotenv: HashMap<String, String>,
+}
+
+impl Settings {
+    fn from_file(path: &Path) -> Self {
+        let contents = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to read config file {}: {}", path.display(), e));
+        let table: toml::Table = contents
+            .parse()
+            .unwrap_or_else(|e| panic!("Failed to parse config file {}: {}", path.display(), e));
+
+        Settings {
+            file: table
+                .into_iter()
+                .map(|(key, value)| (key.to_uppercase(), Self::flatten(&value)))
+                .collect(),
+            dotenv: HashMap::new(),
         }
     }
 
-    fn get_env(key: &str, default: &str) -> String {
-        env::var(key).unwrap_or_else(|_| default.to_string())
+    // `["a", "b"]` becomes `a,b`. Tables become `key:value` pairs, or
+    // `key=a|b` when the value is a list, e.g. for CURRENCY_RATES and
+    // ALLOWED_EVENT_NAMES.
+    fn flatten(value: &toml::Value) -> String {
+        match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Array(items) => items
+                .iter()
+                .map(Self::flatten)
+                .collect::<Vec<_>>()
+                .join(","),
+            toml::Value::Table(table) => table
+                .iter()
+                .map(|(key, value)| match value {
+                    toml::Value::Array(items) => format!(
+                        "{}={}",
+                        key,
+                        items
+                            .iter()
+                            .map(Self::flatten)
+                            .collect::<Vec<_>>()
+                            .join("|")
+                    ),
+                    _ => format!("{}:{}", key, Self::flatten(value)),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            other => other.to_string(),
+        }
     }
 
-    fn get_env_list(key: &str, default: &str) -> Vec<String> {
+    fn get(&self, key: &str) -> Option<String> {
         env::var(key)
-            .unwrap_or_else(|_| default.to_string())
+            .ok()
+            .or_else(|| self.dotenv.get(key).cloned())
+            .or_else(|| self.file.get(key).cloned())
+    }
+
+    // Every variable starting with `prefix`, keyed in lowercase
+    fn get_prefixed(&self, prefix: &str) -> Vec<(String, String)> {
+        let mut values: HashMap<String, String> = HashMap::new();
+        let sources = [
+            self.file.clone(),
+            self.dotenv.clone(),
+            env::vars().collect(),
+        ];
+        // Later sources take precedence, the same as in `get`
+        for source in sources {
+            for (key, value) in source {
+                if key.starts_with(prefix) {
+                    values.insert(key.to_lowercase(), value);
+                }
+            }
+        }
+        let mut values: Vec<(String, String)> = values.into_iter().collect();
+        values.sort();
+        values
+    }
+
+    fn get_env(&self, key: &str, default: &str) -> String {
+        self.get(key).unwrap_or_else(|| default.to_string())
+    }
+
+    // A route path, `c` and `/c` both become `/c`
+    fn get_env_path(&self, key: &str, default: &str) -> String {
+        let path = self.get_env(key, default);
+        let path = path.trim().trim_start_matches('/');
+        if path.is_empty() {
+            default.to_string()
+        } else {
+            format!("/{}", path)
+        }
+    }
+
+    fn get_env_list(&self, key: &str, default: &str) -> Vec<String> {
+        self.get(key)
+            .unwrap_or_else(|| default.to_string())
             .split(',')
             .map(|s| s.trim().to_string())
             .filter(|s| !s.is_empty())
             .collect()
     }
 
-    fn get_env_usize(key: &str, default: usize) -> usize {
-        env::var(key)
-            .unwrap_or_else(|_| default.to_string())
+    // Parses `EUR:1.08,GBP:1.27` into currency -> rate pairs
+    fn get_env_rates(&self, key: &str, default: &str) -> HashMap<String, f64> {
+        self.get_env_list(key, default)
+            .into_iter()
+            .map(|pair| {
+                let (currency, rate) = pair
+                    .split_once(':')
+                    .unwrap_or_else(|| panic!("Failed to parse {}", key));
+                let rate = rate
+                    .trim()
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Failed to parse {}", key));
+                (currency.trim().to_uppercase(), rate)
+            })
+            .collect()
+    }
+
+    // Parses `https://udara.io=signup|purchase,*=signup` into site origin ->
+    // allowed names, `*` applying to sites without their own entry
+    fn get_env_allowlist(&self, key: &str, default: &str) -> HashMap<String, HashSet<String>> {
+        self.get_env_list(key, default)
+            .into_iter()
+            .map(|entry| {
+                let (site, names) = entry
+                    .rsplit_once('=')
+                    .unwrap_or_else(|| panic!("Failed to parse {}", key));
+                let names = names
+                    .split('|')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect();
+                (site.trim().trim_end_matches('/').to_lowercase(), names)
+            })
+            .collect()
+    }
+
+    // Parses `rollups:1h,backup:1d` into job name -> interval. Entries
+    // override the defaults one by one, `0` disables a job.
+    fn get_env_intervals(&self, key: &str, default: &str) -> HashMap<String, Duration> {
+        let parse = |list: &str| {
+            list.split(',')
+                .map(|entry| entry.trim())
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| {
+                    let (name, interval) = entry
+                        .split_once(':')
+                        .unwrap_or_else(|| panic!("Failed to parse {}", key));
+                    let interval = interval.trim();
+                    let (number, unit) = interval.split_at(
+                        interval
+                            .find(|c: char| !c.is_ascii_digit())
+                            .unwrap_or(interval.len()),
+                    );
+                    let number: u64 = number
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Failed to parse {}", key));
+                    let seconds = match unit {
+                        "" | "s" => number,
+                        "m" => number * 60,
+                        "h" => number * 60 * 60,
+                        "d" => number * 24 * 60 * 60,
+                        _ => panic!("Failed to parse {}", key),
+                    };
+                    (name.trim().to_string(), Duration::from_secs(seconds))
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        let mut intervals = parse(default);
+        intervals.extend(parse(&self.get(key).unwrap_or_default()));
+        intervals
+    }
+
+    // Parses CIDR ranges, single addresses are treated as a range of one
+    fn get_env_networks(&self, key: &str, default: &str) -> Vec<IpNet> {
+        self.get_env_list(key, default)
+            .into_iter()
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .unwrap_or_else(|_| panic!("Failed to parse {}", key))
+            })
+            .collect()
+    }
+
+    // Parses `https://udara.io=0.1,*=0.5` into site origin -> sample rate,
+    // each between 0 (exclusive) and 1
+    fn get_env_sample_rates(&self, key: &str, default: &str) -> HashMap<String, f64> {
+        self.get_env_list(key, default)
+            .into_iter()
+            .map(|entry| {
+                let rate = entry
+                    .rsplit_once('=')
+                    .and_then(|(site, rate)| Some((site, rate.trim().parse::<f64>().ok()?)))
+                    .filter(|(_, rate)| *rate > 0.0 && *rate <= 1.0);
+                let (site, rate) = rate.unwrap_or_else(|| panic!("Failed to parse {}", key));
+                (site.trim().trim_end_matches('/').to_lowercase(), rate)
+            })
+            .collect()
+    }
+
+    // Parses `https://udara.io=100000,*=10000` into site origin -> quota
+    fn get_env_quotas(&self, key: &str, default: &str) -> HashMap<String, usize> {
+        self.get_env_list(key, default)
+            .into_iter()
+            .map(|entry| {
+                let (site, quota) = entry
+                    .rsplit_once('=')
+                    .and_then(|(site, quota)| Some((site, quota.trim().parse().ok()?)))
+                    .unwrap_or_else(|| panic!("Failed to parse {}", key));
+                (site.trim().trim_end_matches('/').to_lowercase(), quota)
+            })
+            .collect()
+    }
+
+    fn get_env_usize(&self, key: &str, default: usize) -> usize {
+        self.get(key)
+            .unwrap_or_else(|| default.to_string())
             .parse()
-            .expect(&format!("Failed to parse {}", key))
+            .unwrap_or_else(|_| panic!("Failed to parse {}", key))
     }
 
-    fn get_env_bool(key: &str, default: bool) -> bool {
-        env::var(key)
-            .unwrap_or_else(|_| default.to_string())
+    fn get_env_bool(&self, key: &str, default: bool) -> bool {
+        self.get(key)
+            .unwrap_or_else(|| default.to_string())
             .parse()
-            .expect(&format!("Failed to parse {}", key))
+            .unwrap_or_else(|_| panic!("Failed to parse {}", key))
     }
 }
@@ -1,12 +1,14 @@
+use crate::config::Config;
 use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
-use dotenv::dotenv;
-use std::env;
+use std::error::Error;
 use std::time::Duration;
+use tokio::task;
 
 #[derive(Debug)]
 pub struct ConnectionOptions {
+    pub encryption_key: Option<String>,
     pub enable_wal: bool,
     pub enable_foreign_keys: bool,
     pub busy_timeout: Option<Duration>,
@@ -17,15 +19,20 @@ impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
 {
     fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
         (|| {
+            // The key has to be set before anything else touches the database
+            if let Some(key) = &self.encryption_key {
+                conn.batch_execute(&format!("PRAGMA key = '{}';", key.replace('\'', "''")))?;
+            }
+            // Before switching to WAL, which has to wait for other connections
+            if let Some(d) = self.busy_timeout {
+                conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
+            }
             if self.enable_wal {
                 conn.batch_execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
             }
             if self.enable_foreign_keys {
                 conn.batch_execute("PRAGMA foreign_keys = ON;")?;
             }
-            if let Some(d) = self.busy_timeout {
-                conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
-            }
             Ok(())
         })()
         .map_err(diesel::r2d2::Error::QueryError)
@@ -35,16 +42,35 @@ impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
 // Type alias for the pool type
 pub type DbPool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
 
+// Runs `f` with a pooled connection, off the async executor
+pub async fn blocking<T, F>(pool: &DbPool, f: F) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut SqliteConnection) -> Result<T, Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    let pool = pool.clone();
+    task::spawn_blocking(move || {
+        let mut conn = pool.get()?;
+        f(&mut conn)
+    })
+    .await?
+}
+
 // Function to establish a connection pool
-pub fn establish_connection_pool() -> DbPool {
-    dotenv().ok();
+pub fn establish_connection_pool(config: &Config) -> DbPool {
+    let manager = ConnectionManager::<SqliteConnection>::new(&config.database_url);
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+    // Plain SQLite silently ignores `PRAGMA key`, so refuse to run unencrypted
+    // when a key was given to a build without SQLCipher
+    let encryption_key = config.database_key.clone();
+    if encryption_key.is_some() && !cfg!(feature = "sqlcipher") {
+        panic!("DATABASE_KEY is set but stats was built without the `sqlcipher` feature");
+    }
 
     r2d2::Pool::builder()
-        .max_size(16)
+        .max_size(config.db_pool_size)
         .connection_customizer(Box::new(ConnectionOptions {
+            encryption_key,
             enable_wal: true,
             enable_foreign_keys: true,
             busy_timeout: Some(Duration::from_secs(30)),
@@ -0,0 +1,147 @@
+use crate::db::DbPool;
+use crate::models::{Collector, Event};
+use crate::query::{DateRange, Dimension, DimensionValue, Filter, Metric, QueryRequest};
+use crate::schema::{collectors, events};
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Object, Result, Schema, SimpleObject,
+};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+const DEFAULT_SESSIONS_LIMIT: i64 = 30;
+const MAX_SESSIONS_LIMIT: i64 = 100;
+const DEFAULT_EVENTS_LIMIT: i64 = 100;
+const MAX_EVENTS_LIMIT: i64 = 1000;
+
+// Read-only, the API changes nothing
+pub type StatsSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub fn build_schema(pool: DbPool) -> StatsSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(pool)
+        .finish()
+}
+
+fn connection(
+    ctx: &Context<'_>,
+) -> Result<diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<SqliteConnection>>> {
+    Ok(ctx.data::<DbPool>()?.get()?)
+}
+
+// A row of `summary`, the dimension values it was grouped by and its metrics
+#[derive(SimpleObject)]
+pub struct SummaryRow {
+    dimensions: Vec<DimensionValue>,
+    pageviews: i64,
+    visitors: i64,
+    events: i64,
+}
+
+#[ComplexObject]
+impl Collector {
+    // The visitor's events, oldest first
+    async fn events(&self, ctx: &Context<'_>) -> Result<Vec<Event>> {
+        let mut conn = connection(ctx)?;
+        Ok(Event::belonging_to(self)
+            .order(events::timestamp.asc())
+            .load(&mut conn)?)
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // Metrics grouped by up to three dimensions, the same as POST /query.
+    // Every row has all three metrics, `metrics` decides the default order.
+    #[allow(clippy::too_many_arguments)]
+    async fn summary(
+        &self,
+        ctx: &Context<'_>,
+        metrics: Vec<Metric>,
+        #[graphql(default)] dimensions: Vec<Dimension>,
+        #[graphql(default)] filters: Vec<Filter>,
+        date_range: Option<DateRange>,
+        order_by: Option<Metric>,
+        limit: Option<i64>,
+    ) -> Result<Vec<SummaryRow>> {
+        let request = QueryRequest {
+            metrics,
+            dimensions,
+            filters,
+            date_range,
+            order_by,
+            limit,
+        };
+        let mut conn = connection(ctx)?;
+        let rows = request.load(&mut conn).map_err(|e| e.to_string())?;
+        Ok(rows
+            .into_iter()
+            .map(|row| SummaryRow {
+                dimensions: row.dimension_values(&request.dimensions),
+                pageviews: row.metric(Metric::Pageviews),
+                visitors: row.metric(Metric::Visitors),
+                events: row.metric(Metric::Events),
+            })
+            .collect())
+    }
+
+    // Visitors, newest first, paged like GET /sessions with the last id
+    async fn sessions(
+        &self,
+        ctx: &Context<'_>,
+        before: Option<String>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Collector>> {
+        let limit = limit
+            .unwrap_or(DEFAULT_SESSIONS_LIMIT)
+            .clamp(1, MAX_SESSIONS_LIMIT);
+        let mut query = collectors::table
+            .order(collectors::id.desc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(before) = before {
+            query = query.filter(collectors::id.lt(before));
+        }
+        let mut conn = connection(ctx)?;
+        Ok(query.load(&mut conn)?)
+    }
+
+    // Raw events in `[from, to)`, newest first
+    #[allow(clippy::too_many_arguments)]
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+        name: Option<String>,
+        url: Option<String>,
+        collector_id: Option<String>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Event>> {
+        let limit = limit
+            .unwrap_or(DEFAULT_EVENTS_LIMIT)
+            .clamp(1, MAX_EVENTS_LIMIT);
+        let mut query = events::table
+            .order(events::timestamp.desc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(from) = from {
+            query = query.filter(events::timestamp.ge(from));
+        }
+        if let Some(to) = to {
+            query = query.filter(events::timestamp.lt(to));
+        }
+        if let Some(name) = name {
+            query = query.filter(events::name.eq(name));
+        }
+        if let Some(url) = url {
+            query = query.filter(events::url.eq(url));
+        }
+        if let Some(collector_id) = collector_id {
+            query = query.filter(events::collector_id.eq(collector_id));
+        }
+        let mut conn = connection(ctx)?;
+        Ok(query.load(&mut conn)?)
+    }
+}
@@ -0,0 +1,205 @@
+use crate::config::SharedConfig;
+use crate::db::DbPool;
+use crate::models::NewEvent;
+use crate::utils::backup;
+use crate::utils::limits::OriginQuotas;
+use crate::utils::runtime::RuntimeStatus;
+use crate::utils::spam::ReferrerBlocklist;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text, Timestamp};
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
+
+// Embedded by build.rs
+const GIT_COMMIT: &str = env!("STATS_GIT_COMMIT");
+const BUILT_AT: &str = env!("STATS_BUILT_AT");
+
+pub async fn version() -> impl Responder {
+    let built_at = BUILT_AT
+        .parse()
+        .ok()
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0));
+
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": GIT_COMMIT,
+        "built_at": built_at,
+    }))
+}
+
+pub async fn status(
+    pool: web::Data<DbPool>,
+    events_queue: web::Data<Sender<NewEvent>>,
+    runtime: web::Data<Arc<RuntimeStatus>>,
+    quotas: web::Data<Arc<OriginQuotas>>,
+    config: web::Data<SharedConfig>,
+) -> impl Responder {
+    let config = config.get();
+    let pool_state = pool.state();
+
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": GIT_COMMIT,
+        "started_at": runtime.started_at(),
+        "uptime_seconds": runtime.uptime_seconds(),
+        "queue": {
+            "depth": events_queue.max_capacity() - events_queue.capacity(),
+            "capacity": events_queue.max_capacity(),
+        },
+        "events_processed": runtime.events_processed(),
+        "events_dead_lettered": runtime.events_dead_lettered(),
+        "events_published": runtime.events_published(),
+        "events_capped": runtime.events_capped(),
+        "events_throttled": runtime.events_throttled(),
+        "quotas": quotas.usage(|origin| config.daily_event_quota(origin)),
+        "last_batch_insert": runtime.last_batch_insert(),
+        "db_pool": {
+            "max_size": pool.max_size(),
+            "connections": pool_state.connections,
+            "idle_connections": pool_state.idle_connections,
+        },
+        "jobs": runtime.jobs(),
+        "maintenance": runtime.maintenance(),
+    }))
+}
+
+#[derive(QueryableByName)]
+struct TableName {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct RowCount {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+#[derive(QueryableByName)]
+struct EventRange {
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    oldest: Option<NaiveDateTime>,
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    newest: Option<NaiveDateTime>,
+}
+
+fn load_row_counts(conn: &mut SqliteConnection) -> QueryResult<Map<String, Value>> {
+    let tables: Vec<TableName> = diesel::sql_query(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+    )
+    .load(conn)?;
+
+    let mut counts = Map::new();
+    for table in tables {
+        // Table names come from sqlite_master, quoted in case of odd names
+        let count: RowCount = diesel::sql_query(format!(
+            "SELECT COUNT(*) AS count FROM \"{}\"",
+            table.name.replace('"', "\"\"")
+        ))
+        .get_result(conn)?;
+        counts.insert(table.name, json!(count.count));
+    }
+    Ok(counts)
+}
+
+// File size in bytes, or null when the file doesn't exist (e.g. no WAL yet)
+fn file_size(path: &str) -> Option<u64> {
+    fs::metadata(path).map(|metadata| metadata.len()).ok()
+}
+
+pub async fn db_stats(pool: web::Data<DbPool>, config: web::Data<SharedConfig>) -> impl Responder {
+    let config = config.get();
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let stats = load_row_counts(&mut conn).and_then(|row_counts| {
+        diesel::sql_query("SELECT MIN(timestamp) AS oldest, MAX(timestamp) AS newest FROM events")
+            .get_result::<EventRange>(&mut conn)
+            .map(|range| (row_counts, range))
+    });
+
+    match stats {
+        Ok((row_counts, range)) => HttpResponse::Ok().json(json!({
+            "database_size": file_size(&config.database_url),
+            "wal_size": file_size(&format!("{}-wal", config.database_url)),
+            "row_counts": row_counts,
+            "oldest_event": range.oldest,
+            "newest_event": range.newest,
+        })),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn backup(pool: web::Data<DbPool>, config: web::Data<SharedConfig>) -> impl Responder {
+    let config = config.get();
+    let result = web::block(move || {
+        let mut conn = pool.get()?;
+        backup::backup(
+            &mut conn,
+            Path::new(&config.backup_dir),
+            config.backup_retention,
+        )
+    })
+    .await;
+
+    match result {
+        Ok(Ok(path)) => HttpResponse::Ok().json(json!({
+            "path": path,
+            "size": file_size(&path.to_string_lossy()),
+        })),
+        Ok(Err(e)) => {
+            error!("Backup failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Backup failed: {}", e))
+        }
+        Err(e) => {
+            error!("Backup failed: {:?}", e);
+            HttpResponse::InternalServerError().json("Backup failed")
+        }
+    }
+}
+
+// Applies a freshly loaded configuration, also to the parts that copied
+// settings at startup. Shared by POST /admin/reload-config and SIGHUP.
+pub async fn reload(
+    config: &SharedConfig,
+    referrer_blocklist: &ReferrerBlocklist,
+) -> Result<(), String> {
+    let previous = config.get();
+    let config = config.reload()?;
+
+    referrer_blocklist.configure(&config.referrer_spam_domains);
+    let list_url = &config.referrer_spam_list_url;
+    if !list_url.is_empty() && *list_url != previous.referrer_spam_list_url {
+        match referrer_blocklist.refresh(list_url).await {
+            Ok(count) => info!("Loaded {} referrer spam domains", count),
+            Err(e) => error!("Failed to update referrer spam list: {:?}", e),
+        }
+    }
+
+    info!("Reloaded configuration");
+    Ok(())
+}
+
+pub async fn reload_config(
+    config: web::Data<SharedConfig>,
+    referrer_blocklist: web::Data<Arc<ReferrerBlocklist>>,
+) -> impl Responder {
+    match reload(&config, &referrer_blocklist).await {
+        Ok(()) => HttpResponse::Ok().json(json!({ "reloaded": true })),
+        Err(e) => {
+            error!("Failed to reload configuration: {}", e);
+            HttpResponse::BadRequest().json(format!("Failed to reload configuration: {}", e))
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::db::DbPool;
+use crate::models::AlertRule;
+use crate::schema::alert_rules;
+use crate::utils::alerts::{Comparison, Metric};
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use ulid::Ulid;
+use url::Url;
+
+// Body of POST /admin/alerts and PUT /admin/alerts/{id}
+#[derive(Deserialize)]
+pub struct AlertRuleRequest {
+    name: String,
+    // pageviews, visitors, not_found or event:<name>
+    metric: String,
+    // <, <=, >, >=, = or !=
+    comparison: String,
+    threshold: i64,
+    #[serde(default = "default_window_minutes")]
+    window_minutes: i32,
+    // URL the notifications are POSTed to
+    destination: String,
+}
+
+fn default_window_minutes() -> i32 {
+    60
+}
+
+impl AlertRuleRequest {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name is required".to_string());
+        }
+        if Metric::parse(&self.metric).is_none() {
+            return Err(format!(
+                "Unknown metric {}, use pageviews, visitors, not_found or event:<name>",
+                self.metric
+            ));
+        }
+        if Comparison::parse(&self.comparison).is_none() {
+            return Err(format!(
+                "Unknown comparison {}, use <, <=, >, >=, = or !=",
+                self.comparison
+            ));
+        }
+        if self.window_minutes <= 0 {
+            return Err("window_minutes must be positive".to_string());
+        }
+        match Url::parse(&self.destination) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+            _ => Err(format!("{} is not an http(s) URL", self.destination)),
+        }
+    }
+}
+
+fn database_error(e: diesel::result::Error) -> HttpResponse {
+    error!("Database query failed: {:?}", e);
+    HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+}
+
+pub async fn list(pool: web::Data<DbPool>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match alert_rules::table
+        .order(alert_rules::created_at.asc())
+        .load::<AlertRule>(&mut conn)
+    {
+        Ok(rules) => HttpResponse::Ok().json(rules),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn get(pool: web::Data<DbPool>, id: web::Path<String>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match alert_rules::table
+        .find(id.into_inner())
+        .first::<AlertRule>(&mut conn)
+        .optional()
+    {
+        Ok(Some(rule)) => HttpResponse::Ok().json(rule),
+        Ok(None) => HttpResponse::NotFound().json("No such alert rule"),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn create(pool: web::Data<DbPool>, body: web::Json<AlertRuleRequest>) -> impl Responder {
+    if let Err(message) = body.validate() {
+        return HttpResponse::BadRequest().json(json!({ "error": message }));
+    }
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let body = body.into_inner();
+    let rule = AlertRule {
+        id: Ulid::new().to_string(),
+        name: body.name,
+        metric: body.metric,
+        comparison: body.comparison,
+        threshold: body.threshold,
+        window_minutes: body.window_minutes,
+        destination: body.destination,
+        triggered: false,
+        last_value: None,
+        last_checked_at: None,
+        last_fired_at: None,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    match diesel::insert_into(alert_rules::table)
+        .values(&rule)
+        .execute(&mut conn)
+    {
+        Ok(_) => HttpResponse::Created().json(rule),
+        Err(e) => database_error(e),
+    }
+}
+
+// Replaces the rule's settings. It starts over as not triggered, so a rule
+// that matches after the change notifies again.
+pub async fn update(
+    pool: web::Data<DbPool>,
+    id: web::Path<String>,
+    body: web::Json<AlertRuleRequest>,
+) -> impl Responder {
+    if let Err(message) = body.validate() {
+        return HttpResponse::BadRequest().json(json!({ "error": message }));
+    }
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let body = body.into_inner();
+    let id = id.into_inner();
+    let updated = diesel::update(alert_rules::table.find(&id))
+        .set((
+            alert_rules::name.eq(body.name),
+            alert_rules::metric.eq(body.metric),
+            alert_rules::comparison.eq(body.comparison),
+            alert_rules::threshold.eq(body.threshold),
+            alert_rules::window_minutes.eq(body.window_minutes),
+            alert_rules::destination.eq(body.destination),
+            alert_rules::triggered.eq(false),
+        ))
+        .execute(&mut conn)
+        .and_then(|updated| match updated {
+            0 => Ok(None),
+            _ => alert_rules::table
+                .find(&id)
+                .first::<AlertRule>(&mut conn)
+                .map(Some),
+        });
+
+    match updated {
+        Ok(Some(rule)) => HttpResponse::Ok().json(rule),
+        Ok(None) => HttpResponse::NotFound().json("No such alert rule"),
+        Err(e) => database_error(e),
+    }
+}
+
+pub async fn delete(pool: web::Data<DbPool>, id: web::Path<String>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match diesel::delete(alert_rules::table.find(id.into_inner())).execute(&mut conn) {
+        Ok(0) => HttpResponse::NotFound().json("No such alert rule"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => database_error(e),
+    }
+}
@@ -0,0 +1,48 @@
+use crate::config::SharedConfig;
+use actix_web::{http, web, HttpResponse, Responder};
+use serde_json::json;
+
+// Seconds between heartbeats while the page is visible, like stats.js
+const HEARTBEAT_SECONDS: u32 = 15;
+
+// A config for <amp-analytics>, as AMP pages can't load stats.js. Events go
+// to the collect path as images, since it only takes GET requests, with AMP's
+// client id in place of a collector.
+pub async fn amp_config(config: web::Data<SharedConfig>) -> impl Responder {
+    let config = config.get();
+    let collect_url = format!("{}{}", config.app_url, config.collect_path);
+
+    HttpResponse::Ok()
+        // AMP fetches remote configs with credentials
+        .insert_header((http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"))
+        .insert_header((http::header::CACHE_CONTROL, "public, max-age=3600"))
+        .json(json!({
+            "requests": {
+                "base": format!(
+                    "{}?collector_id=${{clientId(stats)}}&url=${{canonicalUrl}}&referrer=${{documentReferrer}}",
+                    collect_url
+                ),
+                "event": "${base}&name=${eventName}",
+            },
+            "triggers": {
+                "pageview": {
+                    "on": "visible",
+                    "request": "event",
+                    "vars": { "eventName": "enter" },
+                },
+                "heartbeat": {
+                    "on": "timer",
+                    "timerSpec": {
+                        "interval": HEARTBEAT_SECONDS,
+                        "immediate": false,
+                        "startSpec": { "on": "visible", "selector": ":root" },
+                        "stopSpec": { "on": "hidden", "selector": ":root" },
+                    },
+                    "request": "event",
+                    "vars": { "eventName": "heartbeat" },
+                    "extraUrlParams": { "value": HEARTBEAT_SECONDS },
+                },
+            },
+            "transport": { "beacon": false, "xhrpost": false, "image": true },
+        }))
+}
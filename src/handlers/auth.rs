@@ -0,0 +1,121 @@
+use crate::config::SharedConfig;
+use crate::db::{blocking, DbPool};
+use crate::utils::auth::{log_in, log_out, session_user, SESSION_COOKIE};
+use crate::utils::client_ip::client_ip;
+use crate::utils::limits::LoginAttempts;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{http, web, HttpRequest, HttpResponse, Responder};
+use chrono::Duration;
+use log::{error, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+fn session_cookie(req: &HttpRequest, value: String, max_age: Duration) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, value)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        // Behind a TLS terminating proxy this relies on X-Forwarded-Proto
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(actix_web::cookie::time::Duration::seconds(
+            max_age.num_seconds(),
+        ))
+        .finish()
+}
+
+pub async fn login(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    attempts: web::Data<Arc<LoginAttempts>>,
+    body: web::Json<LoginRequest>,
+) -> impl Responder {
+    let config = config.get();
+    let body = body.into_inner();
+    let keys = [
+        client_ip(&req, &config).map(|ip| format!("ip:{}", ip)),
+        Some(format!("user:{}", body.username)),
+    ];
+    let keys: Vec<String> = keys.into_iter().flatten().collect();
+    if let Some(retry_after) = keys
+        .iter()
+        .filter_map(|key| attempts.check(key).err())
+        .max()
+    {
+        return HttpResponse::TooManyRequests()
+            .insert_header((http::header::RETRY_AFTER, retry_after.to_string()))
+            .json(json!({ "error": "Too many failed logins, try again later" }));
+    }
+
+    // Hashing the password takes a while on purpose, so it runs off the
+    // async workers
+    let lifetime = Duration::hours(config.session_lifetime_hours as i64);
+    let username = body.username.clone();
+    let logged_in = blocking(&pool, move |conn| {
+        Ok(log_in(conn, &body.username, &body.password, lifetime)?)
+    })
+    .await;
+    match logged_in {
+        Ok(Some((user, token))) => {
+            keys.iter().for_each(|key| attempts.succeeded(key));
+            HttpResponse::Ok()
+                .cookie(session_cookie(&req, token, lifetime))
+                .json(user)
+        }
+        Ok(None) => {
+            warn!("Failed login for {}", username);
+            keys.iter().for_each(|key| attempts.failed(key));
+            HttpResponse::Unauthorized().json(json!({ "error": "Wrong username or password" }))
+        }
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn logout(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let mut conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(_) => {
+                return HttpResponse::ServiceUnavailable().json("Could not get DB connection")
+            }
+        };
+        if let Err(e) = log_out(&mut conn, cookie.value()) {
+            error!("Database query failed: {:?}", e);
+            return HttpResponse::InternalServerError().json(format!("Database error: {:?}", e));
+        }
+    }
+
+    HttpResponse::NoContent()
+        .cookie(session_cookie(&req, String::new(), Duration::zero()))
+        .finish()
+}
+
+// The logged in user
+pub async fn me(req: HttpRequest, pool: web::Data<DbPool>) -> impl Responder {
+    let Some(cookie) = req.cookie(SESSION_COOKIE) else {
+        return HttpResponse::Unauthorized().json("Not logged in");
+    };
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match session_user(&mut conn, cookie.value()) {
+        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(None) => HttpResponse::Unauthorized().json("Not logged in"),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
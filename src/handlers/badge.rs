@@ -0,0 +1,220 @@
+use crate::config::SharedConfig;
+use crate::db::DbPool;
+use crate::utils::alerts::Metric;
+use crate::utils::auth::users_exist;
+use crate::utils::site_stats::site_totals;
+use actix_web::{http, web, HttpResponse, Responder};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::QueryResult;
+use diesel::SqliteConnection;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+
+// How long browsers and image proxies (like GitHub's camo) may cache a badge
+const CACHE_SECONDS: u32 = 300;
+
+// Longest period a badge counts over, besides `all`
+const MAX_PERIOD_DAYS: i64 = 3650;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BadgeMetric {
+    #[default]
+    Visitors,
+    Pageviews,
+}
+
+#[derive(Deserialize)]
+pub struct BadgeQuery {
+    metric: Option<BadgeMetric>,
+    // e.g. 24h, 7d or all, defaults to 7d
+    period: Option<String>,
+    // Only count this origin, e.g. https://example.com
+    site: Option<String>,
+    // Text on the left, defaults to the metric
+    label: Option<String>,
+    // Background of the count, a color name or hex code without `#`
+    color: Option<String>,
+}
+
+// Length of a period like `24h` or `7d`, or None for `all`
+fn parse_period(period: &str) -> Result<Option<Duration>, String> {
+    if period == "all" {
+        return Ok(None);
+    }
+    let invalid = || {
+        format!(
+            "Invalid period {}, use e.g. 24h, 7d or all, at most {} days",
+            period, MAX_PERIOD_DAYS
+        )
+    };
+    // The unit is the last character, which isn't necessarily one byte
+    let (at, unit) = period.char_indices().last().ok_or_else(invalid)?;
+    let number: i64 = period[..at].parse().map_err(|_| invalid())?;
+    match unit {
+        'h' if number > 0 && number <= MAX_PERIOD_DAYS * 24 => Ok(Some(Duration::hours(number))),
+        'd' if number > 0 && number <= MAX_PERIOD_DAYS => Ok(Some(Duration::days(number))),
+        _ => Err(invalid()),
+    }
+}
+
+// Shortened like shields.io does, e.g. 1.2k or 3.4M
+fn format_count(count: i64) -> String {
+    for (unit, size) in [("M", 1_000_000.0), ("k", 1_000.0)] {
+        if count as f64 >= size {
+            let scaled = count as f64 / size;
+            return if scaled >= 100.0 {
+                format!("{:.0}{}", scaled, unit)
+            } else {
+                format!("{:.1}{}", scaled, unit).replace(".0", "")
+            };
+        }
+    }
+    count.to_string()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Width of `text` in 11px Verdana, close enough without font metrics
+fn text_width(text: &str) -> usize {
+    text.chars().count() * 7 + 10
+}
+
+fn color(name: Option<&str>) -> String {
+    match name {
+        Some(name) if name.chars().all(|c| c.is_ascii_hexdigit()) && name.len() <= 6 => {
+            format!("#{}", name)
+        }
+        Some(name) if name.chars().all(|c| c.is_ascii_alphabetic()) => name.to_string(),
+        _ => "#007ec6".to_string(),
+    }
+}
+
+// A flat shields.io style badge
+fn render(label: &str, value: &str, color: &str) -> String {
+    let (label, value) = (escape(label), escape(value));
+    let label_width = text_width(&label);
+    let value_width = text_width(&value);
+    let width = label_width + value_width;
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {value}">
+<title>{label}: {value}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{value_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+<text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{label_x}" y="14">{label}</text>
+<text x="{value_x}" y="15" fill="#010101" fill-opacity=".3">{value}</text><text x="{value_x}" y="14">{value}</text>
+</g>
+</svg>"##,
+        label_x = label_width / 2,
+        value_x = label_width + value_width / 2,
+    )
+}
+
+fn count(
+    conn: &mut SqliteConnection,
+    metric: BadgeMetric,
+    site: Option<&str>,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> QueryResult<i64> {
+    match (site, metric) {
+        (Some(site), BadgeMetric::Visitors) => {
+            site_totals(conn, site, from, to).map(|(_, visitors)| visitors)
+        }
+        (Some(site), BadgeMetric::Pageviews) => {
+            site_totals(conn, site, from, to).map(|(pageviews, _)| pageviews)
+        }
+        (None, BadgeMetric::Visitors) => Metric::Visitors.measure(conn, from, to),
+        (None, BadgeMetric::Pageviews) => Metric::Pageviews.measure(conn, from, to),
+    }
+}
+
+// Whether `site` opted in to public stats with PUBLIC_STATS_SITES
+fn is_public(sites: &[String], site: &str) -> bool {
+    let site = site.trim_end_matches('/');
+    sites
+        .iter()
+        .any(|origin| origin.trim_end_matches('/') == site)
+}
+
+pub async fn badge(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<BadgeQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let metric = query.metric.unwrap_or_default();
+    let period = query.period.as_deref().unwrap_or("7d");
+    let length = match parse_period(period) {
+        Ok(length) => length,
+        Err(message) => return HttpResponse::BadRequest().json(json!({ "error": message })),
+    };
+
+    let to = Utc::now().naive_utc();
+    let from = match length {
+        Some(length) => match to.checked_sub_signed(length) {
+            Some(from) => from,
+            None => {
+                return HttpResponse::BadRequest()
+                    .json(json!({ "error": format!("Period {} is out of range", period) }))
+            }
+        },
+        None => DateTime::UNIX_EPOCH.naive_utc(),
+    };
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    // Badges are public, so they only count sites that opted in to public
+    // stats. Totals of the whole instance are only shown while the
+    // dashboard is open to anyone too.
+    let allowed = match query.site.as_deref() {
+        Some(site) => Ok(is_public(&config.public_stats_sites, site)),
+        None => users_exist(&mut conn).map(|exist| !exist),
+    };
+    match allowed {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().json("No badge for this site"),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            return HttpResponse::InternalServerError().json(format!("Database error: {:?}", e));
+        }
+    }
+
+    let total = match count(&mut conn, metric, query.site.as_deref(), from, to) {
+        Ok(total) => total,
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            return HttpResponse::InternalServerError().json(format!("Database error: {:?}", e));
+        }
+    };
+
+    let label = query.label.clone().unwrap_or_else(|| {
+        match metric {
+            BadgeMetric::Visitors => "visitors",
+            BadgeMetric::Pageviews => "pageviews",
+        }
+        .to_string()
+    });
+    let value = match length {
+        Some(_) => format!("{} / {}", format_count(total), period),
+        None => format_count(total),
+    };
+
+    HttpResponse::Ok()
+        .insert_header((
+            http::header::CACHE_CONTROL,
+            format!("public, max-age={}", CACHE_SECONDS),
+        ))
+        .content_type("image/svg+xml")
+        .body(render(&label, &value, &color(query.color.as_deref())))
+}
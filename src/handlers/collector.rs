@@ -1,29 +1,323 @@
-use crate::config::Config;
+use crate::config::{Config, SharedConfig};
 use crate::db::DbPool;
-use crate::models::Collector;
-use crate::utils::geoip::geoip_lookup;
+use crate::models::{Collector, PAGEVIEW_EVENT_NAMES};
+use crate::utils::client_ip::client_ip;
+use crate::utils::geoip::{GeoIp, GeoLocation};
+use crate::utils::ip::anonymize;
+use crate::utils::salt::{visitor_hash, RequestSignature, VisitorSalt};
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
 use actix_web::{http, web, HttpRequest, HttpResponse, Responder};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use chrono::Utc;
 use diesel::prelude::*;
 use diesel::result::Error;
-use std::sync::Arc;
+use log::error;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256, Sha384};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
 use ulid::Ulid;
 use woothee::parser::Parser;
 
-fn generate_analytics_js(cid: &str, app_url: &str) -> String {
-    format!(
-        r#""use strict";
-(function() {{
-    var collectorId = "{}";
+const BLOCKED_JS: &str = "window.stats_collect = function() {};\n";
+const BLOCKED_MODULE: &str =
+    "export function init() {}\nexport function track() { return Promise.resolve(); }\n";
+
+// Set by /exclude-me on browsers that should never be counted
+const EXCLUDE_COOKIE: &str = "stats_ignore";
+
+#[derive(Deserialize)]
+pub struct ScriptQuery {
+    // Pins the script to a version, see `SCRIPT_VERSION`
+    v: Option<u32>,
+    // Asked by pinned scripts, which don't come with a collector
+    collector: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct RenewQuery {
+    collector_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExcludeQuery {
+    undo: Option<bool>,
+}
+
+// Lets site owners stop counting their own visits by opening this page once
+// in each browser they use, `?undo=true` counts the browser again
+pub async fn exclude_me(
+    config: web::Data<SharedConfig>,
+    query: web::Query<ExcludeQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let undo = query.undo.unwrap_or(false);
+
+    let mut cookie = Cookie::build(EXCLUDE_COOKIE, "1")
+        .path("/")
+        .http_only(true)
+        // sent along when stats.js is loaded from another site
+        .same_site(SameSite::None)
+        .secure(config.app_url.starts_with("https://"))
+        .max_age(CookieDuration::days(3650))
+        .finish();
+    if undo {
+        cookie.make_removal();
+    }
+
+    let message = if undo {
+        "Visits from this browser are counted again."
+    } else {
+        "Visits from this browser are no longer counted. Open /exclude-me?undo=true to revert."
+    };
+
+    HttpResponse::Ok()
+        .cookie(cookie)
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!doctype html><title>Stats</title><p>{}</p>",
+            message
+        ))
+}
+
+// Stands in for the collector id in the cached script, see `collector_script`
+const COLLECTOR_ID_MARKER: &str = "__STATS_COLLECTOR_ID__";
+
+// Bump whenever the generated script changes, so pinned `?v=` script tags
+// and their integrity hashes are updated with the server
+pub const SCRIPT_VERSION: u32 = 2;
+const VERSION_HEADER: &str = "X-Stats-Script-Version";
+
+// A visitor whose last event is older than this gets a new collector when
+// their browser revalidates stats.js, like when the cached copy expires.
+// Their old collector expires then too, see `collector_expired`.
+pub(crate) const REUSE_COLLECTOR_MINUTES: i64 = 30;
+
+// The classic stats.js, or stats.mjs for bundlers and frameworks
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScriptVariant {
+    Classic,
+    Module,
+}
+
+impl ScriptVariant {
+    fn blocked_body(self) -> &'static str {
+        match self {
+            ScriptVariant::Classic => BLOCKED_JS,
+            ScriptVariant::Module => BLOCKED_MODULE,
+        }
+    }
+}
+
+// stats.js for the current config, minified once with a marker in place of
+// the collector id
+struct CollectorScript {
+    app_url: String,
+    collect_path: String,
+    script_path: String,
+    download_extensions: Vec<String>,
+    body: String,
+    // Part of the ETag, so a config change invalidates cached copies
+    hash: String,
+    // Served for ?v=, without a collector id so it is the same for everyone
+    pinned: String,
+    // Subresource Integrity hash of `pinned`
+    integrity: String,
+}
+
+static COLLECTOR_SCRIPTS: Lazy<Mutex<HashMap<ScriptVariant, Arc<CollectorScript>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The classic script reads its options from the script tag and runs right
+// away, the module waits for `init()`
+const CLASSIC_START: &str = r#"
+    // Options set as data attributes on the script tag
+    var script = document.currentScript;
+    function option(name) {
+        return script ? script.getAttribute('data-' + name) : null;
+    }
+
+    if (ignored) {
+        window.stats_collect = function() {};
+        return;
+    }
+
+    configure({
+        status: option('status'),
+        trackOutbound: option('track-outbound') !== 'false',
+        trackHash: option('track-hash') === 'true',
+        autoPageview: option('auto-pageview') !== 'false',
+        exclude: option('exclude')
+    });
+    window.stats_collect = stats_collect;
+    start();
+"#;
+
+const MODULE_EXPORTS: &str = r#"
+var started = false;
+
+// Starts tracking, once. Takes the options of the classic script's data
+// attributes: { exclude, trackHash, trackOutbound, autoPageview, status }
+export function init(options = {}) {
+    if (started || ignored) {
+        return;
+    }
+    started = true;
+    configure(options);
+    start();
+}
+
+// track('signup') or track('purchase', { amount: 49, currency: 'USD' })
+export function track(name, props = null) {
+    if (ignored) {
+        return Promise.resolve();
+    }
+    return stats_collect(name, props);
+}
+"#;
+
+fn generate_analytics_js(
+    cid: &str,
+    app_url: &str,
+    collect_path: &str,
+    script_path: &str,
+    download_extensions: &[String],
+    variant: ScriptVariant,
+) -> String {
+    let download_extensions =
+        serde_json::to_string(download_extensions).unwrap_or_else(|_| "[]".to_string());
+    let pageview_names =
+        serde_json::to_string(PAGEVIEW_EVENT_NAMES).unwrap_or_else(|_| "[]".to_string());
+
+    let core = format!(
+        r#"    var collectorId = "{}";
     var appUrl = "{}";
+    var collectPath = "{}";
+    var scriptPath = "{}";
+    var downloadExtensions = {};
+    var pageviewNames = {};
+
+    // Browsers flagged with localStorage.setItem('stats_ignore', '1') are
+    // never counted, see also /exclude-me
+    var ignored = false;
+    try {{
+        ignored = !!window.localStorage && localStorage.getItem('stats_ignore') === '1';
+    }} catch (error) {{
+        // storage not available, e.g. blocked by privacy settings
+    }}
+
+    // A script pinned with ?v= is the same for every visitor, so it asks for
+    // a collector instead and keeps it for the tab while the visitor is active.
+    // Either way the collector expires once the tab was idle for as long, and
+    // is renewed before the next event.
+    var reuseCollectorMs = {} * 60 * 1000;
+    var collector = null;
+    var lastSent = Date.now();
+
+    function currentCollector() {{
+        if (!collector) {{
+            collector = collectorId ? Promise.resolve(collectorId) : loadCollector();
+        }} else if (Date.now() - lastSent >= reuseCollectorMs) {{
+            collector = collector.then(renewCollector);
+        }}
+        lastSent = Date.now();
+        return collector;
+    }}
+
+    // Keeps `id` if its visitor is still active, or gets a new collector
+    function renewCollector(id) {{
+        var url = new URL(appUrl + '/collector/renew');
+        if (id) {{
+            url.searchParams.set('collector_id', id);
+        }}
+        return fetch(url, {{ method: 'POST' }})
+        .then(res => res.status === 200 ? res.json() : {{}})
+        .then(data => data.collector_id || null)
+        .catch(() => null);
+    }}
+
+    function loadCollector() {{
+        try {{
+            var saved = JSON.parse(sessionStorage.getItem('stats_collector'));
+            if (saved && Date.now() - saved.seen < reuseCollectorMs) {{
+                return Promise.resolve(saved.id);
+            }}
+        }} catch (error) {{
+            // storage not available
+        }}
+        return fetch(appUrl + scriptPath + '?collector=true')
+        .then(res => res.ok ? res.json() : {{}})
+        .then(data => data.collector_id || null)
+        .catch(() => null);
+    }}
+
+    function rememberCollector(id) {{
+        try {{
+            sessionStorage.setItem('stats_collector', JSON.stringify({{ id: id, seen: Date.now() }}));
+        }} catch (error) {{
+            // storage not available
+        }}
+    }}
+
+    // HTTP status of the current page, hinted by the embedding page through
+    // the `status` option (data-status) or a stats:status meta tag
+    var statusMeta = document.querySelector('meta[name="stats:status"]');
+    var pageStatus = statusMeta && statusMeta.getAttribute('content');
 
-    function init() {{
+    // Tracking options, see `configure`
+    var trackOutbound = true;
+    var trackHash = false;
+    var autoPageview = true;
+    var excludedPaths = [];
+
+    // `exclude` is a list or comma-separated string of paths like /admin/*,
+    // where `*` matches anything
+    function configure(options) {{
+        pageStatus = options.status || pageStatus;
+        trackOutbound = options.trackOutbound !== false;
+        trackHash = options.trackHash === true;
+        autoPageview = options.autoPageview !== false;
+        var exclude = options.exclude || [];
+        excludedPaths = (typeof exclude === 'string' ? exclude.split(',') : exclude).map(function(pattern) {{
+            return String(pattern).trim();
+        }}).filter(Boolean).map(function(pattern) {{
+            var escaped = pattern.replace(/[.+?^${{}}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*');
+            return new RegExp('^' + escaped + '$');
+        }});
+    }}
+
+    function isExcluded() {{
+        return excludedPaths.some(function(pattern) {{
+            return pattern.test(location.pathname);
+        }});
+    }}
+
+    function isDownload(href) {{
+        try {{
+            var path = new URL(href).pathname.toLowerCase();
+            return downloadExtensions.some(function(extension) {{
+                return path.endsWith('.' + extension);
+            }});
+        }} catch (error) {{
+            return false;
+        }}
+    }}
+
+    function listen() {{
         document.addEventListener('click', function(event) {{
             if (event.target.tagName === 'A') {{
                 var target = event.target.getAttribute('target');
-                var href = event.target.getAttribute('href');
+                // the href property is always absolute, unlike the attribute
+                var href = event.target.href;
                 
-                if (target === '_blank') {{
+                if (isDownload(href)) {{
+                    stats_collect('download', href);
+                }} else if (target === '_blank' && trackOutbound) {{
                     stats_collect('leave', href);
                 }}
             }}
@@ -37,32 +331,162 @@ fn generate_analytics_js(cid: &str, app_url: &str) -> String {
         function wrapHistoryMethod(method) {{
             var original = history[method];
             history[method] = function(state, title, url) {{
-                var fullUrl = new URL(url, window.location.origin).href;
-                console.log("📼 history", method, url, fullUrl);
+                console.log("📼 history", method, url);
+                // engagement so far belongs to the page being left
+                flushEngagement();
                 original.apply(this, arguments);
-                stats_collect('visit', fullUrl);
+                trackNavigation();
             }};
         }}
     
         wrapHistoryMethod('pushState');
         wrapHistoryMethod('replaceState');
     
-        // Listen for popstate event
-        window.addEventListener('popstate', function(event) {{
-            stats_collect('visit', location.href);
+        // Fragment changes fire both, trackNavigation counts them once
+        window.addEventListener('popstate', trackNavigation);
+        window.addEventListener('hashchange', trackNavigation);
+    }}
+
+    // A visit for every new url, where a changed #fragment only counts with
+    // data-track-hash="true"
+    var lastUrl = location.href;
+    function trackNavigation() {{
+        var previous = lastUrl;
+        lastUrl = location.href;
+        if (lastUrl === previous || !autoPageview) {{
+            return;
+        }}
+        if (!trackHash && lastUrl.split('#')[0] === previous.split('#')[0]) {{
+            return;
+        }}
+        stats_collect('visit', lastUrl);
+    }}
+
+    // Core Web Vitals, reported once when the page gets hidden
+    function observeVitals() {{
+        if (!('PerformanceObserver' in window)) {{
+            return;
+        }}
+
+        var vitals = {{}};
+
+        function observe(type, callback) {{
+            try {{
+                new PerformanceObserver(function(list) {{
+                    list.getEntries().forEach(callback);
+                }}).observe({{ type: type, buffered: true }});
+            }} catch (error) {{
+                // entry type not supported by this browser
+            }}
+        }}
+
+        observe('largest-contentful-paint', function(entry) {{
+            vitals.lcp = entry.startTime;
+        }});
+        observe('layout-shift', function(entry) {{
+            if (!entry.hadRecentInput) {{
+                vitals.cls = (vitals.cls || 0) + entry.value;
+            }}
+        }});
+        observe('first-input', function(entry) {{
+            vitals.fid = entry.processingStart - entry.startTime;
+        }});
+        observe('event', function(entry) {{
+            if (entry.interactionId) {{
+                vitals.inp = Math.max(vitals.inp || 0, entry.duration);
+            }}
+        }});
+
+        var navigation = performance.getEntriesByType('navigation')[0];
+        if (navigation) {{
+            vitals.ttfb = navigation.responseStart;
+        }}
+
+        var reported = false;
+        document.addEventListener('visibilitychange', function() {{
+            if (document.visibilityState === 'hidden' && !reported) {{
+                reported = true;
+                Object.keys(vitals).forEach(function(metric) {{
+                    send(metric, null, document.referrer, {{ value: vitals[metric] }});
+                }});
+            }}
         }});
     }}
 
-    async function send(type = "pageview", url_override = null, referrer = document.referrer) {{
-        var url = new URL(appUrl + "/collect");
+    // Heartbeats report the seconds the page was visible since the last one,
+    // so time in a backgrounded tab doesn't count as engagement
+    var heartbeatInterval = 15000;
+    var visibleSince = document.visibilityState === 'visible' ? Date.now() : null;
+    var engagedMs = 0;
+
+    function flushEngagement() {{
+        if (visibleSince !== null) {{
+            engagedMs += Date.now() - visibleSince;
+            visibleSince = Date.now();
+        }}
+        if (engagedMs >= 1000) {{
+            send('heartbeat', null, document.referrer, {{ value: Math.round(engagedMs / 1000) }});
+            engagedMs = 0;
+        }}
+    }}
+
+    function trackEngagement() {{
+        setInterval(function() {{
+            if (document.visibilityState === 'visible') {{
+                flushEngagement();
+            }}
+        }}, heartbeatInterval);
+
+        document.addEventListener('visibilitychange', function() {{
+            if (document.visibilityState === 'hidden') {{
+                flushEngagement();
+                visibleSince = null;
+            }} else {{
+                visibleSince = Date.now();
+            }}
+        }});
+    }}
+
+    async function send(type = "pageview", url_override = null, referrer = document.referrer, props = {{}}, renewed = false) {{
+        if (isExcluded()) {{
+            return;
+        }}
+        var url = new URL(appUrl + collectPath);
+        var pageUrl = url_override || window.location.href;
 
-        url.searchParams.set('collector_id', collectorId);
+        var id = await currentCollector();
+        if (!id) {{
+            return;
+        }}
+        if (!collectorId) {{
+            rememberCollector(id);
+        }}
+        url.searchParams.set('collector_id', id);
         url.searchParams.set('name', type);
-        url.searchParams.set('url', url_override || window.location.href);
+        url.searchParams.set('url', pageUrl);
         url.searchParams.set('referrer', referrer);
+        // Only views carry the status, heartbeats, vitals and links followed
+        // from the page aren't hits on it
+        if (pageStatus && pageviewNames.indexOf(type) !== -1) {{
+            url.searchParams.set('status', pageStatus);
+        }}
+        if (props.value !== undefined && props.value !== null) {{
+            url.searchParams.set('value', props.value);
+        }}
+        if (props.currency) {{
+            url.searchParams.set('currency', props.currency);
+        }}
 
-        fetch(url)
-        .then(res => res.json())
+        // keepalive lets requests fired while the page unloads complete
+        fetch(url, {{ keepalive: true }})
+        .then(res => {{
+            // the collector expired, e.g. the tab slept through its session
+            if (res.status === 410 && !renewed) {{
+                collector = renewCollector(id);
+                return send(type, pageUrl, referrer, props, true);
+            }}
+            return res.json();
+        }})
         .then(data => {{
             // console.log("📼", data);
         }})
@@ -71,116 +495,600 @@ fn generate_analytics_js(cid: &str, app_url: &str) -> String {
         }});
     }}
 
+    // stats_collect('purchase', {{ amount: 49, currency: 'USD' }}) records revenue,
+    // a string second argument overrides the url
     async function stats_collect(type, url = null) {{
-        await send(type, url);
+        if (url !== null && typeof url === 'object') {{
+            var props = url;
+            await send(type, props.url || null, document.referrer, {{
+                value: props.amount !== undefined ? props.amount : props.value,
+                currency: props.currency
+            }});
+        }} else {{
+            await send(type, url);
+        }}
     }}
 
-    window.stats_collect = stats_collect;
-    stats_collect('enter');
+    function start() {{
+        if (autoPageview) {{
+            stats_collect('enter');
+        }}
+
+        observeVitals();
+        trackEngagement();
 
-    window.addEventListener('load', function() {{
-        init();
-    }});
-}})();
+        if (document.readyState === 'complete') {{
+            listen();
+        }} else {{
+            window.addEventListener('load', function() {{
+                listen();
+            }});
+        }}
+    }}
 "#,
-        cid, app_url
+        cid,
+        app_url,
+        collect_path,
+        script_path,
+        download_extensions,
+        pageview_names,
+        REUSE_COLLECTOR_MINUTES
+    );
+
+    match variant {
+        ScriptVariant::Classic => format!(
+            "\"use strict\";\n(function() {{\n{}{}}})();\n",
+            core, CLASSIC_START
+        ),
+        ScriptVariant::Module => format!("{}{}", core, MODULE_EXPORTS),
+    }
+}
+
+// Drops indentation, blank lines and whole-line comments. Line breaks stay,
+// so statements that rely on them keep working.
+fn minify_js(script: &str) -> String {
+    script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn collector_script(config: &Config, variant: ScriptVariant) -> Arc<CollectorScript> {
+    let mut cached = COLLECTOR_SCRIPTS.lock().unwrap();
+    if let Some(script) = cached.get(&variant) {
+        if script.app_url == config.app_url
+            && script.collect_path == config.collect_path
+            && script.script_path == config.script_path
+            && script.download_extensions == config.download_extensions
+        {
+            return script.clone();
+        }
+    }
+
+    let generate = |cid| {
+        minify_js(&generate_analytics_js(
+            cid,
+            &config.app_url,
+            &config.collect_path,
+            &config.script_path,
+            &config.download_extensions,
+            variant,
+        ))
+    };
+    let body = generate(COLLECTOR_ID_MARKER);
+    let pinned = generate("");
+    let hash = format!("{:x}", Sha256::digest(body.as_bytes()));
+    let integrity = format!(
+        "sha384-{}",
+        STANDARD.encode(Sha384::digest(pinned.as_bytes()))
+    );
+    let script = Arc::new(CollectorScript {
+        app_url: config.app_url.clone(),
+        collect_path: config.collect_path.clone(),
+        script_path: config.script_path.clone(),
+        download_extensions: config.download_extensions.clone(),
+        body,
+        hash: hash[..16].to_string(),
+        pinned,
+        integrity,
+    });
+    cached.insert(variant, script.clone());
+    script
+}
+
+fn etag(script: &CollectorScript, collector_id: &str) -> String {
+    format!("W/\"{}-{}\"", script.hash, collector_id)
+}
+
+// The collector of an If-None-Match ETag made from the current script
+fn revalidated_collector<'a>(req: &'a HttpRequest, script: &CollectorScript) -> Option<&'a str> {
+    let tag = req
+        .headers()
+        .get(http::header::IF_NONE_MATCH)?
+        .to_str()
+        .ok()?
+        .trim();
+    let tag = tag.strip_prefix("W/").unwrap_or(tag).trim_matches('"');
+    let (hash, collector_id) = tag.split_once('-')?;
+    (hash == script.hash).then_some(collector_id)
+}
+
+// Whether the visitor is still active, so their browser can keep its copy
+// of stats.js and with it the collector
+fn collector_active(conn: &mut SqliteConnection, collector_id: &str) -> QueryResult<bool> {
+    use crate::schema::events;
+
+    let since = Utc::now().naive_utc() - chrono::Duration::minutes(REUSE_COLLECTOR_MINUTES);
+    diesel::select(diesel::dsl::exists(
+        events::table
+            .filter(events::collector_id.eq(collector_id))
+            .filter(events::timestamp.gt(since))
+            .select(events::id),
+    ))
+    .get_result(conn)
+}
+
+// Whether /collect should turn away events of `collector_id`, so the script
+// renews it: it is older than REUSE_COLLECTOR_MINUTES and its visitor
+// hasn't sent an event for as long. Unknown ids are left alone, they may
+// come from imports or server-side integrations.
+pub(crate) fn collector_expired(
+    conn: &mut SqliteConnection,
+    collector_id: &str,
+) -> QueryResult<bool> {
+    use crate::schema::collectors;
+
+    let created = collectors::table
+        .find(collector_id)
+        .select(collectors::timestamp)
+        .first::<chrono::NaiveDateTime>(conn)
+        .optional()?;
+    let since = Utc::now().naive_utc() - chrono::Duration::minutes(REUSE_COLLECTOR_MINUTES);
+    match created {
+        Some(created) if created <= since => Ok(!collector_active(conn, collector_id)?),
+        _ => Ok(false),
+    }
+}
+
+// Remembers the campaign a collector entered with. Only the first one
+// counts, a later `enter` with other parameters doesn't change it.
+pub(crate) fn set_campaign(
+    conn: &mut SqliteConnection,
+    collector_id: &str,
+    (source, campaign): (Option<String>, Option<String>),
+) -> QueryResult<usize> {
+    use crate::schema::collectors;
+
+    diesel::update(
+        collectors::table
+            .find(collector_id)
+            .filter(collectors::utm_source.is_null())
+            .filter(collectors::utm_campaign.is_null()),
     )
+    .set((
+        collectors::utm_source.eq(source),
+        collectors::utm_campaign.eq(campaign),
+    ))
+    .execute(conn)
+}
+
+// The collector of the same visitor on the same site if they were active
+// recently, so a reload or a new tab doesn't start another session, with
+// the id of the visit it belongs to
+fn recent_collector(
+    conn: &mut SqliteConnection,
+    origin_str: &str,
+    hash: &str,
+) -> QueryResult<Option<(String, String)>> {
+    use crate::schema::collectors;
+
+    let latest = collectors::table
+        .filter(collectors::visitor_hash.eq(hash))
+        .filter(collectors::origin.eq(origin_str))
+        .order(collectors::timestamp.desc())
+        .select((collectors::id, collectors::timestamp, collectors::visit_id))
+        .first::<(String, chrono::NaiveDateTime, Option<String>)>(conn)
+        .optional()?;
+    let Some((id, created, visit_id)) = latest else {
+        return Ok(None);
+    };
+
+    let since = Utc::now().naive_utc() - chrono::Duration::minutes(REUSE_COLLECTOR_MINUTES);
+    if created > since || collector_active(conn, &id)? {
+        let visit_id = visit_id.unwrap_or_else(|| id.clone());
+        Ok(Some((id, visit_id)))
+    } else {
+        Ok(None)
+    }
 }
 
+// Whether a visitor is among the `rate` of visitors who are recorded. The
+// hash is uniformly distributed, so the same share of them always is.
+fn in_sample(hash: &str, rate: f64) -> bool {
+    let position = hash
+        .get(..8)
+        .and_then(|prefix| u32::from_str_radix(prefix, 16).ok())
+        .unwrap_or(0);
+    (position as f64) < rate * u32::MAX as f64
+}
+
+// Returns None for visitors left out by the sample rate
+#[allow(clippy::too_many_arguments)]
 fn create_collector(
     pool: &web::Data<DbPool>,
     origin_str: &str,
-    lookup_country: &str,
-    lookup_city: &str,
+    location: &GeoLocation,
     os_option: Option<String>,
     browser_option: Option<String>,
-) -> Result<String, Error> {
-    use crate::schema::collectors::dsl::*;
+    salt: &VisitorSalt,
+    signature: RequestSignature,
+    dedupe: bool,
+    sample_rate: f64,
+) -> Result<Option<String>, Error> {
+    use crate::schema::collectors::dsl::collectors;
 
     let mut conn = pool.get().expect("couldn't get db connection from pool");
 
+    // Only the salted hash is kept, the ip and headers are dropped here
+    let hash = visitor_hash(&salt.current(&mut conn)?, &signature);
+    if sample_rate < 1.0 && !in_sample(&hash, sample_rate) {
+        return Ok(None);
+    }
+    // Without deduplication the visitor still gets a new collector, which
+    // joins the visit of their recent one
+    let recent = recent_collector(&mut conn, origin_str, &hash)?;
+    let visit_id = match recent {
+        Some((id, _)) if dedupe => return Ok(Some(id)),
+        Some((_, visit_id)) => Some(visit_id),
+        None => None,
+    };
+
     let new_collector = Collector {
         id: Ulid::new().to_string(),
         origin: origin_str.to_string(),
-        country: lookup_country.to_string(),
-        city: lookup_city.to_string(),
+        country: location.country.clone(),
+        city: location.city.clone(),
         os: os_option,
         browser: browser_option,
         timestamp: Utc::now().naive_utc(),
+        latitude: location.latitude,
+        longitude: location.longitude,
+        region: location.region.clone(),
+        asn: location.asn.map(|number| number as i32),
+        as_org: location.as_org.clone(),
+        country_code: location.country_code.clone(),
+        visitor_hash: Some(hash),
+        visit_id,
+        utm_source: None,
+        utm_campaign: None,
     };
 
     diesel::insert_into(collectors)
         .values(&new_collector)
         .execute(&mut conn)?;
 
-    Ok(new_collector.id)
+    Ok(Some(new_collector.id))
 }
 
-pub async fn serve_collector_js(
-    req: HttpRequest,
-    config: web::Data<Arc<Config>>,
+pub(crate) enum NewCollector {
+    Created(String),
+    // Left out by SAMPLE_RATES, nothing is recorded for them
+    NotSampled,
+    Failed,
+}
+
+// Records the visitor and returns the id of their collector, a new one
+// unless they are recognised from a recent visit
+pub(crate) async fn new_collector(
+    req: &HttpRequest,
+    config: &Config,
+    origin: String,
+    real_ip: Option<IpAddr>,
     pool: web::Data<DbPool>,
-) -> impl Responder {
-    let origin = req.headers().get("Origin").map_or_else(
-        || "unknown".to_owned(),
-        |v| v.to_str().unwrap_or("unknown").to_owned(),
-    );
+    geoip: web::Data<Arc<GeoIp>>,
+    salt: web::Data<Arc<VisitorSalt>>,
+) -> NewCollector {
+    // The address is only used for the GeoIP lookup and the visitor hash and
+    // is never stored, with anonymization it is truncated before even that
+    let ip = match real_ip {
+        Some(ip) if config.anonymize_ip => anonymize(ip),
+        Some(ip) => ip,
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
 
-    let db_path = "data/GeoLite2-City.mmdb";
-    let real_ip = req
+    let user_agent = req
         .headers()
-        .get("X-Forwarded-For")
-        .and_then(|v| v.to_str().ok());
-    let ip: &str = real_ip.unwrap_or("0.0.0.0");
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_owned();
+    let accept_language = req
+        .headers()
+        .get(http::header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_owned();
 
     let mut os: Option<String> = None;
     let mut browser: Option<String> = None;
 
-    if let Some(user_agent_string) = req.headers().get("User-Agent") {
-        if let Ok(ua_string) = user_agent_string.to_str() {
-            let parser = Parser::new();
-            let result = parser.parse(ua_string);
-            if let Some(ref parsed_result) = result {
-                os = Some(parsed_result.os.to_string());
-                browser = Some(parsed_result.name.to_string());
-            }
+    if !user_agent.is_empty() {
+        let parser = Parser::new();
+        let result = parser.parse(&user_agent);
+        if let Some(ref parsed_result) = result {
+            os = Some(parsed_result.os.to_string());
+            browser = Some(parsed_result.name.to_string());
         }
     }
 
-    let (lookup_country, lookup_city) = match geoip_lookup(ip, db_path) {
-        Ok((_country, _city)) => (_country.to_owned(), _city.to_owned()),
-        Err(_) => ("Unknown".to_owned(), "Unknown".to_owned()),
-    };
+    let location = geoip
+        .lookup(&ip.to_string())
+        .unwrap_or_else(|_| GeoLocation::unknown());
 
+    let dedupe = config.dedupe_visitors;
+    let sample_rate = config.sample_rate(&origin);
     let collector_result = web::block(move || {
         create_collector(
             &pool,
             &origin,
-            &lookup_country,
-            &lookup_city,
+            &location,
             os.clone(),
             browser.clone(),
+            &salt,
+            RequestSignature {
+                ip,
+                user_agent,
+                accept_language,
+            },
+            dedupe,
+            sample_rate,
         )
     })
     .await;
 
     match collector_result {
-        Ok(collector_id) => match collector_id {
-            Ok(id) => {
-                let js_content = generate_analytics_js(&id, &config.app_url);
-                HttpResponse::Ok()
-                    .insert_header((http::header::CACHE_CONTROL, "public, max-age=1800")) // cache for 30 minutes
-                    .content_type("application/javascript")
-                    .body(js_content)
-            }
-            Err(e) => {
-                eprintln!("Error creating collector: {}", e);
-                HttpResponse::InternalServerError().finish()
-            }
-        },
+        Ok(Ok(Some(id))) => NewCollector::Created(id),
+        Ok(Ok(None)) => NewCollector::NotSampled,
+        Ok(Err(e)) => {
+            error!("Error creating collector: {}", e);
+            NewCollector::Failed
+        }
         Err(e) => {
-            eprintln!("Error serving collector JS: {}", e);
-            HttpResponse::InternalServerError().finish()
+            error!("Error serving collector JS: {}", e);
+            NewCollector::Failed
+        }
+    }
+}
+
+// `?v=N` serves the script without a collector, the same bytes for every
+// visitor, so it can be loaded with the integrity hash from /script-integrity
+fn serve_pinned(
+    req: &HttpRequest,
+    config: &Config,
+    variant: ScriptVariant,
+    version: u32,
+) -> HttpResponse {
+    if version != SCRIPT_VERSION {
+        return HttpResponse::NotFound().json(json!({
+            "error": format!(
+                "Version {} of the script isn't served, the current version is {}",
+                version, SCRIPT_VERSION
+            )
+        }));
+    }
+
+    let script = collector_script(config, variant);
+    let tag = format!("\"{}\"", script.integrity);
+    let cached = req
+        .headers()
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim() == tag);
+    let mut response = if cached {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header((http::header::ETAG, tag))
+        .insert_header((http::header::CACHE_CONTROL, "public, max-age=1800"))
+        .insert_header((VERSION_HEADER, SCRIPT_VERSION.to_string()));
+    if cached {
+        response.finish()
+    } else {
+        response
+            .content_type("application/javascript")
+            .body(script.pinned.clone())
+    }
+}
+
+pub async fn serve_collector_js(
+    req: HttpRequest,
+    query: web::Query<ScriptQuery>,
+    config: web::Data<SharedConfig>,
+    pool: web::Data<DbPool>,
+    geoip: web::Data<Arc<GeoIp>>,
+    salt: web::Data<Arc<VisitorSalt>>,
+) -> impl Responder {
+    serve_script(
+        ScriptVariant::Classic,
+        req,
+        query,
+        config,
+        pool,
+        geoip,
+        salt,
+    )
+    .await
+}
+
+// stats.mjs, exporting `init()` and `track()` for bundlers and frameworks
+pub async fn serve_collector_module(
+    req: HttpRequest,
+    query: web::Query<ScriptQuery>,
+    config: web::Data<SharedConfig>,
+    pool: web::Data<DbPool>,
+    geoip: web::Data<Arc<GeoIp>>,
+    salt: web::Data<Arc<VisitorSalt>>,
+) -> impl Responder {
+    serve_script(ScriptVariant::Module, req, query, config, pool, geoip, salt).await
+}
+
+// Both variants create a collector the same way, only the script differs
+async fn serve_script(
+    variant: ScriptVariant,
+    req: HttpRequest,
+    query: web::Query<ScriptQuery>,
+    config: web::Data<SharedConfig>,
+    pool: web::Data<DbPool>,
+    geoip: web::Data<Arc<GeoIp>>,
+    salt: web::Data<Arc<VisitorSalt>>,
+) -> HttpResponse {
+    let config = config.get();
+    if let Some(version) = query.v {
+        return serve_pinned(&req, &config, variant, version);
+    }
+
+    let origin = req.headers().get("Origin").map_or_else(
+        || "unknown".to_owned(),
+        |v| v.to_str().unwrap_or("unknown").to_owned(),
+    );
+    let real_ip = client_ip(&req, &config);
+    let excluded = req.cookie(EXCLUDE_COOKIE).is_some();
+    let blocked = excluded || real_ip.is_some_and(|ip| config.is_blocked(&ip));
+
+    // Pinned scripts ask for their collector separately
+    let no_collector = || {
+        HttpResponse::NoContent()
+            .insert_header((http::header::CACHE_CONTROL, "no-store"))
+            .finish()
+    };
+    if query.collector == Some(true) {
+        if blocked {
+            return no_collector();
         }
+        return match new_collector(&req, &config, origin, real_ip, pool, geoip, salt).await {
+            NewCollector::Created(id) => HttpResponse::Ok()
+                .insert_header((http::header::CACHE_CONTROL, "no-store"))
+                .json(json!({ "collector_id": id })),
+            NewCollector::NotSampled => no_collector(),
+            NewCollector::Failed => HttpResponse::InternalServerError().finish(),
+        };
     }
+
+    // Blocked, opted out and unsampled visitors get a script that records
+    // nothing, so pages calling `stats_collect` keep working
+    let blocked_script = || {
+        HttpResponse::Ok()
+            // not cached, so undoing /exclude-me takes effect right away
+            .insert_header((http::header::CACHE_CONTROL, "no-store"))
+            .insert_header((VERSION_HEADER, SCRIPT_VERSION.to_string()))
+            .content_type("application/javascript")
+            .body(variant.blocked_body())
+    };
+    if blocked {
+        return blocked_script();
+    }
+
+    // A browser revalidating its copy keeps it while the visitor is active
+    let script = collector_script(&config, variant);
+    if let Some(collector_id) = revalidated_collector(&req, &script) {
+        let active = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+            collector_active(&mut conn, collector_id).map_err(|e| e.to_string())
+        });
+        match active {
+            Ok(true) => {
+                return HttpResponse::NotModified()
+                    .insert_header((http::header::ETAG, etag(&script, collector_id)))
+                    .insert_header((http::header::CACHE_CONTROL, "public, max-age=1800"))
+                    .insert_header((VERSION_HEADER, SCRIPT_VERSION.to_string()))
+                    .finish()
+            }
+            Ok(false) => {}
+            Err(e) => error!("Error checking collector activity: {}", e),
+        }
+    }
+
+    match new_collector(&req, &config, origin, real_ip, pool, geoip, salt).await {
+        NewCollector::Created(id) => HttpResponse::Ok()
+            .insert_header((http::header::CACHE_CONTROL, "public, max-age=1800")) // cache for 30 minutes
+            .insert_header((http::header::ETAG, etag(&script, &id)))
+            .insert_header((VERSION_HEADER, SCRIPT_VERSION.to_string()))
+            .content_type("application/javascript")
+            .body(script.body.replacen(COLLECTOR_ID_MARKER, &id, 1)),
+        NewCollector::NotSampled => blocked_script(),
+        NewCollector::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// Called by stats.js when its collector expired, for a tab left open longer
+// than REUSE_COLLECTOR_MINUTES. The collector is kept while its visitor is
+// still active, otherwise they get a new one like a returning visitor.
+pub async fn renew(
+    req: HttpRequest,
+    query: web::Query<RenewQuery>,
+    config: web::Data<SharedConfig>,
+    pool: web::Data<DbPool>,
+    geoip: web::Data<Arc<GeoIp>>,
+    salt: web::Data<Arc<VisitorSalt>>,
+) -> impl Responder {
+    let config = config.get();
+    let real_ip = client_ip(&req, &config);
+    let blocked =
+        req.cookie(EXCLUDE_COOKIE).is_some() || real_ip.is_some_and(|ip| config.is_blocked(&ip));
+    if blocked {
+        return HttpResponse::NoContent()
+            .insert_header((http::header::CACHE_CONTROL, "no-store"))
+            .finish();
+    }
+
+    let renewed = |id: &str| {
+        HttpResponse::Ok()
+            .insert_header((http::header::CACHE_CONTROL, "no-store"))
+            .json(json!({ "collector_id": id }))
+    };
+    if let Some(collector_id) = query.collector_id.as_deref().filter(|id| !id.is_empty()) {
+        let expired = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+            collector_expired(&mut conn, collector_id).map_err(|e| e.to_string())
+        });
+        match expired {
+            Ok(false) => return renewed(collector_id),
+            Ok(true) => {}
+            Err(e) => error!("Error checking collector activity: {}", e),
+        }
+    }
+
+    let origin = req.headers().get("Origin").map_or_else(
+        || "unknown".to_owned(),
+        |v| v.to_str().unwrap_or("unknown").to_owned(),
+    );
+    match new_collector(&req, &config, origin, real_ip, pool, geoip, salt).await {
+        NewCollector::Created(id) => renewed(&id),
+        NewCollector::NotSampled => HttpResponse::NoContent()
+            .insert_header((http::header::CACHE_CONTROL, "no-store"))
+            .finish(),
+        NewCollector::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// What a pinned script tag needs, e.g.
+// <script src="https://stats.example.com/stats.js?v=1" integrity="sha384-..." crossorigin="anonymous">
+pub async fn script_integrity(config: web::Data<SharedConfig>) -> impl Responder {
+    let config = config.get();
+    let describe = |variant, path: &str| {
+        json!({
+            "url": format!("{}{}?v={}", config.app_url, path, SCRIPT_VERSION),
+            "integrity": collector_script(&config, variant).integrity,
+        })
+    };
+
+    HttpResponse::Ok()
+        .insert_header((VERSION_HEADER, SCRIPT_VERSION.to_string()))
+        .json(json!({
+            "version": SCRIPT_VERSION,
+            "script": describe(ScriptVariant::Classic, &config.script_path),
+            "module": describe(ScriptVariant::Module, "/stats.mjs"),
+        }))
 }
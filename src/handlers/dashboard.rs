@@ -0,0 +1,29 @@
+use crate::config::{GeoipProvider, SharedConfig};
+use actix_web::{web, HttpResponse, Responder};
+use serde_json::json;
+
+// What the dashboard needs to know about this server, so the static files
+// in `ui` work for any deployment. Secrets, credentials and paths on disk
+// are left out.
+pub async fn config(config: web::Data<SharedConfig>) -> impl Responder {
+    let config = config.get();
+
+    HttpResponse::Ok().json(json!({
+        "app_url": config.app_url,
+        "script_path": config.script_path,
+        "collect_path": config.collect_path,
+        "sites": config.cors_domains,
+        // Summaries are bucketed in UTC, the dashboard shows them in the
+        // browser's timezone
+        "timezone": "UTC",
+        "reporting_currency": config.reporting_currency,
+        "features": {
+            "geoip": config.geoip_provider != GeoipProvider::None,
+            "clickhouse": config.event_store.writes_clickhouse(),
+            "event_stream": config.event_stream.map(|broker| broker.name()),
+            "sampling": !config.sample_rates.is_empty(),
+            "share_links": !config.share_link_secret.is_empty(),
+            "public_stats": !config.public_stats_sites.is_empty(),
+        },
+    }))
+}
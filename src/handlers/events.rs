@@ -1,16 +1,20 @@
-use crate::config::Config;
+use crate::config::SharedConfig;
 use crate::db::DbPool;
+use crate::handlers::collector::{collector_expired, set_campaign, REUSE_COLLECTOR_MINUTES};
 use crate::models::{Event, NewEvent};
-use actix_web::{web, HttpResponse, Responder};
-use chrono::Utc;
+use crate::utils::client_ip::client_ip;
+use crate::utils::fields::checked_url;
+use crate::utils::ingest::{Ingest, RawEvent, Rejected};
+use crate::utils::limits::RecentCollectors;
+use crate::utils::url::campaign;
+use actix_web::{http, web, HttpRequest, HttpResponse, Responder};
 use diesel::prelude::*;
-use log::info;
-use regex::Regex;
+use log::{error, info};
 use serde::Deserialize;
+use serde_json::json;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::Sender;
-use ulid::Ulid;
-use url::Url;
 
 #[derive(Deserialize)]
 pub struct EventQuery {
@@ -18,56 +22,114 @@ pub struct EventQuery {
     referrer: Option<String>,
     name: String,
     collector_id: String,
+    status: Option<i32>,
+    value: Option<f64>,
+    currency: Option<String>,
+}
+
+// How /collect answers an event that isn't recorded
+fn rejection(rejected: Rejected) -> HttpResponse {
+    match rejected {
+        Rejected::Ignored => HttpResponse::Ok().json("Event recorded successfully"),
+        Rejected::Invalid(_) | Rejected::NameNotAllowed => {
+            HttpResponse::BadRequest().json(rejected.to_string())
+        }
+        Rejected::Throttled { retry_after } => HttpResponse::TooManyRequests()
+            .insert_header((http::header::RETRY_AFTER, retry_after.to_string()))
+            .json(rejected.to_string()),
+        Rejected::Capped | Rejected::OverQuota => {
+            HttpResponse::TooManyRequests().json(rejected.to_string())
+        }
+    }
 }
 
 pub async fn record_event(
-    config: web::Data<Arc<Config>>,
+    req: HttpRequest,
+    config: web::Data<SharedConfig>,
+    ingest: web::Data<Arc<Ingest>>,
+    recent: web::Data<Arc<RecentCollectors>>,
+    pool: web::Data<DbPool>,
     events_queue: web::Data<Sender<NewEvent>>,
     item: web::Query<EventQuery>,
 ) -> impl Responder {
-    let localhost_regex =
-        Regex::new(r"http://(127\.0\.0\.1|localhost|0\.0\.0\.0|\[::1\])(:\d+)?").unwrap();
+    let config = config.get();
 
-    // Block local requests in production
-    // TODO: i don't think cors is taking care of this because
-    // the origin is not available in localhost?
-    if !config.is_development && localhost_regex.is_match(&item.url) {
-        return HttpResponse::BadRequest().finish();
-    }
+    let raw = RawEvent {
+        url: &item.url,
+        referrer: item.referrer.as_deref(),
+        name: &item.name,
+        collector_id: &item.collector_id,
+        status: item.status,
+        value: item.value,
+        currency: item.currency.as_deref(),
+        ip: client_ip(&req, &config),
+    };
+    let new_event = match ingest.prepare(&config, raw) {
+        Ok(event) => event,
+        Err(rejected) => return rejection(rejected),
+    };
 
-    // Remove query parameters from the URL and trailing slashes
-    let clean_url = match Url::parse(&item.url) {
-        Ok(mut url) => {
-            url.set_query(None);
-            let mut url_str = url.to_string();
-            // Remove trailing slash(es)
-            url_str = url_str.trim_end_matches('/').to_string();
-            url_str
-        }
-        Err(_) => {
-            // Also remove trailing slash(es) if URL parsing fails
-            item.url.trim_end_matches('/').to_string()
+    // A tab left open for hours would keep reporting to a collector whose
+    // session ended long ago, 410 tells stats.js to renew it
+    let ttl = Duration::from_secs(REUSE_COLLECTOR_MINUTES as u64 * 60);
+    if !recent.seen_within(&item.collector_id, ttl) {
+        let collector_id = item.collector_id.clone();
+        let pool = pool.clone();
+        let expired = web::block(move || -> Result<bool, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            collector_expired(&mut conn, &collector_id).map_err(|e| e.to_string())
+        })
+        .await;
+        match expired {
+            Ok(Ok(true)) => {
+                return HttpResponse::Gone().json(json!({
+                    "error": "This collector expired, renew it with POST /collector/renew"
+                }))
+            }
+            Ok(Ok(false)) => {}
+            Ok(Err(e)) => error!("Error checking collector activity: {}", e),
+            Err(e) => error!("Error checking collector activity: {:?}", e),
         }
-    };
+    }
 
-    let new_event = NewEvent {
-        id: Ulid::new().to_string(),
-        url: clean_url,
-        referrer: item.referrer.clone(),
-        name: item.name.clone(),
-        timestamp: Utc::now().naive_utc(),
-        collector_id: item.collector_id.clone(),
-    };
+    if let Err(rejected) = ingest.admit(&config, &pool, &new_event).await {
+        return rejection(rejected);
+    }
 
+    // The url is stored without its utm parameters, the collector keeps the
+    // campaign it entered with for revenue attribution
+    let entered = checked_url(&config, &item.url)
+        .ok()
+        .filter(|_| new_event.name == "enter");
+    if let Some(campaign) = entered.as_deref().and_then(campaign) {
+        let collector_id = item.collector_id.clone();
+        let pool = pool.clone();
+        let updated = web::block(move || -> Result<usize, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            set_campaign(&mut conn, &collector_id, campaign).map_err(|e| e.to_string())
+        })
+        .await;
+        match updated {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Error saving the campaign: {}", e),
+            Err(e) => error!("Error saving the campaign: {:?}", e),
+        }
+    }
+
+    let collector_id = new_event.collector_id.clone();
     match events_queue.send(new_event).await {
-        Ok(_) => HttpResponse::Ok().json("Event recorded successfully"),
+        Ok(_) => {
+            recent.seen(&collector_id);
+            HttpResponse::Ok().json("Event recorded successfully")
+        }
         Err(_) => {
-            eprintln!("Failed to send event to the processing channel.");
+            error!("Failed to send event to the processing channel.");
             HttpResponse::ServiceUnavailable().json("Failed to process event")
         }
     }
 }
 
+#[allow(dead_code)]
 pub async fn retrieve_events(pool: web::Data<DbPool>) -> impl Responder {
     info!("Retrieving events");
     use crate::schema::events::dsl::*;
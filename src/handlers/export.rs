@@ -0,0 +1,91 @@
+use crate::db::DbPool;
+use crate::utils::export::{day_range, load_page, ExportRow, PAGE_SIZE};
+use crate::utils::parquet::ParquetWriter;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::NaiveDate;
+use futures_util::stream;
+use log::error;
+use serde::Deserialize;
+use std::io::{self, Write};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+// Hands what the Parquet writer produces to the response body. Writing
+// fails once the client went away, which ends the export.
+struct ChannelWriter(Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Export was cancelled"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_parquet(
+    pool: &DbPool,
+    query: &ExportQuery,
+    out: ChannelWriter,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let (from, to) = day_range(query.from, query.to);
+    let mut conn = pool.get()?;
+    let mut writer = ParquetWriter::new(out)?;
+
+    let mut written = 0;
+    let mut last: Option<ExportRow> = None;
+    loop {
+        let rows = load_page(&mut conn, from, to, last.as_ref())?;
+        writer.write(&rows)?;
+        written += rows.len();
+
+        if (rows.len() as i64) < PAGE_SIZE {
+            break;
+        }
+        last = rows.into_iter().last();
+    }
+    writer.finish()?;
+    Ok(written)
+}
+
+// Events with their visitor details as a Parquet file. The file is sent as
+// each row group is written, so the export never has to fit in memory.
+pub async fn events_parquet(
+    pool: web::Data<DbPool>,
+    query: web::Query<ExportQuery>,
+) -> impl Responder {
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(16);
+
+    let pool = pool.get_ref().clone();
+    let errors = tx.clone();
+    task::spawn_blocking(move || {
+        if let Err(e) = write_parquet(&pool, &query, ChannelWriter(tx)) {
+            error!("Parquet export failed: {:?}", e);
+            // Aborts the response so the client doesn't keep a truncated file
+            let _ = errors.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+
+    let body = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    HttpResponse::Ok()
+        .content_type("application/vnd.apache.parquet")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("events.parquet".to_string())],
+        })
+        .streaming(body)
+}
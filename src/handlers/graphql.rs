@@ -0,0 +1,17 @@
+use crate::graphql::StatsSchema;
+use actix_web::{web, HttpResponse, Responder};
+use async_graphql::http::GraphiQLSource;
+
+pub async fn graphql(
+    schema: web::Data<StatsSchema>,
+    request: web::Json<async_graphql::Request>,
+) -> impl Responder {
+    HttpResponse::Ok().json(schema.execute(request.into_inner()).await)
+}
+
+// GraphiQL, to explore the schema and try queries in the browser
+pub async fn graphiql() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(GraphiQLSource::build().endpoint("/graphql").finish())
+}
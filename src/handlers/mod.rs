@@ -1,4 +1,21 @@
+pub mod admin;
+pub mod alerts;
+pub mod amp;
+pub mod auth;
+pub mod badge;
 pub mod collector;
+pub mod dashboard;
 pub mod events;
+pub mod export;
+pub mod graphql;
+pub mod pixels;
+pub mod public;
+pub mod query;
+pub mod redirect;
 pub mod sessions;
+pub mod share;
 pub mod summary;
+pub mod tokens;
+pub mod url_groups;
+pub mod url_rules;
+pub mod webhooks;
@@ -0,0 +1,177 @@
+use crate::config::SharedConfig;
+use crate::db::DbPool;
+use crate::handlers::collector::{new_collector, NewCollector};
+use crate::models::{NewEvent, Pixel};
+use crate::schema::pixels;
+use crate::utils::client_ip::client_ip;
+use crate::utils::geoip::GeoIp;
+use crate::utils::ingest::{Ingest, RawEvent};
+use crate::utils::salt::VisitorSalt;
+use actix_web::{http, web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
+use ulid::Ulid;
+
+// A transparent 1x1 GIF
+const PIXEL_GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff!\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+// Collectors of pixel opens have this origin, as mail clients send none
+const PIXEL_ORIGIN: &str = "email";
+
+#[derive(Deserialize)]
+pub struct NewPixel {
+    // e.g. the newsletter issue the pixel is embedded in
+    name: String,
+}
+
+pub async fn list(pool: web::Data<DbPool>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match pixels::table
+        .order(pixels::created_at.asc())
+        .load::<Pixel>(&mut conn)
+    {
+        Ok(pixels) => HttpResponse::Ok().json(pixels),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn create(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    body: web::Json<NewPixel>,
+) -> impl Responder {
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "error": "name is required" }));
+    }
+    let config = config.get();
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let pixel = Pixel {
+        id: Ulid::new().to_string(),
+        name: body.into_inner().name,
+        created_at: Utc::now().naive_utc(),
+    };
+    match diesel::insert_into(pixels::table)
+        .values(&pixel)
+        .execute(&mut conn)
+    {
+        Ok(_) => HttpResponse::Created().json(json!({
+            "id": pixel.id,
+            "name": pixel.name,
+            "created_at": pixel.created_at,
+            "url": format!("{}/p/{}.gif", config.app_url, pixel.id),
+        })),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+// Opens recorded before stay, they just no longer show up per pixel
+pub async fn delete(pool: web::Data<DbPool>, id: web::Path<String>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match diesel::delete(pixels::table.find(id.into_inner())).execute(&mut conn) {
+        Ok(0) => HttpResponse::NotFound().json("No such pixel"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+fn gif() -> HttpResponse {
+    HttpResponse::Ok()
+        // every open has to reach the server
+        .insert_header((http::header::CACHE_CONTROL, "no-store, max-age=0"))
+        .content_type("image/gif")
+        .body(PIXEL_GIF)
+}
+
+// Records an `email_open` with the reader's location and client. It goes
+// through the same checks and limits as events sent to /collect. Unknown
+// pixels, blocked readers and opens over a limit still get the image, so
+// nothing looks broken.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve_pixel(
+    req: HttpRequest,
+    id: web::Path<String>,
+    config: web::Data<SharedConfig>,
+    pool: web::Data<DbPool>,
+    geoip: web::Data<Arc<GeoIp>>,
+    salt: web::Data<Arc<VisitorSalt>>,
+    ingest: web::Data<Arc<Ingest>>,
+    events_queue: web::Data<Sender<NewEvent>>,
+) -> impl Responder {
+    let config = config.get();
+    let id = id.into_inner();
+    let real_ip = client_ip(&req, &config);
+
+    let pixel = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+        pixels::table
+            .find(&id)
+            .first::<Pixel>(&mut conn)
+            .optional()
+            .map_err(|e| e.to_string())
+    });
+    match pixel {
+        Ok(Some(_)) => {}
+        Ok(None) => return gif(),
+        Err(e) => {
+            error!("Error looking up pixel: {}", e);
+            return gif();
+        }
+    }
+
+    // Checked before the reader is recorded, so opens that would be
+    // dropped anyway don't leave collectors behind
+    let url = format!("{}/p/{}.gif", config.app_url, id);
+    let raw = RawEvent {
+        url: &url,
+        referrer: None,
+        name: "email_open",
+        collector_id: "",
+        status: None,
+        value: None,
+        currency: None,
+        ip: real_ip,
+    };
+    let Ok(mut open) = ingest.prepare(&config, raw) else {
+        return gif();
+    };
+
+    let origin = PIXEL_ORIGIN.to_string();
+    let NewCollector::Created(collector_id) =
+        new_collector(&req, &config, origin, real_ip, pool.clone(), geoip, salt).await
+    else {
+        return gif();
+    };
+    open.collector_id = collector_id;
+    if ingest.admit(&config, &pool, &open).await.is_err() {
+        return gif();
+    }
+    if events_queue.send(open).await.is_err() {
+        error!("Failed to send event to the processing channel.");
+    }
+    gif()
+}
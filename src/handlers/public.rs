@@ -0,0 +1,80 @@
+use crate::config::SharedConfig;
+use crate::db::DbPool;
+use crate::utils::site_stats::load_site_stats;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+
+const DEFAULT_DAYS: i64 = 30;
+const MAX_DAYS: i64 = 365;
+const DEFAULT_TOP_LIMIT: i64 = 10;
+const MAX_TOP_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct PublicStatsQuery {
+    // Number of days up to now, defaults to 30
+    days: Option<i64>,
+    limit: Option<i64>,
+}
+
+// The PUBLIC_STATS_SITES origin whose host is `site`, e.g. example.com for
+// https://example.com
+fn public_origin<'a>(sites: &'a [String], site: &str) -> Option<&'a str> {
+    sites
+        .iter()
+        .map(|origin| origin.trim_end_matches('/'))
+        .find(|origin| origin.split_once("://").map(|(_, host)| host) == Some(site))
+}
+
+// Visitors, pageviews and top pages of a site that opted in with
+// PUBLIC_STATS_SITES, for public stats pages. Unlike share links this
+// leaves out referrers, countries and browsers.
+pub async fn public_stats(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    site: web::Path<String>,
+    query: web::Query<PublicStatsQuery>,
+) -> impl Responder {
+    let config = config.get();
+    // Sites that didn't opt in look the same as sites without any traffic
+    let origin = match public_origin(&config.public_stats_sites, &site) {
+        Some(origin) => origin,
+        None => return HttpResponse::NotFound().json("No public stats for this site"),
+    };
+
+    let days = query.days.unwrap_or(DEFAULT_DAYS);
+    if !(1..=MAX_DAYS).contains(&days) {
+        return HttpResponse::BadRequest()
+            .json(json!({ "error": format!("days must be between 1 and {}", MAX_DAYS) }));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TOP_LIMIT)
+        .clamp(1, MAX_TOP_LIMIT);
+    let to = Utc::now().naive_utc();
+    let from = to - Duration::days(days);
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match load_site_stats(&mut conn, origin, from, to, limit) {
+        Ok(stats) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "public, max-age=300"))
+            .json(json!({
+                "site": stats.site,
+                "from": stats.from,
+                "to": stats.to,
+                "visitors": stats.visitors,
+                "pageviews": stats.pageviews,
+                "days": stats.days,
+                "pages": stats.pages,
+            })),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
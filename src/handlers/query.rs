@@ -0,0 +1,25 @@
+use crate::db::DbPool;
+use crate::query::{QueryError, QueryRequest};
+use actix_web::{web, HttpResponse, Responder};
+use log::error;
+use serde_json::json;
+
+pub async fn run_query(pool: web::Data<DbPool>, body: web::Json<QueryRequest>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match body.execute(&mut conn) {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(QueryError::Invalid(message)) => HttpResponse::BadRequest().json(json!({
+            "error": message
+        })),
+        Err(e) => {
+            error!("Query failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({
+                "error": e.to_string()
+            }))
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::SharedConfig;
+use crate::db::DbPool;
+use crate::handlers::collector::{new_collector, NewCollector};
+use crate::models::NewEvent;
+use crate::utils::client_ip::client_ip;
+use crate::utils::geoip::GeoIp;
+use crate::utils::ingest::{Ingest, RawEvent};
+use crate::utils::salt::VisitorSalt;
+use actix_web::{http, web, HttpRequest, HttpResponse, Responder};
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
+use url::Url;
+
+#[derive(Deserialize)]
+pub struct RedirectQuery {
+    url: String,
+    // Links on pages with stats.js can pass theirs, others get a collector
+    // like a new visitor
+    collector_id: Option<String>,
+}
+
+// Records a `leave` and sends the visitor on, for links in emails, markdown
+// and other places stats.js can't run. Only REDIRECT_DOMAINS are redirected
+// to, so links to /r can't be made to point anywhere else. The `leave` goes
+// through the same checks and limits as events sent to /collect, the
+// visitor is redirected either way.
+#[allow(clippy::too_many_arguments)]
+pub async fn redirect(
+    req: HttpRequest,
+    query: web::Query<RedirectQuery>,
+    config: web::Data<SharedConfig>,
+    pool: web::Data<DbPool>,
+    geoip: web::Data<Arc<GeoIp>>,
+    salt: web::Data<Arc<VisitorSalt>>,
+    ingest: web::Data<Arc<Ingest>>,
+    events_queue: web::Data<Sender<NewEvent>>,
+) -> impl Responder {
+    let config = config.get();
+    let query = query.into_inner();
+    let destination = match Url::parse(&query.url) {
+        Ok(url) if config.redirect_allowed(&url) => url,
+        _ => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("{} is not on one of the REDIRECT_DOMAINS", query.url)
+            }))
+        }
+    };
+    let found = HttpResponse::Found()
+        .insert_header((http::header::LOCATION, destination.as_str()))
+        .insert_header((http::header::CACHE_CONTROL, "no-store"))
+        .finish();
+
+    let real_ip = client_ip(&req, &config);
+    let referrer = req
+        .headers()
+        .get(http::header::REFERER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    // Spam and internal traffic shouldn't start collectors either
+    if ingest.ignored(&config, referrer.as_deref(), real_ip) {
+        return found;
+    }
+
+    let collector_id = match query.collector_id.filter(|id| !id.is_empty()) {
+        Some(id) => id,
+        None => {
+            let origin = referrer
+                .as_deref()
+                .and_then(|referrer| Url::parse(referrer).ok())
+                .map_or_else(
+                    || "unknown".to_string(),
+                    |url| url.origin().ascii_serialization(),
+                );
+            match new_collector(&req, &config, origin, real_ip, pool.clone(), geoip, salt).await {
+                NewCollector::Created(id) => id,
+                NewCollector::NotSampled | NewCollector::Failed => return found,
+            }
+        }
+    };
+
+    let raw = RawEvent {
+        url: destination.as_str(),
+        referrer: referrer.as_deref(),
+        name: "leave",
+        collector_id: &collector_id,
+        status: None,
+        value: None,
+        currency: None,
+        ip: real_ip,
+    };
+    let Ok(leave) = ingest.prepare(&config, raw) else {
+        return found;
+    };
+    if ingest.admit(&config, &pool, &leave).await.is_err() {
+        return found;
+    }
+    if events_queue.send(leave).await.is_err() {
+        error!("Failed to send event to the processing channel.");
+    }
+    found
+}
@@ -1,14 +1,16 @@
 use crate::db::DbPool;
 use crate::models::{Collector, Event};
 use crate::schema::{collectors, events};
-use crate::utils::city::get_city_coordinates;
+use crate::utils::countries::{country_centroid, country_name};
 use actix_web::{web, HttpResponse, Responder};
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
-use diesel::sql_types::{BigInt, Text};
+use diesel::sql_types::{BigInt, Double, Nullable, Text};
 use diesel::BelongingToDsl;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize)]
 struct CollectorWithEvents {
@@ -16,27 +18,86 @@ struct CollectorWithEvents {
     events: Vec<Event>,
 }
 
-pub async fn retrieve_sessions(pool: web::Data<DbPool>) -> impl Responder {
+// Collectors of the same visitor stitched into one visit, described by its
+// first collector, with the events of all of them
+#[derive(Serialize)]
+struct Visit {
+    collector: Collector,
+    collector_ids: Vec<String>,
+    events: Vec<Event>,
+}
+
+#[derive(Serialize)]
+struct SessionsPage<T> {
+    sessions: Vec<T>,
+    next_cursor: Option<String>,
+}
+
+const DEFAULT_SESSIONS_LIMIT: i64 = 30;
+const MAX_SESSIONS_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct SessionsQuery {
+    before: Option<String>,
+    limit: Option<i64>,
+    // One session per visit rather than per collector, see `Visit`
+    stitch: Option<bool>,
+}
+
+pub async fn retrieve_sessions(
+    pool: web::Data<DbPool>,
+    query: web::Query<SessionsQuery>,
+) -> impl Responder {
     let mut conn: PooledConnection<ConnectionManager<SqliteConnection>> =
         pool.get().expect("couldn't get db connection from pool");
 
-    let results = match collectors::table
-        .order(collectors::timestamp.desc())
-        .limit(30)
-        .load::<Collector>(&mut conn)
-    {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SESSIONS_LIMIT)
+        .clamp(1, MAX_SESSIONS_LIMIT);
+
+    if query.stitch == Some(true) {
+        return match load_visits(&mut conn, query.before.as_deref(), limit) {
+            Ok(page) => HttpResponse::Ok().json(page),
+            Err(e) => {
+                error!("Error loading visits: {:?}", e);
+                HttpResponse::InternalServerError().json("Error loading visits")
+            }
+        };
+    }
+
+    // Collector ids are ULIDs, so ordering by id is ordering by creation time
+    // and the last id of a page is a stable cursor for the next one
+    let mut collectors_query = collectors::table
+        .order(collectors::id.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(before) = &query.before {
+        collectors_query = collectors_query.filter(collectors::id.lt(before.clone()));
+    }
+
+    let results = match collectors_query.load::<Collector>(&mut conn) {
         Ok(results) => results,
         Err(e) => {
-            eprintln!("Error loading collectors: {:?}", e);
+            error!("Error loading collectors: {:?}", e);
             return HttpResponse::InternalServerError().json("Error loading collectors");
         }
     };
 
-    // If there are no collectors, return an empty array
+    // If there are no collectors, return an empty page
     if results.is_empty() {
-        return HttpResponse::Ok().json(Vec::<CollectorWithEvents>::new());
+        return HttpResponse::Ok().json(SessionsPage {
+            sessions: Vec::<CollectorWithEvents>::new(),
+            next_cursor: None,
+        });
     }
 
+    let next_cursor = if results.len() as i64 == limit {
+        results.last().map(|c| c.id.clone())
+    } else {
+        None
+    };
+
     let collector_ids: Vec<String> = results.iter().map(|c| c.id.clone()).collect();
 
     let events_for_collectors = match Event::belonging_to(&results)
@@ -45,7 +106,7 @@ pub async fn retrieve_sessions(pool: web::Data<DbPool>) -> impl Responder {
     {
         Ok(events) => events,
         Err(e) => {
-            eprintln!("Error loading events: {:?}", e);
+            error!("Error loading events: {:?}", e);
             return HttpResponse::InternalServerError().json("Error loading events");
         }
     }
@@ -59,14 +120,105 @@ pub async fn retrieve_sessions(pool: web::Data<DbPool>) -> impl Responder {
         .map(|(collector, events)| CollectorWithEvents { collector, events })
         .collect();
 
-    HttpResponse::Ok().json(collectors_with_events)
+    HttpResponse::Ok().json(SessionsPage {
+        sessions: collectors_with_events,
+        next_cursor,
+    })
+}
+
+// A page of visits, newest first. Visits are paged by their first
+// collector, the others are added to it wherever they fall.
+fn load_visits(
+    conn: &mut SqliteConnection,
+    before: Option<&str>,
+    limit: i64,
+) -> QueryResult<SessionsPage<Visit>> {
+    let mut first_collectors = collectors::table
+        .filter(
+            collectors::visit_id
+                .is_null()
+                .or(collectors::visit_id.eq(collectors::id.nullable())),
+        )
+        .order(collectors::id.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(before) = before {
+        first_collectors = first_collectors.filter(collectors::id.lt(before.to_string()));
+    }
+    let firsts = first_collectors.load::<Collector>(conn)?;
+    let next_cursor = if firsts.len() as i64 == limit {
+        firsts.last().map(|c| c.id.clone())
+    } else {
+        None
+    };
+
+    let visit_ids: Vec<String> = firsts.iter().map(|c| c.id.clone()).collect();
+    let members = collectors::table
+        .filter(collectors::visit_id.eq_any(&visit_ids))
+        .filter(collectors::id.ne_all(&visit_ids))
+        .order(collectors::id.asc())
+        .select((collectors::id, collectors::visit_id.assume_not_null()))
+        .load::<(String, String)>(conn)?;
+    let collector_ids = visit_ids
+        .iter()
+        .cloned()
+        .chain(members.iter().map(|(id, _)| id.clone()))
+        .collect::<Vec<_>>();
+    let events = events::table
+        .filter(events::collector_id.eq_any(&collector_ids))
+        .order((events::timestamp.asc(), events::id.asc()))
+        .load::<Event>(conn)?;
+
+    let visit_of: HashMap<&str, &str> = members
+        .iter()
+        .map(|(id, visit_id)| (id.as_str(), visit_id.as_str()))
+        .collect();
+    let mut visit_events: HashMap<String, Vec<Event>> = HashMap::new();
+    for event in events {
+        let visit_id = visit_of
+            .get(event.collector_id.as_str())
+            .map_or_else(|| event.collector_id.clone(), |id| id.to_string());
+        visit_events.entry(visit_id).or_default().push(event);
+    }
+
+    // Like per collector, visits without events are left out
+    let sessions = firsts
+        .into_iter()
+        .filter_map(|collector| {
+            let events = visit_events.remove(&collector.id)?;
+            let collector_ids = std::iter::once(collector.id.clone())
+                .chain(
+                    members
+                        .iter()
+                        .filter(|(_, visit_id)| *visit_id == collector.id)
+                        .map(|(id, _)| id.clone()),
+                )
+                .collect();
+            Some(Visit {
+                collector,
+                collector_ids,
+                events,
+            })
+        })
+        .collect();
+
+    Ok(SessionsPage {
+        sessions,
+        next_cursor,
+    })
 }
 
 #[derive(QueryableByName)]
 pub struct CityCount {
-    #[sql_type = "Text"]
+    #[diesel(sql_type = Text)]
     pub city: String,
-    #[sql_type = "BigInt"]
+    #[diesel(sql_type = Nullable<Text>)]
+    pub country_code: Option<String>,
+    #[diesel(sql_type = Nullable<Double>)]
+    pub latitude: Option<f64>,
+    #[diesel(sql_type = Nullable<Double>)]
+    pub longitude: Option<f64>,
+    #[diesel(sql_type = BigInt)]
     pub count: i64,
 }
 
@@ -77,28 +229,84 @@ pub struct CityCollectorCount {
     pub size: f64,
     pub color: String,
     pub city: String,
+    pub country_code: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CountryCollectorCount {
+    pub lat: f64,
+    pub lng: f64,
+    pub size: f64,
+    pub color: String,
+    pub country: Option<&'static str>,
+    pub country_code: String,
+}
+
+#[derive(QueryableByName)]
+struct CountryCount {
+    #[diesel(sql_type = Text)]
+    country_code: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MapLevel {
+    #[default]
+    City,
+    // One point per country, at its centroid
+    Country,
+}
+
+#[derive(Deserialize)]
+pub struct MapQuery {
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    #[serde(default)]
+    level: MapLevel,
 }
 
-pub async fn map(pool: web::Data<DbPool>) -> impl Responder {
+const MAP_POINT_COLOR: &str = "#fa4f33";
+
+// Visitors per city, or per country with `level=country`, in the last 7
+// days unless `from` and `to` say otherwise
+pub async fn map(pool: web::Data<DbPool>, query: web::Query<MapQuery>) -> impl Responder {
+    let end_time = query.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let start_time = query.from.unwrap_or_else(|| end_time - Duration::days(7));
+    if start_time >= end_time {
+        return HttpResponse::BadRequest().json("`from` must be before `to`");
+    }
+
     let mut conn: PooledConnection<ConnectionManager<SqliteConnection>> =
         pool.get().expect("couldn't get db connection from pool");
 
-    let seven_days_ago = Utc::now().naive_utc() - Duration::days(7);
+    match query.level {
+        MapLevel::City => city_map(&mut conn, start_time, end_time),
+        MapLevel::Country => country_map(&mut conn, start_time, end_time),
+    }
+}
 
+fn city_map(
+    conn: &mut SqliteConnection,
+    start_time: NaiveDateTime,
+    end_time: NaiveDateTime,
+) -> HttpResponse {
     let query = r#"
-        SELECT city, COUNT(*) as count
+        SELECT city, UPPER(country_code) AS country_code, AVG(latitude) AS latitude, AVG(longitude) AS longitude, COUNT(*) as count
         FROM collectors
-        WHERE timestamp > ?
-        GROUP BY city
+        WHERE timestamp >= ? AND timestamp < ?
+        GROUP BY city, UPPER(country_code)
     "#;
 
     let results: Vec<CityCount> = match diesel::sql_query(query)
-        .bind::<diesel::sql_types::Timestamp, _>(seven_days_ago)
-        .load::<CityCount>(&mut conn)
+        .bind::<diesel::sql_types::Timestamp, _>(start_time)
+        .bind::<diesel::sql_types::Timestamp, _>(end_time)
+        .load::<CityCount>(conn)
     {
         Ok(results) => results,
         Err(e) => {
-            eprintln!("Error querying city collector counts: {:?}", e);
+            error!("Error querying city collector counts: {:?}", e);
             return HttpResponse::InternalServerError()
                 .json("Error querying city collector counts");
         }
@@ -109,19 +317,75 @@ pub async fn map(pool: web::Data<DbPool>) -> impl Responder {
     let mut city_counts: Vec<CityCollectorCount> = Vec::new();
 
     for city_count in results {
-        if let Some((latitude, longitude)) = get_city_coordinates(&city_count.city) {
+        // Coordinates stored from the GeoIP lookup, or the middle of the
+        // country for visitors without them, see `stats backfill-coordinates`
+        let coordinates = match (city_count.latitude, city_count.longitude) {
+            (Some(latitude), Some(longitude)) => Some((latitude, longitude)),
+            _ => city_count
+                .country_code
+                .as_deref()
+                .and_then(country_centroid),
+        };
+
+        if let Some((latitude, longitude)) = coordinates {
             let relative_size = city_count.count as f64 / max_count as f64;
             city_counts.push(CityCollectorCount {
                 city: city_count.city,
+                country_code: city_count.country_code,
                 lat: latitude,
                 lng: longitude,
                 size: relative_size,
-                color: "#fa4f33".to_string(),
+                color: MAP_POINT_COLOR.to_string(),
             });
         } else {
-            eprintln!("Coordinates not found for city: {}", city_count.city);
+            warn!("Coordinates not found for city: {}", city_count.city);
         }
     }
 
     HttpResponse::Ok().json(city_counts)
 }
+
+fn country_map(
+    conn: &mut SqliteConnection,
+    start_time: NaiveDateTime,
+    end_time: NaiveDateTime,
+) -> HttpResponse {
+    let query = r#"
+        SELECT UPPER(country_code) AS country_code, COUNT(*) as count
+        FROM collectors
+        WHERE timestamp >= ? AND timestamp < ? AND country_code IS NOT NULL
+        GROUP BY UPPER(country_code)
+    "#;
+
+    let results: Vec<CountryCount> = match diesel::sql_query(query)
+        .bind::<diesel::sql_types::Timestamp, _>(start_time)
+        .bind::<diesel::sql_types::Timestamp, _>(end_time)
+        .load::<CountryCount>(conn)
+    {
+        Ok(results) => results,
+        Err(e) => {
+            error!("Error querying country collector counts: {:?}", e);
+            return HttpResponse::InternalServerError()
+                .json("Error querying country collector counts");
+        }
+    };
+
+    let max_count = results.iter().map(|c| c.count).max().unwrap_or(1);
+
+    let country_counts: Vec<CountryCollectorCount> = results
+        .into_iter()
+        .filter_map(|country_count| {
+            let (lat, lng) = country_centroid(&country_count.country_code)?;
+            Some(CountryCollectorCount {
+                lat,
+                lng,
+                size: country_count.count as f64 / max_count as f64,
+                color: MAP_POINT_COLOR.to_string(),
+                country: country_name(&country_count.country_code),
+                country_code: country_count.country_code,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(country_counts)
+}
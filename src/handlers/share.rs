@@ -0,0 +1,134 @@
+use crate::config::SharedConfig;
+use crate::db::DbPool;
+use crate::utils::share::{sign, verify, ShareClaims};
+use crate::utils::site_stats::load_site_stats;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Duration, NaiveDate, Utc};
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+const DEFAULT_RANGE_DAYS: i64 = 30;
+const DEFAULT_EXPIRY_DAYS: i64 = 30;
+const MAX_EXPIRY_DAYS: i64 = 3650;
+const DEFAULT_TOP_LIMIT: i64 = 10;
+const MAX_TOP_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct ShareLinkRequest {
+    // Origin of the site, e.g. https://example.com
+    site: String,
+    // First and last day the link shows, defaults to the last 30 days
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    expires_in_days: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct SharedStatsQuery {
+    limit: Option<i64>,
+}
+
+pub async fn create_share_link(
+    config: web::Data<SharedConfig>,
+    body: web::Json<ShareLinkRequest>,
+) -> impl Responder {
+    let config = config.get();
+    if config.share_link_secret.is_empty() {
+        return HttpResponse::NotFound().json("Share links are disabled, set SHARE_LINK_SECRET");
+    }
+    match Url::parse(&body.site) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("{} is not an http(s) origin", body.site)
+            }))
+        }
+    }
+
+    let to = body.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = match body.from {
+        Some(from) => Some(from),
+        None => to.checked_sub_signed(Duration::days(DEFAULT_RANGE_DAYS - 1)),
+    };
+    // The range ends at the start of the day after `to`
+    let (Some(from), Some(end)) = (from, to.succ_opt()) else {
+        return HttpResponse::BadRequest().json(json!({ "error": "from or to is out of range" }));
+    };
+    if from > to {
+        return HttpResponse::BadRequest().json(json!({ "error": "from is after to" }));
+    }
+    let expires_in_days = body.expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if expires_in_days <= 0 || expires_in_days > MAX_EXPIRY_DAYS {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("expires_in_days must be between 1 and {}", MAX_EXPIRY_DAYS)
+        }));
+    }
+    let Some(expires_at) = Utc::now()
+        .naive_utc()
+        .checked_add_signed(Duration::days(expires_in_days))
+    else {
+        return HttpResponse::BadRequest()
+            .json(json!({ "error": "expires_in_days is out of range" }));
+    };
+
+    let claims = ShareClaims::new(
+        &body.site,
+        from.and_hms_opt(0, 0, 0).unwrap(),
+        end.and_hms_opt(0, 0, 0).unwrap(),
+        expires_at,
+    );
+
+    match sign(&config.share_link_secret, &claims) {
+        Ok(token) => HttpResponse::Created().json(json!({
+            "url": format!("{}/shared/{}", config.app_url.trim_end_matches('/'), token),
+            "token": token,
+            "site": claims.site,
+            "from": from,
+            "to": to,
+            "expires_at": expires_at,
+        })),
+        Err(e) => {
+            error!("Failed to sign share link: {}", e);
+            HttpResponse::InternalServerError().json(format!("Failed to sign share link: {}", e))
+        }
+    }
+}
+
+// Read-only stats of the site and range a share link was created for
+pub async fn shared_stats(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    token: web::Path<String>,
+    query: web::Query<SharedStatsQuery>,
+) -> impl Responder {
+    let config = config.get();
+    if config.share_link_secret.is_empty() {
+        return HttpResponse::NotFound().json("Share links are disabled");
+    }
+    let claims = match verify(&config.share_link_secret, &token) {
+        Ok(claims) => claims,
+        Err(_) => return HttpResponse::Unauthorized().json("Invalid or expired share link"),
+    };
+
+    let (from, to) = claims.range();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TOP_LIMIT)
+        .clamp(1, MAX_TOP_LIMIT);
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match load_site_stats(&mut conn, &claims.site, from, to, limit) {
+        Ok(stats) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "private, max-age=60"))
+            .json(stats),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
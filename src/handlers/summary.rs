@@ -1,16 +1,340 @@
-use crate::db::DbPool;
-use actix_web::{web, HttpResponse, Responder};
-use chrono::{Duration, NaiveDateTime, Utc};
+use crate::config::{Config, SharedConfig};
+use crate::db::{blocking, DbPool};
+use crate::models::{
+    BUILTIN_EVENT_NAMES, MEASUREMENT_EVENT_NAMES, PAGEVIEW_EVENT_NAMES, WEB_VITAL_NAMES,
+};
+use crate::utils::active::{ActiveCount, ActiveVisitors, ACTIVE_MINUTES};
+use crate::utils::clickhouse::ClickHouse;
+use crate::utils::rollup::{sql_name_list, CountedEvents, Level};
+use crate::utils::url::clean_url;
+use actix_web::{http, web, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Months, NaiveDateTime, Utc};
 use diesel::prelude::*;
-use diesel::sql_types::{BigInt, Integer, Text, Timestamp};
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
+use diesel::sql_types::{BigInt, Bool, Double, Integer, Nullable, Text, Timestamp};
+use diesel::sqlite::Sqlite;
+use log::error;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
 
 use serde_json::json;
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compare {
+    PreviousPeriod,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    #[default]
+    CountDesc,
+    CountAsc,
+    LabelAsc,
+    LabelDesc,
+}
+
+impl Sort {
+    // ORDER BY clause for a top-N query grouped by `labels`
+    fn order_by(&self, labels: &[&str]) -> String {
+        self.order_by_metric("count", labels)
+    }
+
+    fn order_by_metric(&self, metric: &str, labels: &[&str]) -> String {
+        let direction = match self {
+            Sort::CountDesc | Sort::LabelDesc => "DESC",
+            Sort::CountAsc | Sort::LabelAsc => "ASC",
+        };
+        match self {
+            Sort::CountDesc | Sort::CountAsc => format!("{} {}", metric, direction),
+            Sort::LabelAsc | Sort::LabelDesc => labels
+                .iter()
+                .map(|label| format!("{} {}", label, direction))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+}
+
+const DEFAULT_PAGE_LIMIT: i64 = 25;
+const MAX_PAGE_LIMIT: i64 = 100;
+const MAX_PAGE_OFFSET: i64 = 10_000;
+
+pub struct Page {
+    limit: i64,
+    offset: i64,
+    sort: Sort,
+}
+
+#[derive(Deserialize)]
+pub struct SummaryQuery {
+    url: Option<String>,
+    // Only events on this host, e.g. blog.example.com
+    host: Option<String>,
+    compare: Option<Compare>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    sort: Option<Sort>,
+    include_builtin: Option<bool>,
+    by: Option<OutboundGrouping>,
+    exclude_datacenters: Option<bool>,
+    // Count a page viewed in several tabs of one visit once, see `visit_id`
+    stitch: Option<bool>,
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    origin: Option<String>,
+}
+
+impl Page {
+    fn new(limit: Option<i64>, offset: Option<i64>, sort: Option<Sort>) -> Self {
+        Page {
+            limit: limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT),
+            offset: offset.unwrap_or(0).clamp(0, MAX_PAGE_OFFSET),
+            sort: sort.unwrap_or_default(),
+        }
+    }
+}
+
+impl SummaryQuery {
+    fn page(&self) -> Page {
+        Page::new(self.limit, self.offset, self.sort)
+    }
+
+    fn dimensions(&self) -> Dimensions {
+        Dimensions {
+            country: self.country.clone(),
+            region: self.region.clone(),
+            city: self.city.clone(),
+            browser: self.browser.clone(),
+            os: self.os.clone(),
+            origin: self.origin.clone(),
+            page: self.url.clone(),
+        }
+    }
+
+    fn filters(&self, config: &Config, column: &str) -> Filters {
+        self.dimensions()
+            .filters(config, column, self.exclude_datacenters)
+    }
+
+    // The traffic charts, like the timeseries, only leave out datacenter
+    // traffic when asked for, so without filters they can count rollups
+    fn chart_filters(&self, config: &Config) -> Filters {
+        self.dimensions().filters(
+            config,
+            "collector_id",
+            Some(self.exclude_datacenters.unwrap_or(false)),
+        )
+    }
+
+    // Referrers take `url` as the page the events were on, rather than one
+    // the session viewed
+    fn referrer_filters(&self, config: &Config) -> Filters {
+        Dimensions {
+            page: None,
+            ..self.dimensions()
+        }
+        .filters(config, "collector_id", self.exclude_datacenters)
+    }
+}
+
+// Other dimensions a summary can be narrowed down to, e.g. the top pages of
+// German Android visitors with `country=DE&os=Android`. A session matches
+// when its collector has all of them and it viewed `page`.
+struct Dimensions {
+    // A country code like `DE`, or the name of the country
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    origin: Option<String>,
+    // A path like `/pricing`, or a full url
+    page: Option<String>,
+}
+
+impl Dimensions {
+    // Filters on `column`, the collector id of the rows a summary counts
+    fn filters(&self, config: &Config, column: &str, exclude_datacenters: Option<bool>) -> Filters {
+        let mut filters = Filters {
+            sql: datacenter_filter(exclude_datacenters, config, column),
+            values: Vec::new(),
+        };
+
+        let mut conditions = Vec::new();
+        if let Some(country) = &self.country {
+            if country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) {
+                conditions.push("country_code = ?");
+                filters.values.push(country.to_ascii_uppercase());
+            } else {
+                conditions.push("country = ?");
+                filters.values.push(country.clone());
+            }
+        }
+        for (condition, value) in [
+            ("region = ?", &self.region),
+            ("city = ?", &self.city),
+            ("browser = ?", &self.browser),
+            ("os = ?", &self.os),
+            ("origin = ?", &self.origin),
+        ] {
+            if let Some(value) = value {
+                conditions.push(condition);
+                filters.values.push(value.clone());
+            }
+        }
+        if !conditions.is_empty() {
+            filters.sql.push_str(&format!(
+                " AND {} IN (SELECT id FROM collectors WHERE {})",
+                column,
+                conditions.join(" AND ")
+            ));
+        }
+
+        // Paths are recorded without a trailing slash, urls cleaned the same
+        // way as when they are recorded
+        if let Some(page) = &self.page {
+            let (condition, value) = if page.starts_with('/') {
+                match page.trim_end_matches('/') {
+                    "" => ("path = ?", "/".to_string()),
+                    path => ("path = ?", path.to_string()),
+                }
+            } else {
+                ("url = ?", clean_url(page, config))
+            };
+            filters.sql.push_str(&format!(
+                " AND {} IN (SELECT collector_id FROM events WHERE {})",
+                column, condition
+            ));
+            filters.values.push(value);
+        }
+        filters
+    }
+}
+
+// `AND ...` clauses keeping the sessions a summary is narrowed down to. They
+// go right after the time range of a query, and their values are bound
+// right after the range's.
+#[derive(Default)]
+struct Filters {
+    sql: String,
+    values: Vec<String>,
+}
+
+impl Filters {
+    fn is_empty(&self) -> bool {
+        self.sql.is_empty()
+    }
+
+    fn bind<'a>(
+        &self,
+        query: BoxedSqlQuery<'a, Sqlite, SqlQuery>,
+    ) -> BoxedSqlQuery<'a, Sqlite, SqlQuery> {
+        self.values
+            .iter()
+            .fold(query, |query, value| query.bind::<Text, _>(value.clone()))
+    }
+}
+
+// `AND <column> NOT IN (...)` leaving out collectors on known datacenter
+// networks, or nothing when datacenter traffic is kept
+fn datacenter_filter(exclude: Option<bool>, config: &Config, column: &str) -> String {
+    let exclude = exclude.unwrap_or(config.exclude_datacenters);
+    if !exclude || config.datacenter_asns.is_empty() {
+        return String::new();
+    }
+
+    let asns = config
+        .datacenter_asns
+        .iter()
+        .map(|asn| asn.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "AND {} NOT IN (SELECT id FROM collectors WHERE asn IN ({}))",
+        column, asns
+    )
+}
+
+// The session an event belongs to: its collector, or the visit of its
+// collector when collectors of the same visitor are stitched together
+fn session_column(stitch: bool) -> &'static str {
+    if stitch {
+        "COALESCE((SELECT visit_id FROM collectors WHERE collectors.id = events.collector_id), collector_id)"
+    } else {
+        "collector_id"
+    }
+}
+
+// Start and end of the `n`th window of `length` counting back from `now`,
+// so `n = 0` is the current period and `n = 1` the one before it
+fn window(now: NaiveDateTime, length: Duration, n: i32) -> (NaiveDateTime, NaiveDateTime) {
+    let end = now - length * n;
+    (end - length, end)
+}
+
+// Counts and amounts of sampled sites are multiplied back up to estimate
+// all traffic, `by` being the inverse of the sample rate. Each summary
+// scales its own counts; percentiles, durations and ratios are left alone
+// as sampling doesn't change them.
+trait Scale {
+    fn scale(&mut self, by: f64);
+}
+
+fn scale_count(count: &mut i64, by: f64) {
+    *count = (*count as f64 * by).round() as i64;
+}
+
+impl<T: Scale> Scale for Vec<T> {
+    fn scale(&mut self, by: f64) {
+        self.iter_mut().for_each(|item| item.scale(by));
+    }
+}
+
+fn scaled<T: Scale>(mut summary: T, by: f64) -> T {
+    if by != 1.0 {
+        summary.scale(by);
+    }
+    summary
+}
+
+// Responds with the current period, or with `{ current, previous }` when the
+// caller asked to compare against the preceding period
+fn compared<T: Serialize + Scale>(
+    compare: &Option<Compare>,
+    scale: f64,
+    mut load: impl FnMut(i32) -> QueryResult<T>,
+) -> HttpResponse {
+    let mut load = |n| load(n).map(|summary| scaled(summary, scale));
+    let results = match compare {
+        Some(Compare::PreviousPeriod) => load(0).and_then(|current| {
+            load(1).map(|previous| json!({ "current": current, "previous": previous }))
+        }),
+        None => load(0).map(|current| json!(current)),
+    };
+
+    match results {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
 #[derive(QueryableByName, Debug, Serialize)]
 pub struct EventCounts {
     #[diesel(sql_type = BigInt)]
     pub sessions_in_last_twenty_four_hours: i64,
     #[diesel(sql_type = BigInt)]
+    pub visitors_in_last_twenty_four_hours: i64,
+    #[diesel(sql_type = BigInt)]
     pub events_in_last_twenty_four_hours: i64,
     #[diesel(sql_type = BigInt)]
     pub events_in_last_hour: i64,
@@ -18,255 +342,2019 @@ pub struct EventCounts {
     pub events_in_last_five_minutes: i64,
 }
 
-#[derive(QueryableByName, Debug, Serialize)]
+impl Scale for EventCounts {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.sessions_in_last_twenty_four_hours, by);
+        scale_count(&mut self.visitors_in_last_twenty_four_hours, by);
+        scale_count(&mut self.events_in_last_twenty_four_hours, by);
+        scale_count(&mut self.events_in_last_hour, by);
+        scale_count(&mut self.events_in_last_five_minutes, by);
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct FiveMinuteEventSummary {
+    pub interval: String,
+    pub count: i64,
+}
+
+impl Scale for FiveMinuteEventSummary {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+pub async fn five_minutes(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    clickhouse: web::Data<Arc<ClickHouse>>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let end_time = Utc::now().naive_utc();
+    let start_time = end_time - Duration::days(1);
+
+    let filters = query.chart_filters(&config);
+    let result = if config.event_store.writes_clickhouse() && filters.is_empty() {
+        load_clickhouse_timeseries(&clickhouse, Bucket::FiveMinutes, start_time, end_time).await
+    } else {
+        let mut conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(_) => {
+                return HttpResponse::ServiceUnavailable().json("Could not get DB connection")
+            }
+        };
+        load_timeseries(
+            &mut conn,
+            Bucket::FiveMinutes,
+            start_time,
+            end_time,
+            &filters,
+        )
+        .map_err(Into::into)
+    };
+
+    match result {
+        Ok(buckets) => HttpResponse::Ok().json(scaled(
+            buckets
+                .into_iter()
+                .map(|b| FiveMinuteEventSummary {
+                    interval: b.bucket.format("%Y-%m-%d %H:%M:00").to_string(),
+                    count: b.count,
+                })
+                .collect::<Vec<_>>(),
+            config.sample_scale(None),
+        )),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+fn load_event_counts(
+    conn: &mut SqliteConnection,
+    n: i32,
+    collector_filters: &Filters,
+    event_filters: &Filters,
+) -> QueryResult<EventCounts> {
+    let now = Utc::now().naive_utc();
+    let (day_start, day_end) = window(now, Duration::hours(24), n);
+    let (hour_start, hour_end) = window(now, Duration::hours(1), n);
+    let (minutes_start, minutes_end) = window(now, Duration::minutes(5), n);
+
+    let measurements = sql_name_list(MEASUREMENT_EVENT_NAMES);
+
+    let query = diesel::sql_query(format!(
+        "SELECT \
+        (SELECT COUNT(*) FROM collectors WHERE timestamp >= ? AND timestamp < ? {1}) AS sessions_in_last_twenty_four_hours, \
+        (SELECT COUNT(DISTINCT COALESCE(visitor_hash, id)) FROM collectors WHERE timestamp >= ? AND timestamp < ? {1}) AS visitors_in_last_twenty_four_hours, \
+        (SELECT COUNT(*) FROM events WHERE timestamp >= ? AND timestamp < ? {2} AND name NOT IN ({0})) AS events_in_last_twenty_four_hours, \
+        (SELECT COUNT(*) FROM events WHERE timestamp >= ? AND timestamp < ? {2} AND name NOT IN ({0})) AS events_in_last_five_minutes, \
+        (SELECT COUNT(*) FROM events WHERE timestamp >= ? AND timestamp < ? {2} AND name NOT IN ({0})) AS events_in_last_hour",
+        measurements, collector_filters.sql, event_filters.sql
+    ))
+    .into_boxed();
+
+    let ranges = [
+        (day_start, day_end, collector_filters),
+        (day_start, day_end, collector_filters),
+        (day_start, day_end, event_filters),
+        (minutes_start, minutes_end, event_filters),
+        (hour_start, hour_end, event_filters),
+    ];
+    ranges
+        .into_iter()
+        .fold(query, |query, (start, end, filters)| {
+            filters.bind(query.bind::<Timestamp, _>(start).bind::<Timestamp, _>(end))
+        })
+        .get_result(conn)
+}
+
+pub async fn events(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+
+    let collector_filters = query.filters(&config, "id");
+    let event_filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_event_counts(&mut conn, n, &collector_filters, &event_filters)
+    })
+}
+
+#[derive(Serialize)]
+struct HourlyEventSummary {
+    hour: NaiveDateTime,
+    count: i64,
+}
+
+impl Scale for HourlyEventSummary {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_hourly(
+    conn: &mut SqliteConnection,
+    filters: &Filters,
+) -> QueryResult<Vec<HourlyEventSummary>> {
+    let end_time = Utc::now().naive_utc();
+    let start_time = end_time - Duration::days(1);
+
+    load_timeseries(conn, Bucket::Hour, start_time, end_time, filters).map(|buckets| {
+        buckets
+            .into_iter()
+            .map(|b| HourlyEventSummary {
+                hour: b.bucket,
+                count: b.count,
+            })
+            .collect()
+    })
+}
+
+pub async fn hourly(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match load_hourly(&mut conn, &query.chart_filters(&config)) {
+        Ok(hours) => HttpResponse::Ok().json(scaled(hours, config.sample_scale(None))),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+// Guardrail so a wide range with a small bucket can't produce a huge response
+const MAX_TIMESERIES_BUCKETS: i64 = 1000;
+
+#[derive(Deserialize, Clone, Copy)]
+pub enum Bucket {
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+    #[serde(rename = "1w")]
+    Week,
+}
+
+impl Bucket {
+    fn seconds(&self) -> i64 {
+        match self {
+            Bucket::FiveMinutes => 5 * 60,
+            Bucket::Hour => 60 * 60,
+            Bucket::Day => 24 * 60 * 60,
+            Bucket::Week => 7 * 24 * 60 * 60,
+        }
+    }
+
+    // Shift applied before bucketing so weeks start on Monday
+    // (the unix epoch fell on a Thursday)
+    fn offset(&self) -> i64 {
+        match self {
+            Bucket::Week => 4 * 24 * 60 * 60,
+            _ => 0,
+        }
+    }
+
+    // Start of the bucket that contains `time`
+    fn floor(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let seconds = time.and_utc().timestamp() - self.offset();
+        let start = seconds - seconds.rem_euclid(self.seconds()) + self.offset();
+        DateTime::from_timestamp(start, 0)
+            .map(|t| t.naive_utc())
+            .unwrap_or(time)
+    }
+
+    fn default_range(&self) -> Duration {
+        match self {
+            Bucket::FiveMinutes | Bucket::Hour => Duration::days(1),
+            Bucket::Day => Duration::days(30),
+            Bucket::Week => Duration::weeks(12),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TimeseriesQuery {
+    bucket: Bucket,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    exclude_datacenters: Option<bool>,
+    url: Option<String>,
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    origin: Option<String>,
+}
+
+impl TimeseriesQuery {
+    // Unlike other summaries, datacenter traffic is only left out when asked
+    // for, as ClickHouse can't tell it apart
+    fn filters(&self, config: &Config) -> Filters {
+        Dimensions {
+            country: self.country.clone(),
+            region: self.region.clone(),
+            city: self.city.clone(),
+            browser: self.browser.clone(),
+            os: self.os.clone(),
+            origin: self.origin.clone(),
+            page: self.url.clone(),
+        }
+        .filters(
+            config,
+            "collector_id",
+            Some(self.exclude_datacenters.unwrap_or(false)),
+        )
+    }
+}
+
+#[derive(QueryableByName, Serialize)]
+pub struct TimeseriesBucket {
+    #[diesel(sql_type = Timestamp)]
+    pub bucket: NaiveDateTime,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+}
+
+impl Scale for TimeseriesBucket {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+pub async fn timeseries(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    clickhouse: web::Data<Arc<ClickHouse>>,
+    query: web::Query<TimeseriesQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let bucket = query.bucket;
+    let end_time = query.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let start_time = query
+        .from
+        .unwrap_or_else(|| end_time - bucket.default_range());
+
+    if start_time >= end_time {
+        return HttpResponse::BadRequest().json("`from` must be before `to`");
+    }
+
+    let bucket_count = (end_time - start_time).num_seconds() / bucket.seconds();
+    if bucket_count > MAX_TIMESERIES_BUCKETS {
+        return HttpResponse::BadRequest().json(format!(
+            "Requested range spans {} buckets, the maximum is {}",
+            bucket_count, MAX_TIMESERIES_BUCKETS
+        ));
+    }
+
+    // Collectors are only kept in SQLite, so filtered counts use its events
+    let filters = query.filters(&config);
+    let result = if config.event_store.writes_clickhouse() && filters.is_empty() {
+        load_clickhouse_timeseries(&clickhouse, bucket, start_time, end_time).await
+    } else {
+        let mut conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(_) => {
+                return HttpResponse::ServiceUnavailable().json("Could not get DB connection")
+            }
+        };
+        load_timeseries(&mut conn, bucket, start_time, end_time, &filters).map_err(Into::into)
+    };
+
+    match result {
+        Ok(summary) => HttpResponse::Ok().json(scaled(summary, config.sample_scale(None))),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+// Event counts per bucket between `start_time` and `end_time`, including
+// zero counts for buckets where nothing was recorded
+fn load_timeseries(
+    conn: &mut SqliteConnection,
+    bucket: Bucket,
+    start_time: NaiveDateTime,
+    end_time: NaiveDateTime,
+    filters: &Filters,
+) -> QueryResult<Vec<TimeseriesBucket>> {
+    let level = match bucket {
+        _ if !filters.is_empty() => None,
+        Bucket::FiveMinutes => None,
+        Bucket::Hour => Some(Level::Hourly),
+        Bucket::Day | Bucket::Week => Some(Level::Daily),
+    };
+    let events = CountedEvents::new(conn, level, start_time, end_time)?;
+
+    let sql = format!(
+        "
+        SELECT datetime(
+            (CAST(strftime('%s', timestamp) AS INTEGER) - ?) / ? * ? + ?,
+            'unixepoch'
+        ) AS bucket, SUM(count) AS count
+        FROM ({})
+        GROUP BY bucket
+        ORDER BY bucket ASC;
+    ",
+        events.sql(&filters.sql)
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<BigInt, _>(bucket.offset())
+        .bind::<BigInt, _>(bucket.seconds())
+        .bind::<BigInt, _>(bucket.seconds())
+        .bind::<BigInt, _>(bucket.offset());
+    let rows: Vec<TimeseriesBucket> = filters.bind(events.bind(query)).load(conn)?;
+
+    let counts: HashMap<NaiveDateTime, i64> =
+        rows.into_iter().map(|r| (r.bucket, r.count)).collect();
+
+    Ok(fill_buckets(bucket, start_time, end_time, counts))
+}
+
+// Same as `load_timeseries`, counted by ClickHouse
+async fn load_clickhouse_timeseries(
+    clickhouse: &ClickHouse,
+    bucket: Bucket,
+    start_time: NaiveDateTime,
+    end_time: NaiveDateTime,
+) -> Result<Vec<TimeseriesBucket>, Box<dyn Error + Send + Sync>> {
+    let counts = clickhouse
+        .count_events(bucket.seconds(), bucket.offset(), start_time, end_time)
+        .await?;
+    Ok(fill_buckets(bucket, start_time, end_time, counts))
+}
+
+// One entry per bucket from `start_time` to `end_time`, zero where nothing
+// was recorded
+fn fill_buckets(
+    bucket: Bucket,
+    start_time: NaiveDateTime,
+    end_time: NaiveDateTime,
+    counts: HashMap<NaiveDateTime, i64>,
+) -> Vec<TimeseriesBucket> {
+    let mut filled = Vec::new();
+    let mut current = bucket.floor(start_time);
+    while current <= end_time {
+        filled.push(TimeseriesBucket {
+            bucket: current,
+            count: counts.get(&current).copied().unwrap_or(0),
+        });
+        current += Duration::seconds(bucket.seconds());
+    }
+    filled
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct UrlEventCount {
+    #[diesel(sql_type = Text)]
+    pub url: String,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+}
+
+impl Scale for UrlEventCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_urls(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    host: Option<&str>,
+    search: Option<&str>,
+    page: &Page,
+) -> QueryResult<Vec<UrlEventCount>> {
+    // Filters and hosts can only be applied to the raw events
+    let level = Some(Level::Daily).filter(|_| filters.is_empty() && host.is_none());
+    let events = CountedEvents::new(conn, level, start_time, end_time)?;
+    let filter = match host {
+        Some(_) => format!("{} AND host = ?", filters.sql),
+        None => filters.sql.clone(),
+    };
+    // Rollups keep the url, so searching works on both
+    let search_filter = match search {
+        Some(_) => "WHERE url IN (SELECT url FROM url_search WHERE url_search MATCH ?)",
+        None => "",
+    };
+
+    let sql = format!(
+        "
+        SELECT url, SUM(count) AS count
+        FROM ({})
+        {}
+        GROUP BY url
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        events.sql(&filter),
+        search_filter,
+        page.sort.order_by(&["url"])
+    );
+
+    let mut query = filters.bind(events.bind(diesel::sql_query(sql).into_boxed()));
+    if let Some(host) = host {
+        query = query.bind::<Text, _>(host.to_string());
+    }
+    if let Some(search) = search {
+        // Quoted as one phrase, so the text is matched as is rather than
+        // read as FTS5 query syntax
+        query = query.bind::<Text, _>(format!("\"{}\"", search.replace('"', "\"\"")));
+    }
+    query
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn urls(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_urls(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            query.host.as_deref(),
+            None,
+            &page,
+        )
+    })
+}
+
+// The trigram index of `url_search` can't match anything shorter
+const MIN_URL_SEARCH_LENGTH: usize = 3;
+
+#[derive(Deserialize)]
+pub struct UrlSearchQuery {
+    // Any part of the url, e.g. `docs`, regardless of case
+    q: String,
+}
+
+// Top urls containing `q`, looked up in the index of every url recorded
+// rather than by scanning the events
+pub async fn search_urls(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+    search: web::Query<UrlSearchQuery>,
+) -> impl Responder {
+    let search = search.q.trim();
+    if search.chars().count() < MIN_URL_SEARCH_LENGTH {
+        return HttpResponse::BadRequest().json(format!(
+            "`q` must be at least {} characters",
+            MIN_URL_SEARCH_LENGTH
+        ));
+    }
+
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+    let page = query.page();
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_urls(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            query.host.as_deref(),
+            Some(search),
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct BrowserVisitCount {
+    #[diesel(sql_type = Text)]
+    pub browser: String,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+}
+
+impl Scale for BrowserVisitCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_browsers(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    page: &Page,
+) -> QueryResult<Vec<BrowserVisitCount>> {
+    let sql = format!(
+        "
+        SELECT browser, COUNT(*) AS count
+        FROM collectors
+        WHERE timestamp > ? AND timestamp <= ?
+        {}
+        AND browser IS NOT NULL
+        GROUP BY browser
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        filters.sql,
+        page.sort.order_by(&["browser"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn browsers(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_browsers(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct OsBrowserVisitCount {
+    #[diesel(sql_type = Text)]
+    os: String,
+    #[diesel(sql_type = Text)]
+    browser: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+impl Scale for OsBrowserVisitCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_os_browsers(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    page: &Page,
+) -> QueryResult<Vec<OsBrowserVisitCount>> {
+    let sql = format!(
+        "
+    SELECT os, browser, COUNT(*) AS count
+    FROM collectors
+    WHERE timestamp > ? AND timestamp <= ?
+    {}
+    AND os IS NOT NULL
+    AND browser IS NOT NULL
+    GROUP BY os, browser
+    ORDER BY {}
+    LIMIT ? OFFSET ?;
+",
+        filters.sql,
+        page.sort.order_by(&["os", "browser"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn os_browsers(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_os_browsers(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct CountryVisitCount {
+    #[diesel(sql_type = Text)]
+    country: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    country_code: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+impl Scale for CountryVisitCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_countries(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    page: &Page,
+) -> QueryResult<Vec<CountryVisitCount>> {
+    let sql = format!(
+        "
+    SELECT country, MAX(country_code) AS country_code, COUNT(*) AS count
+    FROM collectors
+    WHERE timestamp > ? AND timestamp <= ?
+    {}
+    GROUP BY country
+    ORDER BY {}
+    LIMIT ? OFFSET ?;
+",
+        filters.sql,
+        page.sort.order_by(&["country"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn countries(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_countries(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct RegionVisitCount {
+    #[diesel(sql_type = Text)]
+    country: String,
+    #[diesel(sql_type = Text)]
+    region: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+impl Scale for RegionVisitCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_regions(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    page: &Page,
+) -> QueryResult<Vec<RegionVisitCount>> {
+    let sql = format!(
+        "
+    SELECT country, region, COUNT(*) AS count
+    FROM collectors
+    WHERE timestamp > ? AND timestamp <= ?
+    {}
+    AND region IS NOT NULL
+    GROUP BY country, region
+    ORDER BY {}
+    LIMIT ? OFFSET ?;
+",
+        filters.sql,
+        page.sort.order_by(&["country", "region"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn regions(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_regions(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct ReferrerCount {
+    #[diesel(sql_type = Text)]
+    domain: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+impl Scale for ReferrerCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_referrers(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    page_url: Option<String>,
+    filters: &Filters,
+    page: &Page,
+) -> QueryResult<Vec<ReferrerCount>> {
+    let sql = format!(
+        "
+    SELECT 
+    CASE 
+        WHEN referrer IS NULL OR referrer = '' THEN 'direct'
+        ELSE COALESCE(NULLIF(SUBSTR(referrer, INSTR(referrer, '//') + 2), ''), referrer) 
+    END AS domain,
+    COUNT(*) AS count
+    FROM events
+    WHERE timestamp > ? AND timestamp <= ?
+    {}
+    AND (? IS NULL OR url = ?)
+    AND name NOT IN ({})
+    GROUP BY domain
+    ORDER BY {}
+    LIMIT ? OFFSET ?;
+    ",
+        filters.sql,
+        sql_name_list(MEASUREMENT_EVENT_NAMES),
+        page.sort.order_by(&["domain"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Nullable<Text>, _>(page_url.clone())
+        .bind::<Nullable<Text>, _>(page_url)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn referrers(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.referrer_filters(&config);
+
+    // Optionally narrow the breakdown down to a single page, cleaned the
+    // same way urls are when they are recorded
+    let page_url = query.url.as_deref().map(|url| clean_url(url, &config));
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_referrers(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            page_url.clone(),
+            &filters,
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct EventNameCount {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+impl Scale for EventNameCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_event_names(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    include_builtin: bool,
+    page: &Page,
+) -> QueryResult<Vec<EventNameCount>> {
+    let builtin_names = sql_name_list(BUILTIN_EVENT_NAMES);
+
+    let sql = format!(
+        "
+        SELECT name, COUNT(*) AS count
+        FROM events
+        WHERE timestamp > ? AND timestamp <= ?
+        {}
+        AND (? OR name NOT IN ({}))
+        GROUP BY name
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        filters.sql,
+        builtin_names,
+        page.sort.order_by(&["name"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Bool, _>(include_builtin)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn events_by_name(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+
+    // Custom events only, unless the built-in collector events are asked for
+    let include_builtin = query.include_builtin.unwrap_or(false);
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_event_names(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            include_builtin,
+            &page,
+        )
+    })
+}
+
+fn load_not_found(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    host: Option<&str>,
+    page: &Page,
+) -> QueryResult<Vec<UrlEventCount>> {
+    let sql = format!(
+        "
+        SELECT url, COUNT(*) AS count
+        FROM events
+        WHERE timestamp > ? AND timestamp <= ?
+        {}
+        AND (? IS NULL OR host = ?)
+        AND ((status = 404 AND name IN ({})) OR name = '404')
+        GROUP BY url
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        filters.sql,
+        sql_name_list(PAGEVIEW_EVENT_NAMES),
+        page.sort.order_by(&["url"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn not_found(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_not_found(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            query.host.as_deref(),
+            &page,
+        )
+    })
+}
+
+fn load_downloads(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    page: &Page,
+) -> QueryResult<Vec<UrlEventCount>> {
+    // `download` events carry the file url as their url
+    let sql = format!(
+        "
+        SELECT url, COUNT(*) AS count
+        FROM events
+        WHERE timestamp > ? AND timestamp <= ?
+        {}
+        AND name = 'download'
+        GROUP BY url
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        filters.sql,
+        page.sort.order_by(&["url"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn downloads(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_downloads(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct PixelOpens {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = BigInt)]
+    opens: i64,
+    #[diesel(sql_type = BigInt)]
+    unique_opens: i64,
+}
+
+impl Scale for PixelOpens {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.opens, by);
+        scale_count(&mut self.unique_opens, by);
+    }
+}
+
+fn load_pixels(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    page: &Page,
+) -> QueryResult<Vec<PixelOpens>> {
+    // Opens are `email_open` events with the pixel's url, so a reader who
+    // opens the email again is counted once in `unique_opens`
+    let sql = format!(
+        "
+        SELECT p.id, p.name, COUNT(e.id) AS opens,
+        COUNT(DISTINCT COALESCE(c.visitor_hash, e.collector_id)) AS unique_opens
+        FROM pixels p
+        LEFT JOIN events e ON e.name = 'email_open'
+            AND e.url LIKE '%/p/' || p.id || '.gif'
+            AND e.timestamp > ? AND e.timestamp <= ?
+            {}
+        LEFT JOIN collectors c ON c.id = e.collector_id
+        GROUP BY p.id, p.name
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        filters.sql,
+        page.sort.order_by_metric("opens", &["p.name"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn pixels(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "e.collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_pixels(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct UrlGroupCount {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Text)]
+    pattern: String,
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+    #[diesel(sql_type = BigInt)]
+    visitors: i64,
+}
+
+impl Scale for UrlGroupCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+        scale_count(&mut self.visitors, by);
+    }
+}
+
+fn load_url_groups(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    host: Option<&str>,
+    page: &Page,
+) -> QueryResult<Vec<UrlGroupCount>> {
+    // Groups are matched against the path of raw events, as rollups only
+    // keep the url. An event in overlapping groups counts towards each.
+    let sql = format!(
+        "
+        SELECT g.id, g.name, g.pattern, COUNT(e.id) AS count,
+        COUNT(DISTINCT e.collector_id) AS visitors
+        FROM url_groups g
+        LEFT JOIN events e ON e.path GLOB g.pattern
+            AND e.timestamp > ? AND e.timestamp <= ?
+            AND e.name NOT IN ({}) {}
+            AND (? IS NULL OR e.host = ?)
+        GROUP BY g.id, g.name, g.pattern
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        sql_name_list(MEASUREMENT_EVENT_NAMES),
+        filters.sql,
+        page.sort.order_by(&["g.name"])
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
+
+pub async fn url_groups(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "e.collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_url_groups(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            query.host.as_deref(),
+            &page,
+        )
+    })
+}
+
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct VitalPercentiles {
+    #[diesel(sql_type = Text)]
+    url: String,
     #[diesel(sql_type = Text)]
-    pub interval: String,
+    metric: String,
     #[diesel(sql_type = BigInt)]
-    pub count: i64,
+    samples: i64,
+    #[diesel(sql_type = Double)]
+    p50: f64,
+    #[diesel(sql_type = Double)]
+    p75: f64,
+    #[diesel(sql_type = Double)]
+    p95: f64,
 }
 
-pub async fn five_minutes(pool: web::Data<DbPool>) -> impl Responder {
-    let start_time = Utc::now().naive_utc() - Duration::days(1);
-    let mut conn = match pool.get() {
-        Ok(conn) => conn,
-        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
-    };
+// Percentiles of a sample are estimates of the whole already
+impl Scale for VitalPercentiles {
+    fn scale(&mut self, _by: f64) {}
+}
 
-    let sql = "
-        SELECT strftime('%Y-%m-%d %H:%M:00', timestamp) AS interval, COUNT(*) AS count
-        FROM events
-        WHERE timestamp > ?
-        GROUP BY strftime('%Y-%m-%d %H:%M', timestamp)
-        ORDER BY interval ASC;
-    ";
+fn load_vitals(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    host: Option<&str>,
+    page: &Page,
+) -> QueryResult<Vec<VitalPercentiles>> {
+    let vital_names = sql_name_list(WEB_VITAL_NAMES);
 
-    let results: Result<Vec<FiveMinuteEventSummary>, diesel::result::Error> =
-        diesel::sql_query(sql)
-            .bind::<Timestamp, _>(start_time)
-            .load(&mut conn);
+    // Nearest-rank percentiles: the smallest value whose rank reaches p * n
+    let sql = format!(
+        "
+        WITH ranked AS (
+            SELECT url, name, value,
+            ROW_NUMBER() OVER (PARTITION BY url, name ORDER BY value) AS position,
+            COUNT(*) OVER (PARTITION BY url, name) AS samples
+            FROM events
+            WHERE timestamp > ? AND timestamp <= ?
+            {}
+            AND (? IS NULL OR host = ?)
+            AND name IN ({})
+            AND value IS NOT NULL
+        )
+        SELECT url, name AS metric, samples,
+        MIN(CASE WHEN position >= samples * 0.50 THEN value END) AS p50,
+        MIN(CASE WHEN position >= samples * 0.75 THEN value END) AS p75,
+        MIN(CASE WHEN position >= samples * 0.95 THEN value END) AS p95
+        FROM ranked
+        GROUP BY url, name
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        filters.sql,
+        vital_names,
+        page.sort.order_by_metric("samples", &["url", "metric"])
+    );
 
-    match results {
-        Ok(summary) => HttpResponse::Ok().json(summary),
-        Err(e) => {
-            eprintln!("Database query failed: {:?}", e); // Log the error to stderr
-            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
-        }
-    }
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(start_time)
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
 }
 
-pub async fn events(pool: web::Data<DbPool>) -> impl Responder {
+pub async fn vitals(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
     let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "collector_id");
 
-    let query = diesel::sql_query(
-        "SELECT \
-        (SELECT COUNT(*) FROM collectors WHERE timestamp >= datetime('now', '-24 hours')) AS sessions_in_last_twenty_four_hours, \
-        (SELECT COUNT(*) FROM events WHERE timestamp >= datetime('now', '-24 hours')) AS events_in_last_twenty_four_hours, \
-        (SELECT COUNT(*) FROM events WHERE timestamp >= datetime('now', '-5 minutes')) AS events_in_last_five_minutes, \
-        (SELECT COUNT(*) FROM events WHERE timestamp >= datetime('now', '-1 hour')) AS events_in_last_hour"
-    );
+    let scale = config.sample_scale(query.host.as_deref());
 
-    match query.load::<EventCounts>(&mut conn) {
-        Ok(counts) => {
-            if let Some(counts) = counts.into_iter().next() {
-                HttpResponse::Ok().json(json!({
-                    "events_in_last_hour": counts.events_in_last_hour,
-                    "events_in_last_five_minutes": counts.events_in_last_five_minutes,
-                    "events_in_last_twenty_four_hours": counts.events_in_last_twenty_four_hours,
-                    "sessions_in_last_twenty_four_hours": counts.sessions_in_last_twenty_four_hours,
-                }))
-            } else {
-                HttpResponse::Ok().json(json!({
-                    "error": "No data available"
-                }))
-            }
-        }
-        Err(e) => HttpResponse::InternalServerError().json(json!({
-            "error": format!("Error querying events: {:?}", e)
-        })),
-    }
+    compared(&query.compare, scale, |n| {
+        load_vitals(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            query.host.as_deref(),
+            &page,
+        )
+    })
 }
 
-#[derive(QueryableByName, Serialize)]
-struct HourlyEventSummary {
-    #[diesel(sql_type = Timestamp)]
-    hour: NaiveDateTime,
-    #[diesel(sql_type = Integer)]
-    count: i32,
+#[derive(Serialize, Deserialize, QueryableByName)]
+pub struct TimeOnPage {
+    #[diesel(sql_type = Text)]
+    url: String,
+    #[diesel(sql_type = BigInt)]
+    views: i64,
+    #[diesel(sql_type = Double)]
+    avg_time_on_page: f64,
+    #[diesel(sql_type = Double)]
+    avg_engaged_time: f64,
 }
 
-pub async fn hourly(pool: web::Data<DbPool>) -> impl Responder {
-    let start_time = Utc::now().naive_utc() - Duration::days(1);
-    let mut conn = match pool.get() {
-        Ok(conn) => conn,
-        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
-    };
+impl Scale for TimeOnPage {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.views, by);
+    }
+}
 
-    let sql = "
-        SELECT strftime('%Y-%m-%d %H:00:00', timestamp) AS hour, COUNT(*) AS count
-        FROM events
-        WHERE timestamp > ?
-        GROUP BY strftime('%Y-%m-%d %H', timestamp)
-        ORDER BY hour ASC;
-    ";
+fn load_time_on_page(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    host: Option<&str>,
+    page: &Page,
+    stitch: bool,
+) -> QueryResult<Vec<TimeOnPage>> {
+    // A view is every event a collector, or a visit when stitched, recorded
+    // on a url. Time on page spans its first to last event, engaged time adds
+    // up the visible seconds reported by heartbeats. `leave` and `download`
+    // events carry another url.
+    let sql = format!(
+        "
+        WITH views AS (
+            SELECT url, {} AS viewer,
+            (julianday(MAX(timestamp)) - julianday(MIN(timestamp))) * 86400 AS time_on_page,
+            COALESCE(SUM(CASE WHEN name = 'heartbeat' THEN value END), 0) AS engaged_time
+            FROM events
+            WHERE timestamp > ? AND timestamp <= ?
+            {}
+            AND (? IS NULL OR host = ?)
+            AND name NOT IN ('leave', 'download')
+            GROUP BY url, viewer
+        )
+        SELECT url, COUNT(*) AS views,
+        CAST(AVG(time_on_page) AS REAL) AS avg_time_on_page,
+        CAST(AVG(engaged_time) AS REAL) AS avg_engaged_time
+        FROM views
+        GROUP BY url
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        session_column(stitch),
+        filters.sql,
+        page.sort.order_by_metric("views", &["url"])
+    );
 
-    let results: Result<Vec<HourlyEventSummary>, diesel::result::Error> = diesel::sql_query(sql)
+    let query = diesel::sql_query(sql)
+        .into_boxed()
         .bind::<Timestamp, _>(start_time)
-        .load(&mut conn);
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
 
-    match results {
-        Ok(summary) => HttpResponse::Ok().json(summary),
-        Err(e) => {
-            eprintln!("Database query failed: {:?}", e);
-            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
-        }
-    }
+pub async fn time_on_page(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_time_on_page(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            query.host.as_deref(),
+            &page,
+            query.stitch.unwrap_or(false),
+        )
+    })
 }
 
 #[derive(Serialize, Deserialize, QueryableByName)]
-pub struct UrlEventCount {
+pub struct LandingBounces {
     #[diesel(sql_type = Text)]
-    pub url: String,
+    url: String,
     #[diesel(sql_type = BigInt)]
-    pub count: i64,
+    sessions: i64,
+    #[diesel(sql_type = BigInt)]
+    bounces: i64,
+    #[diesel(sql_type = Double)]
+    bounce_rate: f64,
 }
 
-pub async fn urls(pool: web::Data<DbPool>) -> impl Responder {
-    let start_time = Utc::now().naive_utc() - Duration::days(7);
-    let mut conn = pool.get().expect("couldn't get db connection from pool");
+impl Scale for LandingBounces {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.sessions, by);
+        scale_count(&mut self.bounces, by);
+    }
+}
 
-    let sql = "
-        SELECT url, COUNT(*) AS count
-        FROM events
-        WHERE timestamp > ?
+fn load_bounces(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    host: Option<&str>,
+    page: &Page,
+    stitch: bool,
+) -> QueryResult<Vec<LandingBounces>> {
+    // A session lands on the url of its first pageview in the window and
+    // bounces when that is its only one. Sessions on several hosts only
+    // count the pageviews on `host`.
+    let sql = format!(
+        "
+        WITH pageviews AS (
+            SELECT {} AS session, url,
+            ROW_NUMBER() OVER session_pageviews AS position,
+            COUNT(*) OVER (PARTITION BY {}) AS pageviews
+            FROM events
+            WHERE timestamp > ? AND timestamp <= ?
+            {}
+            AND (? IS NULL OR host = ?)
+            AND name IN ({})
+            WINDOW session_pageviews AS (PARTITION BY {} ORDER BY timestamp, id)
+        )
+        SELECT url, COUNT(*) AS sessions,
+        COUNT(CASE WHEN pageviews = 1 THEN 1 END) AS bounces,
+        CAST(COUNT(CASE WHEN pageviews = 1 THEN 1 END) AS REAL) / COUNT(*) AS bounce_rate
+        FROM pageviews
+        WHERE position = 1
         GROUP BY url
-        ORDER BY count DESC
-        LIMIT 25;
-    ";
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        session_column(stitch),
+        session_column(stitch),
+        filters.sql,
+        sql_name_list(PAGEVIEW_EVENT_NAMES),
+        session_column(stitch),
+        page.sort.order_by_metric("sessions", &["url"])
+    );
 
-    let results: Result<Vec<UrlEventCount>, diesel::result::Error> = diesel::sql_query(sql)
+    let query = diesel::sql_query(sql)
+        .into_boxed()
         .bind::<Timestamp, _>(start_time)
-        .load(&mut conn);
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
 
-    match results {
-        Ok(url_counts) => HttpResponse::Ok().json(url_counts),
-        Err(e) => {
-            eprintln!("Database query failed: {:?}", e);
-            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+// Bounce rate of each landing page, the share of sessions starting on it
+// that viewed no other page
+pub async fn bounces(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_bounces(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            query.host.as_deref(),
+            &page,
+            query.stitch.unwrap_or(false),
+        )
+    })
+}
+
+#[derive(Deserialize)]
+pub struct EntryExitQuery {
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    // Only sessions landing on this url, e.g. the homepage
+    entry: Option<String>,
+    host: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    sort: Option<Sort>,
+    exclude_datacenters: Option<bool>,
+    stitch: Option<bool>,
+    url: Option<String>,
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
+    browser: Option<String>,
+    os: Option<String>,
+    origin: Option<String>,
+}
+
+impl EntryExitQuery {
+    fn dimensions(&self) -> Dimensions {
+        Dimensions {
+            country: self.country.clone(),
+            region: self.region.clone(),
+            city: self.city.clone(),
+            browser: self.browser.clone(),
+            os: self.os.clone(),
+            origin: self.origin.clone(),
+            page: self.url.clone(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, QueryableByName)]
-pub struct BrowserVisitCount {
+#[derive(Serialize, QueryableByName)]
+pub struct EntryExitCount {
     #[diesel(sql_type = Text)]
-    pub browser: String,
+    entry: String,
+    #[diesel(sql_type = Text)]
+    exit: String,
     #[diesel(sql_type = BigInt)]
-    pub count: i64,
+    count: i64,
 }
 
-pub async fn browsers(pool: web::Data<DbPool>) -> impl Responder {
-    let start_time = Utc::now().naive_utc() - Duration::days(7);
-    let mut conn = pool.get().expect("couldn't get db connection from pool");
+impl Scale for EntryExitCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
 
-    let sql = "
-        SELECT browser, COUNT(*) AS count
-        FROM collectors
-        WHERE timestamp > ?
-        AND browser IS NOT NULL
-        GROUP BY browser
-        ORDER BY count DESC
-        LIMIT 25;
-    ";
+#[allow(clippy::too_many_arguments)]
+fn load_entry_exit(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    host: Option<&str>,
+    entry: Option<&str>,
+    page: &Page,
+    stitch: bool,
+) -> QueryResult<Vec<EntryExitCount>> {
+    // The first and last pageview of each session in the range. Sessions
+    // with a single pageview enter and exit on the same url.
+    let session = session_column(stitch);
+    let sql = format!(
+        "
+        WITH pageviews AS (
+            SELECT {} AS session, url,
+            ROW_NUMBER() OVER (PARTITION BY {} ORDER BY timestamp, id) AS position,
+            ROW_NUMBER() OVER (PARTITION BY {} ORDER BY timestamp DESC, id DESC) AS position_from_end
+            FROM events
+            WHERE timestamp > ? AND timestamp <= ?
+            {}
+            AND (? IS NULL OR host = ?)
+            AND name IN ({})
+        ),
+        journeys AS (
+            SELECT session,
+            MAX(CASE WHEN position = 1 THEN url END) AS entry,
+            MAX(CASE WHEN position_from_end = 1 THEN url END) AS exit
+            FROM pageviews
+            WHERE position = 1 OR position_from_end = 1
+            GROUP BY session
+        )
+        SELECT entry, exit, COUNT(*) AS count
+        FROM journeys
+        WHERE (? IS NULL OR entry = ?)
+        GROUP BY entry, exit
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        session,
+        session,
+        session,
+        filters.sql,
+        sql_name_list(PAGEVIEW_EVENT_NAMES),
+        page.sort.order_by(&["entry", "exit"])
+    );
 
-    let results: Result<Vec<BrowserVisitCount>, diesel::result::Error> = diesel::sql_query(sql)
+    let query = diesel::sql_query(sql)
+        .into_boxed()
         .bind::<Timestamp, _>(start_time)
-        .load(&mut conn);
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<Nullable<Text>, _>(host)
+        .bind::<Nullable<Text>, _>(entry)
+        .bind::<Nullable<Text>, _>(entry)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
 
-    match results {
-        Ok(browser_counts) => HttpResponse::Ok().json(browser_counts),
+// The most common pairs of landing page and last page of a session, over
+// `from` to `to` (the last 7 days by default)
+pub async fn entry_exit(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<EntryExitQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let end_time = query.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let start_time = query.from.unwrap_or_else(|| end_time - Duration::days(7));
+    if start_time >= end_time {
+        return HttpResponse::BadRequest().json("`from` must be before `to`");
+    }
+
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+    let page = Page::new(query.limit, query.offset, query.sort);
+    let filters = query
+        .dimensions()
+        .filters(&config, "collector_id", query.exclude_datacenters);
+
+    match load_entry_exit(
+        &mut conn,
+        (start_time, end_time),
+        &filters,
+        query.host.as_deref(),
+        query.entry.as_deref(),
+        &page,
+        query.stitch.unwrap_or(false),
+    ) {
+        Ok(pairs) => {
+            HttpResponse::Ok().json(scaled(pairs, config.sample_scale(query.host.as_deref())))
+        }
         Err(e) => {
-            eprintln!("Database query failed: {:?}", e);
+            error!("Database query failed: {:?}", e);
             HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
         }
     }
 }
 
-#[derive(Serialize, Deserialize, QueryableByName)]
-pub struct OsBrowserVisitCount {
+#[derive(QueryableByName)]
+struct RevenueRow {
     #[diesel(sql_type = Text)]
-    os: String,
+    referrer: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    utm_source: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    utm_campaign: Option<String>,
     #[diesel(sql_type = Text)]
-    browser: String,
+    currency: String,
+    #[diesel(sql_type = Double)]
+    amount: f64,
     #[diesel(sql_type = BigInt)]
-    count: i64,
+    purchases: i64,
 }
 
-pub async fn os_browsers(pool: web::Data<DbPool>) -> impl Responder {
-    let start_time = Utc::now().naive_utc() - Duration::days(7);
-    let mut conn = pool.get().expect("couldn't get db connection from pool");
+#[derive(Serialize)]
+pub struct ReferrerRevenue {
+    referrer: String,
+    revenue: f64,
+    purchases: i64,
+}
 
-    let sql = "
-    SELECT os, browser, COUNT(*) AS count
-    FROM collectors
-    WHERE timestamp > ?
-    AND os IS NOT NULL
-    AND browser IS NOT NULL
-    GROUP BY os, browser
-    ORDER BY count DESC
-    LIMIT 25;
-";
+impl Scale for ReferrerRevenue {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.purchases, by);
+        self.revenue *= by;
+    }
+}
+
+// Revenue of visitors who entered with this `utm_source` and
+// `utm_campaign`, both None for those who came without
+#[derive(Serialize)]
+pub struct CampaignRevenue {
+    source: Option<String>,
+    campaign: Option<String>,
+    revenue: f64,
+    purchases: i64,
+}
+
+impl Scale for CampaignRevenue {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.purchases, by);
+        self.revenue *= by;
+    }
+}
+
+#[derive(Serialize)]
+pub struct RevenueSummary {
+    currency: String,
+    total: f64,
+    purchases: i64,
+    by_referrer: Vec<ReferrerRevenue>,
+    by_campaign: Vec<CampaignRevenue>,
+    // Amounts in currencies without a configured rate, left out of the totals
+    unconverted: HashMap<String, f64>,
+}
 
-    let results: Result<Vec<OsBrowserVisitCount>, diesel::result::Error> = diesel::sql_query(sql)
+impl Scale for RevenueSummary {
+    fn scale(&mut self, by: f64) {
+        self.total *= by;
+        scale_count(&mut self.purchases, by);
+        self.by_referrer.scale(by);
+        self.by_campaign.scale(by);
+        self.unconverted
+            .values_mut()
+            .for_each(|amount| *amount *= by);
+    }
+}
+
+fn load_revenue(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    config: &Config,
+) -> QueryResult<RevenueSummary> {
+    // Any event with an amount and a currency is revenue. It is attributed to
+    // the referrer and campaign the visitor arrived with, not the ones of the
+    // checkout page.
+    let sql = format!(
+        "
+        WITH purchases AS (
+            SELECT collector_id, currency, value
+            FROM events
+            WHERE timestamp > ? AND timestamp <= ?
+            {}
+            AND value IS NOT NULL
+            AND currency IS NOT NULL
+        ),
+        sources AS (
+            SELECT buyers.collector_id, (
+                SELECT referrer FROM events
+                WHERE events.collector_id = buyers.collector_id
+                AND name = 'enter'
+                ORDER BY timestamp
+                LIMIT 1
+            ) AS referrer, collectors.utm_source, collectors.utm_campaign
+            FROM (SELECT DISTINCT collector_id FROM purchases) AS buyers
+            LEFT JOIN collectors ON collectors.id = buyers.collector_id
+        )
+        SELECT
+        CASE
+            WHEN sources.referrer IS NULL OR sources.referrer = '' THEN 'direct'
+            ELSE COALESCE(NULLIF(SUBSTR(sources.referrer, INSTR(sources.referrer, '//') + 2), ''), sources.referrer)
+        END AS referrer,
+        sources.utm_source AS utm_source,
+        sources.utm_campaign AS utm_campaign,
+        purchases.currency AS currency,
+        CAST(SUM(purchases.value) AS REAL) AS amount,
+        COUNT(*) AS purchases
+        FROM purchases
+        LEFT JOIN sources ON sources.collector_id = purchases.collector_id
+        GROUP BY 1, 2, 3, 4;
+    ",
+        filters.sql
+    );
+
+    let query = diesel::sql_query(sql)
+        .into_boxed()
         .bind::<Timestamp, _>(start_time)
-        .load(&mut conn);
+        .bind::<Timestamp, _>(end_time);
+    let rows: Vec<RevenueRow> = filters.bind(query).load(conn)?;
 
-    match results {
-        Ok(os_browser_counts) => HttpResponse::Ok().json(os_browser_counts),
-        Err(e) => {
-            eprintln!("Database query failed: {:?}", e);
-            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+    let mut by_referrer: HashMap<String, ReferrerRevenue> = HashMap::new();
+    let mut by_campaign: HashMap<(Option<String>, Option<String>), CampaignRevenue> =
+        HashMap::new();
+    let mut unconverted: HashMap<String, f64> = HashMap::new();
+
+    for row in rows {
+        let rate = if row.currency == config.reporting_currency {
+            Some(1.0)
+        } else {
+            config.currency_rates.get(&row.currency).copied()
+        };
+
+        match rate {
+            Some(rate) => {
+                let entry =
+                    by_referrer
+                        .entry(row.referrer.clone())
+                        .or_insert_with(|| ReferrerRevenue {
+                            referrer: row.referrer,
+                            revenue: 0.0,
+                            purchases: 0,
+                        });
+                entry.revenue += row.amount * rate;
+                entry.purchases += row.purchases;
+
+                let campaign = (row.utm_source, row.utm_campaign);
+                let entry =
+                    by_campaign
+                        .entry(campaign.clone())
+                        .or_insert_with(|| CampaignRevenue {
+                            source: campaign.0,
+                            campaign: campaign.1,
+                            revenue: 0.0,
+                            purchases: 0,
+                        });
+                entry.revenue += row.amount * rate;
+                entry.purchases += row.purchases;
+            }
+            None => *unconverted.entry(row.currency).or_insert(0.0) += row.amount,
         }
     }
+
+    let mut by_referrer: Vec<ReferrerRevenue> = by_referrer.into_values().collect();
+    by_referrer.sort_by(|a, b| b.revenue.total_cmp(&a.revenue));
+    let mut by_campaign: Vec<CampaignRevenue> = by_campaign.into_values().collect();
+    by_campaign.sort_by(|a, b| b.revenue.total_cmp(&a.revenue));
+
+    Ok(RevenueSummary {
+        currency: config.reporting_currency.clone(),
+        // folded from 0.0 as the sum of no floats is -0.0
+        total: by_referrer.iter().fold(0.0, |total, r| total + r.revenue),
+        purchases: by_referrer.iter().map(|r| r.purchases).sum(),
+        by_referrer,
+        by_campaign,
+        unconverted,
+    })
+}
+
+pub async fn revenue(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_revenue(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            &config,
+        )
+    })
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboundGrouping {
+    #[default]
+    Url,
+    Domain,
 }
 
 #[derive(Serialize, Deserialize, QueryableByName)]
-pub struct ReferrerCount {
+pub struct OutboundCount {
     #[diesel(sql_type = Text)]
     domain: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
     #[diesel(sql_type = BigInt)]
     count: i64,
 }
 
-pub async fn referrers(pool: web::Data<DbPool>) -> impl Responder {
-    let start_time = Utc::now().naive_utc() - Duration::days(7);
-    let mut conn = pool.get().expect("couldn't get db connection from pool");
+impl Scale for OutboundCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
 
-    let sql = "
-    SELECT 
-    CASE 
-        WHEN referrer IS NULL OR referrer = '' THEN 'direct'
-        ELSE COALESCE(NULLIF(SUBSTR(referrer, INSTR(referrer, '//') + 2), ''), referrer) 
-    END AS domain,
-    COUNT(*) AS count
-    FROM events
-    WHERE timestamp > ?
-    GROUP BY domain
-    ORDER BY count DESC
-    LIMIT 25;
-    ";
+fn load_outbound(
+    conn: &mut SqliteConnection,
+    (start_time, end_time): (NaiveDateTime, NaiveDateTime),
+    filters: &Filters,
+    grouping: OutboundGrouping,
+    page: &Page,
+) -> QueryResult<Vec<OutboundCount>> {
+    // `leave` events carry the link destination as their url
+    let (url_column, group_by) = match grouping {
+        OutboundGrouping::Url => ("url", "url"),
+        OutboundGrouping::Domain => ("NULL", "domain"),
+    };
+
+    let sql = format!(
+        "
+        SELECT
+        CASE
+            WHEN INSTR(url, '//') > 0 THEN SUBSTR(
+                SUBSTR(url, INSTR(url, '//') + 2),
+                1,
+                INSTR(SUBSTR(url, INSTR(url, '//') + 2) || '/', '/') - 1
+            )
+            ELSE url
+        END AS domain,
+        {} AS url,
+        COUNT(*) AS count
+        FROM events
+        WHERE timestamp > ? AND timestamp <= ?
+        {}
+        AND name = 'leave'
+        GROUP BY {}
+        ORDER BY {}
+        LIMIT ? OFFSET ?;
+    ",
+        url_column,
+        filters.sql,
+        group_by,
+        page.sort.order_by(&[group_by])
+    );
 
-    let results: Result<Vec<ReferrerCount>, diesel::result::Error> = diesel::sql_query(sql)
+    let query = diesel::sql_query(sql)
+        .into_boxed()
         .bind::<Timestamp, _>(start_time)
-        .load(&mut conn);
+        .bind::<Timestamp, _>(end_time);
+    filters
+        .bind(query)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<BigInt, _>(page.offset)
+        .load(conn)
+}
 
-    match results {
-        Ok(referrer_counts) => HttpResponse::Ok().json(referrer_counts),
-        Err(e) => {
-            eprintln!("Database query failed: {:?}", e);
-            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
-        }
-    }
+pub async fn outbound(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let now = Utc::now().naive_utc();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+    let page = query.page();
+    let grouping = query.by.unwrap_or_default();
+    let filters = query.filters(&config, "collector_id");
+
+    let scale = config.sample_scale(query.host.as_deref());
+
+    compared(&query.compare, scale, |n| {
+        load_outbound(
+            &mut conn,
+            window(now, Duration::days(7), n),
+            &filters,
+            grouping,
+            &page,
+        )
+    })
 }
 
 #[derive(QueryableByName, Serialize, Deserialize)]
@@ -279,21 +2367,45 @@ pub struct HourlyEventCounts {
     pub count: i64, // The count of events in that hour
 }
 
-pub async fn weekly(pool: web::Data<DbPool>) -> impl Responder {
-    let mut conn = pool.get().expect("couldn't get db connection from pool");
+impl Scale for HourlyEventCounts {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.count, by);
+    }
+}
+
+fn load_weekly(
+    conn: &mut SqliteConnection,
+    filters: &Filters,
+) -> QueryResult<Vec<HourlyEventCounts>> {
+    let end_time = Utc::now().naive_utc();
+    let start_time = end_time - Duration::days(7);
 
-    let query = diesel::sql_query(
+    let level = Some(Level::Hourly).filter(|_| filters.is_empty());
+    let events = CountedEvents::new(conn, level, start_time, end_time)?;
+    let query = diesel::sql_query(format!(
         "SELECT \
         CAST(strftime('%w', timestamp) AS INTEGER) AS day, \
         CAST(strftime('%H', timestamp) AS INTEGER) AS hour, \
-        COUNT(*) as count \
-        FROM events \
-        WHERE timestamp >= datetime('now', '-7 days') \
+        SUM(count) as count \
+        FROM ({}) \
         GROUP BY day, hour",
-    );
+        events.sql(&filters.sql)
+    ));
+    filters.bind(events.bind(query.into_boxed())).load(conn)
+}
 
-    match query.load::<HourlyEventCounts>(&mut conn) {
-        Ok(hourly_counts) => HttpResponse::Ok().json(hourly_counts),
+pub async fn weekly(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let mut conn = pool.get().expect("couldn't get db connection from pool");
+
+    match load_weekly(&mut conn, &query.chart_filters(&config)) {
+        Ok(hourly_counts) => {
+            HttpResponse::Ok().json(scaled(hourly_counts, config.sample_scale(None)))
+        }
         Err(e) => HttpResponse::InternalServerError().json(json!({
             "error": format!("Error querying hourly event counts: {:?}", e)
         })),
@@ -301,66 +2413,310 @@ pub async fn weekly(pool: web::Data<DbPool>) -> impl Responder {
 }
 
 #[derive(QueryableByName, Debug)]
-struct TrafficChange {
+struct TrafficCounts {
     #[diesel(sql_type = BigInt)]
-    current_count: i64,
+    day_current: i64,
     #[diesel(sql_type = BigInt)]
-    previous_count: i64,
+    day_previous: i64,
+    #[diesel(sql_type = BigInt)]
+    week_current: i64,
+    #[diesel(sql_type = BigInt)]
+    week_previous: i64,
+    #[diesel(sql_type = BigInt)]
+    month_current: i64,
+    #[diesel(sql_type = BigInt)]
+    month_previous: i64,
 }
 
-pub async fn percentages(pool: web::Data<DbPool>) -> impl Responder {
-    let mut conn = pool.get().expect("couldn't get db connection from pool");
+impl Scale for TrafficCounts {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.day_current, by);
+        scale_count(&mut self.day_previous, by);
+        scale_count(&mut self.week_current, by);
+        scale_count(&mut self.week_previous, by);
+        scale_count(&mut self.month_current, by);
+        scale_count(&mut self.month_previous, by);
+    }
+}
 
-    // calculates percentage change
-    let calc_percentage_change = |current: i64, previous: i64| -> f64 {
-        if previous == 0 {
-            if current == 0 {
-                0.0
-            } else {
-                f64::INFINITY
-            }
-        } else {
-            ((current as f64 - previous as f64) / previous as f64) * 100.0
-        }
-    };
+#[derive(Serialize)]
+struct TrafficChange {
+    current_count: i64,
+    previous_count: i64,
+    // Percent change from the previous period, None when it had no events
+    change: Option<f64>,
+}
 
-    let results = vec![
-        ("day", "-1 day", "-2 days"),
-        ("week", "-7 days", "-14 days"),
-        ("month", "-1 month", "-2 months"),
-    ]
-    .iter()
-    .map(|(label, current_interval, previous_interval)| {
-        let query = diesel::sql_query(format!(
-            "SELECT \
-                (SELECT COUNT(*) FROM events WHERE timestamp >= datetime('now', '{}')) AS current_count, \
-                (SELECT COUNT(*) FROM events WHERE timestamp BETWEEN datetime('now', '{}') AND datetime('now', '{}')) AS previous_count",
-            current_interval, previous_interval, current_interval
-        ));
-        let result = query.get_results::<TrafficChange>(&mut conn);
-        match result {
-            Ok(tc) if !tc.is_empty() => {
-                // Ensure there is at least one result to prevent division by zero
-                let total_change = tc.iter().map(|traffic_change| calc_percentage_change(traffic_change.current_count, traffic_change.previous_count)).sum::<f64>() / tc.len() as f64;
-                Ok((*label, total_change))
-            },
-            Ok(_) => Ok((*label, 0.0)), // No change if no data
-            Err(e) => Err(e),
+impl TrafficChange {
+    fn new(current_count: i64, previous_count: i64) -> Self {
+        let change = match (current_count, previous_count) {
+            (0, 0) => Some(0.0),
+            (_, 0) => None,
+            (current, previous) => Some((current - previous) as f64 / previous as f64 * 100.0),
+        };
+        TrafficChange {
+            current_count,
+            previous_count,
+            change,
         }
-    })
-    .collect::<Result<Vec<(&str, f64)>, diesel::result::Error>>();
+    }
+}
 
-    match results {
-        Ok(changes) => {
-            let response: serde_json::Value = changes
-                .into_iter()
-                .map(|(label, change)| (label.to_string(), json!(change)))
-                .collect::<serde_json::Map<String, serde_json::Value>>()
-                .into();
-            HttpResponse::Ok().json(response)
-        }
+impl TrafficCounts {
+    fn changes(&self) -> serde_json::Value {
+        json!({
+            "day": TrafficChange::new(self.day_current, self.day_previous),
+            "week": TrafficChange::new(self.week_current, self.week_previous),
+            "month": TrafficChange::new(self.month_current, self.month_previous),
+        })
+    }
+}
+
+// Events in the last day, week and month against the period before each, in
+// one pass over the last two months
+fn load_traffic_counts(
+    conn: &mut SqliteConnection,
+    filters: &Filters,
+) -> QueryResult<TrafficCounts> {
+    let now = Utc::now().naive_utc();
+    let months_ago = |months| now.checked_sub_months(Months::new(months)).unwrap_or(now);
+    let (day, two_days) = (now - Duration::days(1), now - Duration::days(2));
+    let (week, two_weeks) = (now - Duration::days(7), now - Duration::days(14));
+    let (month, two_months) = (months_ago(1), months_ago(2));
+
+    let sql = format!(
+        "
+        SELECT
+        COALESCE(SUM(timestamp >= ?), 0) AS day_current,
+        COALESCE(SUM(timestamp >= ? AND timestamp < ?), 0) AS day_previous,
+        COALESCE(SUM(timestamp >= ?), 0) AS week_current,
+        COALESCE(SUM(timestamp >= ? AND timestamp < ?), 0) AS week_previous,
+        COALESCE(SUM(timestamp >= ?), 0) AS month_current,
+        COALESCE(SUM(timestamp >= ? AND timestamp < ?), 0) AS month_previous
+        FROM events
+        WHERE timestamp >= ? AND timestamp <= ?
+        AND name NOT IN ({})
+        {};
+    ",
+        sql_name_list(MEASUREMENT_EVENT_NAMES),
+        filters.sql
+    );
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(day)
+        .bind::<Timestamp, _>(two_days)
+        .bind::<Timestamp, _>(day)
+        .bind::<Timestamp, _>(week)
+        .bind::<Timestamp, _>(two_weeks)
+        .bind::<Timestamp, _>(week)
+        .bind::<Timestamp, _>(month)
+        .bind::<Timestamp, _>(two_months)
+        .bind::<Timestamp, _>(month)
+        .bind::<Timestamp, _>(two_months)
+        .bind::<Timestamp, _>(now);
+    filters.bind(query).get_result(conn)
+}
+
+pub async fn percentages(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match load_traffic_counts(&mut conn, &query.chart_filters(&config)) {
+        Ok(counts) => HttpResponse::Ok().json(scaled(counts, config.sample_scale(None)).changes()),
         Err(e) => HttpResponse::InternalServerError().json(json!({
             "error": format!("Error querying event traffic changes: {:?}", e)
         })),
     }
 }
+
+// Sections of /summary/overview, named after the endpoints they come from
+const OVERVIEW_SECTIONS: &[&str] = &[
+    "summary",
+    "percentages",
+    "hourly",
+    "weekly",
+    "urls",
+    "osbrowsers",
+    "referrers",
+];
+
+#[derive(Deserialize)]
+pub struct OverviewQuery {
+    // Comma separated sections, e.g. `summary,urls`. All of them by default.
+    include: Option<String>,
+}
+
+// Everything the dashboard shows on load in one response, each section the
+// same as the response of its own endpoint. Takes the same parameters as
+// those, e.g. `limit` or `host`.
+pub async fn overview(
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<SummaryQuery>,
+    overview: web::Query<OverviewQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let sections: Vec<&str> = match &overview.include {
+        Some(include) => include
+            .split(',')
+            .map(str::trim)
+            .filter(|section| !section.is_empty())
+            .collect(),
+        None => OVERVIEW_SECTIONS.to_vec(),
+    };
+    if let Some(unknown) = sections
+        .iter()
+        .find(|section| !OVERVIEW_SECTIONS.contains(section))
+    {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!(
+                "Unknown section {}, expected some of {}",
+                unknown,
+                OVERVIEW_SECTIONS.join(", ")
+            )
+        }));
+    }
+
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+    let week = window(Utc::now().naive_utc(), Duration::days(7), 0);
+    let page = query.page();
+    let scale = config.sample_scale(query.host.as_deref());
+    let unfiltered_scale = config.sample_scale(None);
+
+    let mut response = serde_json::Map::new();
+    for section in sections {
+        let loaded = match section {
+            "summary" => load_event_counts(
+                &mut conn,
+                0,
+                &query.filters(&config, "id"),
+                &query.filters(&config, "collector_id"),
+            )
+            .map(|counts| json!(scaled(counts, scale))),
+            "percentages" => load_traffic_counts(&mut conn, &query.chart_filters(&config))
+                .map(|counts| scaled(counts, unfiltered_scale).changes()),
+            "hourly" => load_hourly(&mut conn, &query.chart_filters(&config))
+                .map(|hours| json!(scaled(hours, unfiltered_scale))),
+            "weekly" => load_weekly(&mut conn, &query.chart_filters(&config))
+                .map(|hours| json!(scaled(hours, unfiltered_scale))),
+            "urls" => load_urls(
+                &mut conn,
+                week,
+                &query.filters(&config, "collector_id"),
+                query.host.as_deref(),
+                None,
+                &page,
+            )
+            .map(|urls| json!(scaled(urls, scale))),
+            "osbrowsers" => load_os_browsers(&mut conn, week, &query.filters(&config, "id"), &page)
+                .map(|counts| json!(scaled(counts, scale))),
+            "referrers" => load_referrers(
+                &mut conn,
+                week,
+                query.url.as_deref().map(|url| clean_url(url, &config)),
+                &query.referrer_filters(&config),
+                &page,
+            )
+            .map(|referrers| json!(scaled(referrers, scale))),
+            _ => unreachable!("sections are checked above"),
+        };
+        match loaded {
+            Ok(value) => {
+                response.insert(section.to_string(), value);
+            }
+            Err(e) => {
+                error!("Database query failed: {:?}", e);
+                return HttpResponse::InternalServerError()
+                    .json(format!("Database error: {:?}", e));
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(response)
+}
+
+// How long /summary/active/poll holds a request, below the idle timeouts of
+// common proxies
+const ACTIVE_POLL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(25);
+
+#[derive(Deserialize)]
+pub struct ActivePollQuery {
+    // `changed_at` of the previous response
+    since: Option<NaiveDateTime>,
+}
+
+impl Scale for ActiveCount {
+    fn scale(&mut self, by: f64) {
+        scale_count(&mut self.visitors, by);
+    }
+}
+
+#[derive(QueryableByName)]
+struct ActiveVisitorCount {
+    #[diesel(sql_type = BigInt)]
+    visitors: i64,
+}
+
+// Active visitors matching `filters`, counted like ActiveVisitors counts all
+fn load_active(conn: &mut SqliteConnection, filters: &Filters) -> QueryResult<i64> {
+    let since = Utc::now().naive_utc() - Duration::minutes(ACTIVE_MINUTES);
+    let sql = format!(
+        "SELECT COUNT(DISTINCT collector_id) AS visitors FROM events WHERE timestamp >= ? {}",
+        filters.sql
+    );
+    let query = diesel::sql_query(sql)
+        .into_boxed()
+        .bind::<Timestamp, _>(since);
+    filters
+        .bind(query)
+        .get_result::<ActiveVisitorCount>(conn)
+        .map(|count| count.visitors)
+}
+
+// The number of active visitors, for realtime widgets where SSE and
+// WebSockets don't get through. Answers once the count changed after
+// `since`, or with the same count after ACTIVE_POLL_TIMEOUT, and right away
+// without `since`. Filtered polls wait for the count of all visitors to
+// change, then count the ones matching.
+pub async fn active_poll(
+    active: web::Data<Arc<ActiveVisitors>>,
+    pool: web::Data<DbPool>,
+    config: web::Data<SharedConfig>,
+    query: web::Query<ActivePollQuery>,
+    dimensions: web::Query<SummaryQuery>,
+) -> impl Responder {
+    let config = config.get();
+    let mut count = active.subscribe();
+    if let Some(since) = query.since {
+        let changed = count.wait_for(|count| count.changed_at > since);
+        let _ = tokio::time::timeout(ACTIVE_POLL_TIMEOUT, changed).await;
+    }
+    let mut current = *count.borrow();
+
+    let filters = dimensions.chart_filters(&config);
+    if !filters.is_empty() {
+        let visitors = blocking(&pool, move |conn| Ok(load_active(conn, &filters)?)).await;
+        match visitors {
+            Ok(visitors) => current.visitors = visitors,
+            Err(e) => {
+                error!("Database query failed: {:?}", e);
+                return HttpResponse::InternalServerError()
+                    .json(format!("Database error: {:?}", e));
+            }
+        }
+    }
+
+    HttpResponse::Ok()
+        .insert_header((http::header::CACHE_CONTROL, "no-store"))
+        .json(scaled(current, config.sample_scale(None)))
+}
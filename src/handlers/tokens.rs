@@ -0,0 +1,116 @@
+use crate::db::DbPool;
+use crate::models::ApiToken;
+use crate::schema::api_tokens;
+use crate::utils::api_tokens::{create_token, Scope};
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+
+const MAX_EXPIRY_DAYS: i64 = 3650;
+
+#[derive(Deserialize)]
+pub struct NewApiToken {
+    // What the token is for, e.g. the name of the script using it
+    name: String,
+    // read, ingest or admin
+    scope: String,
+    // Never expires when left out
+    expires_in_days: Option<i64>,
+}
+
+impl NewApiToken {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name is required".to_string());
+        }
+        if Scope::parse(&self.scope).is_none() {
+            return Err(format!(
+                "Unknown scope {}, use read, ingest or admin",
+                self.scope
+            ));
+        }
+        match self.expires_in_days {
+            Some(days) if !(1..=MAX_EXPIRY_DAYS).contains(&days) => Err(format!(
+                "expires_in_days must be between 1 and {}",
+                MAX_EXPIRY_DAYS
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub async fn list(pool: web::Data<DbPool>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match api_tokens::table
+        .order(api_tokens::created_at.asc())
+        .load::<ApiToken>(&mut conn)
+    {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+// The token itself is only part of this response
+pub async fn create(pool: web::Data<DbPool>, body: web::Json<NewApiToken>) -> impl Responder {
+    if let Err(message) = body.validate() {
+        return HttpResponse::BadRequest().json(json!({ "error": message }));
+    }
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let expires_at = match body.expires_in_days {
+        Some(days) => match Utc::now()
+            .naive_utc()
+            .checked_add_signed(Duration::days(days))
+        {
+            Some(expires_at) => Some(expires_at),
+            None => {
+                return HttpResponse::BadRequest()
+                    .json(json!({ "error": "expires_in_days is out of range" }))
+            }
+        },
+        None => None,
+    };
+    match create_token(&mut conn, &body.name, &body.scope, expires_at) {
+        Ok((token, secret)) => HttpResponse::Created().json(json!({
+            "id": token.id,
+            "name": token.name,
+            "scope": token.scope,
+            "expires_at": token.expires_at,
+            "created_at": token.created_at,
+            "token": secret,
+        })),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn delete(pool: web::Data<DbPool>, id: web::Path<String>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match diesel::delete(api_tokens::table.find(id.into_inner())).execute(&mut conn) {
+        Ok(0) => HttpResponse::NotFound().json("No such token"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::db::DbPool;
+use crate::models::UrlGroup;
+use crate::schema::url_groups;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use ulid::Ulid;
+
+#[derive(Deserialize)]
+pub struct NewUrlGroup {
+    // What the group is reported as, e.g. "Blog"
+    name: String,
+    // A glob matched against url paths, e.g. `/blog/*`. `*` matches any
+    // characters, `?` one and `[...]` one of a set.
+    pattern: String,
+}
+
+pub async fn list(pool: web::Data<DbPool>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match url_groups::table
+        .order(url_groups::created_at.asc())
+        .load::<UrlGroup>(&mut conn)
+    {
+        Ok(groups) => HttpResponse::Ok().json(groups),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+// Groups only change how summaries are reported, so they also cover events
+// recorded before they were added
+pub async fn create(pool: web::Data<DbPool>, body: web::Json<NewUrlGroup>) -> impl Responder {
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "error": "name is required" }));
+    }
+    if !body.pattern.starts_with('/') {
+        return HttpResponse::BadRequest()
+            .json(json!({ "error": "pattern must be a path starting with /" }));
+    }
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let body = body.into_inner();
+    let group = UrlGroup {
+        id: Ulid::new().to_string(),
+        name: body.name,
+        pattern: body.pattern,
+        created_at: Utc::now().naive_utc(),
+    };
+    match diesel::insert_into(url_groups::table)
+        .values(&group)
+        .execute(&mut conn)
+    {
+        Ok(_) => HttpResponse::Created().json(group),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn delete(pool: web::Data<DbPool>, id: web::Path<String>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match diesel::delete(url_groups::table.find(id.into_inner())).execute(&mut conn) {
+        Ok(0) => HttpResponse::NotFound().json("No such url group"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
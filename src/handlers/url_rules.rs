@@ -0,0 +1,98 @@
+use crate::db::DbPool;
+use crate::models::UrlRule;
+use crate::schema::url_rules;
+use crate::utils::url_rules::UrlRules;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use log::error;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use ulid::Ulid;
+
+#[derive(Deserialize)]
+pub struct NewUrlRule {
+    // A regular expression matched against url paths, e.g. `^/users/\d+`
+    pattern: String,
+    // What matches are replaced with, e.g. `/users/:id`. `$1` and `${name}`
+    // refer to capture groups.
+    replacement: String,
+}
+
+pub async fn list(pool: web::Data<DbPool>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match url_rules::table
+        .order(url_rules::created_at.asc())
+        .load::<UrlRule>(&mut conn)
+    {
+        Ok(rules) => HttpResponse::Ok().json(rules),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+// Applies to events recorded from now on, stored urls are left as they are
+pub async fn create(
+    pool: web::Data<DbPool>,
+    rules: web::Data<Arc<UrlRules>>,
+    body: web::Json<NewUrlRule>,
+) -> impl Responder {
+    if let Err(e) = Regex::new(&body.pattern) {
+        return HttpResponse::BadRequest()
+            .json(json!({ "error": format!("Invalid pattern: {}", e) }));
+    }
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let body = body.into_inner();
+    let rule = UrlRule {
+        id: Ulid::new().to_string(),
+        pattern: body.pattern,
+        replacement: body.replacement,
+        created_at: Utc::now().naive_utc(),
+    };
+    let saved = diesel::insert_into(url_rules::table)
+        .values(&rule)
+        .execute(&mut conn)
+        .and_then(|_| rules.load(&mut conn));
+    match saved {
+        Ok(_) => HttpResponse::Created().json(rule),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn delete(
+    pool: web::Data<DbPool>,
+    rules: web::Data<Arc<UrlRules>>,
+    id: web::Path<String>,
+) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let deleted = diesel::delete(url_rules::table.find(id.into_inner()))
+        .execute(&mut conn)
+        .and_then(|count| rules.load(&mut conn).map(|_| count));
+    match deleted {
+        Ok(0) => HttpResponse::NotFound().json("No such url rule"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
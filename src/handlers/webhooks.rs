@@ -0,0 +1,115 @@
+use crate::db::DbPool;
+use crate::models::Webhook;
+use crate::schema::webhooks;
+use crate::utils::webhooks::Rule;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use ulid::Ulid;
+use url::Url;
+
+#[derive(Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    // spike, zero or milestone
+    rule: String,
+    #[serde(default)]
+    threshold: i64,
+    #[serde(default = "default_window_minutes")]
+    window_minutes: i32,
+}
+
+fn default_window_minutes() -> i32 {
+    60
+}
+
+impl NewWebhook {
+    fn validate(&self) -> Result<(), String> {
+        let rule = Rule::parse(&self.rule)
+            .ok_or_else(|| format!("Unknown rule {}, use spike, zero or milestone", self.rule))?;
+        match Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => return Err(format!("{} is not an http(s) URL", self.url)),
+        }
+        if rule != Rule::Zero && self.threshold <= 0 {
+            return Err(format!(
+                "A {} webhook needs a positive threshold",
+                self.rule
+            ));
+        }
+        if self.window_minutes <= 0 {
+            return Err("window_minutes must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub async fn list(pool: web::Data<DbPool>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match webhooks::table
+        .order(webhooks::created_at.asc())
+        .load::<Webhook>(&mut conn)
+    {
+        Ok(webhooks) => HttpResponse::Ok().json(webhooks),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn create(pool: web::Data<DbPool>, body: web::Json<NewWebhook>) -> impl Responder {
+    if let Err(message) = body.validate() {
+        return HttpResponse::BadRequest().json(json!({ "error": message }));
+    }
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    let body = body.into_inner();
+    let webhook = Webhook {
+        id: Ulid::new().to_string(),
+        url: body.url,
+        rule: body.rule,
+        threshold: body.threshold,
+        window_minutes: body.window_minutes,
+        triggered: false,
+        last_fired_at: None,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    match diesel::insert_into(webhooks::table)
+        .values(&webhook)
+        .execute(&mut conn)
+    {
+        Ok(_) => HttpResponse::Created().json(webhook),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
+
+pub async fn delete(pool: web::Data<DbPool>, id: web::Path<String>) -> impl Responder {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return HttpResponse::ServiceUnavailable().json("Could not get DB connection"),
+    };
+
+    match diesel::delete(webhooks::table.find(id.into_inner())).execute(&mut conn) {
+        Ok(0) => HttpResponse::NotFound().json("No such webhook"),
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            error!("Database query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(format!("Database error: {:?}", e))
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::config::{Config, LogFormat, LogRotation};
+use chrono::Utc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+// Log output for the whole service. `log` macros used across the codebase are
+// forwarded to the same subscriber. Verbosity is set with RUST_LOG as before,
+// also from `.env` or the config file.
+//
+// With LOG_FILE set, logs are also written to that file, rotated by date and,
+// with LOG_MAX_SIZE, by size. The returned guard flushes the file on drop and has to be kept alive until exit.
+pub fn init(config: &Config) -> Option<WorkerGuard> {
+    let filter = EnvFilter::try_new(&config.log_filter).unwrap_or_else(|_| EnvFilter::new("error"));
+
+    let (file_writer, guard) = match open_log_file(config) {
+        Some((writer, guard)) => (Some(writer), Some(guard)),
+        None => (None, None),
+    };
+
+    let registry = tracing_subscriber::registry().with(filter);
+    match config.log_format {
+        LogFormat::Text => registry
+            .with(fmt::layer())
+            .with(file_writer.map(|writer| fmt::layer().with_writer(writer).with_ansi(false)))
+            .init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().flatten_event(true))
+            .with(file_writer.map(|writer| {
+                fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_writer(writer)
+                    .with_ansi(false)
+            }))
+            .init(),
+    }
+
+    guard
+}
+
+fn open_log_file(
+    config: &Config,
+) -> Option<(tracing_appender::non_blocking::NonBlocking, WorkerGuard)> {
+    if config.log_file.is_empty() {
+        return None;
+    }
+
+    let path = Path::new(&config.log_file);
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .unwrap_or_else(|| panic!("LOG_FILE must point to a file"));
+
+    if config.log_max_size > 0 {
+        let appender = SizeRollingFile::open(
+            directory.to_path_buf(),
+            file_name.to_string_lossy().into_owned(),
+            config.log_rotation,
+            config.log_max_size as u64 * 1024 * 1024,
+            config.log_max_files,
+        )
+        .unwrap_or_else(|e| panic!("Failed to open LOG_FILE: {}", e));
+        return Some(tracing_appender::non_blocking(appender));
+    }
+
+    let rotation = match config.log_rotation {
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+
+    // Rotated files are named `<LOG_FILE>.<date>`, the oldest are removed
+    // once there are more than LOG_MAX_FILES of them
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name.to_string_lossy());
+    if config.log_max_files > 0 {
+        builder = builder.max_log_files(config.log_max_files);
+    }
+    let appender = builder
+        .build(directory)
+        .unwrap_or_else(|e| panic!("Failed to open LOG_FILE: {}", e));
+
+    Some(tracing_appender::non_blocking(appender))
+}
+
+// The part of a log file name that changes with LOG_ROTATION, named like
+// tracing-appender names its files
+fn period(rotation: LogRotation) -> String {
+    match rotation {
+        LogRotation::Hourly => Utc::now().format("%Y-%m-%d-%H").to_string(),
+        LogRotation::Daily => Utc::now().format("%Y-%m-%d").to_string(),
+        LogRotation::Never => String::new(),
+    }
+}
+
+// Log file rotated by LOG_ROTATION like RollingFileAppender, which can't
+// rotate by size, and also once it would grow past `max_size` bytes. The
+// files of one period are `<LOG_FILE>.<date>`, `<LOG_FILE>.<date>.1`, ...
+struct SizeRollingFile {
+    directory: PathBuf,
+    prefix: String,
+    rotation: LogRotation,
+    max_size: u64,
+    max_files: usize,
+    period: String,
+    index: usize,
+    file: File,
+    size: u64,
+}
+
+impl SizeRollingFile {
+    fn open(
+        directory: PathBuf,
+        prefix: String,
+        rotation: LogRotation,
+        max_size: u64,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+
+        // Carry on with the newest file of the period after a restart. Older
+        // ones may have been removed already, so the counter can start above 1.
+        let period = period(rotation);
+        let current = format!("{}.", Self::file_name(&prefix, &period, 0));
+        let index = fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                name.to_string_lossy()
+                    .strip_prefix(&current)
+                    .and_then(|index| index.parse::<usize>().ok())
+            })
+            .max()
+            .unwrap_or(0);
+        let (file, size) = Self::append(&directory.join(Self::file_name(&prefix, &period, index)))?;
+
+        Ok(SizeRollingFile {
+            directory,
+            prefix,
+            rotation,
+            max_size,
+            max_files,
+            period,
+            index,
+            file,
+            size,
+        })
+    }
+
+    fn file_name(prefix: &str, period: &str, index: usize) -> String {
+        let mut name = prefix.to_string();
+        if !period.is_empty() {
+            name = format!("{}.{}", name, period);
+        }
+        if index > 0 {
+            name = format!("{}.{}", name, index);
+        }
+        name
+    }
+
+    fn append(path: &Path) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    fn roll(&mut self, period: String) -> io::Result<()> {
+        if period == self.period {
+            self.index += 1;
+        } else {
+            self.period = period;
+            self.index = 0;
+        }
+        let path = self
+            .directory
+            .join(Self::file_name(&self.prefix, &self.period, self.index));
+        (self.file, self.size) = Self::append(&path)?;
+        self.remove_old_files();
+        Ok(())
+    }
+
+    // Keeps the newest LOG_MAX_FILES files, the one being written included
+    fn remove_old_files(&self) {
+        if self.max_files == 0 {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(&self.directory) else {
+            return;
+        };
+
+        let rotated = format!("{}.", self.prefix);
+        let mut files: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name == self.prefix || name.starts_with(&rotated)
+            })
+            .filter_map(|entry| {
+                let modified = entry.metadata().and_then(|m| m.modified()).ok()?;
+                Some((modified, entry.path()))
+            })
+            .collect();
+        files.sort();
+        files.reverse();
+        for (_, path) in files.into_iter().skip(self.max_files) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Write for SizeRollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = period(self.rotation);
+        // A single line larger than the limit still goes to a fresh file
+        if period != self.period || (self.size > 0 && self.size + buf.len() as u64 > self.max_size)
+        {
+            self.roll(period)?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
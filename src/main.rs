@@ -1,83 +1,485 @@
+mod cli;
 mod config;
 mod db;
+mod graphql;
 mod handlers;
+mod logging;
 mod middleware;
 mod models;
+mod query;
 mod schema;
 mod utils;
 
-use crate::config::Config;
-use crate::db::establish_connection_pool;
-use crate::handlers::{collector, events, sessions, summary};
+use crate::cli::{Cli, Command};
+use crate::config::{Config, SharedConfig};
+use crate::db::{establish_connection_pool, DbPool};
+use crate::graphql::build_schema;
+use crate::handlers::{
+    admin, alerts, amp, auth, badge, collector, dashboard, events, export, pixels, public,
+    redirect, sessions, share, summary, tokens, url_groups, url_rules, webhooks,
+};
 use crate::models::NewEvent;
-use crate::utils::queue::process_events_async;
+use crate::utils::active::ActiveVisitors;
+use crate::utils::alerts::evaluate_alerts;
+use crate::utils::archive::{archive_events, Archive};
+use crate::utils::backup::{backup, backup_due};
+use crate::utils::bigquery::{export_to_bigquery, BigQuery};
+use crate::utils::cache::SummaryCache;
+use crate::utils::clickhouse::ClickHouse;
+use crate::utils::geoip::GeoIp;
+use crate::utils::ingest::Ingest;
+use crate::utils::limits::{
+    CollectorCaps, CollectorRates, LoginAttempts, OriginQuotas, RecentCollectors,
+};
+use crate::utils::maintenance::{checkpoint_wal, incremental_vacuum};
+use crate::utils::queue::{process_events_async, QueueOptions};
+use crate::utils::retention::anonymize_collectors;
+use crate::utils::retry::Backoff;
+use crate::utils::rollup::update_rollups;
+use crate::utils::runtime::RuntimeStatus;
+use crate::utils::salt::VisitorSalt;
+use crate::utils::scheduler::Scheduler;
+use crate::utils::spam::ReferrerBlocklist;
+use crate::utils::stream::EventStream;
+use crate::utils::udp::listen_udp;
+use crate::utils::url_rules::UrlRules;
+use crate::utils::webhooks::fire_webhooks;
 use actix_files as fs;
+use actix_web::middleware::{from_fn, Compress};
 use actix_web::{web, App, HttpResponse, HttpServer};
-use env_logger;
-use log::info;
+use clap::Parser;
+use log::{error, info};
+use middleware::auth::require_login;
 use middleware::cors::setup_cors;
+use middleware::request_id::request_id;
+use middleware::request_log::log_requests;
+use middleware::summary_cache::cache_summaries;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio::time::sleep;
-
-// Scheduler tasks
-async fn HourlyScheduler() {
-    loop {
-        // Task to be executed every 12 hours
-        println!("Scheduler running...");
-        // Replace the following line with your actual task
-        // Perform your task here...
-
-        // Sleep for 1 hour
-        sleep(Duration::from_secs(3600)).await;
-    }
+
+// Registers the background jobs, see JOB_INTERVALS
+fn schedule_jobs(
+    scheduler: &Scheduler,
+    pool: &DbPool,
+    config: &web::Data<SharedConfig>,
+    salt: &Arc<VisitorSalt>,
+    referrer_blocklist: &Arc<ReferrerBlocklist>,
+    geoip: &Arc<GeoIp>,
+    runtime: &Arc<RuntimeStatus>,
+) {
+    // Rotate the visitor salt once the day changes
+    let salt = salt.clone();
+    scheduler.spawn_blocking("salt", pool.clone(), move |conn| {
+        let discarded = salt.rotate(conn)?;
+        if discarded > 0 {
+            info!("Discarded {} old visitor salts", discarded);
+        }
+        Ok(())
+    });
+
+    // Strip identifying details from collectors past the configured age
+    let anonymize_config = config.clone();
+    scheduler.spawn_blocking("anonymize", pool.clone(), move |conn| {
+        let days = anonymize_config.get().anonymize_after_days;
+        if days > 0 {
+            let anonymized = anonymize_collectors(conn, days)?;
+            if anonymized > 0 {
+                info!("Anonymized {} old collectors", anonymized);
+            }
+        }
+        Ok(())
+    });
+
+    // Move old events to cold storage
+    let archive_config = config.clone();
+    let archive_pool = pool.clone();
+    scheduler.spawn("archive", move || {
+        let config = archive_config.get();
+        let pool = archive_pool.clone();
+        async move {
+            if config.archive_after_days > 0 && !config.archive_url.is_empty() {
+                let archive = Archive::new(&config.archive_url, &config.object_store_options)?;
+                archive_events(
+                    &pool,
+                    &archive,
+                    config.archive_after_days,
+                    config.bigquery_enabled(),
+                )
+                .await?;
+            }
+            Ok(())
+        }
+    });
+
+    // Roll up the hours that ended since the last run
+    scheduler.spawn_blocking("rollups", pool.clone(), |conn| {
+        update_rollups(conn)?;
+        Ok(())
+    });
+
+    // Send the days that ended since the last run to BigQuery
+    let bigquery_config = config.clone();
+    let bigquery_pool = pool.clone();
+    scheduler.spawn("bigquery", move || {
+        let config = bigquery_config.get();
+        let pool = bigquery_pool.clone();
+        async move {
+            if config.bigquery_enabled() {
+                let bigquery = BigQuery::from_config(&config)?;
+                export_to_bigquery(&pool, &bigquery).await?;
+            }
+            Ok(())
+        }
+    });
+
+    // Tell the webhooks whose traffic rule started matching
+    let notify_client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to create notification client");
+    let webhook_pool = pool.clone();
+    let webhook_client = notify_client.clone();
+    scheduler.spawn("webhooks", move || {
+        let pool = webhook_pool.clone();
+        let client = webhook_client.clone();
+        async move {
+            fire_webhooks(&pool, &client).await?;
+            Ok(())
+        }
+    });
+
+    // Notify the destinations of alert rules that started or stopped matching
+    let alerts_pool = pool.clone();
+    scheduler.spawn("alerts", move || {
+        let pool = alerts_pool.clone();
+        let client = notify_client.clone();
+        async move {
+            evaluate_alerts(&pool, &client).await?;
+            Ok(())
+        }
+    });
+
+    // Keep the WAL from growing while events keep arriving
+    let checkpoint_runtime = runtime.clone();
+    scheduler.spawn_blocking("wal-checkpoint", pool.clone(), move |conn| {
+        let checkpoint = checkpoint_wal(conn)?;
+        checkpoint_runtime.record_checkpoint(
+            checkpoint.log as i64,
+            checkpoint.checkpointed as i64,
+            checkpoint.busy != 0,
+        );
+        Ok(())
+    });
+
+    // Give the space of deleted rows back to the file system
+    let vacuum_runtime = runtime.clone();
+    scheduler.spawn_blocking("vacuum", pool.clone(), move |conn| {
+        if let Some(vacuum) = incremental_vacuum(conn)? {
+            info!("Vacuum reclaimed {} pages", vacuum.pages);
+            vacuum_runtime.record_vacuum(vacuum.pages, vacuum.bytes);
+        }
+        Ok(())
+    });
+
+    // Snapshot the database once the newest backup is old enough, so a
+    // restart doesn't take another one right away
+    let backup_config = config.clone();
+    scheduler.spawn_blocking("backup", pool.clone(), move |conn| {
+        let config = backup_config.get();
+        let backup_dir = Path::new(&config.backup_dir);
+        let interval = config.job_interval("backup").unwrap_or_default();
+        if backup_due(backup_dir, interval) {
+            let path = backup(conn, backup_dir, config.backup_retention)?;
+            info!("Backed up database to {}", path.display());
+        }
+        Ok(())
+    });
+
+    // Keep the downloaded referrer spam list up to date
+    let refresh_config = config.clone();
+    let referrer_blocklist = referrer_blocklist.clone();
+    scheduler.spawn("referrer-spam-list", move || {
+        let list_url = refresh_config.get().referrer_spam_list_url.clone();
+        let referrer_blocklist = referrer_blocklist.clone();
+        async move {
+            if !list_url.is_empty() {
+                let count = referrer_blocklist.refresh(&list_url).await?;
+                info!("Loaded {} referrer spam domains", count);
+            }
+            Ok(())
+        }
+    });
+
+    // Pick up GeoIP databases that were replaced, e.g. by geoipupdate
+    let geoip = geoip.clone();
+    scheduler.spawn("geoip-refresh", move || {
+        let geoip = geoip.clone();
+        async move {
+            let reloaded = web::block(move || geoip.refresh()).await??;
+            if reloaded > 0 {
+                info!("Reloaded {} GeoIP databases", reloaded);
+            }
+            Ok(())
+        }
+    });
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    let cli = Cli::parse();
+    let config = Arc::new(Config::load(cli.config.as_deref()));
+    let _log_guard = logging::init(&config);
 
-    let config = Arc::new(Config::new());
+    match cli.command {
+        None | Some(Command::Serve) => serve(config, cli.config).await,
+        Some(command) => cli::run(&config, command).await,
+    }
+}
+
+async fn serve(config: Arc<Config>, config_path: Option<PathBuf>) -> std::io::Result<()> {
     let address = format!("127.0.0.1:{}", config.service_port);
-    let pool = establish_connection_pool();
+    let pool = establish_connection_pool(&config);
+    let geoip = Arc::new(GeoIp::from_config(&config));
 
     info!("Stats analytics");
     info!("Starting server at http://{}", address);
 
-    // Start scheduler
-    let scheduler = tokio::spawn(async {
-        HourlyScheduler().await;
-    });
+    let shared_config = web::Data::new(SharedConfig::new(config.clone(), config_path));
+    let runtime = Arc::new(RuntimeStatus::new());
+    let salt = Arc::new(VisitorSalt::new());
+    let referrer_blocklist = Arc::new(ReferrerBlocklist::new(&config.referrer_spam_domains));
+    let summary_cache = Arc::new(SummaryCache::new());
+    let active_visitors = Arc::new(ActiveVisitors::new());
+    let quotas = Arc::new(OriginQuotas::new());
+    let recent_collectors = Arc::new(RecentCollectors::new());
+    let login_attempts = Arc::new(LoginAttempts::new());
+    let url_rules = Arc::new(UrlRules::new());
+    let ingest = Arc::new(Ingest::new(
+        referrer_blocklist.clone(),
+        url_rules.clone(),
+        Arc::new(CollectorCaps::new()),
+        Arc::new(CollectorRates::new()),
+        quotas.clone(),
+        runtime.clone(),
+    ));
+    match pool.get() {
+        Ok(mut conn) => {
+            if let Err(e) = url_rules.load(&mut conn) {
+                error!("Failed to load the url rules: {}", e);
+            }
+        }
+        Err(e) => error!("Failed to load the url rules: {}", e),
+    }
+    let clickhouse = Arc::new(ClickHouse::from_config(&config));
+    let stream =
+        Arc::new(EventStream::connect(&config).await.map_err(|e| {
+            std::io::Error::other(format!("Failed to connect event stream: {}", e))
+        })?);
+
+    if config.event_store.writes_clickhouse() {
+        if let Err(e) = clickhouse.create_tables().await {
+            error!("Failed to create the ClickHouse events table: {}", e);
+        }
+    }
+
+    // Reload the configuration on SIGHUP
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reload_config = shared_config.clone();
+        let reload_blocklist = referrer_blocklist.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(e) = admin::reload(&reload_config, &reload_blocklist).await {
+                    error!("Failed to reload configuration: {}", e);
+                }
+            }
+        });
+    }
+
+    // Start the background jobs
+    let scheduler = Scheduler::new(shared_config.clone(), runtime.clone());
+    schedule_jobs(
+        &scheduler,
+        &pool,
+        &shared_config,
+        &salt,
+        &referrer_blocklist,
+        &geoip,
+        &runtime,
+    );
 
     // Setup the background processing queue
-    let (events_queue, rx) = mpsc::channel::<NewEvent>(500);
+    let (events_queue, rx) = mpsc::channel::<NewEvent>(config.queue_capacity);
     let db_pool = pool.clone();
+    let queue_runtime = runtime.clone();
+    let queue_options = QueueOptions {
+        batch_size: config.processing_batch_size,
+        batch_timeout: Duration::from_secs(config.processing_batch_timeout_secs),
+        backoff: Backoff::from_config(&config),
+        event_store: config.event_store,
+        clickhouse: clickhouse.clone(),
+        stream,
+        summary_cache: summary_cache.clone(),
+        active_visitors: active_visitors.clone(),
+        dead_letter_file: PathBuf::from(&config.dead_letter_file),
+    };
+    tokio::spawn(async move {
+        process_events_async(rx, db_pool, queue_runtime, queue_options).await;
+    });
+
+    let active_pool = pool.clone();
+    let tracked_visitors = active_visitors.clone();
     tokio::spawn(async move {
-        process_events_async(rx, db_pool).await;
+        tracked_visitors.track(active_pool).await;
     });
 
+    if !config.udp_listen_address.is_empty() {
+        let address = config.udp_listen_address.clone();
+        let udp_config = shared_config.clone();
+        let udp_queue = events_queue.clone();
+        let udp_ingest = ingest.clone();
+        let udp_pool = pool.clone();
+        tokio::spawn(async move {
+            let listener = listen_udp(address.clone(), udp_config, udp_ingest, udp_pool, udp_queue);
+            if let Err(e) = listener.await {
+                error!("UDP listener at {} failed: {}", address, e);
+            }
+        });
+    }
+
+    let graphql_schema = web::Data::new(build_schema(pool.clone()));
+    let (script_path, collect_path) = (config.script_path.clone(), config.collect_path.clone());
+
     // Start the HTTP server
     // serves the API and the static dashboard in the `ui` directory
     HttpServer::new(move || {
         App::new()
-            .wrap(setup_cors(&config.cors_domains))
-            .app_data(web::Data::new(config.clone()))
+            .wrap(from_fn(cache_summaries))
+            .wrap(from_fn(require_login))
+            .wrap(setup_cors(shared_config.clone()))
+            .wrap(from_fn(log_requests))
+            .wrap(from_fn(request_id))
+            .app_data(shared_config.clone())
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(geoip.clone()))
+            .app_data(web::Data::new(salt.clone()))
+            .app_data(web::Data::new(referrer_blocklist.clone()))
+            .app_data(web::Data::new(url_rules.clone()))
+            .app_data(web::Data::new(ingest.clone()))
+            .app_data(web::Data::new(quotas.clone()))
+            .app_data(web::Data::new(recent_collectors.clone()))
+            .app_data(web::Data::new(login_attempts.clone()))
+            .app_data(web::Data::new(summary_cache.clone()))
+            .app_data(web::Data::new(active_visitors.clone()))
             .app_data(web::Data::new(events_queue.clone()))
-            .route("/collect", web::get().to(events::record_event))
+            .app_data(web::Data::new(runtime.clone()))
+            .app_data(web::Data::new(clickhouse.clone()))
+            .app_data(graphql_schema.clone())
+            .route(&collect_path, web::get().to(events::record_event))
             .route("/sessions", web::get().to(sessions::retrieve_sessions))
             .route("/sessions/map", web::get().to(sessions::map))
             .route("/summary", web::get().to(summary::events))
             .route("/summary/urls", web::get().to(summary::urls))
+            .route("/summary/urls/bounce", web::get().to(summary::bounces))
+            .route("/summary/urls/search", web::get().to(summary::search_urls))
+            .route("/summary/entry-exit", web::get().to(summary::entry_exit))
             .route("/summary/hourly", web::get().to(summary::hourly))
             .route("/summary/weekly", web::get().to(summary::weekly))
             .route("/summary/fiveminutes", web::get().to(summary::five_minutes))
+            .route("/summary/timeseries", web::get().to(summary::timeseries))
             .route("/summary/browsers", web::get().to(summary::browsers))
             .route("/summary/osbrowsers", web::get().to(summary::os_browsers))
+            .route("/summary/countries", web::get().to(summary::countries))
+            .route("/summary/regions", web::get().to(summary::regions))
             .route("/summary/referrers", web::get().to(summary::referrers))
+            .route(
+                "/summary/events-by-name",
+                web::get().to(summary::events_by_name),
+            )
+            .route("/summary/not-found", web::get().to(summary::not_found))
+            .route("/summary/outbound", web::get().to(summary::outbound))
+            .route("/summary/downloads", web::get().to(summary::downloads))
+            .route("/summary/vitals", web::get().to(summary::vitals))
+            .route("/summary/pixels", web::get().to(summary::pixels))
+            .route("/summary/url-groups", web::get().to(summary::url_groups))
+            .route(
+                "/summary/time-on-page",
+                web::get().to(summary::time_on_page),
+            )
+            .route("/summary/revenue", web::get().to(summary::revenue))
             .route("/summary/percentages", web::get().to(summary::percentages))
-            .route("/stats.js", web::get().to(collector::serve_collector_js))
+            .route("/summary/overview", web::get().to(summary::overview))
+            .route("/summary/active/poll", web::get().to(summary::active_poll))
+            .route("/query", web::post().to(handlers::query::run_query))
+            .route("/graphql", web::post().to(handlers::graphql::graphql))
+            .route("/graphql", web::get().to(handlers::graphql::graphiql))
+            .route(
+                "/export/events.parquet",
+                web::get().to(export::events_parquet),
+            )
+            .route("/badge.svg", web::get().to(badge::badge))
+            .route("/share-links", web::post().to(share::create_share_link))
+            .route("/shared/{token}", web::get().to(share::shared_stats))
+            .route(
+                "/public/{site}/stats.json",
+                web::get().to(public::public_stats),
+            )
+            .route("/admin/status", web::get().to(admin::status))
+            .route("/admin/db-stats", web::get().to(admin::db_stats))
+            .route("/admin/backup", web::post().to(admin::backup))
+            .route("/admin/reload-config", web::post().to(admin::reload_config))
+            .route("/admin/webhooks", web::get().to(webhooks::list))
+            .route("/admin/webhooks", web::post().to(webhooks::create))
+            .route("/admin/webhooks/{id}", web::delete().to(webhooks::delete))
+            .route("/admin/alerts", web::get().to(alerts::list))
+            .route("/admin/alerts", web::post().to(alerts::create))
+            .route("/admin/alerts/{id}", web::get().to(alerts::get))
+            .route("/admin/alerts/{id}", web::put().to(alerts::update))
+            .route("/admin/alerts/{id}", web::delete().to(alerts::delete))
+            .route("/admin/pixels", web::get().to(pixels::list))
+            .route("/admin/pixels", web::post().to(pixels::create))
+            .route("/admin/pixels/{id}", web::delete().to(pixels::delete))
+            .route("/p/{id}.gif", web::get().to(pixels::serve_pixel))
+            .route("/r", web::get().to(redirect::redirect))
+            .route("/admin/url-groups", web::get().to(url_groups::list))
+            .route("/admin/url-groups", web::post().to(url_groups::create))
+            .route(
+                "/admin/url-groups/{id}",
+                web::delete().to(url_groups::delete),
+            )
+            .route("/admin/url-rules", web::get().to(url_rules::list))
+            .route("/admin/url-rules", web::post().to(url_rules::create))
+            .route("/admin/url-rules/{id}", web::delete().to(url_rules::delete))
+            .route("/admin/tokens", web::get().to(tokens::list))
+            .route("/admin/tokens", web::post().to(tokens::create))
+            .route("/admin/tokens/{id}", web::delete().to(tokens::delete))
+            .route("/version", web::get().to(admin::version))
+            .route("/api/config", web::get().to(dashboard::config))
+            .route("/login", web::post().to(auth::login))
+            .route("/logout", web::post().to(auth::logout))
+            .route("/me", web::get().to(auth::me))
+            .service(
+                web::resource(&script_path)
+                    .wrap(Compress::default())
+                    .route(web::get().to(collector::serve_collector_js)),
+            )
+            .service(
+                web::resource("/stats.mjs")
+                    .wrap(Compress::default())
+                    .route(web::get().to(collector::serve_collector_module)),
+            )
+            .route("/amp.json", web::get().to(amp::amp_config))
+            .route(
+                "/script-integrity",
+                web::get().to(collector::script_integrity),
+            )
+            .route("/collector/renew", web::post().to(collector::renew))
+            .route("/exclude-me", web::get().to(collector::exclude_me))
             .service(fs::Files::new("/", "ui").index_file("index.html"))
             .default_service(web::route().to(|| async { HttpResponse::NoContent().finish() }))
     })
@@ -0,0 +1,203 @@
+use crate::config::{Config, SharedConfig};
+use crate::db::DbPool;
+use crate::utils::api_tokens::token_scope;
+use crate::utils::auth::{session_user, users_exist, SESSION_COOKIE};
+use crate::utils::share::{verify, SHARED_SUMMARIES};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method, Uri};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use log::error;
+
+// Reachable without logging in: tracking (also at SCRIPT_PATH and
+// COLLECT_PATH, email pixels and redirects), the public share and badge
+// endpoints, and what the login page needs
+const PUBLIC_PATHS: &[&str] = &[
+    "/stats.mjs",
+    "/script-integrity",
+    "/amp.json",
+    "/r",
+    "/collector/renew",
+    "/exclude-me",
+    "/badge.svg",
+    "/version",
+    "/login",
+    "/logout",
+    "/login.html",
+    "/styles.css",
+    "/favico.png",
+    "/og.png",
+];
+const PUBLIC_PREFIXES: &[&str] = &["/shared/", "/public/", "/p/"];
+
+fn is_public(config: &Config, path: &str) -> bool {
+    path == config.script_path
+        || path == config.collect_path
+        || PUBLIC_PATHS.contains(&path)
+        || PUBLIC_PREFIXES.iter().any(|p| path.starts_with(p))
+}
+
+enum Access {
+    Allowed,
+    // Not logged in, or an unknown or expired API token
+    Unauthorized,
+    // An API token whose scope doesn't cover the request
+    Forbidden,
+}
+
+// A request with an API token needs a token whose scope covers it. Others
+// may go ahead with a valid session, or when there are no users yet and the
+// dashboard is open like before accounts existed.
+fn access(
+    pool: &DbPool,
+    config: &Config,
+    req: &ServiceRequest,
+    bearer: Option<&str>,
+    session: Option<&str>,
+) -> Result<Access, String> {
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+    if let Some(bearer) = bearer {
+        return Ok(
+            match token_scope(&mut conn, bearer).map_err(|e| e.to_string())? {
+                Some(scope) if scope.allows(req.method(), req.path(), &config.collect_path) => {
+                    Access::Allowed
+                }
+                Some(_) => Access::Forbidden,
+                None => Access::Unauthorized,
+            },
+        );
+    }
+    if let Some(session) = session {
+        if session_user(&mut conn, session)
+            .map_err(|e| e.to_string())?
+            .is_some()
+        {
+            return Ok(Access::Allowed);
+        }
+    }
+    match users_exist(&mut conn).map_err(|e| e.to_string())? {
+        true => Ok(Access::Unauthorized),
+        false => Ok(Access::Allowed),
+    }
+}
+
+// The token of a share link sent as `?share=<token>` to a summary
+fn share_token(req: &ServiceRequest) -> Option<String> {
+    if req.method() != Method::GET || !req.path().starts_with("/summary") {
+        return None;
+    }
+    url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(key, _)| key == "share")
+        .map(|(_, value)| value.into_owned())
+}
+
+// Lets a valid share link open the summaries in SHARED_SUMMARIES, with the
+// query rewritten so only its site and range are counted
+fn pin_to_share_link(
+    config: &Config,
+    req: &mut ServiceRequest,
+    token: &str,
+) -> Option<HttpResponse> {
+    if config.share_link_secret.is_empty() {
+        return Some(HttpResponse::NotFound().json("Share links are disabled"));
+    }
+    let Ok(claims) = verify(&config.share_link_secret, token) else {
+        return Some(HttpResponse::Unauthorized().json("Invalid or expired share link"));
+    };
+    if !SHARED_SUMMARIES.contains(&req.path()) {
+        return Some(
+            HttpResponse::Forbidden().json("A share link only opens summaries with a date range"),
+        );
+    }
+
+    let uri = format!("{}?{}", req.path(), claims.pin(req.query_string()));
+    match uri.parse::<Uri>() {
+        Ok(uri) => {
+            req.head_mut().uri = uri;
+            None
+        }
+        Err(_) => Some(HttpResponse::BadRequest().json("Invalid query string")),
+    }
+}
+
+// Keeps the dashboard and its API behind a login once a user is added with
+// `stats create-user`, or an API token sent as `Authorization: Bearer`.
+// Browsers are sent to the login page, API clients get a 401. Share links
+// open some summaries of their site on their own.
+pub async fn require_login(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let config = req
+        .app_data::<web::Data<SharedConfig>>()
+        .map(|config| config.get());
+    let public = config
+        .as_ref()
+        .is_some_and(|config| is_public(config, req.path()));
+    if req.method() == Method::OPTIONS || public {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+    if let (Some(config), Some(token)) = (&config, share_token(&req)) {
+        if let Some(response) = pin_to_share_link(config, &mut req, &token) {
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    let pool = req.app_data::<web::Data<DbPool>>().cloned();
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let session = req.cookie(SESSION_COOKIE);
+    let access = match (pool, config) {
+        (Some(pool), Some(config)) => access(
+            &pool,
+            &config,
+            &req,
+            bearer.as_deref(),
+            session.as_ref().map(|c| c.value()),
+        ),
+        _ => Err("No database pool or config".to_string()),
+    };
+
+    let response = match access {
+        Ok(Access::Allowed) => {
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        Ok(Access::Forbidden) => {
+            HttpResponse::Forbidden().json("The API token's scope doesn't allow this")
+        }
+        Ok(Access::Unauthorized) => {
+            let wants_html = req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|accept| accept.contains("text/html"));
+            if req.method() == Method::GET && wants_html {
+                HttpResponse::SeeOther()
+                    .insert_header((header::LOCATION, "/login.html"))
+                    .finish()
+            } else {
+                HttpResponse::Unauthorized().json("Login required")
+            }
+        }
+        Err(e) => {
+            error!("Could not check the session: {}", e);
+            HttpResponse::ServiceUnavailable().json("Could not get DB connection")
+        }
+    };
+    Ok(req.into_response(response).map_into_right_body())
+}
@@ -1,14 +1,18 @@
+use crate::config::SharedConfig;
 use actix_cors::Cors;
-use actix_web::http::header::{self};
+use actix_web::http::header::{self, HeaderName};
+use actix_web::web;
 use log::warn;
-use std::collections::HashSet;
-
-pub fn setup_cors(cors_domains: &Vec<String>) -> Cors {
-    let allowed_domains_set: HashSet<String> = cors_domains.iter().cloned().collect();
 
+// CORS_DOMAINS is looked up on every request so a config reload applies
+pub fn setup_cors(config: web::Data<SharedConfig>) -> Cors {
     Cors::default()
         .allowed_origin_fn(move |origin, _req_head| match origin.to_str() {
-            Ok(origin_str) => allowed_domains_set.contains(origin_str),
+            Ok(origin_str) => config
+                .get()
+                .cors_domains
+                .iter()
+                .any(|domain| domain == origin_str),
             Err(_) => {
                 warn!("CORS blocked: Missing or invalid origin");
                 false
@@ -17,5 +21,6 @@ pub fn setup_cors(cors_domains: &Vec<String>) -> Cors {
         .allowed_methods(vec!["GET", "POST"])
         .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
         .allowed_header(header::CONTENT_TYPE)
+        .expose_headers(vec![HeaderName::from_static("x-request-id")])
         .max_age(3600)
 }
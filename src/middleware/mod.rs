@@ -1 +1,5 @@
+pub mod auth;
 pub mod cors;
+pub mod request_id;
+pub mod request_log;
+pub mod summary_cache;
@@ -0,0 +1,57 @@
+use crate::config::SharedConfig;
+use crate::utils::client_ip::client_ip;
+use crate::utils::ip::anonymize;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::time::Instant;
+use tracing::info;
+
+// Logs one line per request with the matched route, client, collector and
+// latency as separate fields, so they show up as keys with LOG_FORMAT=json
+pub async fn log_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| req.path().to_string());
+
+    // Respect ANONYMIZE_IP, raw addresses never end up in the logs with it
+    let client_ip = req
+        .app_data::<web::Data<SharedConfig>>()
+        .and_then(|config| {
+            let config = config.get();
+            client_ip(req.request(), &config).map(|ip| {
+                if config.anonymize_ip {
+                    anonymize(ip)
+                } else {
+                    ip
+                }
+            })
+        })
+        .map(|ip| ip.to_string())
+        .unwrap_or_default();
+
+    let collector_id = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(key, _)| key == "collector_id")
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_default();
+
+    let res = next.call(req).await?;
+
+    info!(
+        method,
+        route,
+        status = res.status().as_u16(),
+        client_ip,
+        collector_id,
+        latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+        "request"
+    );
+
+    Ok(res)
+}
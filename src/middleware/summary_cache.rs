@@ -0,0 +1,62 @@
+use crate::config::SharedConfig;
+use crate::utils::cache::SummaryCache;
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Long polls wait for a change, a cached answer would hand back the old count
+const UNCACHED_PATHS: &[&str] = &["/summary/active/poll"];
+
+// Serves GET requests to /summary from SummaryCache for SUMMARY_CACHE_SECONDS,
+// only successful responses are kept
+pub async fn cache_summaries(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let ttl = req
+        .app_data::<web::Data<SharedConfig>>()
+        .map(|config| Duration::from_secs(config.get().summary_cache_seconds as u64))
+        .unwrap_or_default();
+    let cache = req.app_data::<web::Data<Arc<SummaryCache>>>().cloned();
+    let (Some(cache), false) = (cache, ttl.is_zero()) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    if req.method() != Method::GET
+        || !req.path().starts_with("/summary")
+        || UNCACHED_PATHS.contains(&req.path())
+    {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let key = format!("{}?{}", req.path(), req.query_string());
+    if let Some(cached) = cache.get(&key, ttl) {
+        let res = HttpResponse::Ok()
+            .content_type(cached.content_type)
+            .insert_header((header::AGE, cached.stored.elapsed().as_secs().to_string()))
+            .body(cached.body);
+        return Ok(req.into_response(res));
+    }
+
+    let res = next.call(req).await?;
+    if res.status() != StatusCode::OK {
+        return Ok(res.map_into_boxed_body());
+    }
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/json")
+        .to_string();
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = body::to_bytes(body).await.map_err(Into::into)?;
+    cache.insert(key, content_type, body.clone(), ttl);
+    Ok(ServiceResponse::new(
+        req,
+        res.set_body(body).map_into_boxed_body(),
+    ))
+}
@@ -1,10 +1,43 @@
-use super::schema::{collectors, events};
+use super::schema::{
+    alert_rules, api_tokens, collectors, events, pixels, url_groups, url_rules, user_sessions,
+    users, webhooks,
+};
+use async_graphql::SimpleObject;
 use chrono::NaiveDateTime;
 use diesel::{Associations, Identifiable, Insertable, Queryable};
 use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, Insertable, Identifiable, Serialize, Deserialize)]
+// Event names fired by the generated collector script, and `pageview`,
+// which pages without automatic pageviews send themselves
+pub const BUILTIN_EVENT_NAMES: &[&str] = &[
+    "enter",
+    "pageview",
+    "exit",
+    "leave",
+    "visit",
+    "download",
+    "heartbeat",
+    "email_open",
+    "lcp",
+    "cls",
+    "fid",
+    "inp",
+    "ttfb",
+];
+
+// Events that count as a page being viewed
+pub const PAGEVIEW_EVENT_NAMES: &[&str] = &["enter", "visit", "pageview"];
+
+// Core Web Vitals reported by the collector script, with the metric in `value`
+pub const WEB_VITAL_NAMES: &[&str] = &["lcp", "cls", "fid", "inp", "ttfb"];
+
+// Events that measure a page rather than record a visit or interaction,
+// left out of traffic counts so they don't inflate them
+pub const MEASUREMENT_EVENT_NAMES: &[&str] = &["heartbeat", "lcp", "cls", "fid", "inp", "ttfb"];
+
+#[derive(Queryable, Insertable, Identifiable, Serialize, Deserialize, SimpleObject)]
 #[diesel(table_name = collectors)]
+#[graphql(complex)]
 pub struct Collector {
     pub id: String,
     pub origin: String,
@@ -13,9 +46,21 @@ pub struct Collector {
     pub os: Option<String>,
     pub browser: Option<String>,
     pub timestamp: NaiveDateTime,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub region: Option<String>,
+    pub asn: Option<i32>,
+    pub as_org: Option<String>,
+    pub country_code: Option<String>,
+    pub visitor_hash: Option<String>,
+    // The first collector of the visit, None for a visit of its own
+    pub visit_id: Option<String>,
+    // The campaign of the page the collector entered on
+    pub utm_source: Option<String>,
+    pub utm_campaign: Option<String>,
 }
 
-#[derive(Queryable, Associations, Identifiable, Serialize, Deserialize)]
+#[derive(Queryable, Associations, Identifiable, Serialize, Deserialize, SimpleObject)]
 #[diesel(belongs_to(Collector, foreign_key = collector_id))]
 #[diesel(table_name = events)]
 pub struct Event {
@@ -25,16 +70,14 @@ pub struct Event {
     pub name: String,
     pub timestamp: NaiveDateTime,
     pub collector_id: String,
+    pub status: Option<i32>,
+    pub value: Option<f64>,
+    pub currency: Option<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
 }
 
-#[derive(Deserialize)]
-pub struct EventRequest {
-    pub url: String,
-    pub name: String,
-    pub collector_id: String,
-}
-
-#[derive(Insertable, Deserialize)]
+#[derive(Insertable, Serialize, Deserialize)]
 #[diesel(table_name = events)]
 pub struct NewEvent {
     pub id: String,
@@ -43,4 +86,97 @@ pub struct NewEvent {
     pub name: String,
     pub timestamp: NaiveDateTime,
     pub collector_id: String,
+    pub status: Option<i32>,
+    pub value: Option<f64>,
+    pub currency: Option<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+}
+
+#[derive(Queryable, Insertable, Identifiable, Serialize, Deserialize)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub rule: String,
+    pub threshold: i64,
+    pub window_minutes: i32,
+    pub triggered: bool,
+    pub last_fired_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Identifiable, Serialize, Deserialize)]
+#[diesel(table_name = alert_rules)]
+pub struct AlertRule {
+    pub id: String,
+    pub name: String,
+    pub metric: String,
+    pub comparison: String,
+    pub threshold: i64,
+    pub window_minutes: i32,
+    pub destination: String,
+    pub triggered: bool,
+    pub last_value: Option<i64>,
+    pub last_checked_at: Option<NaiveDateTime>,
+    pub last_fired_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Identifiable, Serialize)]
+#[diesel(table_name = users)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    #[serde(skip)]
+    pub password_hash: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Identifiable)]
+#[diesel(table_name = user_sessions)]
+pub struct UserSession {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Identifiable, Serialize)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub scope: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Identifiable, Serialize, Deserialize)]
+#[diesel(table_name = pixels)]
+pub struct Pixel {
+    pub id: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Identifiable, Serialize, Deserialize)]
+#[diesel(table_name = url_groups)]
+pub struct UrlGroup {
+    pub id: String,
+    pub name: String,
+    pub pattern: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Identifiable, Serialize, Deserialize)]
+#[diesel(table_name = url_rules)]
+pub struct UrlRule {
+    pub id: String,
+    pub pattern: String,
+    pub replacement: String,
+    pub created_at: NaiveDateTime,
 }
@@ -0,0 +1,314 @@
+use crate::models::{MEASUREMENT_EVENT_NAMES, PAGEVIEW_EVENT_NAMES};
+use crate::utils::rollup::sql_name_list;
+use async_graphql::{Enum, InputObject, SimpleObject};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::query_builder::BoxedSqlQuery;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Nullable, Text, Timestamp};
+use diesel::sqlite::Sqlite;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+// Upper bounds so a single request can't ask for an unbounded result
+const MAX_DIMENSIONS: usize = 3;
+const MAX_FILTERS: usize = 10;
+const MAX_LIMIT: i64 = 1000;
+const DEFAULT_LIMIT: i64 = 100;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Pageviews,
+    Visitors,
+    Events,
+}
+
+impl Metric {
+    fn alias(&self) -> &'static str {
+        match self {
+            Metric::Pageviews => "pageviews",
+            Metric::Visitors => "visitors",
+            Metric::Events => "events",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum Dimension {
+    Url,
+    Referrer,
+    Name,
+    Origin,
+    Country,
+    City,
+    Os,
+    Browser,
+    Date,
+    Hour,
+}
+
+impl Dimension {
+    pub fn key(&self) -> &'static str {
+        match self {
+            Dimension::Url => "url",
+            Dimension::Referrer => "referrer",
+            Dimension::Name => "name",
+            Dimension::Origin => "origin",
+            Dimension::Country => "country",
+            Dimension::City => "city",
+            Dimension::Os => "os",
+            Dimension::Browser => "browser",
+            Dimension::Date => "date",
+            Dimension::Hour => "hour",
+        }
+    }
+
+    // Column expression for the dimension, assuming `events e` joined to `collectors c`.
+    // Only these fixed expressions are ever interpolated into the SQL.
+    pub fn column(&self) -> &'static str {
+        match self {
+            Dimension::Url => "e.url",
+            Dimension::Referrer => "e.referrer",
+            Dimension::Name => "e.name",
+            Dimension::Origin => "c.origin",
+            Dimension::Country => "c.country",
+            Dimension::City => "c.city",
+            Dimension::Os => "c.os",
+            Dimension::Browser => "c.browser",
+            Dimension::Date => "strftime('%Y-%m-%d', e.timestamp)",
+            Dimension::Hour => "strftime('%Y-%m-%d %H:00:00', e.timestamp)",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterOp {
+    Eq,
+    Neq,
+    Contains,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, InputObject)]
+pub struct Filter {
+    pub dimension: Dimension,
+    #[serde(default = "default_filter_op")]
+    #[graphql(default_with = "default_filter_op()")]
+    pub op: FilterOp,
+    pub value: String,
+}
+
+fn default_filter_op() -> FilterOp {
+    FilterOp::Eq
+}
+
+impl Filter {
+    // SQL fragment with a single `?` placeholder for the filter value
+    pub fn to_sql(&self) -> String {
+        let column = self.dimension.column();
+        match self.op {
+            FilterOp::Eq => format!("{} = ?", column),
+            FilterOp::Neq => format!("({} IS NULL OR {} != ?)", column, column),
+            FilterOp::Contains => format!("instr({}, ?) > 0", column),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, InputObject)]
+pub struct DateRange {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct QueryRequest {
+    pub metrics: Vec<Metric>,
+    #[serde(default)]
+    pub dimensions: Vec<Dimension>,
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    pub date_range: Option<DateRange>,
+    pub order_by: Option<Metric>,
+    pub limit: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+pub struct QueryRow {
+    #[diesel(sql_type = Nullable<Text>)]
+    d0: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    d1: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    d2: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    pageviews: i64,
+    #[diesel(sql_type = BigInt)]
+    visitors: i64,
+    #[diesel(sql_type = BigInt)]
+    events: i64,
+}
+
+// A dimension's value in a row of results
+#[derive(SimpleObject)]
+pub struct DimensionValue {
+    pub dimension: Dimension,
+    pub value: Option<String>,
+}
+
+impl QueryRow {
+    pub fn dimension_values(&self, dimensions: &[Dimension]) -> Vec<DimensionValue> {
+        let values = [&self.d0, &self.d1, &self.d2];
+        dimensions
+            .iter()
+            .zip(values)
+            .map(|(dimension, value)| DimensionValue {
+                dimension: *dimension,
+                value: value.clone(),
+            })
+            .collect()
+    }
+
+    pub fn metric(&self, metric: Metric) -> i64 {
+        match metric {
+            Metric::Pageviews => self.pageviews,
+            Metric::Visitors => self.visitors,
+            Metric::Events => self.events,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    Invalid(String),
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::Invalid(message) => write!(f, "{}", message),
+            QueryError::Database(e) => write!(f, "Database error: {:?}", e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for QueryError {
+    fn from(e: diesel::result::Error) -> Self {
+        QueryError::Database(e)
+    }
+}
+
+impl QueryRequest {
+    fn validate(&self) -> Result<(), QueryError> {
+        if self.metrics.is_empty() {
+            return Err(QueryError::Invalid(
+                "At least one metric is required".to_string(),
+            ));
+        }
+        if self.dimensions.len() > MAX_DIMENSIONS {
+            return Err(QueryError::Invalid(format!(
+                "At most {} dimensions are supported",
+                MAX_DIMENSIONS
+            )));
+        }
+        if self.filters.len() > MAX_FILTERS {
+            return Err(QueryError::Invalid(format!(
+                "At most {} filters are supported",
+                MAX_FILTERS
+            )));
+        }
+        let (from, to) = self.time_bounds();
+        if from >= to {
+            return Err(QueryError::Invalid(
+                "date_range.from has to be before date_range.to".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn time_bounds(&self) -> (NaiveDateTime, NaiveDateTime) {
+        let now = Utc::now().naive_utc();
+        let range = self.date_range.as_ref();
+        let to = range.and_then(|r| r.to).unwrap_or(now);
+        let from = range.and_then(|r| r.from).unwrap_or(to - Duration::days(7));
+        (from, to)
+    }
+
+    fn build(&self) -> (String, Vec<String>) {
+        let mut select = Vec::new();
+        for i in 0..MAX_DIMENSIONS {
+            match self.dimensions.get(i) {
+                Some(dimension) => select.push(format!("{} AS d{}", dimension.column(), i)),
+                None => select.push(format!("NULL AS d{}", i)),
+            }
+        }
+
+        let mut sql = format!(
+            "SELECT {}, \
+            COUNT(CASE WHEN e.name IN ({}) THEN 1 END) AS pageviews, \
+            COUNT(DISTINCT COALESCE(c.visitor_hash, e.collector_id)) AS visitors, \
+            COUNT(CASE WHEN e.name NOT IN ({}) THEN 1 END) AS events \
+            FROM events e \
+            LEFT JOIN collectors c ON c.id = e.collector_id \
+            WHERE e.timestamp >= ? AND e.timestamp < ?",
+            select.join(", "),
+            sql_name_list(PAGEVIEW_EVENT_NAMES),
+            sql_name_list(MEASUREMENT_EVENT_NAMES)
+        );
+
+        let mut values = Vec::new();
+        for filter in &self.filters {
+            sql.push_str(" AND ");
+            sql.push_str(&filter.to_sql());
+            values.push(filter.value.clone());
+        }
+
+        if !self.dimensions.is_empty() {
+            let group_by: Vec<String> = (0..self.dimensions.len())
+                .map(|i| format!("d{}", i))
+                .collect();
+            sql.push_str(&format!(" GROUP BY {}", group_by.join(", ")));
+        }
+
+        let order_by = self.order_by.unwrap_or(self.metrics[0]);
+        sql.push_str(&format!(" ORDER BY {} DESC LIMIT ?", order_by.alias()));
+
+        (sql, values)
+    }
+
+    pub fn load(&self, conn: &mut SqliteConnection) -> Result<Vec<QueryRow>, QueryError> {
+        self.validate()?;
+
+        let (from, to) = self.time_bounds();
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let (sql, values) = self.build();
+
+        let mut query: BoxedSqlQuery<'_, Sqlite, _> = sql_query(sql)
+            .into_boxed()
+            .bind::<Timestamp, _>(from)
+            .bind::<Timestamp, _>(to);
+        for value in values {
+            query = query.bind::<Text, _>(value);
+        }
+        Ok(query.bind::<BigInt, _>(limit).load::<QueryRow>(conn)?)
+    }
+
+    pub fn execute(&self, conn: &mut SqliteConnection) -> Result<Vec<Value>, QueryError> {
+        Ok(self
+            .load(conn)?
+            .into_iter()
+            .map(|row| {
+                let mut object = Map::new();
+                for value in row.dimension_values(&self.dimensions) {
+                    object.insert(value.dimension.key().to_string(), json!(value.value));
+                }
+                for metric in &self.metrics {
+                    object.insert(metric.alias().to_string(), json!(row.metric(*metric)));
+                }
+                Value::Object(object)
+            })
+            .collect())
+    }
+}
@@ -1,5 +1,34 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    alert_rules (id) {
+        id -> Text,
+        name -> Text,
+        metric -> Text,
+        comparison -> Text,
+        threshold -> BigInt,
+        window_minutes -> Integer,
+        destination -> Text,
+        triggered -> Bool,
+        last_value -> Nullable<BigInt>,
+        last_checked_at -> Nullable<Timestamp>,
+        last_fired_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    api_tokens (id) {
+        id -> Text,
+        name -> Text,
+        scope -> Text,
+        token_hash -> Text,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     collectors (id) {
         id -> Text,
@@ -9,6 +38,16 @@ diesel::table! {
         os -> Nullable<Text>,
         browser -> Nullable<Text>,
         timestamp -> Timestamp,
+        latitude -> Nullable<Double>,
+        longitude -> Nullable<Double>,
+        region -> Nullable<Text>,
+        asn -> Nullable<Integer>,
+        as_org -> Nullable<Text>,
+        country_code -> Nullable<Text>,
+        visitor_hash -> Nullable<Text>,
+        visit_id -> Nullable<Text>,
+        utm_source -> Nullable<Text>,
+        utm_campaign -> Nullable<Text>,
     }
 }
 
@@ -20,10 +59,130 @@ diesel::table! {
         name -> Text,
         timestamp -> Timestamp,
         collector_id -> Text,
+        status -> Nullable<Integer>,
+        value -> Nullable<Double>,
+        currency -> Nullable<Text>,
+        host -> Nullable<Text>,
+        path -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    exports (name) {
+        name -> Text,
+        exported_to -> Timestamp,
+    }
+}
+
+diesel::table! {
+    pixels (id) {
+        id -> Text,
+        name -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    rollups (name) {
+        name -> Text,
+        rolled_up_to -> Timestamp,
+    }
+}
+
+diesel::table! {
+    salts (day) {
+        day -> Date,
+        salt -> Text,
+    }
+}
+
+diesel::table! {
+    stats_daily (day, url) {
+        day -> Timestamp,
+        url -> Text,
+        events -> Integer,
+    }
+}
+
+diesel::table! {
+    stats_hourly (hour, url) {
+        hour -> Timestamp,
+        url -> Text,
+        events -> Integer,
+    }
+}
+
+diesel::table! {
+    url_groups (id) {
+        id -> Text,
+        name -> Text,
+        pattern -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    url_rules (id) {
+        id -> Text,
+        pattern -> Text,
+        replacement -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    urls (id) {
+        id -> Integer,
+        url -> Text,
+    }
+}
+
+diesel::table! {
+    user_sessions (id) {
+        id -> Text,
+        user_id -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    users (id) {
+        id -> Text,
+        username -> Text,
+        password_hash -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Text,
+        url -> Text,
+        rule -> Text,
+        threshold -> BigInt,
+        window_minutes -> Integer,
+        triggered -> Bool,
+        last_fired_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    alert_rules,
+    api_tokens,
     collectors,
     events,
+    exports,
+    pixels,
+    rollups,
+    salts,
+    stats_daily,
+    stats_hourly,
+    url_groups,
+    url_rules,
+    urls,
+    user_sessions,
+    users,
+    webhooks,
 );
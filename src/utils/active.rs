@@ -0,0 +1,104 @@
+use crate::db::DbPool;
+use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Timestamp};
+use log::error;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::task;
+use tokio::time::sleep;
+
+// Visitors count as active while they sent an event this recently, like
+// `events_in_last_five_minutes`
+pub const ACTIVE_MINUTES: i64 = 5;
+
+// How often the count is redone without new events, so visitors who left
+// drop out of it
+const RECOUNT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+pub struct ActiveCount {
+    pub visitors: i64,
+    // When `visitors` last changed, pollers pass it back as `since`
+    pub changed_at: NaiveDateTime,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+// The number of active visitors, recounted by one task however many
+// dashboards wait on it, so long polling costs a query every few seconds
+// rather than one per request
+pub struct ActiveVisitors {
+    count: watch::Sender<ActiveCount>,
+    recount: Notify,
+}
+
+impl ActiveVisitors {
+    pub fn new() -> Self {
+        ActiveVisitors {
+            count: watch::Sender::new(ActiveCount {
+                visitors: 0,
+                changed_at: Utc::now().naive_utc(),
+            }),
+            recount: Notify::new(),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ActiveCount> {
+        self.count.subscribe()
+    }
+
+    // Recounts right away rather than at the next interval, e.g. once a
+    // batch of events is written
+    pub fn refresh(&self) {
+        self.recount.notify_one();
+    }
+
+    // Keeps the count up to date until the server stops
+    pub async fn track(&self, pool: DbPool) {
+        loop {
+            let pool = pool.clone();
+            let counted = task::spawn_blocking(move || -> Result<i64, String> {
+                let mut conn = pool.get().map_err(|e| e.to_string())?;
+                count_active(&mut conn).map_err(|e| e.to_string())
+            })
+            .await;
+            match counted {
+                Ok(Ok(visitors)) => {
+                    self.count.send_if_modified(|count| {
+                        if count.visitors == visitors {
+                            return false;
+                        }
+                        *count = ActiveCount {
+                            visitors,
+                            changed_at: Utc::now().naive_utc(),
+                        };
+                        true
+                    });
+                }
+                Ok(Err(e)) => error!("Failed to count active visitors: {}", e),
+                Err(e) => error!("Failed to count active visitors: {:?}", e),
+            }
+
+            tokio::select! {
+                _ = sleep(RECOUNT_INTERVAL) => {}
+                _ = self.recount.notified() => {}
+            }
+        }
+    }
+}
+
+fn count_active(conn: &mut SqliteConnection) -> QueryResult<i64> {
+    let since = Utc::now().naive_utc() - ChronoDuration::minutes(ACTIVE_MINUTES);
+    diesel::sql_query(
+        "SELECT COUNT(DISTINCT collector_id) AS count FROM events WHERE timestamp >= ?",
+    )
+    .bind::<Timestamp, _>(since)
+    .get_result::<Count>(conn)
+    .map(|row| row.count)
+}
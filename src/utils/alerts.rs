@@ -0,0 +1,205 @@
+use crate::db::{blocking, DbPool};
+use crate::models::{AlertRule, MEASUREMENT_EVENT_NAMES, PAGEVIEW_EVENT_NAMES};
+use crate::schema::alert_rules;
+use crate::utils::webhooks::transition;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::dsl::count;
+use diesel::prelude::*;
+use log::{error, info};
+use serde_json::json;
+use std::error::Error;
+
+// What a rule measures over its window
+pub enum Metric {
+    // Page loads, the `enter`, `visit` and `pageview` events
+    Pageviews,
+    // Collectors with at least one such event
+    Visitors,
+    // Pages that answered 404
+    NotFound,
+    // Events with the given name, written `event:signup`
+    Event(String),
+}
+
+impl Metric {
+    pub fn parse(metric: &str) -> Option<Self> {
+        match metric {
+            "pageviews" => Some(Metric::Pageviews),
+            "visitors" => Some(Metric::Visitors),
+            "not_found" => Some(Metric::NotFound),
+            _ => metric
+                .strip_prefix("event:")
+                .filter(|name| !name.is_empty())
+                .map(|name| Metric::Event(name.to_string())),
+        }
+    }
+
+    pub fn measure(
+        &self,
+        conn: &mut SqliteConnection,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> QueryResult<i64> {
+        use crate::schema::events::dsl::*;
+
+        let in_window = events.filter(timestamp.gt(start)).filter(timestamp.le(end));
+        match self {
+            Metric::Pageviews => in_window
+                .filter(name.eq_any(PAGEVIEW_EVENT_NAMES))
+                .count()
+                .get_result(conn),
+            Metric::Visitors => in_window
+                .filter(name.ne_all(MEASUREMENT_EVENT_NAMES))
+                .select(count(collector_id).aggregate_distinct())
+                .first(conn),
+            Metric::NotFound => in_window
+                .filter(status.eq(404).or(name.eq("404")))
+                .count()
+                .get_result(conn),
+            Metric::Event(event_name) => in_window
+                .filter(name.eq(event_name))
+                .count()
+                .get_result(conn),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    pub fn parse(comparison: &str) -> Option<Self> {
+        match comparison {
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessOrEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            "=" | "==" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, value: i64, threshold: i64) -> bool {
+        match self {
+            Comparison::Less => value < threshold,
+            Comparison::LessOrEqual => value <= threshold,
+            Comparison::Greater => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+// The rule's current value and whether it matches, or None for a rule that
+// was stored with a metric or comparison this version doesn't know
+fn check(
+    conn: &mut SqliteConnection,
+    rule: &AlertRule,
+    now: NaiveDateTime,
+) -> QueryResult<Option<(i64, bool)>> {
+    let (Some(metric), Some(comparison)) = (
+        Metric::parse(&rule.metric),
+        Comparison::parse(&rule.comparison),
+    ) else {
+        return Ok(None);
+    };
+    let start = now - Duration::minutes(rule.window_minutes as i64);
+    let value = metric.measure(conn, start, now)?;
+    Ok(Some((value, comparison.matches(value, rule.threshold))))
+}
+
+fn payload(rule: &AlertRule, value: i64, firing: bool, now: NaiveDateTime) -> serde_json::Value {
+    let status = if firing { "firing" } else { "resolved" };
+    let text = format!(
+        "[{}] {}: {} over the last {} minutes is {} ({} {})",
+        status, rule.name, rule.metric, rule.window_minutes, value, rule.comparison, rule.threshold
+    );
+    json!({
+        "alert_id": rule.id,
+        "name": rule.name,
+        "status": status,
+        "metric": rule.metric,
+        "comparison": rule.comparison,
+        "threshold": rule.threshold,
+        "window_minutes": rule.window_minutes,
+        "value": value,
+        "checked_at": now,
+        "text": text,
+    })
+}
+
+// Checks every alert rule and notifies its destination when the rule starts
+// or stops matching. A failed notification keeps the previous state, so the
+// next check sends it again. Returns the number of notifications sent.
+pub async fn evaluate_alerts(
+    pool: &DbPool,
+    client: &reqwest::Client,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let now = Utc::now().naive_utc();
+    let checked = blocking(pool, move |conn| {
+        let rules: Vec<AlertRule> = alert_rules::table.load(conn)?;
+        let mut checked = Vec::new();
+        for rule in rules {
+            if let Some((value, matches)) = check(conn, &rule, now)? {
+                checked.push((rule, value, matches));
+            }
+        }
+        Ok(checked)
+    })
+    .await?;
+
+    let mut sent = 0;
+    let mut failed = 0;
+    for (rule, value, matches) in checked {
+        let state = (rule.triggered, rule.last_fired_at);
+        let moved = transition(client, &rule.destination, state, matches, true, now, || {
+            payload(&rule, value, matches, now)
+        })
+        .await;
+        let (triggered, fired_at) = match moved {
+            Ok(moved) => {
+                if moved.sent {
+                    info!(
+                        "Alert {} is {}",
+                        rule.name,
+                        if matches { "firing" } else { "resolved" }
+                    );
+                    sent += 1;
+                }
+                (moved.triggered, moved.last_fired_at)
+            }
+            Err(e) => {
+                error!("Alert {} to {} failed: {}", rule.name, rule.destination, e);
+                failed += 1;
+                state
+            }
+        };
+
+        blocking(pool, move |conn| {
+            diesel::update(alert_rules::table.find(rule.id))
+                .set((
+                    alert_rules::triggered.eq(triggered),
+                    alert_rules::last_value.eq(value),
+                    alert_rules::last_checked_at.eq(now),
+                    alert_rules::last_fired_at.eq(fired_at),
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
+        .await?;
+    }
+
+    if failed > 0 {
+        return Err(format!("{} alert notifications could not be delivered", failed).into());
+    }
+    Ok(sent)
+}
@@ -0,0 +1,94 @@
+use crate::models::ApiToken;
+use crate::schema::api_tokens;
+use crate::utils::auth::secret_hash;
+use actix_web::http::Method;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use ulid::Ulid;
+
+// Makes leaked tokens easy to recognise, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "stats_";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Scope {
+    // Summaries, sessions, exports and queries
+    Read,
+    // Sending events
+    Ingest,
+    // Everything, like a logged in user
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "ingest" => Some(Scope::Ingest),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn allows(&self, method: &Method, path: &str, collect_path: &str) -> bool {
+        match self {
+            Scope::Admin => true,
+            Scope::Read => {
+                let reads = *method == Method::GET || *method == Method::HEAD;
+                (reads && !path.starts_with("/admin/")) || path == "/query" || path == "/graphql"
+            }
+            Scope::Ingest => path == collect_path,
+        }
+    }
+}
+
+// Stores a new token and returns it with its secret, which isn't kept
+pub fn create_token(
+    conn: &mut SqliteConnection,
+    name: &str,
+    scope: &str,
+    expires_at: Option<NaiveDateTime>,
+) -> QueryResult<(ApiToken, String)> {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    let secret = format!("{}{}", TOKEN_PREFIX, secret);
+    let token = ApiToken {
+        id: Ulid::new().to_string(),
+        name: name.to_string(),
+        scope: scope.to_string(),
+        token_hash: secret_hash(&secret),
+        expires_at,
+        last_used_at: None,
+        created_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(api_tokens::table)
+        .values(&token)
+        .execute(conn)?;
+    Ok((token, secret))
+}
+
+// The scope of a token that exists and hasn't expired, noting that it was used
+pub fn token_scope(conn: &mut SqliteConnection, secret: &str) -> QueryResult<Option<Scope>> {
+    let now = Utc::now().naive_utc();
+    let token = api_tokens::table
+        .filter(api_tokens::token_hash.eq(secret_hash(secret)))
+        .filter(
+            api_tokens::expires_at
+                .is_null()
+                .or(api_tokens::expires_at.gt(now)),
+        )
+        .first::<ApiToken>(conn)
+        .optional()?;
+    let Some(token) = token else {
+        return Ok(None);
+    };
+
+    diesel::update(api_tokens::table.find(&token.id))
+        .set(api_tokens::last_used_at.eq(now))
+        .execute(conn)?;
+    Ok(Scope::parse(&token.scope))
+}
@@ -0,0 +1,152 @@
+use crate::db::{blocking, DbPool};
+use crate::utils::bigquery::exported_to;
+use crate::utils::export::{load_page, ExportRow, PAGE_SIZE};
+use crate::utils::parquet::ParquetWriter;
+use crate::utils::retention::delete_orphan_collectors;
+use crate::utils::rollup::update_rollups;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use diesel::dsl::min;
+use diesel::prelude::*;
+use log::info;
+use object_store::path::Path;
+use object_store::{parse_url_opts, ObjectStore};
+use std::error::Error;
+use ulid::Ulid;
+use url::Url;
+
+// Where old events are moved to, a bucket or directory given as a URL like
+// `s3://bucket/prefix` or `file:///var/lib/stats/archive`
+pub struct Archive {
+    store: Box<dyn ObjectStore>,
+    prefix: Path,
+}
+
+impl Archive {
+    // S3 credentials, region and endpoint come from the usual AWS_*
+    // environment variables
+    pub fn new(
+        url: &str,
+        options: &[(String, String)],
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let url = Url::parse(url)?;
+        let (store, prefix) = parse_url_opts(&url, options.iter().cloned())?;
+        Ok(Archive { store, prefix })
+    }
+}
+
+fn oldest_day(
+    conn: &mut SqliteConnection,
+    before: NaiveDateTime,
+) -> QueryResult<Option<NaiveDate>> {
+    use crate::schema::events::dsl::*;
+
+    let oldest: Option<NaiveDateTime> = events
+        .filter(timestamp.lt(before))
+        .select(min(timestamp))
+        .first(conn)?;
+    Ok(oldest.map(|oldest| oldest.date()))
+}
+
+// All events of one day as a Parquet file, with the ids that went into it
+fn write_day(
+    conn: &mut SqliteConnection,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> Result<(Vec<u8>, Vec<String>), Box<dyn Error + Send + Sync>> {
+    let mut writer = ParquetWriter::new(Vec::new())?;
+    let mut ids = Vec::new();
+    let mut last: Option<ExportRow> = None;
+    loop {
+        let rows = load_page(conn, from, to, last.as_ref())?;
+        writer.write(&rows)?;
+        ids.extend(rows.iter().map(|row| row.id.clone()));
+
+        if (rows.len() as i64) < PAGE_SIZE {
+            break;
+        }
+        last = rows.into_iter().last();
+    }
+    Ok((writer.finish()?, ids))
+}
+
+// Removes the archived events, and the collectors left without events
+fn delete_archived(
+    conn: &mut SqliteConnection,
+    ids: &[String],
+    before: NaiveDateTime,
+) -> QueryResult<()> {
+    use crate::schema::events;
+
+    conn.transaction(|conn| {
+        for chunk in ids.chunks(1000) {
+            diesel::delete(events::table.filter(events::id.eq_any(chunk))).execute(conn)?;
+        }
+        delete_orphan_collectors(conn, before)?;
+        Ok(())
+    })
+}
+
+// Moves events older than `days` to the archive, one Parquet file per day
+// under `date=YYYY-MM-DD/`. Events are only deleted once their file is
+// stored. Rollups are kept, so long-range summaries still include them.
+// With `wait_for_bigquery`, days the BigQuery export hasn't sent yet stay in
+// the database until it has. Returns the number of events archived.
+pub async fn archive_events(
+    pool: &DbPool,
+    archive: &Archive,
+    days: usize,
+    wait_for_bigquery: bool,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    // Whole days only, so a day never ends up split over several runs
+    let mut cutoff = (Utc::now().naive_utc() - Duration::days(days as i64))
+        .date()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+
+    // The export goes by whole days too
+    if wait_for_bigquery {
+        let exported = blocking(pool, |conn| Ok(exported_to(conn)?)).await?;
+        match exported {
+            Some(exported) if exported < cutoff => {
+                info!(
+                    "Archiving events before {}, the day the BigQuery export is at",
+                    exported.date()
+                );
+                cutoff = exported;
+            }
+            Some(_) => {}
+            None => {
+                info!("Not archiving events before they are exported to BigQuery");
+                return Ok(0);
+            }
+        }
+    }
+
+    // Count the events into the rollups before they leave the database
+    blocking(pool, |conn| Ok(update_rollups(conn)?)).await?;
+
+    let mut archived = 0;
+    loop {
+        let day = blocking(pool, move |conn| Ok(oldest_day(conn, cutoff)?)).await?;
+        let Some(day) = day else {
+            break;
+        };
+        let from = day.and_hms_opt(0, 0, 0).unwrap();
+        let to = (from + Duration::days(1)).min(cutoff);
+
+        let (file, ids) = blocking(pool, move |conn| write_day(conn, from, to)).await?;
+
+        let path = archive
+            .prefix
+            .child(format!("date={}", day))
+            .child(format!("{}.parquet", Ulid::new()));
+        archive.store.put(&path, file.into()).await?;
+
+        let count = ids.len();
+        blocking(pool, move |conn| Ok(delete_archived(conn, &ids, to)?)).await?;
+
+        info!("Archived {} events from {} to {}", count, day, path);
+        archived += count;
+    }
+    Ok(archived)
+}
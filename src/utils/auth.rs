@@ -0,0 +1,145 @@
+use crate::models::{User, UserSession};
+use crate::schema::{user_sessions, users};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use ulid::Ulid;
+
+pub const SESSION_COOKIE: &str = "stats_session";
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+fn hash_password(password: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?
+        .to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+// Session cookies and API tokens are stored by their hash, so a copy of the
+// database can't be used to log in
+pub fn secret_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Whether logins are required, which they are as soon as a user exists
+pub fn users_exist(conn: &mut SqliteConnection) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(users::table.select(users::id))).get_result(conn)
+}
+
+// Adds a user, or sets a new password when the username is taken
+pub fn save_user(
+    conn: &mut SqliteConnection,
+    username: &str,
+    password: &str,
+) -> Result<User, Box<dyn Error + Send + Sync>> {
+    if username.trim().is_empty() {
+        return Err("username is required".into());
+    }
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!("Passwords need at least {} characters", MIN_PASSWORD_LENGTH).into());
+    }
+    let password_hash = hash_password(password)?;
+
+    let existing = users::table
+        .filter(users::username.eq(username))
+        .first::<User>(conn)
+        .optional()?;
+    let user = match existing {
+        Some(user) => {
+            diesel::update(users::table.find(&user.id))
+                .set(users::password_hash.eq(&password_hash))
+                .execute(conn)?;
+            // Whoever knew the old password is logged out
+            diesel::delete(user_sessions::table.filter(user_sessions::user_id.eq(&user.id)))
+                .execute(conn)?;
+            User {
+                password_hash,
+                ..user
+            }
+        }
+        None => {
+            let user = User {
+                id: Ulid::new().to_string(),
+                username: username.to_string(),
+                password_hash,
+                created_at: Utc::now().naive_utc(),
+            };
+            diesel::insert_into(users::table)
+                .values(&user)
+                .execute(conn)?;
+            user
+        }
+    };
+    Ok(user)
+}
+
+// Checks the credentials and starts a session, returning its cookie value.
+// None when the username or password is wrong.
+pub fn log_in(
+    conn: &mut SqliteConnection,
+    username: &str,
+    password: &str,
+    lifetime: Duration,
+) -> QueryResult<Option<(User, String)>> {
+    let Some(user) = users::table
+        .filter(users::username.eq(username))
+        .first::<User>(conn)
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    if !verify_password(password, &user.password_hash) {
+        return Ok(None);
+    }
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(43)
+        .map(char::from)
+        .collect();
+    let now = Utc::now().naive_utc();
+    // Expired sessions are cleaned up whenever someone logs in
+    diesel::delete(user_sessions::table.filter(user_sessions::expires_at.le(now))).execute(conn)?;
+    diesel::insert_into(user_sessions::table)
+        .values(&UserSession {
+            id: secret_hash(&token),
+            user_id: user.id.clone(),
+            created_at: now,
+            expires_at: now + lifetime,
+        })
+        .execute(conn)?;
+    Ok(Some((user, token)))
+}
+
+pub fn log_out(conn: &mut SqliteConnection, token: &str) -> QueryResult<usize> {
+    diesel::delete(user_sessions::table.find(secret_hash(token))).execute(conn)
+}
+
+// The user a session cookie belongs to, unless it expired
+pub fn session_user(conn: &mut SqliteConnection, token: &str) -> QueryResult<Option<User>> {
+    user_sessions::table
+        .inner_join(users::table.on(users::id.eq(user_sessions::user_id)))
+        .filter(user_sessions::id.eq(secret_hash(token)))
+        .filter(user_sessions::expires_at.gt(Utc::now().naive_utc()))
+        .select(users::all_columns)
+        .first::<User>(conn)
+        .optional()
+}
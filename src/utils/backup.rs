@@ -0,0 +1,193 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const BACKUP_PREFIX: &str = "stats-";
+const BACKUP_EXTENSION: &str = ".sqlite";
+
+// Backups in `dir`, newest first. The timestamp in the name sorts them.
+fn list_backups(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_EXTENSION)
+                })
+        })
+        .collect();
+    backups.sort();
+    backups.reverse();
+    Ok(backups)
+}
+
+// Writes a consistent snapshot of the live database to `path` with
+// `VACUUM INTO`, which works alongside other connections in WAL mode
+pub fn snapshot(
+    conn: &mut SqliteConnection,
+    path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+
+    diesel::sql_query("VACUUM INTO ?")
+        .bind::<Text, _>(path.to_string_lossy())
+        .execute(conn)?;
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct TableName {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+// Diesel's record of the migrations that built the live schema, which the
+// restore leaves alone since it doesn't change the schema
+const MIGRATIONS_TABLE: &str = "__diesel_schema_migrations";
+
+fn table_names(
+    conn: &mut SqliteConnection,
+    schema: &str,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let tables: Vec<TableName> = diesel::sql_query(format!(
+        "SELECT name FROM {}.sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        schema
+    ))
+    .load(conn)?;
+    Ok(tables
+        .into_iter()
+        .map(|table| table.name)
+        .filter(|name| name != MIGRATIONS_TABLE)
+        .collect())
+}
+
+fn column_names(
+    conn: &mut SqliteConnection,
+    schema: &str,
+    table: &str,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let columns: Vec<TableName> = diesel::sql_query("SELECT name FROM pragma_table_info(?, ?)")
+        .bind::<Text, _>(table)
+        .bind::<Text, _>(schema)
+        .load(conn)?;
+    Ok(columns.into_iter().map(|column| column.name).collect())
+}
+
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[diesel(sql_type = Text)]
+    integrity_check: String,
+}
+
+// Replaces the contents of the live database with the backup at `path`.
+// The backup is checked first and every table is copied in one transaction,
+// so a failure (e.g. a backup from an incompatible schema) changes nothing.
+// Columns are copied by name, so a backup from before or after a migration
+// that added columns restores into the current schema: columns the backup
+// lacks get their default and tables it lacks end up empty.
+pub fn restore(
+    conn: &mut SqliteConnection,
+    path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !path.is_file() {
+        return Err(format!("{} does not exist", path.display()).into());
+    }
+
+    diesel::sql_query("ATTACH DATABASE ? AS backup")
+        .bind::<Text, _>(path.to_string_lossy())
+        .execute(conn)?;
+
+    let result = (|| -> Result<(), Box<dyn Error + Send + Sync>> {
+        let check: IntegrityCheck =
+            diesel::sql_query("PRAGMA backup.integrity_check(1)").get_result(conn)?;
+        if check.integrity_check != "ok" {
+            return Err(format!("Backup is corrupt: {}", check.integrity_check).into());
+        }
+
+        conn.transaction(|conn| {
+            diesel::sql_query("PRAGMA defer_foreign_keys = ON").execute(conn)?;
+            let backup_tables = table_names(conn, "backup")?;
+            for table in table_names(conn, "main")? {
+                diesel::sql_query(format!("DELETE FROM main.{}", quoted(&table))).execute(conn)?;
+                if !backup_tables.contains(&table) {
+                    continue;
+                }
+
+                let backup_columns = column_names(conn, "backup", &table)?;
+                let columns = column_names(conn, "main", &table)?
+                    .into_iter()
+                    .filter(|column| backup_columns.contains(column))
+                    .map(|column| quoted(&column))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                if columns.is_empty() {
+                    continue;
+                }
+                diesel::sql_query(format!(
+                    "INSERT INTO main.{0} ({1}) SELECT {1} FROM backup.{0}",
+                    quoted(&table),
+                    columns
+                ))
+                .execute(conn)?;
+            }
+            Ok::<_, diesel::result::Error>(())
+        })?;
+        Ok(())
+    })();
+
+    diesel::sql_query("DETACH DATABASE backup").execute(conn)?;
+    result
+}
+
+// Snapshots the database into `dir` and removes all but the newest
+// `retention` backups there
+pub fn backup(
+    conn: &mut SqliteConnection,
+    dir: &Path,
+    retention: usize,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        Utc::now().format("%Y%m%d-%H%M%S"),
+        BACKUP_EXTENSION
+    ));
+    snapshot(conn, &path)?;
+
+    if retention > 0 {
+        for old_backup in list_backups(dir)?.into_iter().skip(retention) {
+            fs::remove_file(old_backup)?;
+        }
+    }
+
+    Ok(path)
+}
+
+// Whether the newest backup in `dir` is older than `interval`
+pub fn backup_due(dir: &Path, interval: Duration) -> bool {
+    let newest = list_backups(dir)
+        .ok()
+        .and_then(|backups| backups.into_iter().next())
+        .and_then(|path| fs::metadata(path).and_then(|m| m.modified()).ok());
+
+    match newest {
+        Some(modified) => SystemTime::now()
+            .duration_since(modified)
+            .map(|age| age >= interval)
+            .unwrap_or(false),
+        None => true,
+    }
+}
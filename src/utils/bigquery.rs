@@ -0,0 +1,276 @@
+use crate::config::Config;
+use crate::db::{blocking, DbPool};
+use crate::schema::exports;
+use crate::utils::export::{load_page, ExportRow, PAGE_SIZE};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::dsl::min;
+use diesel::prelude::*;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs;
+
+const API_URL: &str = "https://bigquery.googleapis.com/bigquery/v2";
+const SCOPE: &str = "https://www.googleapis.com/auth/bigquery";
+
+// Progress is stored under this name in the `exports` table
+const EXPORT_NAME: &str = "bigquery";
+
+// Rows per streaming insert, well under BigQuery's request limits
+const INSERT_BATCH_SIZE: usize = 500;
+
+// The fields of a service account key file that are needed to sign in
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+// Sends events to a BigQuery table through the REST API, signed in with a
+// service account key
+pub struct BigQuery {
+    client: reqwest::Client,
+    account: ServiceAccount,
+    project: String,
+    dataset: String,
+    table: String,
+}
+
+// Returns the body of a successful response, or the error BigQuery sent
+async fn read_response(
+    response: reqwest::Response,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let status = response.status();
+    let text = response.text().await?;
+    if status.is_success() {
+        Ok(text)
+    } else {
+        Err(format!("BigQuery returned {}: {}", status, text.trim()).into())
+    }
+}
+
+impl BigQuery {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let account: ServiceAccount =
+            serde_json::from_str(&fs::read_to_string(&config.bigquery_credentials)?)?;
+        let project = match config.bigquery_project.as_str() {
+            "" => account.project_id.clone(),
+            project => project.to_string(),
+        };
+        Ok(BigQuery {
+            client: reqwest::Client::new(),
+            account,
+            project,
+            dataset: config.bigquery_dataset.clone(),
+            table: config.bigquery_table.clone(),
+        })
+    }
+
+    // Exchanges a token signed with the service account key for an access token
+    async fn access_token(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            iss: &self.account.client_email,
+            scope: SCOPE,
+            aud: &self.account.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+        let assertion = jsonwebtoken::encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(self.account.private_key.as_bytes())?,
+        )?;
+
+        let response = self
+            .client
+            .post(&self.account.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await?;
+        let token: Token = serde_json::from_str(&read_response(response).await?)?;
+        Ok(token.access_token)
+    }
+
+    // Creates the table, partitioned by day, unless it already exists
+    async fn create_table(&self, token: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let field = |name: &str, kind: &str, mode: &str| json!({ "name": name, "type": kind, "mode": mode });
+        let table = json!({
+            "tableReference": {
+                "projectId": self.project,
+                "datasetId": self.dataset,
+                "tableId": self.table,
+            },
+            "schema": { "fields": [
+                field("id", "STRING", "REQUIRED"),
+                field("timestamp", "TIMESTAMP", "REQUIRED"),
+                field("name", "STRING", "REQUIRED"),
+                field("url", "STRING", "REQUIRED"),
+                field("referrer", "STRING", "NULLABLE"),
+                field("status", "INTEGER", "NULLABLE"),
+                field("value", "FLOAT", "NULLABLE"),
+                field("currency", "STRING", "NULLABLE"),
+                field("collector_id", "STRING", "REQUIRED"),
+                field("origin", "STRING", "NULLABLE"),
+                field("country", "STRING", "NULLABLE"),
+                field("region", "STRING", "NULLABLE"),
+                field("city", "STRING", "NULLABLE"),
+                field("os", "STRING", "NULLABLE"),
+                field("browser", "STRING", "NULLABLE"),
+            ]},
+            "timePartitioning": { "type": "DAY", "field": "timestamp" },
+        });
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/projects/{}/datasets/{}/tables",
+                API_URL, self.project, self.dataset
+            ))
+            .bearer_auth(token)
+            .json(&table)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            return Ok(());
+        }
+        read_response(response).await?;
+        Ok(())
+    }
+
+    // Streams the rows into the table. The event id is used as insert id, so
+    // BigQuery drops the duplicates when a failed request is sent again.
+    async fn insert_rows(
+        &self,
+        token: &str,
+        rows: &[ExportRow],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rows = rows
+            .iter()
+            .map(|row| {
+                let mut values = serde_json::to_value(row)?;
+                values["timestamp"] =
+                    json!(row.timestamp.format("%Y-%m-%d %H:%M:%S%.6f").to_string());
+                Ok(json!({ "insertId": row.id, "json": values }))
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/projects/{}/datasets/{}/tables/{}/insertAll",
+                API_URL, self.project, self.dataset, self.table
+            ))
+            .bearer_auth(token)
+            .json(&json!({ "rows": rows }))
+            .send()
+            .await?;
+
+        // Rejected rows are reported in a successful response
+        let body: Value = serde_json::from_str(&read_response(response).await?)?;
+        match body.get("insertErrors") {
+            Some(errors) => Err(format!("BigQuery rejected rows: {}", errors).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Start of the first day that wasn't exported yet, None before the first export
+pub fn exported_to(conn: &mut SqliteConnection) -> QueryResult<Option<NaiveDateTime>> {
+    exports::table
+        .find(EXPORT_NAME)
+        .select(exports::exported_to)
+        .first(conn)
+        .optional()
+}
+
+fn oldest_event(conn: &mut SqliteConnection) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::events::dsl::*;
+
+    events.select(min(timestamp)).first(conn)
+}
+
+fn set_exported_to(conn: &mut SqliteConnection, to: NaiveDateTime) -> QueryResult<usize> {
+    diesel::replace_into(exports::table)
+        .values((exports::name.eq(EXPORT_NAME), exports::exported_to.eq(to)))
+        .execute(conn)
+}
+
+// Sends every complete day that wasn't exported yet, starting with the day
+// of the oldest event. Progress is saved after each day, so an interrupted
+// export continues where it stopped. Returns the number of events sent.
+pub async fn export_to_bigquery(
+    pool: &DbPool,
+    bigquery: &BigQuery,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let today = Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap();
+    let start = blocking(pool, |conn| {
+        Ok(match exported_to(conn)? {
+            Some(exported_to) => Some(exported_to),
+            None => oldest_event(conn)?.map(|oldest| oldest.date().and_hms_opt(0, 0, 0).unwrap()),
+        })
+    })
+    .await?;
+    let Some(mut from) = start else {
+        return Ok(0);
+    };
+    if from >= today {
+        return Ok(0);
+    }
+
+    bigquery
+        .create_table(&bigquery.access_token().await?)
+        .await?;
+
+    let mut exported = 0;
+    while from < today {
+        // Access tokens expire after an hour, a long backlog can take longer
+        let token = bigquery.access_token().await?;
+        let to = from + Duration::days(1);
+        let mut last: Option<ExportRow> = None;
+        let mut count = 0;
+        loop {
+            let after = last.take();
+            let rows = blocking(pool, move |conn| {
+                Ok(load_page(conn, from, to, after.as_ref())?)
+            })
+            .await?;
+            for batch in rows.chunks(INSERT_BATCH_SIZE) {
+                bigquery.insert_rows(&token, batch).await?;
+            }
+            count += rows.len();
+
+            if (rows.len() as i64) < PAGE_SIZE {
+                break;
+            }
+            last = rows.into_iter().last();
+        }
+
+        blocking(pool, move |conn| Ok(set_exported_to(conn, to)?)).await?;
+        info!("Exported {} events from {} to BigQuery", count, from.date());
+        exported += count;
+        from = to;
+    }
+    Ok(exported)
+}
@@ -0,0 +1,61 @@
+use actix_web::web::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Responses kept at most, so distinct queries can't grow the cache unbounded
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub content_type: String,
+    pub body: Bytes,
+    pub stored: Instant,
+}
+
+// Recent summary responses by path and query string. Dashboards poll the
+// same summaries every few seconds, which would otherwise run the same
+// aggregation over the events table each time. Cleared whenever a batch of
+// events is written, so new events show up right away.
+pub struct SummaryCache {
+    entries: Mutex<HashMap<String, CachedResponse>>,
+}
+
+impl SummaryCache {
+    pub fn new() -> Self {
+        SummaryCache {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The response stored under `key` unless it is older than `ttl`
+    pub fn get(&self, key: &str, ttl: Duration) -> Option<CachedResponse> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|entry| entry.stored.elapsed() < ttl)
+            .cloned()
+    }
+
+    pub fn insert(&self, key: String, content_type: String, body: Bytes, ttl: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, entry| entry.stored.elapsed() < ttl);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.insert(
+            key,
+            CachedResponse {
+                content_type,
+                body,
+                stored: Instant::now(),
+            },
+        );
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
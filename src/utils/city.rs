@@ -1,35 +1,240 @@
-use once_cell::sync::Lazy;
+use diesel::prelude::*;
+use log::{error, info};
+use lru::LruCache;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, BufRead};
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::sync::Mutex;
-use strsim::jaro_winkler;
+use strsim::{jaro_winkler, levenshtein};
 
+// Edits a misspelled or differently written city name may be away from the
+// name in the city data. Short names get fewer, so `Rome` doesn't match
+// every four letter city.
+const MAX_EDITS: usize = 2;
+const SHORT_NAME_LENGTH: usize = 5;
+
+// Names without a direct match remembered at most, so a burst of unknown
+// names can't grow the cache unbounded
+const SEARCH_CACHE_SIZE: usize = 10_000;
+
+#[derive(Clone, Copy)]
 struct CityInfo {
     latitude: f64,
     longitude: f64,
 }
 
-static CITY_MAP: Lazy<HashMap<String, CityInfo>> =
-    Lazy::new(|| load_city_data("data/cities5000.txt").expect("Failed to load city data"));
+struct Node {
+    name: String,
+    city: CityInfo,
+    // Children by their edit distance to `name`
+    children: Vec<(usize, usize)>,
+}
+
+// BK-tree over lowercased city names. A search only descends into children
+// whose distance can still be within the tolerance, so it compares against
+// a small part of the names rather than every one of them.
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<Node>,
+}
+
+impl BkTree {
+    fn insert(&mut self, key: String, city: CityInfo) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                name: key,
+                city,
+                children: Vec::new(),
+            });
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let distance = levenshtein(&key, &self.nodes[current].name);
+            if distance == 0 {
+                return;
+            }
+            match self.nodes[current]
+                .children
+                .iter()
+                .find(|(d, _)| *d == distance)
+            {
+                Some(&(_, child)) => current = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node {
+                        name: key,
+                        city,
+                        children: Vec::new(),
+                    });
+                    self.nodes[current].children.push((distance, child));
+                    return;
+                }
+            }
+        }
+    }
+
+    // The most similar name within `tolerance` edits of `key`, with its
+    // Jaro-Winkler similarity
+    fn closest(&self, key: &str, tolerance: usize) -> Option<(f64, CityInfo)> {
+        let mut best: Option<(f64, CityInfo)> = None;
+        let mut pending = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(current) = pending.pop() {
+            let node = &self.nodes[current];
+            let distance = levenshtein(key, &node.name);
+            if distance <= tolerance {
+                let similarity = jaro_winkler(key, &node.name);
+                if best.is_none_or(|(highest, _)| similarity > highest) {
+                    best = Some((similarity, node.city));
+                }
+            }
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(distance) <= tolerance)
+                    .map(|&(_, child)| child),
+            );
+        }
+        best
+    }
+}
+
+// City names for exact lookups, and a BK-tree per first letter for fuzzy
+// ones. Misspellings rarely change the first letter, and keeping to its tree
+// bounds a miss to a few thousand names at most.
+struct CityIndex {
+    exact: HashMap<String, CityInfo>,
+    trees: HashMap<char, BkTree>,
+}
+
+impl CityIndex {
+    fn new() -> Self {
+        CityIndex {
+            exact: HashMap::new(),
+            trees: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, name: String, city: CityInfo) {
+        let key = name.to_lowercase();
+        // Of cities with the same name, the first one is kept
+        self.exact.entry(name).or_insert(city);
+        if let Some(first) = key.chars().next() {
+            self.trees.entry(first).or_default().insert(key, city);
+        }
+    }
+
+    // The most similar city within MAX_EDITS of `name`
+    fn closest(&self, name: &str) -> Option<CityInfo> {
+        let key = name.to_lowercase();
+        let tree = self.trees.get(&key.chars().next()?)?;
+        let tolerance = if key.chars().count() < SHORT_NAME_LENGTH {
+            1
+        } else {
+            MAX_EDITS
+        };
+
+        tree.closest(&key, tolerance)
+            .filter(|(similarity, _)| *similarity > 0.8)
+            .map(|(_, city)| city)
+    }
+}
+
+type SearchCache = LruCache<String, Option<(f64, f64)>>;
+
+// Coordinates of cities by name, from a GeoNames export like
+// cities5000.txt. Read once at startup, without the file every lookup misses
+// and the map places visitors in the middle of their country instead.
+pub struct Cities {
+    index: Option<CityIndex>,
+    cache: Mutex<SearchCache>,
+}
+
+impl Cities {
+    // An empty path skips the city data
+    pub fn load(path: &str) -> Self {
+        let index = match path {
+            "" => None,
+            path => match load_city_data(path) {
+                Ok(index) => {
+                    info!("Loaded {} cities from {}", index.exact.len(), path);
+                    Some(index)
+                }
+                Err(e) => {
+                    error!(
+                        "City data {} not loaded: {}. Visitors without coordinates are shown at the middle of their country.",
+                        path, e
+                    );
+                    None
+                }
+            },
+        };
+
+        Cities {
+            index,
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(SEARCH_CACHE_SIZE).expect("SEARCH_CACHE_SIZE is not zero"),
+            )),
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.index.is_some()
+    }
 
-static SEARCH_CACHE: Lazy<Mutex<HashMap<String, Option<(f64, f64)>>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+    pub fn coordinates(&self, city_name: &str) -> Option<(f64, f64)> {
+        let index = self.index.as_ref()?;
+        if city_name == "Unknown" {
+            return None;
+        }
+
+        // Find direct match
+        if let Some(info) = index.exact.get(city_name) {
+            return Some((info.latitude, info.longitude));
+        }
+
+        // Then try to get the result from the cache
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(cached_result) = cache.get(city_name) {
+                return *cached_result;
+            }
+        }
+
+        // Find closest match
+        let result = index
+            .closest(city_name)
+            .map(|info| (info.latitude, info.longitude));
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.put(city_name.to_string(), result);
+        }
 
-fn load_city_data<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, CityInfo>> {
+        result
+    }
+}
+
+fn load_city_data<P: AsRef<Path>>(path: P) -> io::Result<CityIndex> {
     let file = File::open(path)?;
     let reader = io::BufReader::new(file);
-    let mut city_map: HashMap<String, CityInfo> = HashMap::new();
+    let mut index = CityIndex::new();
 
     for line in reader.lines() {
         let line = line?;
         let parts: Vec<&str> = line.split('\t').collect();
-        if parts.len() > 4 {
+        if parts.len() > 5 {
             let city_name = parts[2].to_string();
             let latitude = parts[4].parse::<f64>().unwrap_or(0.0);
             let longitude = parts[5].parse::<f64>().unwrap_or(0.0);
-            city_map.insert(
+            index.insert(
                 city_name,
                 CityInfo {
                     latitude,
@@ -39,49 +244,37 @@ fn load_city_data<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, CityInf
         }
     }
 
-    Ok(city_map)
+    Ok(index)
 }
 
-pub fn get_city_coordinates(city_name: &str) -> Option<(f64, f64)> {
-    if city_name == "Unknown" {
-        return None;
-    }
-
-    // First, try to get the result from the cache
-    {
-        let cache = SEARCH_CACHE.lock().unwrap();
-        if let Some(cached_result) = cache.get(city_name) {
-            return *cached_result;
-        }
-    }
-
-    // Find direct match
-    if let Some(info) = CITY_MAP.get(city_name) {
-        return Some((info.latitude, info.longitude));
-    }
+// Stores coordinates on visitors recorded before they were kept, or whose
+// GeoIP lookup had none, by looking up their city. Returns how many were
+// updated.
+pub fn backfill_coordinates(conn: &mut SqliteConnection, cities: &Cities) -> QueryResult<usize> {
+    use crate::schema::collectors;
 
-    // Find closest match
-    let mut best_match: Option<&CityInfo> = None;
-    let mut highest_similarity = 0.0;
+    let names: Vec<String> = collectors::table
+        .filter(collectors::latitude.is_null())
+        .filter(collectors::city.ne("Unknown"))
+        .select(collectors::city)
+        .distinct()
+        .load(conn)?;
 
-    for (key, info) in CITY_MAP.iter() {
-        let similarity = jaro_winkler(city_name, key);
-        if similarity > highest_similarity {
-            highest_similarity = similarity;
-            best_match = Some(info);
-        }
+    let mut updated = 0;
+    for city in names {
+        let Some((latitude, longitude)) = cities.coordinates(&city) else {
+            continue;
+        };
+        updated += diesel::update(
+            collectors::table
+                .filter(collectors::city.eq(&city))
+                .filter(collectors::latitude.is_null()),
+        )
+        .set((
+            collectors::latitude.eq(latitude),
+            collectors::longitude.eq(longitude),
+        ))
+        .execute(conn)?;
     }
-
-    let result = if highest_similarity > 0.8 {
-        best_match.map(|info| (info.latitude, info.longitude))
-    } else {
-        None
-    };
-
-    {
-        let mut cache = SEARCH_CACHE.lock().unwrap();
-        cache.insert(city_name.to_string(), result);
-    }
-
-    result
+    Ok(updated)
 }
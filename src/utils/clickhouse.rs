@@ -0,0 +1,171 @@
+use crate::config::Config;
+use crate::models::{NewEvent, MEASUREMENT_EVENT_NAMES};
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+// Events are written with their id in the sorting key, so a replayed batch
+// is merged away instead of counted twice, see `count_events`
+const CREATE_EVENTS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id String,
+        url String,
+        referrer Nullable(String),
+        name LowCardinality(String),
+        timestamp DateTime64(3, 'UTC'),
+        collector_id String,
+        status Nullable(Int32),
+        value Nullable(Float64),
+        currency LowCardinality(Nullable(String)),
+        host LowCardinality(Nullable(String)),
+        path Nullable(String)
+    )
+    ENGINE = ReplacingMergeTree
+    PARTITION BY toYYYYMM(timestamp)
+    ORDER BY (timestamp, id)
+";
+
+// Tables created before events had a host and path
+const ADD_HOST_AND_PATH: &str = "
+    ALTER TABLE events
+    ADD COLUMN IF NOT EXISTS host LowCardinality(Nullable(String)),
+    ADD COLUMN IF NOT EXISTS path Nullable(String)
+";
+
+// Writes events to ClickHouse and runs the time-series aggregations on them,
+// over the HTTP interface
+pub struct ClickHouse {
+    client: reqwest::Client,
+    url: String,
+    database: String,
+    user: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct BucketCount {
+    bucket: String,
+    count: i64,
+}
+
+impl ClickHouse {
+    pub fn from_config(config: &Config) -> Self {
+        ClickHouse {
+            client: reqwest::Client::new(),
+            url: config.clickhouse_url.clone(),
+            database: config.clickhouse_database.clone(),
+            user: config.clickhouse_user.clone(),
+            password: config.clickhouse_password.clone(),
+        }
+    }
+
+    // Runs `query` with `body` as its input data. ClickHouse reports errors
+    // in the response body, which is returned as the error.
+    async fn execute(
+        &self,
+        query: &str,
+        params: &[(&str, String)],
+        body: String,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("X-ClickHouse-User", &self.user)
+            .header("X-ClickHouse-Key", &self.password)
+            .query(&[
+                ("database", self.database.as_str()),
+                ("query", query),
+                ("date_time_input_format", "best_effort"),
+                ("output_format_json_quote_64bit_integers", "0"),
+            ])
+            .query(params)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+        if status.is_success() {
+            Ok(text)
+        } else {
+            Err(format!("ClickHouse returned {}: {}", status, text.trim()).into())
+        }
+    }
+
+    pub async fn create_tables(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.execute(CREATE_EVENTS_TABLE, &[], String::new())
+            .await?;
+        self.execute(ADD_HOST_AND_PATH, &[], String::new()).await?;
+        Ok(())
+    }
+
+    pub async fn insert_events(
+        &self,
+        events: &[NewEvent],
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let mut rows = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut rows, event)?;
+            rows.push(b'\n');
+        }
+        self.execute(
+            "INSERT INTO events FORMAT JSONEachRow",
+            &[],
+            String::from_utf8(rows)?,
+        )
+        .await?;
+        Ok(events.len())
+    }
+
+    // Event counts per bucket of `seconds`, shifted by `offset` seconds, for
+    // events after `start` up to and including `end`. Measurements are left
+    // out like in the SQLite summaries.
+    pub async fn count_events(
+        &self,
+        seconds: i64,
+        offset: i64,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<HashMap<NaiveDateTime, i64>, Box<dyn Error + Send + Sync>> {
+        let names = MEASUREMENT_EVENT_NAMES
+            .iter()
+            .map(|name| format!("'{}'", name))
+            .collect::<Vec<_>>()
+            .join(",");
+        let body = self
+            .execute(
+                "SELECT toDateTime(
+                    intDiv(toUnixTimestamp(timestamp) - {offset:Int64}, {seconds:Int64})
+                        * {seconds:Int64} + {offset:Int64},
+                    'UTC'
+                ) AS bucket, uniqExact(id) AS count
+                FROM events
+                WHERE timestamp > {start:DateTime64(3, 'UTC')}
+                    AND timestamp <= {end:DateTime64(3, 'UTC')}
+                    AND name NOT IN {names:Array(String)}
+                GROUP BY bucket
+                FORMAT JSONEachRow",
+                &[
+                    ("param_seconds", seconds.to_string()),
+                    ("param_offset", offset.to_string()),
+                    (
+                        "param_start",
+                        start.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+                    ),
+                    ("param_end", end.format("%Y-%m-%d %H:%M:%S%.3f").to_string()),
+                    ("param_names", format!("[{}]", names)),
+                ],
+                String::new(),
+            )
+            .await?;
+
+        let mut counts = HashMap::new();
+        for line in body.lines().filter(|line| !line.is_empty()) {
+            let row: BucketCount = serde_json::from_str(line)?;
+            let bucket = NaiveDateTime::parse_from_str(&row.bucket, "%Y-%m-%d %H:%M:%S")?;
+            counts.insert(bucket, row.count);
+        }
+        Ok(counts)
+    }
+}
@@ -0,0 +1,254 @@
+use crate::config::{ClientIpChain, Config};
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+// Address of the visitor. Forwarding headers are only honored when the
+// request comes from one of the trusted proxies, anyone else could set them
+// to whatever they like. Otherwise it's the address of the connecting peer.
+pub fn client_ip(req: &HttpRequest, config: &Config) -> Option<IpAddr> {
+    resolve(
+        req,
+        &config.trusted_proxies,
+        &config.client_ip_header,
+        config.client_ip_chain,
+    )
+}
+
+fn resolve(
+    req: &HttpRequest,
+    trusted_proxies: &[IpNet],
+    ip_header: &str,
+    chain: ClientIpChain,
+) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+
+    if !peer.is_some_and(|ip| is_trusted(&ip, trusted_proxies)) {
+        return peer;
+    }
+
+    forwarded_ip(req, trusted_proxies, ip_header, chain).or(peer)
+}
+
+fn is_trusted(ip: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|network| network.contains(ip))
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+// A single address header like `CF-Connecting-IP` is only read when
+// CLIENT_IP_HEADER names it, as proxies that don't overwrite it pass on
+// whatever the client sent. Otherwise the chain of CLIENT_IP_CHAIN is walked.
+// The other chain is never read for the same reason, e.g. nginx appends to
+// `X-Forwarded-For` but passes a client's `Forwarded` header through.
+fn forwarded_ip(
+    req: &HttpRequest,
+    trusted_proxies: &[IpNet],
+    ip_header: &str,
+    chain: ClientIpChain,
+) -> Option<IpAddr> {
+    if !ip_header.is_empty() {
+        return header(req, ip_header).and_then(parse_node);
+    }
+    match chain {
+        ClientIpChain::Forwarded => header(req, "Forwarded")
+            .and_then(|value| first_untrusted_hop(&forwarded_for(value), trusted_proxies)),
+        ClientIpChain::XForwardedFor => header(req, "X-Forwarded-For").and_then(|value| {
+            let chain: Vec<Option<IpAddr>> = value.split(',').map(parse_node).collect();
+            first_untrusted_hop(&chain, trusted_proxies)
+        }),
+    }
+}
+
+// Each proxy appends the address it received the request from, so walking the
+// chain from the right and skipping our own proxies gives the first address
+// nobody we trust vouched for. Entries left of it may be made up by the client.
+// An entry that isn't a valid address ends the walk, nothing beyond it can
+// be relied on.
+fn first_untrusted_hop(chain: &[Option<IpAddr>], trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    for hop in chain.iter().rev() {
+        let ip = (*hop)?;
+        if !is_trusted(&ip, trusted_proxies) {
+            return Some(ip);
+        }
+    }
+
+    // Every hop is one of our proxies, so the request started there
+    chain.first().copied().flatten()
+}
+
+// `for=` addresses of a `Forwarded` header, e.g.
+// `for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`
+fn forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, node) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    parse_node(node)
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+// Parses an address that may be quoted, bracketed or carry a port.
+// Obfuscated identifiers and `unknown` yield nothing.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn networks(networks: &[&str]) -> Vec<IpNet> {
+        networks
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn request(peer: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        headers
+            .iter()
+            .fold(
+                TestRequest::default().peer_addr(peer.parse().unwrap()),
+                |request, header| request.insert_header(*header),
+            )
+            .to_http_request()
+    }
+
+    #[test]
+    fn matches_addresses_and_ranges() {
+        let trusted = networks(&["10.0.0.0/8", "192.0.2.1/32", "2001:db8::/32"]);
+        assert!(is_trusted(&ip("10.1.2.3"), &trusted));
+        assert!(is_trusted(&ip("192.0.2.1"), &trusted));
+        assert!(is_trusted(&ip("2001:db8::1"), &trusted));
+        assert!(!is_trusted(&ip("11.0.0.1"), &trusted));
+        assert!(!is_trusted(&ip("192.0.2.2"), &trusted));
+        assert!(!is_trusted(&ip("2001:db9::1"), &trusted));
+    }
+
+    #[test]
+    fn walks_the_chain_from_the_right() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let chain = [
+            Some(ip("1.1.1.1")),
+            Some(ip("2.2.2.2")),
+            Some(ip("10.0.0.2")),
+        ];
+        assert_eq!(first_untrusted_hop(&chain, &trusted), Some(ip("2.2.2.2")));
+    }
+
+    #[test]
+    fn stops_at_an_invalid_hop() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let chain = [Some(ip("1.1.1.1")), None, Some(ip("10.0.0.2"))];
+        assert_eq!(first_untrusted_hop(&chain, &trusted), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_first_hop_when_all_are_trusted() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let chain = [Some(ip("10.0.0.3")), Some(ip("10.0.0.2"))];
+        assert_eq!(first_untrusted_hop(&chain, &trusted), Some(ip("10.0.0.3")));
+        assert_eq!(first_untrusted_hop(&[], &trusted), None);
+    }
+
+    #[test]
+    fn parses_forwarded_elements() {
+        let chain = forwarded_for(
+            r#"for=192.0.2.60;proto=http, for="[2001:db8::1]:4711", for=unknown, by=10.0.0.1"#,
+        );
+        assert_eq!(
+            chain,
+            vec![Some(ip("192.0.2.60")), Some(ip("2001:db8::1")), None, None]
+        );
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let trusted = networks(&["127.0.0.1/32"]);
+        let req = request("203.0.113.9:1234", &[("X-Forwarded-For", "1.1.1.1")]);
+        assert_eq!(
+            resolve(&req, &trusted, "", ClientIpChain::XForwardedFor),
+            Some(ip("203.0.113.9"))
+        );
+    }
+
+    #[test]
+    fn ignores_single_address_headers_unless_configured() {
+        let trusted = networks(&["127.0.0.1/32"]);
+        let req = request(
+            "127.0.0.1:1234",
+            &[
+                ("CF-Connecting-IP", "6.6.6.6"),
+                ("X-Real-IP", "7.7.7.7"),
+                ("X-Forwarded-For", "6.6.6.6, 1.1.1.1"),
+            ],
+        );
+        assert_eq!(
+            resolve(&req, &trusted, "", ClientIpChain::XForwardedFor),
+            Some(ip("1.1.1.1"))
+        );
+        assert_eq!(
+            resolve(
+                &req,
+                &trusted,
+                "cf-connecting-ip",
+                ClientIpChain::XForwardedFor
+            ),
+            Some(ip("6.6.6.6"))
+        );
+    }
+
+    #[test]
+    fn only_reads_the_configured_chain() {
+        let trusted = networks(&["127.0.0.1/32"]);
+        let req = request(
+            "127.0.0.1:1234",
+            &[("Forwarded", "for=3.3.3.3"), ("X-Forwarded-For", "1.1.1.1")],
+        );
+        assert_eq!(
+            resolve(&req, &trusted, "", ClientIpChain::XForwardedFor),
+            Some(ip("1.1.1.1"))
+        );
+        assert_eq!(
+            resolve(&req, &trusted, "", ClientIpChain::Forwarded),
+            Some(ip("3.3.3.3"))
+        );
+
+        // A client's own `Forwarded` header is ignored behind a proxy that
+        // only appends to `X-Forwarded-For`
+        let req = request("127.0.0.1:1234", &[("Forwarded", "for=3.3.3.3")]);
+        assert_eq!(
+            resolve(&req, &trusted, "", ClientIpChain::XForwardedFor),
+            Some(ip("127.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn uses_the_peer_without_forwarding_headers() {
+        let trusted = networks(&["127.0.0.1/32"]);
+        let req = request("127.0.0.1:1234", &[]);
+        assert_eq!(
+            resolve(&req, &trusted, "", ClientIpChain::XForwardedFor),
+            Some(ip("127.0.0.1"))
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::Path;
+
+// Ranges of a DB-IP "IP to Country Lite" CSV, e.g. dbip-country-lite-2024-01.csv
+// from https://db-ip.com/db/download/ip-to-country-lite. Each line is the
+// first and last address of a range and its country code, for IPv4 and IPv6
+// alike:
+//
+//   1.0.0.0,1.0.0.255,AU
+//   2001:200::,2001:200:ffff:ffff:ffff:ffff:ffff:ffff,JP
+pub struct CountryRanges {
+    ipv4: Vec<(u32, u32, String)>,
+    ipv6: Vec<(u128, u128, String)>,
+}
+
+impl CountryRanges {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(path)?;
+        let mut ranges = CountryRanges {
+            ipv4: Vec::new(),
+            ipv6: Vec::new(),
+        };
+
+        for record in reader.records() {
+            let record = record?;
+            let (Some(first), Some(last), Some(country)) =
+                (record.get(0), record.get(1), record.get(2))
+            else {
+                return Err(format!("expected 3 columns on line {}", line(&record)).into());
+            };
+            // "ZZ" marks reserved and unassigned ranges
+            if country.len() != 2 || country == "ZZ" {
+                continue;
+            }
+            let country = country.to_ascii_uppercase();
+            match (first.parse::<IpAddr>()?, last.parse::<IpAddr>()?) {
+                (IpAddr::V4(first), IpAddr::V4(last)) => {
+                    ranges.ipv4.push((first.into(), last.into(), country))
+                }
+                (IpAddr::V6(first), IpAddr::V6(last)) => {
+                    ranges.ipv6.push((first.into(), last.into(), country))
+                }
+                _ => return Err(format!("mixed address families on line {}", line(&record)).into()),
+            }
+        }
+        if ranges.ipv4.is_empty() && ranges.ipv6.is_empty() {
+            return Err("no country ranges found".into());
+        }
+
+        // The files are sorted already, this only guards the binary search
+        ranges.ipv4.sort_unstable_by_key(|range| range.0);
+        ranges.ipv6.sort_unstable_by_key(|range| range.0);
+        Ok(ranges)
+    }
+
+    // The ISO 3166-1 alpha-2 code of the country `ip` is in
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let ipv4 = match ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        };
+        match (ipv4, ip) {
+            (Some(ip), _) => find(&self.ipv4, u32::from(ip)),
+            (None, IpAddr::V6(ip)) => find(&self.ipv6, u128::from(ip)),
+            (None, IpAddr::V4(_)) => unreachable!("IPv4 addresses are handled above"),
+        }
+    }
+}
+
+// The last range starting at or before `ip`, if it also ends after it
+fn find<T: Ord + Copy>(ranges: &[(T, T, String)], ip: T) -> Option<&str> {
+    let index = ranges
+        .partition_point(|range| range.0 <= ip)
+        .checked_sub(1)?;
+    let (_, last, country) = &ranges[index];
+    (ip <= *last).then_some(country.as_str())
+}
+
+fn line(record: &csv::StringRecord) -> u64 {
+    record.position().map_or(0, |position| position.line())
+}
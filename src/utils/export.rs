@@ -0,0 +1,140 @@
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Double, Integer, Nullable, Text, Timestamp};
+use serde::Serialize;
+use std::error::Error;
+use std::io::{BufWriter, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+// An event together with the visitor details of its collector
+#[derive(QueryableByName, Serialize)]
+pub struct ExportRow {
+    #[diesel(sql_type = Text)]
+    pub id: String,
+    #[diesel(sql_type = Timestamp)]
+    pub timestamp: NaiveDateTime,
+    #[diesel(sql_type = Text)]
+    pub name: String,
+    #[diesel(sql_type = Text)]
+    pub url: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub referrer: Option<String>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub status: Option<i32>,
+    #[diesel(sql_type = Nullable<Double>)]
+    pub value: Option<f64>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub currency: Option<String>,
+    #[diesel(sql_type = Text)]
+    pub collector_id: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub origin: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub country: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub region: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub city: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub os: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub browser: Option<String>,
+}
+
+enum Sink<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Json(BufWriter<W>),
+}
+
+// Start and end of an export from the first to the last day it includes.
+// Without a first day everything is exported, the last day defaults to today.
+pub fn day_range(from: Option<NaiveDate>, to: Option<NaiveDate>) -> (NaiveDateTime, NaiveDateTime) {
+    let from = from.map_or(NaiveDateTime::MIN, |day| day.and_hms_opt(0, 0, 0).unwrap());
+    let to = to
+        .unwrap_or_else(|| Utc::now().date_naive())
+        .succ_opt()
+        .map_or(NaiveDateTime::MAX, |day| day.and_hms_opt(0, 0, 0).unwrap());
+    (from, to)
+}
+
+// Rows are read in pages so large exports don't have to fit in memory
+pub const PAGE_SIZE: i64 = 5000;
+
+// The page of events between `from` and `to` that follows the (timestamp, id)
+// of the last row of the previous page. Keyset pagination, so concurrent
+// inserts can't shift the pages.
+pub fn load_page(
+    conn: &mut SqliteConnection,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    after: Option<&ExportRow>,
+) -> QueryResult<Vec<ExportRow>> {
+    let (after_timestamp, after_id) = after
+        .map(|row| (row.timestamp, row.id.clone()))
+        .unwrap_or((from, String::new()));
+    diesel::sql_query(
+        "SELECT e.id, e.timestamp, e.name, e.url, e.referrer, e.status, e.value, e.currency,
+            e.collector_id, c.origin, c.country, c.region, c.city, c.os, c.browser
+        FROM events e
+        LEFT JOIN collectors c ON c.id = e.collector_id
+        WHERE e.timestamp >= ? AND e.timestamp < ?
+        AND (e.timestamp > ? OR (e.timestamp = ? AND e.id > ?))
+        ORDER BY e.timestamp, e.id
+        LIMIT ?",
+    )
+    .bind::<Timestamp, _>(from)
+    .bind::<Timestamp, _>(to)
+    .bind::<Timestamp, _>(after_timestamp)
+    .bind::<Timestamp, _>(after_timestamp)
+    .bind::<Text, _>(after_id)
+    .bind::<BigInt, _>(PAGE_SIZE)
+    .load(conn)
+}
+
+// Writes every event between `from` and `to` to `out`, as CSV with a header
+// row or as one JSON object per line. Returns the number of events written.
+pub fn export_events(
+    conn: &mut SqliteConnection,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    format: ExportFormat,
+    out: impl Write,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut sink = match format {
+        ExportFormat::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(out))),
+        ExportFormat::Json => Sink::Json(BufWriter::new(out)),
+    };
+
+    let mut written = 0;
+    let mut last: Option<ExportRow> = None;
+    loop {
+        let rows = load_page(conn, from, to, last.as_ref())?;
+
+        for row in &rows {
+            match &mut sink {
+                Sink::Csv(writer) => writer.serialize(row)?,
+                Sink::Json(writer) => {
+                    serde_json::to_writer(&mut *writer, row)?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+        }
+        written += rows.len();
+
+        if (rows.len() as i64) < PAGE_SIZE {
+            break;
+        }
+        last = rows.into_iter().last();
+    }
+
+    match &mut sink {
+        Sink::Csv(writer) => writer.flush()?,
+        Sink::Json(writer) => writer.flush()?,
+    }
+    Ok(written)
+}
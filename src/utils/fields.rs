@@ -0,0 +1,44 @@
+use crate::config::{Config, OversizedFields};
+
+// Checks a field of an incoming event before it is stored. Control
+// characters are never accepted, values over `max` characters are cut short
+// or refused depending on OVERSIZED_FIELDS.
+fn checked_field(
+    field: &str,
+    value: &str,
+    max: usize,
+    oversized: OversizedFields,
+) -> Result<String, String> {
+    if value.chars().any(char::is_control) {
+        return Err(format!("{} contains control characters", field));
+    }
+    if value.chars().count() <= max {
+        return Ok(value.to_string());
+    }
+    match oversized {
+        OversizedFields::Truncate => Ok(value.chars().take(max).collect()),
+        OversizedFields::Reject => Err(format!("{} is longer than {} characters", field, max)),
+    }
+}
+
+pub fn checked_url(config: &Config, url: &str) -> Result<String, String> {
+    checked_field("url", url, config.max_url_length, config.oversized_fields)
+}
+
+pub fn checked_referrer(config: &Config, referrer: &str) -> Result<String, String> {
+    checked_field(
+        "referrer",
+        referrer,
+        config.max_referrer_length,
+        config.oversized_fields,
+    )
+}
+
+pub fn checked_name(config: &Config, name: &str) -> Result<String, String> {
+    checked_field(
+        "name",
+        name,
+        config.max_event_name_length,
+        config.oversized_fields,
+    )
+}
@@ -1,29 +1,401 @@
-use maxminddb::geoip2::City;
+use crate::config::{Config, GeoipProvider};
+use crate::utils::city::Cities;
+use crate::utils::countries::country_name;
+use crate::utils::dbip::CountryRanges;
+use crate::utils::ip2location::Ip2LocationDb;
+use log::{info, warn};
+use maxminddb::geoip2::{Asn, City};
 use maxminddb::Reader;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-pub fn geoip_lookup(
-    ip: &str,
-    db_path: &str,
-) -> Result<(String, String), Box<dyn std::error::Error>> {
-    let reader = Reader::open_readfile(db_path)?;
-    let ip: IpAddr = ip.parse()?;
+// Every GeoLite2 name is available in English
+const DEFAULT_LOCALE: &str = "en";
+
+pub struct GeoLocation {
+    pub country: String,
+    // ISO 3166-1 alpha-2 code of the country
+    pub country_code: Option<String>,
+    pub city: String,
+    pub region: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+}
+
+impl GeoLocation {
+    pub fn unknown() -> Self {
+        GeoLocation {
+            country: "Unknown".to_string(),
+            country_code: None,
+            city: "Unknown".to_string(),
+            region: None,
+            latitude: None,
+            longitude: None,
+            asn: None,
+            as_org: None,
+        }
+    }
+}
+
+type OpenFn<T> = fn(&Path) -> Result<T, Box<dyn Error>>;
+
+struct LoadedReader<T> {
+    reader: Option<Arc<T>>,
+    modified: Option<SystemTime>,
+}
+
+// Database file loaded once and shared between workers. The `geoip-refresh`
+// job re-reads it when its modification time changes, e.g. after a GeoLite2
+// update.
+struct Database<T> {
+    path: PathBuf,
+    open: OpenFn<T>,
+    state: Mutex<LoadedReader<T>>,
+}
+
+impl<T> Database<T> {
+    fn new(path: PathBuf, open: OpenFn<T>) -> Self {
+        let (reader, modified) = Self::load(&path, open);
+
+        Database {
+            path,
+            open,
+            state: Mutex::new(LoadedReader { reader, modified }),
+        }
+    }
+
+    fn load(path: &Path, open: OpenFn<T>) -> (Option<Arc<T>>, Option<SystemTime>) {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        match open(path) {
+            Ok(reader) => {
+                info!("Loaded GeoIP database from {}", path.display());
+                (Some(Arc::new(reader)), modified)
+            }
+            Err(e) => {
+                warn!("GeoIP database {} not loaded: {}", path.display(), e);
+                (None, modified)
+            }
+        }
+    }
+
+    fn reader(&self) -> Option<Arc<T>> {
+        self.state.lock().unwrap().reader.clone()
+    }
+
+    // Re-reads the file if it changed, returns whether it did. A file that
+    // fails to open, e.g. one still being copied in place, doesn't replace
+    // the database that works and is tried again on the next refresh.
+    fn refresh(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.state.lock().unwrap().modified {
+            return Ok(false);
+        }
+
+        // Lookups carry on with the old reader while the new one loads
+        let reader = (self.open)(&self.path)
+            .map_err(|e| format!("GeoIP database {} not loaded: {}", self.path.display(), e))?;
+        info!("Reloaded GeoIP database from {}", self.path.display());
+
+        let mut state = self.state.lock().unwrap();
+        state.reader = Some(Arc::new(reader));
+        state.modified = modified;
+        Ok(true)
+    }
+}
+
+fn open_mmdb(path: &Path) -> Result<Reader<Vec<u8>>, Box<dyn Error>> {
+    Ok(Reader::open_readfile(path)?)
+}
+
+// Where the location of an ip address comes from, see GEOIP_PROVIDER
+pub trait GeoProvider: Send + Sync {
+    // The location of `ip`, without its network operator
+    fn locate(&self, ip: IpAddr) -> Result<GeoLocation, Box<dyn Error>>;
+
+    // Re-reads the database if its file changed, returns whether it did
+    fn refresh(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(false)
+    }
+}
+
+// MaxMind's GeoLite2 or GeoIP2 City database, with names in `locale` where
+// the database has them and English otherwise
+pub struct MaxMind {
+    database: Database<Reader<Vec<u8>>>,
+    locale: String,
+}
+
+impl MaxMind {
+    pub fn new(path: &str, locale: &str) -> Self {
+        MaxMind {
+            database: Database::new(path.into(), open_mmdb),
+            locale: locale.to_string(),
+        }
+    }
+}
+
+impl GeoProvider for MaxMind {
+    fn locate(&self, ip: IpAddr) -> Result<GeoLocation, Box<dyn Error>> {
+        let reader = self.database.reader().ok_or("GeoIP database not loaded")?;
+        geoip_lookup(&reader, ip, &self.locale)
+    }
+
+    fn refresh(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.database.refresh()
+    }
+}
+
+// An IP2Location BIN database, e.g. the free IP2LOCATION-LITE-DB5. Names are
+// always English, countries are spelled like in GeoLite2 so switching
+// providers doesn't split them.
+pub struct Ip2Location {
+    database: Database<Ip2LocationDb>,
+}
+
+impl Ip2Location {
+    pub fn new(path: &str) -> Self {
+        Ip2Location {
+            database: Database::new(path.into(), Ip2LocationDb::open),
+        }
+    }
+}
+
+impl GeoProvider for Ip2Location {
+    fn locate(&self, ip: IpAddr) -> Result<GeoLocation, Box<dyn Error>> {
+        let database = self.database.reader().ok_or("GeoIP database not loaded")?;
+        let record = database.lookup(ip).ok_or("GeoIP lookup failed")?;
 
+        Ok(GeoLocation {
+            country: record
+                .country_code
+                .as_deref()
+                .and_then(country_name)
+                .map(str::to_string)
+                .or(record.country_name)
+                .unwrap_or_else(|| "Unknown".to_string()),
+            country_code: record.country_code,
+            city: record.city.unwrap_or_else(|| "Unknown".to_string()),
+            region: record.region,
+            latitude: record.latitude,
+            longitude: record.longitude,
+            asn: None,
+            as_org: None,
+        })
+    }
+
+    fn refresh(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.database.refresh()
+    }
+}
+
+// A DB-IP country CSV, which only knows countries. GeoIp falls back to it
+// when the location database is missing or doesn't know an address, so
+// country stats work without a GeoLite2 download.
+pub struct DbIpCountry {
+    database: Database<CountryRanges>,
+}
+
+impl DbIpCountry {
+    pub fn new(path: &str) -> Self {
+        DbIpCountry {
+            database: Database::new(path.into(), CountryRanges::open),
+        }
+    }
+}
+
+impl GeoProvider for DbIpCountry {
+    fn locate(&self, ip: IpAddr) -> Result<GeoLocation, Box<dyn Error>> {
+        let database = self.database.reader().ok_or("GeoIP database not loaded")?;
+        let code = database.country(ip).ok_or("GeoIP lookup failed")?;
+
+        Ok(GeoLocation {
+            country: country_name(code).unwrap_or("Unknown").to_string(),
+            country_code: Some(code.to_string()),
+            ..GeoLocation::unknown()
+        })
+    }
+
+    fn refresh(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.database.refresh()
+    }
+}
+
+// For GEOIP_PROVIDER=none, every visitor is "Unknown"
+pub struct NoGeoProvider;
+
+impl GeoProvider for NoGeoProvider {
+    fn locate(&self, _ip: IpAddr) -> Result<GeoLocation, Box<dyn Error>> {
+        Err("GeoIP lookups are disabled".into())
+    }
+}
+
+// The configured provider plus the optional GeoLite2-ASN database, which
+// adds the network operator to each location when it is present. Addresses
+// the provider can't locate get their country from `fallback`, and without
+// either database they are stored as "Unknown". Locations without
+// coordinates get those of their city from `cities`.
+pub struct GeoIp {
+    provider: Box<dyn GeoProvider>,
+    fallback: Option<DbIpCountry>,
+    asn: Option<Database<Reader<Vec<u8>>>>,
+    cities: Option<Cities>,
+}
+
+impl GeoIp {
+    pub fn from_config(config: &Config) -> Self {
+        let provider: Box<dyn GeoProvider> = match config.geoip_provider {
+            GeoipProvider::Maxmind => {
+                Box::new(MaxMind::new(&config.geoip_database, &config.geo_locale))
+            }
+            GeoipProvider::Ip2location => Box::new(Ip2Location::new(&config.geoip_database)),
+            GeoipProvider::None => return Self::disabled(),
+        };
+
+        // An empty path skips the fallback or the ASN lookup
+        let fallback_path = &config.geoip_fallback_database;
+        let asn_path = &config.geoip_asn_database;
+        GeoIp {
+            provider,
+            fallback: (!fallback_path.is_empty()).then(|| DbIpCountry::new(fallback_path)),
+            asn: (!asn_path.is_empty()).then(|| Database::new(asn_path.into(), open_mmdb)),
+            cities: Some(Cities::load(&config.city_data)),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        info!("GeoIP lookups are disabled");
+        GeoIp {
+            provider: Box::new(NoGeoProvider),
+            fallback: None,
+            asn: None,
+            cities: None,
+        }
+    }
+
+    // Re-reads the database files that changed since they were loaded, see
+    // the `geoip-refresh` job. Returns how many were reloaded.
+    pub fn refresh(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let results = [
+            self.provider.refresh(),
+            self.fallback
+                .as_ref()
+                .map_or(Ok(false), |fallback| fallback.refresh()),
+            self.asn.as_ref().map_or(Ok(false), |asn| asn.refresh()),
+        ];
+
+        let mut reloaded = 0;
+        let mut errors = Vec::new();
+        for result in results {
+            match result {
+                Ok(true) => reloaded += 1,
+                Ok(false) => {}
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.join(", ").into());
+        }
+        Ok(reloaded)
+    }
+
+    pub fn lookup(&self, ip: &str) -> Result<GeoLocation, Box<dyn Error>> {
+        let ip: IpAddr = ip.parse()?;
+        let mut location = match (self.provider.locate(ip), &self.fallback) {
+            (Ok(location), _) => location,
+            (Err(e), Some(fallback)) => fallback.locate(ip).map_err(|_| e)?,
+            (Err(e), None) => return Err(e),
+        };
+
+        if location.latitude.is_none() {
+            if let Some((latitude, longitude)) = self
+                .cities
+                .as_ref()
+                .and_then(|cities| cities.coordinates(&location.city))
+            {
+                location.latitude = Some(latitude);
+                location.longitude = Some(longitude);
+            }
+        }
+
+        if let Some(asn_reader) = self.asn.as_ref().and_then(|asn| asn.reader()) {
+            if let Ok((asn, as_org)) = asn_lookup(&asn_reader, ip) {
+                location.asn = asn;
+                location.as_org = as_org;
+            }
+        }
+
+        Ok(location)
+    }
+}
+
+// The name in `locale`, or the English one when there is none in it
+fn localized<'a>(mut names: BTreeMap<&'a str, &'a str>, locale: &str) -> Option<&'a str> {
+    names
+        .remove(locale)
+        .or_else(|| names.remove(DEFAULT_LOCALE))
+}
+
+pub fn geoip_lookup(
+    reader: &Reader<Vec<u8>>,
+    ip: IpAddr,
+    locale: &str,
+) -> Result<GeoLocation, Box<dyn Error>> {
     if let Ok(lookup_city) = reader.lookup::<City<'_>>(ip) {
+        let country_code = lookup_city.country.as_ref().and_then(|c| c.iso_code);
+
         let country_name = lookup_city
             .country
             .and_then(|c| c.names)
-            .and_then(|mut names| names.remove("en"))
-            .unwrap_or_else(|| "Unknown");
+            .and_then(|names| localized(names, locale))
+            .unwrap_or("Unknown");
 
         let city_name = lookup_city
             .city
             .and_then(|c| c.names)
-            .and_then(|mut names| names.remove("en"))
-            .unwrap_or_else(|| "Unknown");
+            .and_then(|names| localized(names, locale))
+            .unwrap_or("Unknown");
 
-        Ok((country_name.to_string(), city_name.to_string()))
+        // The first subdivision is the largest one, e.g. a state or province
+        let region_name = lookup_city
+            .subdivisions
+            .and_then(|subdivisions| subdivisions.into_iter().next())
+            .and_then(|s| s.names)
+            .and_then(|names| localized(names, locale));
+
+        let location = lookup_city.location;
+
+        Ok(GeoLocation {
+            country: country_name.to_string(),
+            country_code: country_code.map(|code| code.to_string()),
+            city: city_name.to_string(),
+            region: region_name.map(|r| r.to_string()),
+            latitude: location.as_ref().and_then(|l| l.latitude),
+            longitude: location.as_ref().and_then(|l| l.longitude),
+            asn: None,
+            as_org: None,
+        })
     } else {
         Err("GeoIP lookup failed".into())
     }
 }
+
+pub fn asn_lookup(
+    reader: &Reader<Vec<u8>>,
+    ip: IpAddr,
+) -> Result<(Option<u32>, Option<String>), Box<dyn Error>> {
+    let lookup_asn = reader.lookup::<Asn<'_>>(ip)?;
+
+    Ok((
+        lookup_asn.autonomous_system_number,
+        lookup_asn
+            .autonomous_system_organization
+            .map(|org| org.to_string()),
+    ))
+}
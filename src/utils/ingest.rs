@@ -0,0 +1,248 @@
+use crate::config::{Config, UnknownEventNames};
+use crate::db::DbPool;
+use crate::models::NewEvent;
+use crate::utils::fields::{checked_name, checked_referrer, checked_url};
+use crate::utils::limits::{CollectorCaps, CollectorOrigins, CollectorRates, OriginQuotas};
+use crate::utils::runtime::RuntimeStatus;
+use crate::utils::spam::ReferrerBlocklist;
+use crate::utils::url::{clean_url, host_and_path};
+use crate::utils::url_rules::UrlRules;
+use actix_web::web;
+use chrono::Utc;
+use diesel::prelude::*;
+use log::error;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use ulid::Ulid;
+
+static LOCALHOST: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"http://(127\.0\.0\.1|localhost|0\.0\.0\.0|\[::1\])(:\d+)?").unwrap());
+
+// An event as it was sent, before any of it is trusted
+pub struct RawEvent<'a> {
+    pub url: &'a str,
+    pub referrer: Option<&'a str>,
+    pub name: &'a str,
+    pub collector_id: &'a str,
+    pub status: Option<i32>,
+    pub value: Option<f64>,
+    pub currency: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+}
+
+// Why an event isn't queued
+pub enum Rejected {
+    Invalid(String),
+    // Internal traffic and referrer spam, acknowledged but dropped
+    Ignored,
+    NameNotAllowed,
+    Throttled { retry_after: u64 },
+    Capped,
+    OverQuota,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejected::Invalid(e) => write!(f, "{}", e),
+            Rejected::Ignored => write!(f, "Event ignored"),
+            Rejected::NameNotAllowed => write!(f, "Event name not allowed"),
+            Rejected::Throttled { .. } => {
+                write!(f, "Too many events from this collector, slow down")
+            }
+            Rejected::Capped => write!(f, "Too many events from this collector"),
+            Rejected::OverQuota => write!(f, "The site's daily event quota is exceeded"),
+        }
+    }
+}
+
+// Events of collectors that aren't in the database, e.g. from server-side
+// integrations, share the quota of this origin, usually the `*` one
+const UNKNOWN_ORIGIN: &str = "unknown";
+
+// The checks every event goes through, whether it was sent to /collect,
+// recorded by /r or received over UDP
+pub struct Ingest {
+    referrer_blocklist: Arc<ReferrerBlocklist>,
+    url_rules: Arc<UrlRules>,
+    caps: Arc<CollectorCaps>,
+    rates: Arc<CollectorRates>,
+    quotas: Arc<OriginQuotas>,
+    origins: CollectorOrigins,
+    runtime: Arc<RuntimeStatus>,
+}
+
+// Treat events named after an HTTP status (e.g. `stats_collect('404')`)
+// as carrying that status
+fn status_from_name(name: &str) -> Option<i32> {
+    if name.len() == 3 && name.chars().all(|c| c.is_ascii_digit()) {
+        name.parse().ok().filter(|code| (100..600).contains(code))
+    } else {
+        None
+    }
+}
+
+// Normalizes an ISO 4217 style currency code, e.g. `usd` -> `USD`
+fn currency_code(currency: &str) -> Option<String> {
+    let code = currency.trim().to_uppercase();
+    if code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+impl Ingest {
+    pub fn new(
+        referrer_blocklist: Arc<ReferrerBlocklist>,
+        url_rules: Arc<UrlRules>,
+        caps: Arc<CollectorCaps>,
+        rates: Arc<CollectorRates>,
+        quotas: Arc<OriginQuotas>,
+        runtime: Arc<RuntimeStatus>,
+    ) -> Self {
+        Ingest {
+            referrer_blocklist,
+            url_rules,
+            caps,
+            rates,
+            quotas,
+            origins: CollectorOrigins::new(),
+            runtime,
+        }
+    }
+
+    // Whether events with this referrer or from this address are dropped
+    pub fn ignored(&self, config: &Config, referrer: Option<&str>, ip: Option<IpAddr>) -> bool {
+        referrer.is_some_and(|referrer| self.referrer_blocklist.is_spam(referrer))
+            || ip.is_some_and(|ip| config.is_blocked(&ip))
+    }
+
+    // Checks the fields of an event and turns it into one ready to store,
+    // with its url cleaned and rewritten by the url rules
+    pub fn prepare(&self, config: &Config, event: RawEvent) -> Result<NewEvent, Rejected> {
+        // Hostile clients could otherwise fill the events table with
+        // megabytes of junk through the query string
+        let url = checked_url(config, event.url).map_err(Rejected::Invalid)?;
+        let referrer = event
+            .referrer
+            .map(|referrer| checked_referrer(config, referrer))
+            .transpose()
+            .map_err(Rejected::Invalid)?;
+        let raw_name = checked_name(config, event.name).map_err(Rejected::Invalid)?;
+
+        // Block local requests in production
+        // TODO: i don't think cors is taking care of this because
+        // the origin is not available in localhost?
+        if !config.is_development && LOCALHOST.is_match(&url) {
+            return Err(Rejected::Invalid(
+                "Local events are not recorded".to_string(),
+            ));
+        }
+
+        if self.ignored(config, referrer.as_deref(), event.ip) {
+            return Err(Rejected::Ignored);
+        }
+
+        // Unknown custom event names would otherwise grow the events table
+        // without bound when a client misbehaves
+        let mut name = raw_name.clone();
+        if status_from_name(&name).is_none() && !config.event_name_allowed(&url, &name) {
+            match config.unknown_event_names {
+                UnknownEventNames::Reject => return Err(Rejected::NameNotAllowed),
+                UnknownEventNames::Other => name = "other".to_string(),
+            }
+        }
+
+        let url = self.url_rules.apply(&clean_url(&url, config));
+        let (host, path) = host_and_path(&url);
+        Ok(NewEvent {
+            id: Ulid::new().to_string(),
+            url,
+            referrer,
+            name,
+            timestamp: Utc::now().naive_utc(),
+            collector_id: event.collector_id.to_string(),
+            status: event.status.or_else(|| status_from_name(&raw_name)),
+            value: event.value.filter(|v| v.is_finite()),
+            currency: event.currency.and_then(currency_code),
+            host,
+            path,
+        })
+    }
+
+    // Counts the event against its collector's rate limit and hourly cap
+    // and its site's daily quota, an error once it is over one of them
+    pub async fn admit(
+        &self,
+        config: &Config,
+        pool: &DbPool,
+        event: &NewEvent,
+    ) -> Result<(), Rejected> {
+        if let Err(retry_after) = self
+            .rates
+            .check(&event.collector_id, config.collector_rate_limit)
+        {
+            self.runtime.record_throttled();
+            return Err(Rejected::Throttled { retry_after });
+        }
+        if !self
+            .caps
+            .allow(&event.collector_id, config.max_events_per_collector_hour)
+        {
+            self.runtime.record_capped();
+            return Err(Rejected::Capped);
+        }
+        if config.daily_event_quotas.is_empty() {
+            return Ok(());
+        }
+        let origin = self.collector_origin(pool, &event.collector_id).await;
+        if !self
+            .quotas
+            .allow(&origin, config.daily_event_quota(&origin))
+        {
+            return Err(Rejected::OverQuota);
+        }
+        Ok(())
+    }
+
+    // The origin the collector was created for. Quotas go by it rather than
+    // by the url of the event, which a client can set to anything.
+    async fn collector_origin(&self, pool: &DbPool, collector_id: &str) -> String {
+        use crate::schema::collectors;
+
+        if let Some(origin) = self.origins.get(collector_id) {
+            return origin;
+        }
+        let pool = pool.clone();
+        let id = collector_id.to_string();
+        let found = web::block(move || -> Result<Option<String>, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            collectors::table
+                .find(&id)
+                .select(collectors::origin)
+                .first::<String>(&mut conn)
+                .optional()
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        match found {
+            Ok(Ok(Some(origin))) => {
+                self.origins.put(collector_id, &origin);
+                origin
+            }
+            Ok(Ok(None)) => UNKNOWN_ORIGIN.to_string(),
+            Ok(Err(e)) => {
+                error!("Error looking up collector origin: {}", e);
+                UNKNOWN_ORIGIN.to_string()
+            }
+            Err(e) => {
+                error!("Error looking up collector origin: {:?}", e);
+                UNKNOWN_ORIGIN.to_string()
+            }
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Truncates an address to its network so it no longer identifies a single
+// visitor: the last octet of IPv4 and the last 80 bits of IPv6 are zeroed.
+// The result is still precise enough for a city level GeoIP lookup.
+pub fn anonymize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                0,
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+// Columns of each database type, 1 being the start of the range. 0 means
+// the type doesn't have it, e.g. DB1 only knows countries.
+const COUNTRY_POSITION: [usize; 27] = [
+    0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+];
+const REGION_POSITION: [usize; 27] = [
+    0, 0, 0, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
+];
+const CITY_POSITION: [usize; 27] = [
+    0, 0, 0, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4,
+];
+const LATITUDE_POSITION: [usize; 27] = [
+    0, 0, 0, 0, 0, 5, 5, 0, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5,
+];
+const LONGITUDE_POSITION: [usize; 27] = [
+    0, 0, 0, 0, 0, 6, 6, 0, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6,
+];
+
+// Size of the header before the ip ranges
+const HEADER_SIZE: usize = 64;
+
+#[derive(Default)]
+pub struct Ip2LocationRecord {
+    pub country_code: Option<String>,
+    pub country_name: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+// An IP2Location BIN database read into memory. The file holds sorted
+// tables of ip ranges for IPv4 and IPv6, with fixed size rows whose columns
+// are numbers or offsets of length-prefixed strings. Offsets in the header
+// count from 1.
+pub struct Ip2LocationDb {
+    data: Vec<u8>,
+    db_type: usize,
+    columns: usize,
+    ipv4_count: usize,
+    ipv4_base: usize,
+    ipv6_count: usize,
+    ipv6_base: usize,
+}
+
+impl Ip2LocationDb {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    fn from_bytes(data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        if data.len() < HEADER_SIZE {
+            return Err("not an IP2Location BIN file".into());
+        }
+        let header = |offset: usize| {
+            u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+        };
+
+        let database = Ip2LocationDb {
+            db_type: data[0] as usize,
+            columns: data[1] as usize,
+            ipv4_count: header(5),
+            ipv4_base: header(9),
+            ipv6_count: header(13),
+            ipv6_base: header(17),
+            data,
+        };
+        if database.db_type == 0 || database.db_type >= COUNTRY_POSITION.len() {
+            return Err(format!("unknown IP2Location database type {}", database.db_type).into());
+        }
+        if database.columns < 2 || database.ipv4_count + database.ipv6_count == 0 {
+            return Err("not an IP2Location BIN file".into());
+        }
+        // A file that was cut short, e.g. by an interrupted download
+        if !database.table_fits(false) || !database.table_fits(true) {
+            return Err("IP2Location BIN file is truncated".into());
+        }
+        Ok(database)
+    }
+
+    // IPv6 rows start with a 16 byte address instead of 4 bytes
+    fn row_size(&self, ipv6: bool) -> usize {
+        self.columns * 4 + if ipv6 { 12 } else { 0 }
+    }
+
+    // Whether the table's rows, including the one where the last range
+    // ends, are all in the file
+    fn table_fits(&self, ipv6: bool) -> bool {
+        let (count, base) = if ipv6 {
+            (self.ipv6_count, self.ipv6_base)
+        } else {
+            (self.ipv4_count, self.ipv4_base)
+        };
+        if count == 0 {
+            return true;
+        }
+        (count + 1)
+            .checked_mul(self.row_size(ipv6))
+            .zip(base.checked_sub(1))
+            .and_then(|(size, start)| start.checked_add(size))
+            .is_some_and(|end| end <= self.data.len())
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Ip2LocationRecord> {
+        let ipv4 = match ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        };
+        // The last address of a range is where the next one starts, so the
+        // highest address is looked up as the one before it
+        let row = match (ipv4, ip) {
+            (Some(ip), _) => self.find(u32::from(ip).min(u32::MAX - 1).into(), false)?,
+            (None, IpAddr::V6(ip)) => self.find(u128::from(ip).min(u128::MAX - 1), true)?,
+            (None, IpAddr::V4(_)) => unreachable!("IPv4 addresses are handled above"),
+        };
+
+        let country = self.pointer(row, &COUNTRY_POSITION);
+        let record = Ip2LocationRecord {
+            country_code: country.and_then(|at| self.string(at)),
+            // The name follows the two letter code and its length
+            country_name: country.and_then(|at| self.string(at + 3)),
+            region: self
+                .pointer(row, &REGION_POSITION)
+                .and_then(|at| self.string(at)),
+            city: self
+                .pointer(row, &CITY_POSITION)
+                .and_then(|at| self.string(at)),
+            latitude: self.float(row, &LATITUDE_POSITION),
+            longitude: self.float(row, &LONGITUDE_POSITION),
+        };
+        // Reserved and unassigned ranges have no country
+        record.country_code.as_ref()?;
+        Some(record)
+    }
+
+    // Binary search for the row whose range holds `ip`. Every table ends
+    // with one more row, where the range of the last one ends.
+    fn find(&self, ip: u128, ipv6: bool) -> Option<Row> {
+        let (count, base) = if ipv6 {
+            (self.ipv6_count, self.ipv6_base)
+        } else {
+            (self.ipv4_count, self.ipv4_base)
+        };
+        if count == 0 || base == 0 {
+            return None;
+        }
+        let row_size = self.row_size(ipv6);
+        let start = |index: usize| -> Option<u128> {
+            let offset = base - 1 + index * row_size;
+            if ipv6 {
+                self.bytes(offset).map(u128::from_le_bytes)
+            } else {
+                self.bytes(offset).map(u32::from_le_bytes).map(u128::from)
+            }
+        };
+
+        let (mut low, mut high) = (0, count);
+        while low <= high {
+            let middle = (low + high) / 2;
+            let (from, to) = (start(middle)?, start(middle + 1)?);
+            if ip >= from && ip < to {
+                return Some(Row {
+                    offset: base - 1 + middle * row_size,
+                    ipv6,
+                });
+            }
+            if ip < from {
+                high = middle.checked_sub(1)?;
+            } else {
+                low = middle + 1;
+            }
+        }
+        None
+    }
+
+    fn column(&self, row: &Row, positions: &[usize; 27]) -> Option<usize> {
+        let position = positions[self.db_type];
+        if position == 0 {
+            return None;
+        }
+        let ip_size = if row.ipv6 { 16 } else { 4 };
+        Some(row.offset + ip_size + (position - 2) * 4)
+    }
+
+    fn pointer(&self, row: Row, positions: &[usize; 27]) -> Option<usize> {
+        let at = self.column(&row, positions)?;
+        self.bytes(at).map(u32::from_le_bytes).map(|p| p as usize)
+    }
+
+    fn float(&self, row: Row, positions: &[usize; 27]) -> Option<f64> {
+        let at = self.column(&row, positions)?;
+        let value = self.bytes(at).map(f32::from_le_bytes)? as f64;
+        // Coordinates are single precision, more digits would only be noise
+        Some((value * 1e6).round() / 1e6)
+    }
+
+    // A length-prefixed string, "-" marks values that aren't known
+    fn string(&self, at: usize) -> Option<String> {
+        let length = *self.data.get(at)? as usize;
+        let bytes = self.data.get(at + 1..at + 1 + length)?;
+        match std::str::from_utf8(bytes).ok()? {
+            "" | "-" => None,
+            value => Some(value.to_string()),
+        }
+    }
+
+    fn bytes<const N: usize>(&self, at: usize) -> Option<[u8; N]> {
+        self.data.get(at..at + N)?.try_into().ok()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Row {
+    offset: usize,
+    ipv6: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // Country code and name, region, city, latitude and longitude
+    type Place = (
+        &'static str,
+        &'static str,
+        &'static str,
+        &'static str,
+        f32,
+        f32,
+    );
+
+    // Reserved ranges have "-" for every value
+    const RESERVED: Place = ("-", "-", "-", "-", 0.0, 0.0);
+    const BRISBANE: Place = (
+        "AU",
+        "Australia",
+        "Queensland",
+        "Brisbane",
+        -27.46794,
+        153.02809,
+    );
+    const MOUNTAIN_VIEW: Place = (
+        "US",
+        "United States of America",
+        "California",
+        "Mountain View",
+        37.38605,
+        -122.08385,
+    );
+    const SAO_PAULO: Place = (
+        "BR",
+        "Brazil",
+        "Sao Paulo",
+        "Sao Paulo",
+        -23.5475,
+        -46.63611,
+    );
+    const BERLIN: Place = ("DE", "Germany", "Berlin", "Berlin", 52.52437, 13.41053);
+    const TOKYO: Place = ("JP", "Japan", "Tokyo", "Tokyo", 35.6895, 139.69171);
+
+    // Length-prefixed strings, each starting at `start` plus its place in `strings`
+    fn push_strings(strings: &mut Vec<u8>, start: usize, values: &[&str]) -> u32 {
+        let at = start + strings.len();
+        for value in values {
+            strings.push(value.len() as u8);
+            strings.extend(value.as_bytes());
+        }
+        at as u32
+    }
+
+    // A DB5 BIN file with a few ranges in each table. The last row of each
+    // table is where the range before it ends.
+    fn fixture() -> Vec<u8> {
+        let ipv4: [(u128, Place); 5] = [
+            (0, RESERVED),
+            (u32::from(Ipv4Addr::new(1, 0, 0, 0)).into(), BRISBANE),
+            (u32::from(Ipv4Addr::new(8, 8, 8, 0)).into(), MOUNTAIN_VIEW),
+            (u32::from(Ipv4Addr::new(200, 0, 0, 0)).into(), SAO_PAULO),
+            (u32::MAX.into(), RESERVED),
+        ];
+        let ipv6: [(u128, Place); 5] = [
+            (0, RESERVED),
+            ("2001:db8::".parse::<Ipv6Addr>().unwrap().into(), BERLIN),
+            ("2001:db9::".parse::<Ipv6Addr>().unwrap().into(), RESERVED),
+            ("2400::".parse::<Ipv6Addr>().unwrap().into(), TOKYO),
+            (u128::MAX, RESERVED),
+        ];
+
+        let columns = 6;
+        let ipv4_size = ipv4.len() * columns * 4;
+        let ipv6_size = ipv6.len() * (columns * 4 + 12);
+        let strings_start = HEADER_SIZE + ipv4_size + ipv6_size;
+
+        let mut data = vec![0; HEADER_SIZE];
+        data[0] = 5;
+        data[1] = columns as u8;
+        data[5..9].copy_from_slice(&(ipv4.len() as u32 - 1).to_le_bytes());
+        data[9..13].copy_from_slice(&(HEADER_SIZE as u32 + 1).to_le_bytes());
+        data[13..17].copy_from_slice(&(ipv6.len() as u32 - 1).to_le_bytes());
+        data[17..21].copy_from_slice(&((HEADER_SIZE + ipv4_size) as u32 + 1).to_le_bytes());
+
+        let mut strings = Vec::new();
+        let rows = ipv4
+            .iter()
+            .map(|row| (row, false))
+            .chain(ipv6.iter().map(|row| (row, true)));
+        for ((start, place), ipv6) in rows {
+            let (code, name, region, city, latitude, longitude) = *place;
+            if ipv6 {
+                data.extend(start.to_le_bytes());
+            } else {
+                data.extend((*start as u32).to_le_bytes());
+            }
+            data.extend(push_strings(&mut strings, strings_start, &[code, name]).to_le_bytes());
+            data.extend(push_strings(&mut strings, strings_start, &[region]).to_le_bytes());
+            data.extend(push_strings(&mut strings, strings_start, &[city]).to_le_bytes());
+            data.extend(latitude.to_le_bytes());
+            data.extend(longitude.to_le_bytes());
+        }
+        data.extend(strings);
+        data
+    }
+
+    // Where the strings start in the fixture, after five rows in each table
+    const TABLES_END: usize = HEADER_SIZE + 5 * 6 * 4 + 5 * (6 * 4 + 12);
+
+    fn lookup(ip: &str) -> Option<Ip2LocationRecord> {
+        Ip2LocationDb::from_bytes(fixture())
+            .unwrap()
+            .lookup(ip.parse().unwrap())
+    }
+
+    fn assert_place(record: Option<Ip2LocationRecord>, place: Place) {
+        let record = record.expect("address not found");
+        let (code, name, region, city, latitude, longitude) = place;
+        assert_eq!(record.country_code.as_deref(), Some(code));
+        assert_eq!(record.country_name.as_deref(), Some(name));
+        assert_eq!(record.region.as_deref(), Some(region));
+        assert_eq!(record.city.as_deref(), Some(city));
+        assert!((record.latitude.unwrap() - latitude as f64).abs() < 1e-5);
+        assert!((record.longitude.unwrap() - longitude as f64).abs() < 1e-5);
+    }
+
+    #[test]
+    fn finds_ipv4_addresses() {
+        assert_place(lookup("1.2.3.4"), BRISBANE);
+        assert_place(lookup("8.8.8.8"), MOUNTAIN_VIEW);
+        assert_place(lookup("8.8.7.255"), BRISBANE);
+        assert!(lookup("0.0.0.1").is_none());
+    }
+
+    #[test]
+    fn finds_ipv6_addresses() {
+        assert_place(lookup("2001:db8::1"), BERLIN);
+        assert!(lookup("2001:db9::1").is_none());
+        assert!(lookup("::2").is_none());
+    }
+
+    #[test]
+    fn finds_mapped_ipv4_addresses_in_the_ipv4_table() {
+        assert_place(lookup("::ffff:1.2.3.4"), BRISBANE);
+        assert_place(lookup("::ffff:200.1.2.3"), SAO_PAULO);
+    }
+
+    #[test]
+    fn finds_the_last_range() {
+        assert_place(lookup("200.0.0.0"), SAO_PAULO);
+        assert_place(lookup("255.255.255.255"), SAO_PAULO);
+        assert_place(lookup("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"), TOKYO);
+    }
+
+    #[test]
+    fn refuses_truncated_files() {
+        let data = fixture();
+        for length in 0..TABLES_END {
+            assert!(
+                Ip2LocationDb::from_bytes(data[..length].to_vec()).is_err(),
+                "{} bytes were accepted",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn lookups_in_truncated_strings_stay_in_bounds() {
+        let data = fixture();
+        for length in TABLES_END..data.len() {
+            let database = Ip2LocationDb::from_bytes(data[..length].to_vec()).unwrap();
+            for ip in [
+                "1.2.3.4",
+                "255.255.255.255",
+                "2001:db8::1",
+                "::ffff:8.8.8.8",
+            ] {
+                database.lookup(ip.parse().unwrap());
+            }
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use chrono::{NaiveDate, NaiveDateTime, Timelike, Utc};
+use lru::LruCache;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Events accepted per collector in the current hour, so a client stuck in a
+// loop can't flood the events table. The counts start over every hour,
+// and only the TRACKED_COLLECTORS most recently counted are kept.
+pub struct CollectorCaps {
+    counts: Mutex<(NaiveDateTime, LruCache<String, usize>)>,
+}
+
+impl CollectorCaps {
+    pub fn new() -> Self {
+        CollectorCaps {
+            counts: Mutex::new((
+                Self::current_hour(),
+                LruCache::new(NonZeroUsize::new(TRACKED_COLLECTORS).unwrap()),
+            )),
+        }
+    }
+
+    // The start of the current UTC hour
+    fn current_hour() -> NaiveDateTime {
+        let now = Utc::now();
+        now.date_naive().and_hms_opt(now.hour(), 0, 0).unwrap()
+    }
+
+    // Counts an event of `collector_id`, false once it is over `cap` for the
+    // hour. A cap of 0 allows any number.
+    pub fn allow(&self, collector_id: &str, cap: usize) -> bool {
+        if cap == 0 {
+            return true;
+        }
+
+        let hour = Self::current_hour();
+        let mut counts = self.counts.lock().unwrap();
+        let (counted_hour, collectors) = &mut *counts;
+        if *counted_hour != hour {
+            *counted_hour = hour;
+            collectors.clear();
+        }
+
+        let count = collectors.get_or_insert_mut(collector_id.to_string(), || 0);
+        if *count >= cap {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+// Collectors whose rates are tracked, the least recently seen are forgotten
+const TRACKED_COLLECTORS: usize = 10_000;
+
+// Sites whose events are counted against a quota each day
+const TRACKED_ORIGINS: usize = 10_000;
+
+// Throttles collectors sending events faster than they could by hand or
+// from stats.js, by collector rather than IP address as many visitors can
+// share one behind NAT. Each collector gets a bucket of `per_minute` events
+// that refills continuously.
+pub struct CollectorRates {
+    buckets: Mutex<LruCache<String, (f64, Instant)>>,
+}
+
+impl CollectorRates {
+    pub fn new() -> Self {
+        CollectorRates {
+            buckets: Mutex::new(LruCache::new(
+                NonZeroUsize::new(TRACKED_COLLECTORS).unwrap(),
+            )),
+        }
+    }
+
+    // Takes an event from the bucket of `collector_id`. Err with the seconds
+    // until the next one is allowed when it's empty. A limit of 0 allows any
+    // rate.
+    pub fn check(&self, collector_id: &str, per_minute: usize) -> Result<(), u64> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+
+        let capacity = per_minute as f64;
+        let per_second = capacity / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, last) =
+            buckets.get_or_insert_mut(collector_id.to_string(), || (capacity, now));
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * per_second).min(capacity);
+        *last = now;
+
+        if *tokens < 1.0 {
+            return Err(((1.0 - *tokens) / per_second).ceil() as u64);
+        }
+        *tokens -= 1.0;
+        Ok(())
+    }
+}
+
+// Collectors that sent an event recently, so /collect only has to ask the
+// database whether a collector expired when it hasn't seen it in a while
+pub struct RecentCollectors {
+    seen: Mutex<LruCache<String, Instant>>,
+}
+
+impl RecentCollectors {
+    pub fn new() -> Self {
+        RecentCollectors {
+            seen: Mutex::new(LruCache::new(
+                NonZeroUsize::new(TRACKED_COLLECTORS).unwrap(),
+            )),
+        }
+    }
+
+    // Whether `collector_id` was seen in the last `within`
+    pub fn seen_within(&self, collector_id: &str, within: Duration) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        seen.get(collector_id)
+            .is_some_and(|last| last.elapsed() < within)
+    }
+
+    pub fn seen(&self, collector_id: &str) {
+        let mut seen = self.seen.lock().unwrap();
+        seen.put(collector_id.to_string(), Instant::now());
+    }
+}
+
+// The origins collectors were created for, so quotas don't have to ask the
+// database for every event
+pub struct CollectorOrigins {
+    origins: Mutex<LruCache<String, String>>,
+}
+
+impl CollectorOrigins {
+    pub fn new() -> Self {
+        CollectorOrigins {
+            origins: Mutex::new(LruCache::new(
+                NonZeroUsize::new(TRACKED_COLLECTORS).unwrap(),
+            )),
+        }
+    }
+
+    pub fn get(&self, collector_id: &str) -> Option<String> {
+        self.origins.lock().unwrap().get(collector_id).cloned()
+    }
+
+    pub fn put(&self, collector_id: &str, origin: &str) {
+        let mut origins = self.origins.lock().unwrap();
+        origins.put(collector_id.to_string(), origin.to_string());
+    }
+}
+
+// Events accepted per site today, so one noisy site can't starve the others
+// on a shared server. At most TRACKED_ORIGINS sites are counted a day,
+// events of any more are refused so the counts can't grow without bound.
+// Counted in memory from the start of the UTC day, or
+// from when the server started if that was later.
+pub struct OriginQuotas {
+    counts: Mutex<(NaiveDate, HashMap<String, usize>)>,
+}
+
+#[derive(Serialize)]
+pub struct QuotaUsage {
+    pub events: usize,
+    pub quota: usize,
+    pub exceeded: bool,
+}
+
+impl OriginQuotas {
+    pub fn new() -> Self {
+        OriginQuotas {
+            counts: Mutex::new((Utc::now().date_naive(), HashMap::new())),
+        }
+    }
+
+    // Counts an event of the site with `origin`, false once it is over its
+    // quota for the day. Sites without a quota aren't counted.
+    pub fn allow(&self, origin: &str, quota: Option<usize>) -> bool {
+        let Some(quota) = quota else {
+            return true;
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let origins = Self::today(&mut counts);
+        if !origins.contains_key(origin) && origins.len() >= TRACKED_ORIGINS {
+            return false;
+        }
+        let count = origins.entry(origin.to_string()).or_insert(0);
+        if *count >= quota {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    // Today's events of the sites with a quota, by origin
+    pub fn usage(&self, quota: impl Fn(&str) -> Option<usize>) -> BTreeMap<String, QuotaUsage> {
+        let mut counts = self.counts.lock().unwrap();
+        Self::today(&mut counts)
+            .iter()
+            .filter_map(|(origin, events)| {
+                quota(origin).map(|quota| {
+                    let usage = QuotaUsage {
+                        events: *events,
+                        quota,
+                        exceeded: *events >= quota,
+                    };
+                    (origin.clone(), usage)
+                })
+            })
+            .collect()
+    }
+
+    fn today(counts: &mut (NaiveDate, HashMap<String, usize>)) -> &mut HashMap<String, usize> {
+        let today = Utc::now().date_naive();
+        if counts.0 != today {
+            *counts = (today, HashMap::new());
+        }
+        &mut counts.1
+    }
+}
+
+// Failed logins allowed before each further one has to wait
+const FREE_LOGIN_ATTEMPTS: u32 = 5;
+
+// The longest a login is held back
+const MAX_LOGIN_DELAY: Duration = Duration::from_secs(15 * 60);
+
+// Failed logins by client IP and by username, so passwords can't be
+// guessed quickly from one address or against one account from many. After
+// FREE_LOGIN_ATTEMPTS failures each attempt has to wait twice as long as
+// the one before, up to MAX_LOGIN_DELAY. A successful login starts over.
+pub struct LoginAttempts {
+    failures: Mutex<LruCache<String, (u32, Instant)>>,
+}
+
+impl LoginAttempts {
+    pub fn new() -> Self {
+        LoginAttempts {
+            failures: Mutex::new(LruCache::new(
+                NonZeroUsize::new(TRACKED_COLLECTORS).unwrap(),
+            )),
+        }
+    }
+
+    // Err with the seconds until `key` may try again
+    pub fn check(&self, key: &str) -> Result<(), u64> {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(key) {
+            Some((_, until)) if *until > Instant::now() => {
+                Err(until.duration_since(Instant::now()).as_secs().max(1))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn failed(&self, key: &str) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let (count, until) = failures.get_or_insert_mut(key.to_string(), || (0, now));
+        *count += 1;
+        if *count > FREE_LOGIN_ATTEMPTS {
+            let doublings = (*count - FREE_LOGIN_ATTEMPTS - 1).min(10);
+            *until = now + Duration::from_secs(1 << doublings).min(MAX_LOGIN_DELAY);
+        }
+    }
+
+    pub fn succeeded(&self, key: &str) {
+        self.failures.lock().unwrap().pop(key);
+    }
+}
@@ -0,0 +1,78 @@
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer};
+
+#[derive(QueryableByName)]
+pub struct Checkpoint {
+    // 1 when readers kept the checkpoint from finishing, it's retried next run
+    #[diesel(sql_type = Integer)]
+    pub busy: i32,
+    // Frames in the WAL, and how many of them were written to the database
+    #[diesel(sql_type = Integer)]
+    pub log: i32,
+    #[diesel(sql_type = Integer)]
+    pub checkpointed: i32,
+}
+
+// Writes the WAL back into the database and truncates it, otherwise it keeps
+// growing as long as events arrive faster than automatic checkpoints finish
+pub fn checkpoint_wal(conn: &mut SqliteConnection) -> QueryResult<Checkpoint> {
+    diesel::sql_query("PRAGMA wal_checkpoint(TRUNCATE)").get_result(conn)
+}
+
+#[derive(QueryableByName)]
+struct AutoVacuum {
+    #[diesel(sql_type = Integer)]
+    auto_vacuum: i32,
+}
+
+#[derive(QueryableByName)]
+struct FreelistCount {
+    #[diesel(sql_type = BigInt)]
+    freelist_count: i64,
+}
+
+#[derive(QueryableByName)]
+struct PageSize {
+    #[diesel(sql_type = BigInt)]
+    page_size: i64,
+}
+
+const AUTO_VACUUM_INCREMENTAL: i32 = 2;
+
+pub struct Vacuum {
+    pub pages: i64,
+    pub bytes: i64,
+}
+
+// Returns the free pages left behind by deleted rows to the file system. Only
+// works once `stats vacuum` switched the database to incremental auto-vacuum,
+// returns None before that.
+pub fn incremental_vacuum(conn: &mut SqliteConnection) -> QueryResult<Option<Vacuum>> {
+    let mode: AutoVacuum = diesel::sql_query("PRAGMA auto_vacuum").get_result(conn)?;
+    if mode.auto_vacuum != AUTO_VACUUM_INCREMENTAL {
+        return Ok(None);
+    }
+
+    let free_pages = |conn: &mut SqliteConnection| {
+        diesel::sql_query("PRAGMA freelist_count")
+            .get_result::<FreelistCount>(conn)
+            .map(|count| count.freelist_count)
+    };
+    let before = free_pages(conn)?;
+    // Run through sqlite3_exec, a prepared statement frees a single page per step
+    conn.batch_execute("PRAGMA incremental_vacuum")?;
+    let pages = before - free_pages(conn)?;
+
+    let page_size: PageSize = diesel::sql_query("PRAGMA page_size").get_result(conn)?;
+    Ok(Some(Vacuum {
+        pages,
+        bytes: pages * page_size.page_size,
+    }))
+}
+
+// Switches the database to incremental auto-vacuum. That takes a full VACUUM,
+// which rewrites the whole file and blocks writers until it's done.
+pub fn enable_incremental_vacuum(conn: &mut SqliteConnection) -> QueryResult<()> {
+    conn.batch_execute("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")
+}
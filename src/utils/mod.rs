@@ -1,3 +1,39 @@
+pub mod active;
+pub mod alerts;
+pub mod api_tokens;
+pub mod archive;
+pub mod auth;
+pub mod backup;
+pub mod bigquery;
+pub mod cache;
 pub mod city;
+pub mod clickhouse;
+pub mod client_ip;
+pub mod countries;
+pub mod dbip;
+pub mod export;
+pub mod fields;
 pub mod geoip;
+pub mod ingest;
+pub mod ip;
+pub mod ip2location;
+pub mod limits;
+pub mod maintenance;
+pub mod parquet;
 pub mod queue;
+pub mod retention;
+pub mod retry;
+pub mod rollup;
+pub mod runtime;
+pub mod salt;
+pub mod scheduler;
+pub mod seed;
+pub mod share;
+pub mod site_stats;
+pub mod spam;
+pub mod stream;
+pub mod udp;
+pub mod umami;
+pub mod url;
+pub mod url_rules;
+pub mod webhooks;
@@ -0,0 +1,108 @@
+use crate::utils::export::ExportRow;
+use arrow_array::builder::{
+    Float64Builder, Int32Builder, StringBuilder, TimestampMicrosecondBuilder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::error::Error;
+use std::io::Write;
+use std::sync::Arc;
+
+// Rows are buffered and written out in groups of this size
+const ROW_GROUP_SIZE: usize = 50_000;
+
+fn schema() -> SchemaRef {
+    let text = |name: &str, nullable: bool| Field::new(name, DataType::Utf8, nullable);
+    Arc::new(Schema::new(vec![
+        text("id", false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into())),
+            false,
+        ),
+        text("name", false),
+        text("url", false),
+        text("referrer", true),
+        Field::new("status", DataType::Int32, true),
+        Field::new("value", DataType::Float64, true),
+        text("currency", true),
+        text("collector_id", false),
+        text("origin", true),
+        text("country", true),
+        text("region", true),
+        text("city", true),
+        text("os", true),
+        text("browser", true),
+    ]))
+}
+
+// Writes export rows as a Snappy compressed Parquet file with the same
+// columns as the CSV export
+pub struct ParquetWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    pub fn new(out: W) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let schema = schema();
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(ROW_GROUP_SIZE)
+            .build();
+        Ok(ParquetWriter {
+            writer: ArrowWriter::try_new(out, schema.clone(), Some(properties))?,
+            schema,
+        })
+    }
+
+    pub fn write(&mut self, rows: &[ExportRow]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let text = |value: fn(&ExportRow) -> Option<&str>| -> ArrayRef {
+            let mut builder = StringBuilder::new();
+            for row in rows {
+                builder.append_option(value(row));
+            }
+            Arc::new(builder.finish())
+        };
+
+        let mut timestamps = TimestampMicrosecondBuilder::new().with_timezone("+00:00");
+        let mut statuses = Int32Builder::new();
+        let mut values = Float64Builder::new();
+        for row in rows {
+            timestamps.append_value(row.timestamp.and_utc().timestamp_micros());
+            statuses.append_option(row.status);
+            values.append_option(row.value);
+        }
+
+        let batch = RecordBatch::try_new(
+            self.schema.clone(),
+            vec![
+                text(|row| Some(&row.id)),
+                Arc::new(timestamps.finish()),
+                text(|row| Some(&row.name)),
+                text(|row| Some(&row.url)),
+                text(|row| row.referrer.as_deref()),
+                Arc::new(statuses.finish()),
+                Arc::new(values.finish()),
+                text(|row| row.currency.as_deref()),
+                text(|row| Some(&row.collector_id)),
+                text(|row| row.origin.as_deref()),
+                text(|row| row.country.as_deref()),
+                text(|row| row.region.as_deref()),
+                text(|row| row.city.as_deref()),
+                text(|row| row.os.as_deref()),
+                text(|row| row.browser.as_deref()),
+            ],
+        )?;
+        self.writer.write(&batch)?;
+        Ok(())
+    }
+
+    // Writes the buffered rows and the file footer
+    pub fn finish(self) -> Result<W, Box<dyn Error + Send + Sync>> {
+        Ok(self.writer.into_inner()?)
+    }
+}
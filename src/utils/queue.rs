@@ -1,56 +1,218 @@
+use crate::config::EventStore;
 use crate::db::DbPool;
 use crate::models::NewEvent;
+use crate::utils::active::ActiveVisitors;
+use crate::utils::cache::SummaryCache;
+use crate::utils::clickhouse::ClickHouse;
+use crate::utils::retry::Backoff;
+use crate::utils::runtime::RuntimeStatus;
+use crate::utils::stream::EventStream;
 use diesel::prelude::*;
+use log::error;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::sync::mpsc::Receiver;
 use tokio::task;
 use tokio::time::{interval, Duration};
 
-pub async fn process_events_async(mut rx: Receiver<NewEvent>, db_pool: DbPool) {
-    let batch_size = 100;
-    let batch_timeout = Duration::from_secs(5);
+pub struct QueueOptions {
+    pub batch_size: usize,
+    pub batch_timeout: Duration,
+    pub backoff: Backoff,
+    pub event_store: EventStore,
+    pub clickhouse: Arc<ClickHouse>,
+    pub stream: Arc<EventStream>,
+    // Cleared after every batch so summaries include the new events
+    pub summary_cache: Arc<SummaryCache>,
+    // Recounted after every batch so pollers see new visitors right away
+    pub active_visitors: Arc<ActiveVisitors>,
+    // Batches that still fail after every retry are appended here
+    pub dead_letter_file: PathBuf,
+}
 
-    let mut interval = interval(batch_timeout);
-    let mut batch: Vec<NewEvent> = Vec::new();
+pub async fn process_events_async(
+    mut rx: Receiver<NewEvent>,
+    db_pool: DbPool,
+    runtime: Arc<RuntimeStatus>,
+    options: QueueOptions,
+) {
+    let mut interval = interval(options.batch_timeout);
+    let mut batch: Vec<NewEvent> = Vec::with_capacity(options.batch_size);
 
     loop {
         tokio::select! {
             Some(event) = rx.recv() => {
                 batch.push(event);
-                if batch.len() >= batch_size {
+                if batch.len() >= options.batch_size {
                     let db_pool_clone = db_pool.clone();
-                    let batch_to_insert = std::mem::replace(&mut batch, Vec::new());
-                    insert_batch(batch_to_insert, db_pool_clone).await;
+                    let batch_to_insert = std::mem::take(&mut batch);
+                    insert_batch(batch_to_insert, db_pool_clone, &runtime, &options).await;
                 }
             },
             _ = interval.tick() => {
                 if !batch.is_empty() {
                     let db_pool_clone = db_pool.clone();
-                    let batch_to_insert = std::mem::replace(&mut batch, Vec::new());
-                    insert_batch(batch_to_insert, db_pool_clone).await;
+                    let batch_to_insert = std::mem::take(&mut batch);
+                    insert_batch(batch_to_insert, db_pool_clone, &runtime, &options).await;
                 }
             },
         }
     }
 }
 
-async fn insert_batch(batch: Vec<NewEvent>, db_pool: DbPool) {
-    // Use `spawn_blocking` to move the blocking operation off the async executor
-    let result = task::spawn_blocking(move || {
-        // Now that `batch` is owned, it can be moved into the closure safely
-        let mut conn = db_pool
-            .get()
-            .expect("Failed to get DB connection from pool");
-
-        // Use `batch` directly as it is now owned by this closure
-        diesel::insert_into(crate::schema::events::table)
-            .values(&batch)
-            .execute(&mut conn)
+fn insert_events(conn: &mut SqliteConnection, batch: &[NewEvent]) -> QueryResult<usize> {
+    // Large PROCESSING_BATCH_SIZE values are split up to stay under
+    // SQLite's limit on bound parameters
+    conn.transaction(|conn| {
+        let mut inserted = 0;
+        for chunk in batch.chunks(1000) {
+            inserted += diesel::insert_into(crate::schema::events::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        Ok(inserted)
     })
-    .await
-    .expect("Failed to execute block_in_place");
+}
+
+async fn insert_batch(
+    batch: Vec<NewEvent>,
+    db_pool: DbPool,
+    runtime: &RuntimeStatus,
+    options: &QueueOptions,
+) {
+    let batch = Arc::new(batch);
+    let mut failed = false;
+
+    let result = options
+        .backoff
+        .retry("Batch insert", || {
+            let db_pool = db_pool.clone();
+            let batch = batch.clone();
+            // Use `spawn_blocking` to move the blocking operation off the async executor
+            async move {
+                task::spawn_blocking(move || -> Result<usize, Box<dyn Error + Send + Sync>> {
+                    let mut conn = db_pool.get()?;
+                    Ok(insert_events(&mut conn, &batch)?)
+                })
+                .await
+                .expect("Failed to execute block_in_place")
+            }
+        })
+        .await;
+    if let Err(e) = result {
+        error!("Failed to insert batch of {} events: {:?}", batch.len(), e);
+        failed = true;
+    }
+
+    if options.event_store.writes_clickhouse() {
+        let result = options
+            .backoff
+            .retry("ClickHouse insert", || {
+                options.clickhouse.insert_events(&batch)
+            })
+            .await;
+        if let Err(e) = result {
+            error!(
+                "Failed to insert batch of {} events into ClickHouse: {:?}",
+                batch.len(),
+                e
+            );
+            failed = true;
+        }
+    }
 
-    match result {
-        Ok(_) => println!("Batch inserted successfully."),
-        Err(e) => eprintln!("Failed to insert batch: {:?}", e),
+    // The stream only mirrors the events, what can't be published is dropped
+    if options.stream.is_enabled() {
+        let result = options
+            .backoff
+            .retry("Event stream publish", || options.stream.publish(&batch))
+            .await;
+        match result {
+            Ok(()) => runtime.record_published(batch.len()),
+            Err(e) => error!(
+                "Failed to publish batch of {} events to the event stream: {:?}",
+                batch.len(),
+                e
+            ),
+        }
     }
+
+    if !failed {
+        options.summary_cache.clear();
+        options.active_visitors.refresh();
+        runtime.record_batch_insert(batch.len());
+        println!("Batch inserted successfully.");
+        return;
+    }
+
+    // Replaying is safe for the store that did get the batch, see
+    // `replay_dead_letters`
+    match write_dead_letters(&options.dead_letter_file, &batch) {
+        Ok(()) => {
+            runtime.record_dead_letters(batch.len());
+            error!(
+                "Wrote {} events to {}, replay them with `stats replay`",
+                batch.len(),
+                options.dead_letter_file.display()
+            );
+        }
+        Err(e) => error!(
+            "Failed to write dead letters to {}, {} events lost: {:?}",
+            options.dead_letter_file.display(),
+            batch.len(),
+            e
+        ),
+    }
+}
+
+// Appends the events to `path`, one JSON object per line
+fn write_dead_letters(path: &Path, batch: &[NewEvent]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut lines = Vec::new();
+    for event in batch {
+        serde_json::to_writer(&mut lines, event)?;
+        lines.push(b'\n');
+    }
+    file.write_all(&lines)
+}
+
+// Inserts the events of a dead-letter file into every configured store and
+// removes it. Events that were recorded in the meantime are skipped by SQLite
+// and merged away by ClickHouse, so a replay can safely be repeated.
+pub async fn replay_dead_letters(
+    conn: &mut SqliteConnection,
+    event_store: EventStore,
+    clickhouse: &ClickHouse,
+    path: &Path,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let file = fs::File::open(path)?;
+    let mut events = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            events.push(serde_json::from_str::<NewEvent>(&line)?);
+        }
+    }
+
+    let mut inserted = conn.transaction(|conn| {
+        let mut inserted = 0;
+        for chunk in events.chunks(1000) {
+            inserted += diesel::insert_or_ignore_into(crate::schema::events::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        Ok::<_, diesel::result::Error>(inserted)
+    })?;
+    if event_store.writes_clickhouse() {
+        inserted = clickhouse.insert_events(&events).await?;
+    }
+
+    fs::remove_file(path)?;
+    Ok(inserted)
 }
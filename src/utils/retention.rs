@@ -0,0 +1,49 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::Timestamp;
+
+// Deletes events older than `days`, then the collectors left without any
+// events. Returns the number of events and collectors removed.
+pub fn prune(conn: &mut SqliteConnection, days: usize) -> QueryResult<(usize, usize)> {
+    let cutoff = Utc::now().naive_utc() - Duration::days(days as i64);
+
+    conn.transaction(|conn| {
+        let events = diesel::sql_query("DELETE FROM events WHERE timestamp < ?")
+            .bind::<Timestamp, _>(cutoff)
+            .execute(conn)?;
+        let collectors = delete_orphan_collectors(conn, cutoff)?;
+        Ok((events, collectors))
+    })
+}
+
+// Deletes collectors from before `cutoff` that have no events left
+pub fn delete_orphan_collectors(
+    conn: &mut SqliteConnection,
+    cutoff: NaiveDateTime,
+) -> QueryResult<usize> {
+    diesel::sql_query(
+        "DELETE FROM collectors
+        WHERE timestamp < ?
+        AND NOT EXISTS (SELECT 1 FROM events e WHERE e.collector_id = collectors.id)",
+    )
+    .bind::<Timestamp, _>(cutoff)
+    .execute(conn)
+}
+
+// Strips the details that could single out a visitor from collectors older
+// than `days`: city, coordinates, OS, browser and the visitor hash. Origin,
+// country, region and network stay, so long-range aggregates keep working.
+pub fn anonymize_collectors(conn: &mut SqliteConnection, days: usize) -> QueryResult<usize> {
+    let cutoff = Utc::now().naive_utc() - Duration::days(days as i64);
+
+    diesel::sql_query(
+        "UPDATE collectors
+        SET city = 'Unknown', latitude = NULL, longitude = NULL,
+            os = NULL, browser = NULL, visitor_hash = NULL
+        WHERE timestamp < ?
+        AND (city != 'Unknown' OR latitude IS NOT NULL OR longitude IS NOT NULL
+            OR os IS NOT NULL OR browser IS NOT NULL OR visitor_hash IS NOT NULL)",
+    )
+    .bind::<Timestamp, _>(cutoff)
+    .execute(conn)
+}
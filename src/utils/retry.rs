@@ -0,0 +1,59 @@
+use crate::config::Config;
+use log::warn;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::sleep;
+
+// Upper bound for a single wait, however many attempts are configured
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+// Retries with exponential backoff, for work that can fail transiently,
+// e.g. while another connection holds the SQLite write lock
+#[derive(Clone, Copy)]
+pub struct Backoff {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Backoff {
+    pub fn from_config(config: &Config) -> Self {
+        Backoff {
+            max_attempts: config.retry_max_attempts.max(1),
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+        }
+    }
+
+    // Wait after the `attempt`th failure: base, 2 × base, 4 × base, ...
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(MAX_DELAY)
+    }
+
+    // Runs `f` until it succeeds or has failed `max_attempts` times, returning
+    // the last error then
+    pub async fn retry<T, E, F, Fut>(&self, what: &str, mut f: F) -> Result<T, E>
+    where
+        E: Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_attempts => {
+                    let delay = self.delay(attempt);
+                    warn!(
+                        "{} failed (attempt {} of {}), retrying in {:?}: {}",
+                        what, attempt, self.max_attempts, delay, e
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
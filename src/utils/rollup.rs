@@ -0,0 +1,316 @@
+use crate::models::MEASUREMENT_EVENT_NAMES;
+use crate::schema::rollups;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
+use diesel::sql_types::{Nullable, Timestamp};
+use diesel::sqlite::Sqlite;
+
+// Shorter ranges are cheap enough to count from the raw events, which are
+// exact up to the last second
+const MIN_ROLLUP_RANGE_HOURS: i64 = 48;
+
+// Events reach the database in batches, so an hour is only rolled up once
+// this long has passed since it ended
+const LATE_EVENTS_GRACE_MINUTES: i64 = 10;
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Hourly,
+    Daily,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Hourly => "hourly",
+            Level::Daily => "daily",
+        }
+    }
+
+    fn table(&self) -> &'static str {
+        match self {
+            Level::Hourly => "stats_hourly",
+            Level::Daily => "stats_daily",
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            Level::Hourly => "hour",
+            Level::Daily => "day",
+        }
+    }
+
+    fn seconds(&self) -> i64 {
+        match self {
+            Level::Hourly => 60 * 60,
+            Level::Daily => 24 * 60 * 60,
+        }
+    }
+
+    // Start of the period that contains `time`
+    fn floor(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let seconds = time.and_utc().timestamp();
+        DateTime::from_timestamp(seconds - seconds.rem_euclid(self.seconds()), 0)
+            .map(|t| t.naive_utc())
+            .unwrap_or(time)
+    }
+
+    // Start of the first period that begins at or after `time`
+    fn ceil(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let floor = self.floor(time);
+        if floor == time {
+            floor
+        } else {
+            floor + Duration::seconds(self.seconds())
+        }
+    }
+}
+
+// Quoted, comma separated event names for use in an `IN (...)` clause
+pub fn sql_name_list(names: &[&str]) -> String {
+    names
+        .iter()
+        .map(|name| format!("'{}'", name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn rolled_up_to(
+    conn: &mut SqliteConnection,
+    level: Level,
+) -> QueryResult<Option<NaiveDateTime>> {
+    rollups::table
+        .find(level.name())
+        .select(rollups::rolled_up_to)
+        .first(conn)
+        .optional()
+}
+
+fn set_rolled_up_to(
+    conn: &mut SqliteConnection,
+    level: Level,
+    to: NaiveDateTime,
+) -> QueryResult<()> {
+    diesel::replace_into(rollups::table)
+        .values((rollups::name.eq(level.name()), rollups::rolled_up_to.eq(to)))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Recounts the hours in `[from, to)` from the events table
+fn roll_up_hours(
+    conn: &mut SqliteConnection,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> QueryResult<usize> {
+    diesel::sql_query("DELETE FROM stats_hourly WHERE hour >= ? AND hour < ?")
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .execute(conn)?;
+
+    diesel::sql_query(format!(
+        "INSERT INTO stats_hourly (hour, url, events)
+        SELECT strftime('%Y-%m-%d %H:00:00', timestamp), url, COUNT(*)
+        FROM events
+        WHERE timestamp >= ? AND timestamp < ?
+        AND name NOT IN ({})
+        GROUP BY 1, url",
+        sql_name_list(MEASUREMENT_EVENT_NAMES)
+    ))
+    .bind::<Timestamp, _>(from)
+    .bind::<Timestamp, _>(to)
+    .execute(conn)
+}
+
+// Recounts the days in `[from, to)` from the hourly rollup
+fn roll_up_days(
+    conn: &mut SqliteConnection,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> QueryResult<usize> {
+    diesel::sql_query("DELETE FROM stats_daily WHERE day >= ? AND day < ?")
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .execute(conn)?;
+
+    diesel::sql_query(
+        "INSERT INTO stats_daily (day, url, events)
+        SELECT strftime('%Y-%m-%d 00:00:00', hour), url, SUM(events)
+        FROM stats_hourly
+        WHERE hour >= ? AND hour < ?
+        GROUP BY 1, url",
+    )
+    .bind::<Timestamp, _>(from)
+    .bind::<Timestamp, _>(to)
+    .execute(conn)
+}
+
+// Rolls up the hours in `[from, to)` and the days they complete. Returns the
+// number of hourly rows written.
+fn roll_up(
+    conn: &mut SqliteConnection,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> QueryResult<usize> {
+    if from >= to {
+        return Ok(0);
+    }
+
+    conn.transaction(|conn| {
+        let rows = roll_up_hours(conn, from, to)?;
+        set_rolled_up_to(conn, Level::Hourly, to)?;
+
+        let (day_from, day_to) = (Level::Daily.floor(from), Level::Daily.floor(to));
+        if day_from < day_to {
+            roll_up_days(conn, day_from, day_to)?;
+            set_rolled_up_to(conn, Level::Daily, day_to)?;
+        }
+        Ok(rows)
+    })
+}
+
+#[derive(QueryableByName)]
+struct FirstEvent {
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    timestamp: Option<NaiveDateTime>,
+}
+
+fn rollup_end() -> NaiveDateTime {
+    Level::Hourly.floor(Utc::now().naive_utc() - Duration::minutes(LATE_EVENTS_GRACE_MINUTES))
+}
+
+// Rolls up the events recorded since the last run. The last rolled up hour
+// is counted again to pick up events that were still queued at the time.
+pub fn update_rollups(conn: &mut SqliteConnection) -> QueryResult<usize> {
+    let from = match rolled_up_to(conn, Level::Hourly)? {
+        Some(rolled_up_to) => rolled_up_to - Duration::hours(1),
+        None => return rebuild_rollups(conn, None),
+    };
+    roll_up(conn, from, rollup_end())
+}
+
+// Recounts the rollups from `from`, or from the oldest event, e.g. after
+// importing events. Rollups older than that are kept, so they outlive events
+// removed by `stats prune`.
+pub fn rebuild_rollups(
+    conn: &mut SqliteConnection,
+    from: Option<NaiveDateTime>,
+) -> QueryResult<usize> {
+    let from = match from {
+        Some(from) => from,
+        None => {
+            let first: FirstEvent =
+                diesel::sql_query("SELECT MIN(timestamp) AS timestamp FROM events")
+                    .get_result(conn)?;
+            match first.timestamp {
+                Some(timestamp) => timestamp,
+                None => return Ok(0),
+            }
+        }
+    };
+    roll_up(conn, Level::Hourly.floor(from), rollup_end())
+}
+
+// Non-measurement events in `(start, end]`, as a subquery with `timestamp`,
+// `url` and `count` columns to be summed up. Ranges longer than 48 hours read
+// their whole hours or days from a rollup and only the edges from the events.
+pub enum CountedEvents {
+    Raw {
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    },
+    RolledUp {
+        level: Level,
+        start: NaiveDateTime,
+        rollup_start: NaiveDateTime,
+        rollup_end: NaiveDateTime,
+        end: NaiveDateTime,
+    },
+}
+
+impl CountedEvents {
+    // `level` is the coarsest rollup the caller can group by, or None to
+    // always count raw events
+    pub fn new(
+        conn: &mut SqliteConnection,
+        level: Option<Level>,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> QueryResult<Self> {
+        let level = match level {
+            Some(level) if end - start > Duration::hours(MIN_ROLLUP_RANGE_HOURS) => level,
+            _ => return Ok(CountedEvents::Raw { start, end }),
+        };
+        let rolled_up_to = match rolled_up_to(conn, level)? {
+            Some(rolled_up_to) => rolled_up_to,
+            None => return Ok(CountedEvents::Raw { start, end }),
+        };
+
+        let rollup_start = level.ceil(start);
+        let rollup_end = level.floor(end).min(rolled_up_to);
+        if rollup_start >= rollup_end {
+            return Ok(CountedEvents::Raw { start, end });
+        }
+
+        Ok(CountedEvents::RolledUp {
+            level,
+            start,
+            rollup_start,
+            rollup_end,
+            end,
+        })
+    }
+
+    // `filter` is added to the conditions on the raw events, e.g. to leave out
+    // datacenter traffic. Rollups can't be filtered, pass `level: None` with it.
+    pub fn sql(&self, filter: &str) -> String {
+        let names = sql_name_list(MEASUREMENT_EVENT_NAMES);
+        match self {
+            CountedEvents::Raw { .. } => format!(
+                "SELECT timestamp, url, 1 AS count FROM events
+                WHERE timestamp > ? AND timestamp <= ? {} AND name NOT IN ({})",
+                filter, names
+            ),
+            CountedEvents::RolledUp { level, .. } => format!(
+                "SELECT timestamp, url, 1 AS count FROM events
+                WHERE timestamp > ? AND timestamp < ? AND name NOT IN ({0})
+                UNION ALL
+                SELECT {1} AS timestamp, url, events AS count FROM {2}
+                WHERE {1} >= ? AND {1} < ?
+                UNION ALL
+                SELECT timestamp, url, 1 AS count FROM events
+                WHERE timestamp >= ? AND timestamp <= ? AND name NOT IN ({0})",
+                names,
+                level.column(),
+                level.table()
+            ),
+        }
+    }
+
+    // Binds the parameters of `sql`
+    pub fn bind<'a>(
+        &self,
+        query: BoxedSqlQuery<'a, Sqlite, SqlQuery>,
+    ) -> BoxedSqlQuery<'a, Sqlite, SqlQuery> {
+        match *self {
+            CountedEvents::Raw { start, end } => {
+                query.bind::<Timestamp, _>(start).bind::<Timestamp, _>(end)
+            }
+            CountedEvents::RolledUp {
+                start,
+                rollup_start,
+                rollup_end,
+                end,
+                ..
+            } => query
+                .bind::<Timestamp, _>(start)
+                .bind::<Timestamp, _>(rollup_start)
+                .bind::<Timestamp, _>(rollup_start)
+                .bind::<Timestamp, _>(rollup_end)
+                .bind::<Timestamp, _>(rollup_end)
+                .bind::<Timestamp, _>(end),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Counters and timestamps the background tasks update as they run, reported
+// by /admin/status
+pub struct RuntimeStatus {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    events_processed: AtomicU64,
+    events_dead_lettered: AtomicU64,
+    events_published: AtomicU64,
+    events_capped: AtomicU64,
+    events_throttled: AtomicU64,
+    last_batch_insert: Mutex<Option<DateTime<Utc>>>,
+    jobs: Mutex<BTreeMap<&'static str, JobStatus>>,
+    maintenance: Mutex<MaintenanceStatus>,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct MaintenanceStatus {
+    pub last_checkpoint: Option<DateTime<Utc>>,
+    pub last_checkpoint_wal_frames: Option<i64>,
+    pub last_checkpoint_frames: Option<i64>,
+    pub last_checkpoint_busy: Option<bool>,
+    pub last_vacuum: Option<DateTime<Utc>>,
+    pub pages_reclaimed: i64,
+    pub bytes_reclaimed: i64,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct JobStatus {
+    // None while the job is disabled
+    pub interval_secs: Option<u64>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_run_succeeded: Option<bool>,
+    pub last_error: Option<String>,
+    pub runs: u64,
+    pub failures: u64,
+}
+
+impl RuntimeStatus {
+    pub fn new() -> Self {
+        RuntimeStatus {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            events_processed: AtomicU64::new(0),
+            events_dead_lettered: AtomicU64::new(0),
+            events_published: AtomicU64::new(0),
+            events_capped: AtomicU64::new(0),
+            events_throttled: AtomicU64::new(0),
+            last_batch_insert: Mutex::new(None),
+            jobs: Mutex::new(BTreeMap::new()),
+            maintenance: Mutex::new(MaintenanceStatus::default()),
+        }
+    }
+
+    pub fn record_batch_insert(&self, events: usize) {
+        self.events_processed
+            .fetch_add(events as u64, Ordering::Relaxed);
+        *self.last_batch_insert.lock().unwrap() = Some(Utc::now());
+    }
+
+    pub fn record_dead_letters(&self, events: usize) {
+        self.events_dead_lettered
+            .fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_published(&self, events: usize) {
+        self.events_published
+            .fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_capped(&self) {
+        self.events_capped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_throttled(&self) {
+        self.events_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_checkpoint(&self, wal_frames: i64, frames: i64, busy: bool) {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        maintenance.last_checkpoint = Some(Utc::now());
+        maintenance.last_checkpoint_wal_frames = Some(wal_frames);
+        maintenance.last_checkpoint_frames = Some(frames);
+        maintenance.last_checkpoint_busy = Some(busy);
+    }
+
+    pub fn record_vacuum(&self, pages: i64, bytes: i64) {
+        let mut maintenance = self.maintenance.lock().unwrap();
+        maintenance.last_vacuum = Some(Utc::now());
+        maintenance.pages_reclaimed += pages;
+        maintenance.bytes_reclaimed += bytes;
+    }
+
+    pub fn record_job_scheduled(
+        &self,
+        name: &'static str,
+        interval: Option<Duration>,
+        next_run: Option<DateTime<Utc>>,
+    ) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.entry(name).or_default();
+        job.interval_secs = interval.map(|interval| interval.as_secs());
+        job.next_run = next_run;
+    }
+
+    pub fn record_job_run(&self, name: &'static str, error: Option<String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.entry(name).or_default();
+        job.last_run = Some(Utc::now());
+        job.last_run_succeeded = Some(error.is_none());
+        job.runs += 1;
+        if error.is_some() {
+            job.failures += 1;
+        }
+        job.last_error = error;
+    }
+
+    pub fn uptime_seconds(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    pub fn events_processed(&self) -> u64 {
+        self.events_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn events_dead_lettered(&self) -> u64 {
+        self.events_dead_lettered.load(Ordering::Relaxed)
+    }
+
+    pub fn events_published(&self) -> u64 {
+        self.events_published.load(Ordering::Relaxed)
+    }
+
+    pub fn events_capped(&self) -> u64 {
+        self.events_capped.load(Ordering::Relaxed)
+    }
+
+    pub fn events_throttled(&self) -> u64 {
+        self.events_throttled.load(Ordering::Relaxed)
+    }
+
+    pub fn last_batch_insert(&self) -> Option<DateTime<Utc>> {
+        *self.last_batch_insert.lock().unwrap()
+    }
+
+    pub fn maintenance(&self) -> MaintenanceStatus {
+        self.maintenance.lock().unwrap().clone()
+    }
+
+    pub fn jobs(&self) -> BTreeMap<&'static str, JobStatus> {
+        self.jobs.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,80 @@
+use crate::schema::salts;
+use crate::utils::ip::anonymize;
+use chrono::{NaiveDate, Utc};
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// Daily rotating salt for hashing visitor identities. The salt is persisted
+// so a restart doesn't split the day's visitors, and deleted once the day is
+// over so the hashes can't be recomputed from an IP and user agent later.
+pub struct VisitorSalt {
+    cached: Mutex<Option<(NaiveDate, String)>>,
+}
+
+impl VisitorSalt {
+    pub fn new() -> Self {
+        VisitorSalt {
+            cached: Mutex::new(None),
+        }
+    }
+
+    // Today's salt, created on the first request of the day
+    pub fn current(&self, conn: &mut SqliteConnection) -> QueryResult<String> {
+        let today = Utc::now().date_naive();
+        let mut cached = self.cached.lock().unwrap();
+
+        if let Some((day, salt)) = cached.as_ref() {
+            if *day == today {
+                return Ok(salt.clone());
+            }
+        }
+
+        let new_salt: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        // Another worker may have created it first, in which case theirs is kept
+        diesel::insert_or_ignore_into(salts::table)
+            .values((salts::day.eq(today), salts::salt.eq(new_salt)))
+            .execute(conn)?;
+
+        let salt: String = salts::table
+            .filter(salts::day.eq(today))
+            .select(salts::salt)
+            .first(conn)?;
+
+        *cached = Some((today, salt.clone()));
+        Ok(salt)
+    }
+
+    // Makes sure today's salt exists and discards the ones from earlier days
+    pub fn rotate(&self, conn: &mut SqliteConnection) -> QueryResult<usize> {
+        self.current(conn)?;
+        diesel::delete(salts::table.filter(salts::day.lt(Utc::now().date_naive()))).execute(conn)
+    }
+}
+
+// Recognises a visitor without cookies or client storage, by their network,
+// browser and languages. Only the salted hash of it is kept.
+pub struct RequestSignature {
+    pub ip: IpAddr,
+    pub user_agent: String,
+    pub accept_language: String,
+}
+
+pub fn visitor_hash(salt: &str, signature: &RequestSignature) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    // The network rather than the address, so a visitor whose address
+    // changes within it, like with IPv6 privacy extensions, stays the same
+    hasher.update(anonymize(signature.ip).to_string().as_bytes());
+    hasher.update(signature.user_agent.as_bytes());
+    hasher.update(signature.accept_language.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
@@ -0,0 +1,100 @@
+use crate::config::SharedConfig;
+use crate::db::DbPool;
+use crate::utils::retry::Backoff;
+use crate::utils::runtime::RuntimeStatus;
+use actix_web::web;
+use chrono::Utc;
+use diesel::SqliteConnection;
+use log::error;
+use rand::Rng;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub type JobResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+// How often a disabled job checks whether a config reload enabled it
+const DISABLED_RECHECK: Duration = Duration::from_secs(60);
+
+// Runs the background jobs, each on its own timer so a slow backup doesn't
+// hold up the others. Intervals come from JOB_INTERVALS and are looked up
+// after every run, so a config reload applies from the next run on.
+pub struct Scheduler {
+    config: web::Data<SharedConfig>,
+    runtime: Arc<RuntimeStatus>,
+}
+
+impl Scheduler {
+    pub fn new(config: web::Data<SharedConfig>, runtime: Arc<RuntimeStatus>) -> Self {
+        Scheduler { config, runtime }
+    }
+
+    // Delay before the next run, spread out by up to JOB_JITTER_SECS so jobs
+    // with the same interval don't all hit the database at once
+    fn jitter(config: &SharedConfig) -> Duration {
+        let max = config.get().job_jitter_secs;
+        Duration::from_secs(rand::thread_rng().gen_range(0..=max))
+    }
+
+    // Runs `job` right after startup and then every interval
+    pub fn spawn<F, Fut>(&self, name: &'static str, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = JobResult> + Send + 'static,
+    {
+        let config = self.config.clone();
+        let runtime = self.runtime.clone();
+
+        tokio::spawn(async move {
+            let mut delay = Self::jitter(&config);
+            loop {
+                let interval = config.get().job_interval(name);
+                let next_run = interval.map(|_| Utc::now() + delay);
+                runtime.record_job_scheduled(name, interval, next_run);
+                sleep(delay).await;
+
+                if config.get().job_interval(name).is_none() {
+                    delay = DISABLED_RECHECK;
+                    continue;
+                }
+
+                let backoff = Backoff::from_config(&config.get());
+                let result = backoff.retry(&format!("Job {}", name), &job).await;
+                if let Err(e) = &result {
+                    error!(
+                        "Job {} failed after {} attempts: {}",
+                        name, backoff.max_attempts, e
+                    );
+                }
+                runtime.record_job_run(name, result.err().map(|e| e.to_string()));
+
+                delay = match config.get().job_interval(name) {
+                    Some(interval) => interval + Self::jitter(&config),
+                    None => DISABLED_RECHECK,
+                };
+            }
+        });
+    }
+
+    // Like `spawn`, for jobs that work on the database. They run on the
+    // blocking thread pool with a pooled connection.
+    pub fn spawn_blocking<F>(&self, name: &'static str, pool: DbPool, job: F)
+    where
+        F: Fn(&mut SqliteConnection) -> JobResult + Send + Sync + 'static,
+    {
+        let job = Arc::new(job);
+        self.spawn(name, move || {
+            let pool = pool.clone();
+            let job = job.clone();
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    let mut conn = pool.get()?;
+                    job(&mut conn)
+                })
+                .await?
+            }
+        });
+    }
+}
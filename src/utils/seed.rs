@@ -0,0 +1,132 @@
+use crate::models::{Collector, NewEvent};
+use crate::schema::{collectors, events};
+use crate::utils::url::host_and_path;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use ulid::Ulid;
+
+const PAGES: &[&str] = &[
+    "/",
+    "/about",
+    "/blog",
+    "/blog/hello-world",
+    "/pricing",
+    "/contact",
+];
+const REFERRERS: &[&str] = &[
+    "https://www.google.com/",
+    "https://news.ycombinator.com/",
+    "https://twitter.com/",
+    "https://duckduckgo.com/",
+];
+const LOCATIONS: &[(&str, &str, &str, &str, f64, f64)] = &[
+    (
+        "United States",
+        "US",
+        "California",
+        "San Francisco",
+        37.77,
+        -122.42,
+    ),
+    ("United Kingdom", "GB", "England", "London", 51.51, -0.13),
+    ("Germany", "DE", "Berlin", "Berlin", 52.52, 13.40),
+    (
+        "Sri Lanka",
+        "LK",
+        "Western Province",
+        "Colombo",
+        6.93,
+        79.85,
+    ),
+    ("Japan", "JP", "Tokyo", "Tokyo", 35.68, 139.69),
+];
+const PLATFORMS: &[(&str, &str)] = &[
+    ("Mac OSX", "Safari"),
+    ("Mac OSX", "Chrome"),
+    ("Windows 10", "Chrome"),
+    ("Windows 10", "Firefox"),
+    ("iOS", "Safari"),
+    ("Android", "Chrome"),
+];
+
+// Fills the database with `visitors` made-up visitors spread over the last
+// `days` days, each viewing a few pages, so the dashboard has something to
+// show during development. Returns the number of events inserted.
+pub fn seed(
+    conn: &mut SqliteConnection,
+    origin: &str,
+    visitors: usize,
+    days: usize,
+) -> QueryResult<usize> {
+    let mut rng = rand::thread_rng();
+    let now = Utc::now().naive_utc();
+    let mut new_collectors = Vec::with_capacity(visitors);
+    let mut new_events = Vec::new();
+
+    for _ in 0..visitors {
+        let (country, country_code, region, city, latitude, longitude) =
+            *LOCATIONS.choose(&mut rng).unwrap();
+        let (os, browser) = *PLATFORMS.choose(&mut rng).unwrap();
+        let arrived = now - Duration::seconds(rng.gen_range(0..(days.max(1) as i64 * 86400)));
+        let collector_id = Ulid::new().to_string();
+
+        new_collectors.push(Collector {
+            id: collector_id.clone(),
+            origin: origin.to_string(),
+            country: country.to_string(),
+            city: city.to_string(),
+            os: Some(os.to_string()),
+            browser: Some(browser.to_string()),
+            timestamp: arrived,
+            latitude: Some(latitude),
+            longitude: Some(longitude),
+            region: Some(region.to_string()),
+            asn: None,
+            as_org: None,
+            country_code: Some(country_code.to_string()),
+            visitor_hash: None,
+            visit_id: None,
+            utm_source: None,
+            utm_campaign: None,
+        });
+
+        let mut timestamp = arrived;
+        let mut referrer = REFERRERS.choose(&mut rng).map(|r| r.to_string());
+        for i in 0..rng.gen_range(1..=5) {
+            let page = PAGES.choose(&mut rng).unwrap();
+            let url = format!("{}{}", origin, page);
+            let (host, path) = host_and_path(&url);
+            new_events.push(NewEvent {
+                id: Ulid::new().to_string(),
+                url,
+                referrer: referrer.take(),
+                name: if i == 0 { "enter" } else { "visit" }.to_string(),
+                timestamp,
+                collector_id: collector_id.clone(),
+                status: None,
+                value: None,
+                currency: None,
+                host,
+                path,
+            });
+            timestamp += Duration::seconds(rng.gen_range(5..300));
+        }
+    }
+
+    conn.transaction(|conn| {
+        // Stay well under SQLite's bound parameter limit
+        for chunk in new_collectors.chunks(500) {
+            diesel::insert_into(collectors::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        for chunk in new_events.chunks(500) {
+            diesel::insert_into(events::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+        Ok(new_events.len())
+    })
+}
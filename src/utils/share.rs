@@ -0,0 +1,90 @@
+use crate::utils::url::host_and_path;
+use chrono::{DateTime, NaiveDateTime};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+
+// Summaries a share link opens with `?share=<token>`. Only those taking a
+// `from` and `to` range, as the others count back from now and couldn't be
+// kept inside the link's range.
+pub const SHARED_SUMMARIES: &[&str] = &["/summary/timeseries", "/summary/entry-exit"];
+
+// What a share link grants: read access to one site's stats between `from`
+// and `to`, until `exp`. All three are unix timestamps.
+#[derive(Serialize, Deserialize)]
+pub struct ShareClaims {
+    pub site: String,
+    pub from: i64,
+    pub to: i64,
+    pub exp: i64,
+}
+
+impl ShareClaims {
+    pub fn new(site: &str, from: NaiveDateTime, to: NaiveDateTime, expires: NaiveDateTime) -> Self {
+        ShareClaims {
+            site: site.trim_end_matches('/').to_string(),
+            from: from.and_utc().timestamp(),
+            to: to.and_utc().timestamp(),
+            exp: expires.and_utc().timestamp(),
+        }
+    }
+
+    pub fn range(&self) -> (NaiveDateTime, NaiveDateTime) {
+        let time = |seconds| {
+            DateTime::from_timestamp(seconds, 0)
+                .unwrap_or_default()
+                .naive_utc()
+        };
+        (time(self.from), time(self.to))
+    }
+
+    // The query string of a summary with its site and range pinned to the
+    // claims: `origin` and `host` are the site's, `from` and `to` are kept
+    // inside the range and default to its ends
+    pub fn pin(&self, query: &str) -> String {
+        let (start, end) = self.range();
+        let (mut from, mut to) = (start, end);
+        let mut pinned = form_urlencoded::Serializer::new(String::new());
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let time = value.parse::<NaiveDateTime>().ok();
+            match key.as_ref() {
+                "from" => from = time.map_or(start, |t| t.clamp(start, end)),
+                "to" => to = time.map_or(end, |t| t.clamp(start, end)),
+                "origin" | "host" | "share" => {}
+                _ => {
+                    pinned.append_pair(&key, &value);
+                }
+            }
+        }
+
+        pinned.append_pair("origin", &self.site);
+        if let (Some(host), _) = host_and_path(&self.site) {
+            pinned.append_pair("host", &host);
+        }
+        pinned
+            .append_pair("from", &from.format("%Y-%m-%dT%H:%M:%S").to_string())
+            .append_pair("to", &to.format("%Y-%m-%dT%H:%M:%S").to_string())
+            .finish()
+    }
+}
+
+// Share links are JWTs signed with SHARE_LINK_SECRET, so nothing has to be
+// stored and changing the secret revokes every link at once
+pub fn sign(secret: &str, claims: &ShareClaims) -> jsonwebtoken::errors::Result<String> {
+    jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+}
+
+// The claims of a token signed with `secret` that hasn't expired yet
+pub fn verify(secret: &str, token: &str) -> jsonwebtoken::errors::Result<ShareClaims> {
+    let validation = Validation::new(Algorithm::HS256);
+    jsonwebtoken::decode(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &validation,
+    )
+    .map(|data| data.claims)
+}
@@ -0,0 +1,180 @@
+use crate::models::MEASUREMENT_EVENT_NAMES;
+use crate::utils::rollup::sql_name_list;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
+use diesel::sql_types::{BigInt, Integer, Text, Timestamp};
+use diesel::sqlite::Sqlite;
+use serde::Serialize;
+
+#[derive(QueryableByName, Serialize)]
+pub struct LabelCount {
+    #[diesel(sql_type = Text)]
+    pub label: String,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+}
+
+#[derive(QueryableByName)]
+struct Totals {
+    #[diesel(sql_type = BigInt)]
+    pageviews: i64,
+    #[diesel(sql_type = BigInt)]
+    visitors: i64,
+}
+
+// Aggregates of one site's events in `[from, to)`, counted from the raw
+// events since rollups aren't kept per site
+#[derive(Serialize)]
+pub struct SiteStats {
+    pub site: String,
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub pageviews: i64,
+    pub visitors: i64,
+    pub days: Vec<LabelCount>,
+    pub pages: Vec<LabelCount>,
+    pub referrers: Vec<LabelCount>,
+    pub countries: Vec<LabelCount>,
+    pub browsers: Vec<LabelCount>,
+}
+
+// Events of `site`, an origin like `https://example.com`, that count as
+// traffic. Binds from, to and the site, see `bind_scope`.
+fn scope() -> String {
+    format!(
+        "e.timestamp >= ? AND e.timestamp < ?
+        AND (e.url = ? OR substr(e.url, 1, ?) = ?)
+        AND e.name NOT IN ({})",
+        sql_name_list(MEASUREMENT_EVENT_NAMES)
+    )
+}
+
+fn bind_scope<'a>(
+    query: BoxedSqlQuery<'a, Sqlite, SqlQuery>,
+    site: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> BoxedSqlQuery<'a, Sqlite, SqlQuery> {
+    let prefix = format!("{}/", site);
+    query
+        .bind::<Timestamp, _>(from)
+        .bind::<Timestamp, _>(to)
+        .bind::<Text, _>(site.to_string())
+        .bind::<Integer, _>(prefix.len() as i32)
+        .bind::<Text, _>(prefix)
+}
+
+// Top `limit` values of the `label` column, counting events or, with
+// `visitors`, distinct collectors
+fn top(
+    conn: &mut SqliteConnection,
+    site: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    label: &str,
+    visitors: bool,
+    limit: i64,
+) -> QueryResult<Vec<LabelCount>> {
+    let count = if visitors {
+        "COUNT(DISTINCT e.collector_id)"
+    } else {
+        "COUNT(*)"
+    };
+    let sql = format!(
+        "SELECT {0} AS label, {1} AS count
+        FROM events e JOIN collectors c ON c.id = e.collector_id
+        WHERE {2} AND {0} IS NOT NULL
+        GROUP BY {0}
+        ORDER BY count DESC, label ASC
+        LIMIT ?",
+        label,
+        count,
+        scope()
+    );
+    bind_scope(diesel::sql_query(sql).into_boxed(), site, from, to)
+        .bind::<BigInt, _>(limit)
+        .load(conn)
+}
+
+// Pageviews and visitors of the site
+pub fn site_totals(
+    conn: &mut SqliteConnection,
+    site: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> QueryResult<(i64, i64)> {
+    let totals: Totals = bind_scope(
+        diesel::sql_query(format!(
+            "SELECT COUNT(*) AS pageviews, COUNT(DISTINCT e.collector_id) AS visitors
+            FROM events e WHERE {}",
+            scope()
+        ))
+        .into_boxed(),
+        site.trim_end_matches('/'),
+        from,
+        to,
+    )
+    .get_result(conn)?;
+    Ok((totals.pageviews, totals.visitors))
+}
+
+// Only traffic numbers and top lists are included, never visitor level data
+pub fn load_site_stats(
+    conn: &mut SqliteConnection,
+    site: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    limit: i64,
+) -> QueryResult<SiteStats> {
+    let site = site.trim_end_matches('/');
+    let (pageviews, visitors) = site_totals(conn, site, from, to)?;
+
+    let days: Vec<LabelCount> = bind_scope(
+        diesel::sql_query(format!(
+            "SELECT date(e.timestamp) AS label, COUNT(*) AS count
+            FROM events e WHERE {}
+            GROUP BY label ORDER BY label",
+            scope()
+        ))
+        .into_boxed(),
+        site,
+        from,
+        to,
+    )
+    .load(conn)?;
+
+    // Internal navigation isn't a referrer
+    let prefix = format!("{}/", site);
+    let referrers: Vec<LabelCount> = bind_scope(
+        diesel::sql_query(format!(
+            "SELECT e.referrer AS label, COUNT(*) AS count
+            FROM events e WHERE {}
+            AND e.referrer IS NOT NULL AND e.referrer != ''
+            AND substr(e.referrer, 1, ?) != ?
+            GROUP BY label ORDER BY count DESC, label ASC LIMIT ?",
+            scope()
+        ))
+        .into_boxed(),
+        site,
+        from,
+        to,
+    )
+    .bind::<Integer, _>(prefix.len() as i32)
+    .bind::<Text, _>(prefix)
+    .bind::<BigInt, _>(limit)
+    .load(conn)?;
+
+    Ok(SiteStats {
+        site: site.to_string(),
+        from,
+        to,
+        pageviews,
+        visitors,
+        days,
+        pages: top(conn, site, from, to, "e.url", false, limit)?,
+        referrers,
+        countries: top(conn, site, from, to, "c.country", true, limit)?,
+        browsers: top(conn, site, from, to, "c.browser", true, limit)?,
+    })
+}
@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+use url::Url;
+
+// Referrer spam domains, dropped when events are recorded so they never reach
+// the events table. Starts from the configured domains and can be refreshed
+// from a published list, e.g. https://github.com/matomo-org/referrer-spam-list
+pub struct ReferrerBlocklist {
+    configured: RwLock<HashSet<String>>,
+    downloaded: RwLock<HashSet<String>>,
+    domains: RwLock<HashSet<String>>,
+}
+
+impl ReferrerBlocklist {
+    pub fn new(domains: &[String]) -> Self {
+        let blocklist = ReferrerBlocklist {
+            configured: RwLock::new(HashSet::new()),
+            downloaded: RwLock::new(HashSet::new()),
+            domains: RwLock::new(HashSet::new()),
+        };
+        blocklist.configure(domains);
+        blocklist
+    }
+
+    // Replaces the configured part of the list, e.g. after a config reload
+    pub fn configure(&self, domains: &[String]) {
+        *self.configured.write().unwrap() = domains.iter().map(|d| d.to_lowercase()).collect();
+        self.merge();
+    }
+
+    fn merge(&self) {
+        let mut domains = self.configured.read().unwrap().clone();
+        domains.extend(self.downloaded.read().unwrap().iter().cloned());
+        *self.domains.write().unwrap() = domains;
+    }
+
+    // Whether the referrer's host, or any domain it is a subdomain of, is listed
+    pub fn is_spam(&self, referrer: &str) -> bool {
+        let host = match Url::parse(referrer)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+        {
+            Some(host) => host,
+            None => return false,
+        };
+
+        let domains = self.domains.read().unwrap();
+        let mut candidate = host.as_str();
+        loop {
+            if domains.contains(candidate) {
+                return true;
+            }
+            match candidate.split_once('.') {
+                // stop before checking the bare top-level domain
+                Some((_, parent)) if parent.contains('.') => candidate = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    // Replaces the downloaded part of the list, one domain per line
+    pub async fn refresh(&self, list_url: &str) -> Result<usize, reqwest::Error> {
+        let body = reqwest::get(list_url)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let downloaded: HashSet<String> = body
+            .lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+
+        let count = downloaded.len();
+        *self.downloaded.write().unwrap() = downloaded;
+        self.merge();
+        Ok(count)
+    }
+}
@@ -0,0 +1,93 @@
+use crate::config::Config;
+use crate::models::NewEvent;
+use std::error::Error;
+
+#[cfg(feature = "kafka")]
+use rdkafka::config::ClientConfig;
+#[cfg(feature = "kafka")]
+use rdkafka::producer::{FutureProducer, FutureRecord};
+#[cfg(feature = "kafka")]
+use std::time::Duration;
+
+// Mirrors accepted events to a Kafka topic or NATS subject, one JSON message
+// per event. Only brokers enabled with their cargo feature are available.
+pub enum EventStream {
+    Disabled,
+    #[cfg(feature = "kafka")]
+    Kafka {
+        producer: FutureProducer,
+        topic: String,
+    },
+    #[cfg(feature = "nats")]
+    Nats {
+        client: async_nats::Client,
+        subject: String,
+    },
+}
+
+impl EventStream {
+    pub async fn connect(config: &Config) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        match config.event_stream {
+            None => Ok(EventStream::Disabled),
+            #[cfg(feature = "kafka")]
+            Some(crate::config::StreamBroker::Kafka) => {
+                let producer = ClientConfig::new()
+                    .set("bootstrap.servers", &config.event_stream_servers)
+                    .set("message.timeout.ms", "10000")
+                    .create()?;
+                Ok(EventStream::Kafka {
+                    producer,
+                    topic: config.event_stream_topic.clone(),
+                })
+            }
+            #[cfg(feature = "nats")]
+            Some(crate::config::StreamBroker::Nats) => Ok(EventStream::Nats {
+                client: async_nats::connect(&config.event_stream_servers).await?,
+                subject: config.event_stream_topic.clone(),
+            }),
+            #[allow(unreachable_patterns)]
+            Some(broker) => Err(format!(
+                "EVENT_STREAM is {}, but Stats was built without the `{}` feature",
+                broker.name(),
+                broker.name()
+            )
+            .into()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, EventStream::Disabled)
+    }
+
+    // Without a broker feature there's nothing to publish to
+    #[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(unused_variables))]
+    pub async fn publish(&self, events: &[NewEvent]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let messages = events
+            .iter()
+            .map(|event| Ok((event.collector_id.as_str(), serde_json::to_vec(event)?)))
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+
+        match self {
+            EventStream::Disabled => {}
+            // Keyed by collector, so a visitor's events stay in order on one partition
+            #[cfg(feature = "kafka")]
+            EventStream::Kafka { producer, topic } => {
+                for (key, payload) in &messages {
+                    let record = FutureRecord::to(topic).key(*key).payload(payload);
+                    producer
+                        .send(record, Duration::from_secs(0))
+                        .await
+                        .map_err(|(e, _)| e)?;
+                }
+            }
+            #[cfg(feature = "nats")]
+            EventStream::Nats { client, subject } => {
+                for (_, payload) in messages {
+                    client.publish(subject.clone(), payload.into()).await?;
+                }
+                client.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use crate::config::{Config, SharedConfig};
+use crate::db::DbPool;
+use crate::models::NewEvent;
+use crate::utils::ingest::{Ingest, RawEvent, Rejected};
+use actix_web::web;
+use log::{error, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Sender;
+
+// Large enough for any datagram
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+// Parses one `collector_id|name|url` line and checks it like an event sent
+// to /collect; a name that isn't allowed drops the line or becomes `other`.
+fn parse_line(
+    config: &Config,
+    ingest: &Ingest,
+    peer: SocketAddr,
+    line: &str,
+) -> Result<NewEvent, Rejected> {
+    let mut fields = line.splitn(3, '|').map(str::trim);
+    let (Some(collector_id), Some(name), Some(url)) = (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(Rejected::Invalid(
+            "expected collector_id|name|url".to_string(),
+        ));
+    };
+    if collector_id.is_empty() || name.is_empty() || url.is_empty() {
+        return Err(Rejected::Invalid(
+            "collector_id, name and url can't be empty".to_string(),
+        ));
+    }
+    let raw = RawEvent {
+        url,
+        referrer: None,
+        name,
+        collector_id,
+        status: None,
+        value: None,
+        currency: None,
+        ip: Some(peer.ip()),
+    };
+    ingest.prepare(config, raw)
+}
+
+// Receives datagrams of newline separated `collector_id|name|url` lines,
+// StatsD style, and queues them like events sent to /collect, with the same
+// limits. Nothing is sent back, so bad lines, events over a limit and a full
+// queue are only logged.
+pub async fn listen_udp(
+    address: String,
+    config: web::Data<SharedConfig>,
+    ingest: Arc<Ingest>,
+    pool: DbPool,
+    events_queue: Sender<NewEvent>,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(&address).await?;
+    info!("Listening for UDP events at {}", address);
+
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (length, peer) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Failed to receive UDP event: {}", e);
+                continue;
+            }
+        };
+        let config = config.get();
+        if config.is_blocked(&peer.ip()) {
+            continue;
+        }
+
+        let datagram = String::from_utf8_lossy(&buffer[..length]);
+        for line in datagram.lines().filter(|line| !line.trim().is_empty()) {
+            let event = match parse_line(&config, &ingest, peer, line) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Ignoring UDP event from {}: {}", peer, e);
+                    continue;
+                }
+            };
+            if let Err(e) = ingest.admit(&config, &pool, &event).await {
+                warn!("Ignoring UDP event from {}: {}", peer, e);
+                continue;
+            }
+            if events_queue.try_send(event).is_err() {
+                warn!("Event queue is full, dropping UDP event from {}", peer);
+            }
+        }
+    }
+}
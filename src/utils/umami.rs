@@ -0,0 +1,389 @@
+use crate::models::{Collector, NewEvent};
+use crate::schema::{collectors, events};
+use crate::utils::countries::country_name;
+use crate::utils::rollup::rebuild_rollups;
+use crate::utils::url::host_and_path;
+use chrono::{DateTime, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+use log::error;
+use std::error::Error;
+use tokio_postgres::NoTls;
+use ulid::Ulid;
+
+// Sessions or events read from Umami per query
+const PAGE_SIZE: i64 = 5000;
+
+// Umami ids are UUIDs, Postgres pages start after the smallest one
+const NIL_UUID: &str = "00000000-0000-0000-0000-000000000000";
+
+const SQLITE_SESSIONS: &str = "
+    SELECT s.session_id AS id, w.domain, s.browser, s.os, s.device, s.country, s.city,
+        CAST(s.created_at AS TEXT) AS created_at
+    FROM session s JOIN website w ON w.website_id = s.website_id
+    WHERE s.session_id > ? ORDER BY s.session_id LIMIT ?";
+
+const POSTGRES_SESSIONS: &str = "
+    SELECT s.session_id::text, w.domain, s.browser, s.os, s.device, s.country, s.city,
+        to_char(s.created_at, 'YYYY-MM-DD HH24:MI:SS.US')
+    FROM session s JOIN website w ON w.website_id = s.website_id
+    WHERE s.session_id > $1::uuid ORDER BY s.session_id LIMIT $2";
+
+const SQLITE_EVENTS: &str = "
+    SELECT e.event_id AS id, e.session_id, w.domain, e.url_path, e.url_query,
+        e.referrer_domain, e.referrer_path, e.referrer_query, e.event_type, e.event_name,
+        CAST(e.created_at AS TEXT) AS created_at,
+        CAST(s.created_at AS TEXT) AS session_created_at
+    FROM website_event e JOIN website w ON w.website_id = e.website_id
+    JOIN session s ON s.session_id = e.session_id
+    WHERE e.event_id > ? ORDER BY e.event_id LIMIT ?";
+
+const POSTGRES_EVENTS: &str = "
+    SELECT e.event_id::text, e.session_id::text, w.domain, e.url_path, e.url_query,
+        e.referrer_domain, e.referrer_path, e.referrer_query, e.event_type, e.event_name,
+        to_char(e.created_at, 'YYYY-MM-DD HH24:MI:SS.US'),
+        to_char(s.created_at, 'YYYY-MM-DD HH24:MI:SS.US')
+    FROM website_event e JOIN website w ON w.website_id = e.website_id
+    JOIN session s ON s.session_id = e.session_id
+    WHERE e.event_id > $1::uuid ORDER BY e.event_id LIMIT $2";
+
+#[derive(QueryableByName)]
+struct Session {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    domain: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    browser: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    os: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    device: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    country: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    city: Option<String>,
+    #[diesel(sql_type = Text)]
+    created_at: String,
+}
+
+#[derive(QueryableByName)]
+struct WebsiteEvent {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Text)]
+    session_id: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    domain: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    url_path: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    url_query: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    referrer_domain: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    referrer_path: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    referrer_query: Option<String>,
+    #[diesel(sql_type = Integer)]
+    event_type: i32,
+    #[diesel(sql_type = Nullable<Text>)]
+    event_name: Option<String>,
+    #[diesel(sql_type = Text)]
+    created_at: String,
+    #[diesel(sql_type = Text)]
+    session_created_at: String,
+}
+
+// An Umami v2 database, a SQLite file or a Postgres connection URL
+pub enum Source {
+    Sqlite(SqliteConnection),
+    Postgres(tokio_postgres::Client),
+}
+
+impl Source {
+    pub async fn open(source: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if !(source.starts_with("postgres://") || source.starts_with("postgresql://")) {
+            return Ok(Source::Sqlite(SqliteConnection::establish(source)?));
+        }
+
+        let (client, connection) = tokio_postgres::connect(source, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("Umami database connection failed: {}", e);
+            }
+        });
+        // Read timestamps in UTC, like Stats stores them
+        client.batch_execute("SET TIME ZONE 'UTC'").await?;
+        Ok(Source::Postgres(client))
+    }
+
+    fn first_id(&self) -> String {
+        match self {
+            Source::Sqlite(_) => String::new(),
+            Source::Postgres(_) => NIL_UUID.to_string(),
+        }
+    }
+
+    async fn sessions(
+        &mut self,
+        after: &str,
+    ) -> Result<Vec<Session>, Box<dyn Error + Send + Sync>> {
+        match self {
+            Source::Sqlite(conn) => Ok(diesel::sql_query(SQLITE_SESSIONS)
+                .bind::<Text, _>(after)
+                .bind::<BigInt, _>(PAGE_SIZE)
+                .load(conn)?),
+            Source::Postgres(client) => Ok(client
+                .query(POSTGRES_SESSIONS, &[&after, &PAGE_SIZE])
+                .await?
+                .iter()
+                .map(|row| Session {
+                    id: row.get(0),
+                    domain: row.get(1),
+                    browser: row.get(2),
+                    os: row.get(3),
+                    device: row.get(4),
+                    country: row.get(5),
+                    city: row.get(6),
+                    created_at: row.get(7),
+                })
+                .collect()),
+        }
+    }
+
+    async fn events(
+        &mut self,
+        after: &str,
+    ) -> Result<Vec<WebsiteEvent>, Box<dyn Error + Send + Sync>> {
+        match self {
+            Source::Sqlite(conn) => Ok(diesel::sql_query(SQLITE_EVENTS)
+                .bind::<Text, _>(after)
+                .bind::<BigInt, _>(PAGE_SIZE)
+                .load(conn)?),
+            Source::Postgres(client) => Ok(client
+                .query(POSTGRES_EVENTS, &[&after, &PAGE_SIZE])
+                .await?
+                .iter()
+                .map(|row| WebsiteEvent {
+                    id: row.get(0),
+                    session_id: row.get(1),
+                    domain: row.get(2),
+                    url_path: row.get(3),
+                    url_query: row.get(4),
+                    referrer_domain: row.get(5),
+                    referrer_path: row.get(6),
+                    referrer_query: row.get(7),
+                    event_type: row.get(8),
+                    event_name: row.get(9),
+                    created_at: row.get(10),
+                    session_created_at: row.get(11),
+                })
+                .collect()),
+        }
+    }
+}
+
+// Umami stores timestamps as text, or as milliseconds in SQLite files
+// written by Prisma
+fn parse_timestamp(value: &str) -> Option<NaiveDateTime> {
+    if let Ok(millis) = value.parse::<i64>() {
+        return DateTime::from_timestamp_millis(millis).map(|time| time.naive_utc());
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.naive_utc());
+    }
+    ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+}
+
+// Umami stores the bare domain of a website, Stats the page's origin
+fn origin(domain: Option<&str>) -> String {
+    match domain.filter(|domain| !domain.is_empty()) {
+        Some(domain) if domain.contains("://") => domain.trim_end_matches('/').to_string(),
+        Some(domain) => format!("https://{}", domain.trim_end_matches('/')),
+        None => "unknown".to_string(),
+    }
+}
+
+fn with_query(url: String, query: Option<&str>) -> String {
+    match query.filter(|query| !query.is_empty()) {
+        Some(query) => format!("{}?{}", url, query.trim_start_matches('?')),
+        None => url,
+    }
+}
+
+// Umami names browsers like `detect-browser`, Stats like woothee does for
+// live visitors. Names without a counterpart are kept as they are.
+fn browser_name(browser: &str) -> String {
+    match browser {
+        "chrome" | "crios" | "chromium-webview" => "Chrome",
+        "firefox" | "fxios" => "Firefox",
+        "safari" | "ios" | "ios-webview" => "Safari",
+        "edge" | "edge-chromium" | "edge-ios" => "Edge",
+        "opera" | "opera-mini" => "Opera",
+        "ie" => "Internet Explorer",
+        "yandexbrowser" => "Yandex Browser",
+        "samsung" => "Samsung Internet",
+        "android" => "Android Browser",
+        other => other,
+    }
+    .to_string()
+}
+
+fn os_name(os: &str, device: Option<&str>) -> String {
+    match os {
+        "Mac OS" => "Mac OSX",
+        "iOS" if device == Some("tablet") => "iPad",
+        "iOS" => "iPhone",
+        "Android OS" => "Android",
+        "Chrome OS" => "ChromeOS",
+        other => other,
+    }
+    .to_string()
+}
+
+// Imported sessions get ULIDs like the collectors Stats creates, so they sort
+// by creation time in the sessions list. The random part is taken from the
+// Umami UUID, so importing again gives the same ids.
+fn collector_id(session_id: &str, created_at: &str) -> Option<String> {
+    let uuid = u128::from_str_radix(&session_id.replace('-', ""), 16).ok()?;
+    let millis = u64::try_from(parse_timestamp(created_at)?.and_utc().timestamp_millis()).ok()?;
+    Some(Ulid::from_parts(millis, uuid & ((1 << 80) - 1)).to_string())
+}
+
+fn collector(session: Session) -> Option<Collector> {
+    let country_code = session
+        .country
+        .filter(|code| code.len() == 2)
+        .map(|code| code.to_ascii_uppercase());
+    Some(Collector {
+        timestamp: parse_timestamp(&session.created_at)?,
+        id: collector_id(&session.id, &session.created_at)?,
+        origin: origin(session.domain.as_deref()),
+        country: country_code
+            .as_deref()
+            .and_then(country_name)
+            .unwrap_or("Unknown")
+            .to_string(),
+        city: session
+            .city
+            .filter(|city| !city.is_empty())
+            .unwrap_or_else(|| "Unknown".to_string()),
+        os: session.os.map(|os| os_name(&os, session.device.as_deref())),
+        browser: session.browser.map(|browser| browser_name(&browser)),
+        latitude: None,
+        longitude: None,
+        // Umami keeps ISO subdivision codes, not the names Stats shows
+        region: None,
+        asn: None,
+        as_org: None,
+        country_code,
+        visitor_hash: None,
+        visit_id: None,
+        utm_source: None,
+        utm_campaign: None,
+    })
+}
+
+// Page views become `visit` events and custom events keep their name
+fn event(event: WebsiteEvent) -> Option<NewEvent> {
+    let name = match event.event_type {
+        1 => "visit".to_string(),
+        _ => event.event_name.filter(|name| !name.is_empty())?,
+    };
+    let origin = origin(event.domain.as_deref());
+    let url = with_query(
+        format!("{}{}", origin, event.url_path.as_deref().unwrap_or("/")),
+        event.url_query.as_deref(),
+    );
+    let referrer = event
+        .referrer_domain
+        .filter(|domain| !domain.is_empty())
+        .map(|domain| {
+            with_query(
+                format!(
+                    "https://{}{}",
+                    domain,
+                    event.referrer_path.as_deref().unwrap_or("/")
+                ),
+                event.referrer_query.as_deref(),
+            )
+        });
+    let (host, path) = host_and_path(&url);
+    Some(NewEvent {
+        timestamp: parse_timestamp(&event.created_at)?,
+        id: event.id,
+        url,
+        referrer,
+        name,
+        collector_id: collector_id(&event.session_id, &event.session_created_at)?,
+        status: None,
+        value: None,
+        currency: None,
+        host,
+        path,
+    })
+}
+
+// Copies the sessions and events of an Umami database into Stats, keeping
+// the Umami event ids and deriving visitor ids from the session ids, so
+// running it again skips what was already imported. The
+// rollups are recounted from the oldest imported event afterwards. Returns
+// the number of visitors and events inserted.
+pub async fn import(
+    conn: &mut SqliteConnection,
+    source: &mut Source,
+) -> Result<(usize, usize), Box<dyn Error + Send + Sync>> {
+    let mut visitors = 0;
+    let mut after = source.first_id();
+    loop {
+        let sessions = source.sessions(&after).await?;
+        let Some(last) = sessions.last() else {
+            break;
+        };
+        after = last.id.clone();
+
+        let rows: Vec<Collector> = sessions.into_iter().filter_map(collector).collect();
+        visitors += conn.transaction(|conn| {
+            let mut inserted = 0;
+            for chunk in rows.chunks(1000) {
+                inserted += diesel::insert_or_ignore_into(collectors::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+            QueryResult::Ok(inserted)
+        })?;
+    }
+
+    let mut imported = 0;
+    let mut oldest: Option<NaiveDateTime> = None;
+    let mut after = source.first_id();
+    loop {
+        let page = source.events(&after).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = last.id.clone();
+
+        let rows: Vec<NewEvent> = page.into_iter().filter_map(event).collect();
+        if let Some(first) = rows.iter().map(|row| row.timestamp).min() {
+            oldest = Some(oldest.map_or(first, |oldest| oldest.min(first)));
+        }
+        imported += conn.transaction(|conn| {
+            let mut inserted = 0;
+            for chunk in rows.chunks(1000) {
+                inserted += diesel::insert_or_ignore_into(events::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+            QueryResult::Ok(inserted)
+        })?;
+    }
+
+    if let Some(oldest) = oldest {
+        rebuild_rollups(conn, Some(oldest))?;
+    }
+    Ok((visitors, imported))
+}
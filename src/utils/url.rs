@@ -0,0 +1,106 @@
+use crate::config::Config;
+use url::Url;
+
+// Rewrites the percent-encoding of a path the same way every time:
+// unreserved characters like `~` are decoded and the rest use uppercase hex,
+// so `/%7euser/caf%c3%a9` becomes `/~user/caf%C3%A9`
+fn normalize_percent_encoding(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut normalized = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = match (bytes[i], bytes.get(i + 1..i + 3)) {
+            (b'%', Some(hex)) => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                normalized.push(byte as char);
+                i += 3;
+            }
+            Some(byte) => {
+                normalized.push_str(&format!("%{:02X}", byte));
+                i += 3;
+            }
+            None => {
+                normalized.push(bytes[i] as char);
+                i += 1;
+            }
+        }
+    }
+    normalized
+}
+
+// Remove query parameters, except those KEEP_QUERY_PARAMS lists for the
+// site, and trailing slashes from the URL. Parsing turns internationalized
+// hosts into punycode, so `münchen.example` and `xn--mnchen-3ya.example`
+// are recorded as the same site.
+pub fn clean_url(raw_url: &str, config: &Config) -> String {
+    match Url::parse(raw_url) {
+        Ok(mut url) => {
+            let kept: Vec<(String, String)> = match config.kept_query_params(&url) {
+                Some(names) => url
+                    .query_pairs()
+                    .filter(|(name, _)| names.contains(name.as_ref()))
+                    .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                    .collect(),
+                None => Vec::new(),
+            };
+            let mut path = normalize_percent_encoding(url.path());
+            if kept.is_empty() {
+                url.set_query(None);
+            } else {
+                url.query_pairs_mut().clear().extend_pairs(kept);
+                // `/blog/?page=2` is the same page as `/blog?page=2`
+                path = path.trim_end_matches('/').to_string();
+            }
+            url.set_path(&path);
+            let mut url_str = url.to_string();
+            // Remove trailing slash(es)
+            url_str = url_str.trim_end_matches('/').to_string();
+            url_str
+        }
+        Err(_) => {
+            // Also remove trailing slash(es) if URL parsing fails
+            raw_url.trim_end_matches('/').to_string()
+        }
+    }
+}
+
+// The host (with a non-default port) and path of a URL, stored next to it
+// so summaries can filter by site or compare paths across sites. None for
+// URLs that don't parse or have no host.
+pub fn host_and_path(raw_url: &str) -> (Option<String>, Option<String>) {
+    let Ok(url) = Url::parse(raw_url) else {
+        return (None, None);
+    };
+    let Some(host) = url.host_str() else {
+        return (None, None);
+    };
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = match url.path().trim_end_matches('/') {
+        "" => "/".to_string(),
+        path => path.to_string(),
+    };
+    (Some(host), Some(path))
+}
+
+// The `utm_source` and `utm_campaign` of a URL, which clean_url strips.
+// None when it has neither.
+pub fn campaign(raw_url: &str) -> Option<(Option<String>, Option<String>)> {
+    let url = Url::parse(raw_url).ok()?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, value)| key == name && !value.is_empty())
+            .map(|(_, value)| value.into_owned())
+    };
+    match (param("utm_source"), param("utm_campaign")) {
+        (None, None) => None,
+        campaign => Some(campaign),
+    }
+}
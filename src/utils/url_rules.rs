@@ -0,0 +1,69 @@
+use crate::models::UrlRule;
+use crate::schema::url_rules;
+use diesel::prelude::*;
+use log::warn;
+use regex::Regex;
+use std::sync::RwLock;
+use url::Url;
+
+// The url rules, compiled once and reloaded whenever they change, so ids
+// and other dynamic path segments are folded into one url before an event
+// is stored, e.g. `/users/12345/profile` into `/users/:id/profile`
+pub struct UrlRules {
+    rules: RwLock<Vec<(Regex, String)>>,
+}
+
+impl UrlRules {
+    pub fn new() -> Self {
+        UrlRules {
+            rules: RwLock::new(Vec::new()),
+        }
+    }
+
+    // Replaces the compiled rules with the stored ones. Returns how many
+    // there are.
+    pub fn load(&self, conn: &mut SqliteConnection) -> QueryResult<usize> {
+        let stored = url_rules::table
+            .order(url_rules::created_at.asc())
+            .load::<UrlRule>(conn)?;
+
+        let rules: Vec<(Regex, String)> = stored
+            .into_iter()
+            .filter_map(|rule| match Regex::new(&rule.pattern) {
+                Ok(regex) => Some((regex, rule.replacement)),
+                Err(e) => {
+                    warn!("Skipping url rule {}: {}", rule.id, e);
+                    None
+                }
+            })
+            .collect();
+        let count = rules.len();
+        *self.rules.write().unwrap() = rules;
+        Ok(count)
+    }
+
+    // Rewrites the path of `raw_url` with every rule in turn. The host is
+    // left alone, so a pattern like `^/users/\d+` matches on every site.
+    pub fn apply(&self, raw_url: &str) -> String {
+        let rules = self.rules.read().unwrap();
+        if rules.is_empty() {
+            return raw_url.to_string();
+        }
+
+        let rewrite = |path: &str| {
+            rules
+                .iter()
+                .fold(path.to_string(), |path, (regex, replacement)| {
+                    regex.replace_all(&path, replacement.as_str()).into_owned()
+                })
+        };
+        match Url::parse(raw_url) {
+            Ok(mut url) if url.has_host() => {
+                let path = rewrite(url.path());
+                url.set_path(&path);
+                url.to_string().trim_end_matches('/').to_string()
+            }
+            _ => rewrite(raw_url),
+        }
+    }
+}
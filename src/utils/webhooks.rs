@@ -0,0 +1,205 @@
+use crate::db::{blocking, DbPool};
+use crate::models::Webhook;
+use crate::schema::webhooks;
+use crate::utils::rollup::{CountedEvents, Level};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use log::{error, info};
+use serde_json::json;
+use std::error::Error;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rule {
+    // At least `threshold` events in the window
+    Spike,
+    // No events at all in the window
+    Zero,
+    // At least `threshold` events in total
+    Milestone,
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> Option<Self> {
+        match rule {
+            "spike" => Some(Rule::Spike),
+            "zero" => Some(Rule::Zero),
+            "milestone" => Some(Rule::Milestone),
+            _ => None,
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct Total {
+    #[diesel(sql_type = BigInt)]
+    total: i64,
+}
+
+// Events in `(start, end]`, without measurements, like the summaries count them
+pub fn count_events(
+    conn: &mut SqliteConnection,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> QueryResult<i64> {
+    let counted = CountedEvents::new(conn, Some(Level::Daily), start, end)?;
+    let query = diesel::sql_query(format!(
+        "SELECT CAST(COALESCE(SUM(count), 0) AS BIGINT) AS total FROM ({})",
+        counted.sql("")
+    ))
+    .into_boxed();
+    let total: Total = counted.bind(query).get_result(conn)?;
+    Ok(total.total)
+}
+
+// The count the webhook's rule looks at, and whether the rule matches it
+fn check(
+    conn: &mut SqliteConnection,
+    webhook: &Webhook,
+    now: NaiveDateTime,
+) -> QueryResult<Option<(i64, bool)>> {
+    let Some(rule) = Rule::parse(&webhook.rule) else {
+        return Ok(None);
+    };
+    let window_start = now - Duration::minutes(webhook.window_minutes as i64);
+    Ok(Some(match rule {
+        Rule::Spike => {
+            let count = count_events(conn, window_start, now)?;
+            (count, count >= webhook.threshold)
+        }
+        Rule::Zero => {
+            let count = count_events(conn, window_start, now)?;
+            (count, count == 0)
+        }
+        Rule::Milestone => {
+            let count = count_events(conn, DateTime::UNIX_EPOCH.naive_utc(), now)?;
+            (count, count >= webhook.threshold)
+        }
+    }))
+}
+
+fn message(webhook: &Webhook, count: i64) -> String {
+    match Rule::parse(&webhook.rule) {
+        Some(Rule::Spike) => format!(
+            "Traffic spike: {} events in the last {} minutes",
+            count, webhook.window_minutes
+        ),
+        Some(Rule::Zero) => format!("No events in the last {} minutes", webhook.window_minutes),
+        _ => format!("Milestone reached: {} events", count),
+    }
+}
+
+fn payload(webhook: &Webhook, count: i64, now: NaiveDateTime) -> serde_json::Value {
+    json!({
+        "webhook_id": webhook.id,
+        "rule": webhook.rule,
+        "threshold": webhook.threshold,
+        "window_minutes": webhook.window_minutes,
+        "count": count,
+        "fired_at": now,
+        "text": message(webhook, count),
+    })
+}
+
+// Where a webhook or alert rule stands after a check
+pub struct Transition {
+    pub triggered: bool,
+    pub last_fired_at: Option<NaiveDateTime>,
+    // Whether a notification went out
+    pub sent: bool,
+}
+
+// Moves a webhook or alert rule to whether a check found it `matches`. Its
+// `url` gets the payload when it starts matching, and when it stops if it
+// `resolves`. Payloads carry a `text`, which is what Slack and compatible
+// chat webhooks show. A failed delivery is an error and leaves the rule as
+// it was, so the next check sends it again.
+pub async fn transition(
+    client: &reqwest::Client,
+    url: &str,
+    (triggered, last_fired_at): (bool, Option<NaiveDateTime>),
+    matches: bool,
+    resolves: bool,
+    now: NaiveDateTime,
+    payload: impl FnOnce() -> serde_json::Value,
+) -> Result<Transition, Box<dyn Error + Send + Sync>> {
+    let notify = matches != triggered && (matches || resolves);
+    if notify {
+        client
+            .post(url)
+            .json(&payload())
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(Transition {
+        triggered: matches,
+        last_fired_at: if notify && matches {
+            Some(now)
+        } else {
+            last_fired_at
+        },
+        sent: notify,
+    })
+}
+
+// Checks every webhook's rule against the current counts and POSTs to the
+// ones that started matching since the last check. A failed delivery leaves
+// the webhook untriggered, so the next check sends it again. Returns the
+// number of webhooks fired.
+pub async fn fire_webhooks(
+    pool: &DbPool,
+    client: &reqwest::Client,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let now = Utc::now().naive_utc();
+    let checked = blocking(pool, move |conn| {
+        let all: Vec<Webhook> = webhooks::table.load(conn)?;
+        let mut checked = Vec::new();
+        for webhook in all {
+            if let Some((count, matches)) = check(conn, &webhook, now)? {
+                checked.push((webhook, count, matches));
+            }
+        }
+        Ok(checked)
+    })
+    .await?;
+
+    let mut fired = 0;
+    let mut failed = 0;
+    for (webhook, count, matches) in checked {
+        let state = (webhook.triggered, webhook.last_fired_at);
+        let moved = transition(client, &webhook.url, state, matches, false, now, || {
+            payload(&webhook, count, now)
+        })
+        .await;
+        let moved = match moved {
+            Ok(moved) => moved,
+            Err(e) => {
+                error!("Webhook {} to {} failed: {}", webhook.id, webhook.url, e);
+                failed += 1;
+                continue;
+            }
+        };
+        if moved.sent {
+            info!("Fired webhook {} ({})", webhook.id, webhook.rule);
+            fired += 1;
+        }
+        if moved.triggered != webhook.triggered {
+            blocking(pool, move |conn| {
+                diesel::update(webhooks::table.find(webhook.id))
+                    .set((
+                        webhooks::triggered.eq(moved.triggered),
+                        webhooks::last_fired_at.eq(moved.last_fired_at),
+                    ))
+                    .execute(conn)?;
+                Ok(())
+            })
+            .await?;
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} webhooks could not be delivered", failed).into());
+    }
+    Ok(fired)
+}
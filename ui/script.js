@@ -71,9 +71,7 @@ function mapHourlyEventsToLocalTime(events) {
   return customDayHours;
 }
 
-async function renderHourlySummary() {
-  const response = await fetch("/summary/hourly");
-  const hourlyEvents = await response.json();
+function renderHourlySummary(hourlyEvents) {
   const hourlyDiv = document.getElementById("hourly");
   const localEvents = mapHourlyEventsToLocalTime(hourlyEvents);
   const maxCount = Math.max(...localEvents.map((event) => event.count));
@@ -106,9 +104,7 @@ async function renderHourlySummary() {
               `;
 }
 
-async function renderUrls() {
-  const response = await fetch("/summary/urls");
-  const urls = await response.json();
+function renderUrls(urls) {
   const urlsDiv = document.getElementById("urls");
 
   urlsDiv.innerHTML = `
@@ -150,9 +146,7 @@ async function renderUrls() {
             `;
 }
 
-async function renderBrowsers() {
-  const response = await fetch("/summary/osbrowsers");
-  const urls = await response.json();
+function renderBrowsers(urls) {
   const urlsDiv = document.getElementById("browsers");
 
   urlsDiv.innerHTML = `
@@ -181,9 +175,7 @@ async function renderBrowsers() {
             `;
 }
 
-async function renderReferrers() {
-  const response = await fetch("/summary/referrers");
-  const urls = await response.json();
+function renderReferrers(urls) {
   const urlsDiv = document.getElementById("referrers");
 
   urlsDiv.innerHTML = `
@@ -248,7 +240,7 @@ function prettyPrintTimeDifference(utcTimestamp1, utcTimestamp2) {
 
 async function renderSessions() {
   const response = await fetch("/sessions");
-  const sessions = await response.json();
+  const { sessions } = await response.json();
   const sessionsDiv = document.getElementById("sessions");
 
   sessionsDiv.innerHTML = `
@@ -316,9 +308,7 @@ async function renderSessions() {
             `;
 }
 
-async function renderSummary() {
-  const summaryResponse = await fetch("/summary");
-  const summary = await summaryResponse.json();
+function renderSummary(summary) {
   Object.keys(summary).forEach((key) => {
     const element = document.getElementById(key);
     if (element) {
@@ -327,10 +317,14 @@ async function renderSummary() {
   });
 }
 
-const renderSinglePercentageChange = (element, percentage) => {
+const renderSinglePercentageChange = (element, { change: percentage }) => {
   const ele = document.getElementById(element);
   let text = "-";
-  if (percentage < 0) {
+  if (percentage === null) {
+    // nothing to compare with in the previous period
+    ele.classList.remove("pos");
+    ele.classList.remove("neg");
+  } else if (percentage < 0) {
     ele.classList.remove("pos");
     ele.classList.add("neg");
     text = `↓${Math.abs(Math.round(percentage * 10) / 10)}%`;
@@ -346,10 +340,7 @@ const renderSinglePercentageChange = (element, percentage) => {
   ele.innerText = text;
 };
 
-async function renderPercentageChanges() {
-  const percentagesResponse = await fetch("/summary/percentages");
-  const percentages = await percentagesResponse.json();
-
+function renderPercentageChanges(percentages) {
   renderSinglePercentageChange("pDay", percentages.day);
   renderSinglePercentageChange("pWeek", percentages.week);
   renderSinglePercentageChange("pMonth", percentages.month);
@@ -389,9 +380,7 @@ function convertUtcToLocal(utcDay, utcHour, offset) {
   return { localDay, localHour };
 }
 
-async function renderWeeklyHeatmap() {
-  const response = await fetch("/summary/weekly");
-  const utcEventCounts = await response.json();
+function renderWeeklyHeatmap(utcEventCounts) {
   const heatmapDiv = document.getElementById("weekly");
 
   // Local timezone offset in hours
@@ -477,19 +466,40 @@ async function renderGlobe() {
 }
 
 // Fetch and render all analytics
+// The summaries come from one request, add `osbrowsers` to `include` with
+// renderBrowsers
+async function renderOverview() {
+  const response = await fetch(
+    "/summary/overview?include=summary,percentages,hourly,weekly,urls,referrers",
+  );
+  const overview = await response.json();
+
+  renderSummary(overview.summary);
+  renderPercentageChanges(overview.percentages);
+  renderHourlySummary(overview.hourly);
+  renderUrls(overview.urls);
+  // renderBrowsers(overview.osbrowsers);
+  renderReferrers(overview.referrers);
+  renderWeeklyHeatmap(overview.weekly);
+}
+
+// Settings of the server, fetched once
+const dashboardConfig = fetch("/api/config").then((response) =>
+  response.json(),
+);
+
 async function fetchAndRenderAnalytics() {
   try {
+    const config = await dashboardConfig;
+    // Without location lookups every visitor is in "Unknown"
+    const globe = document.querySelector(".globecontainer");
+    globe.style.display = config.features.geoip ? "" : "none";
+
     await Promise.all([
       renderHeader(),
-      renderPercentageChanges(),
       renderSessions(),
-      renderSummary(),
-      renderHourlySummary(),
-      renderUrls(),
-      // renderBrowsers(),
-      renderReferrers(),
-      renderWeeklyHeatmap(),
-      renderGlobe(),
+      renderOverview(),
+      config.features.geoip && renderGlobe(),
     ]);
   } catch (error) {
     console.error("Error fetching analytics:", error);